@@ -0,0 +1,2 @@
+ALTER TABLE mails DROP COLUMN delivered;
+ALTER TABLE mails DROP COLUMN size;
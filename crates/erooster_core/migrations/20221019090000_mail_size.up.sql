@@ -0,0 +1,2 @@
+ALTER TABLE mails ADD COLUMN IF NOT EXISTS size BIGINT;
+ALTER TABLE mails ADD COLUMN IF NOT EXISTS delivered BIGINT;
@@ -0,0 +1 @@
+DROP TABLE mailboxes;
\ No newline at end of file
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS mailboxes (
+    id BIGSERIAL NOT NULL PRIMARY KEY UNIQUE,
+    username TEXT NOT NULL,
+    mailbox TEXT NOT NULL,
+    subscribed BOOLEAN NOT NULL DEFAULT FALSE,
+    attributes TEXT[] NOT NULL DEFAULT '{}',
+    UNIQUE (username, mailbox)
+);
@@ -0,0 +1 @@
+DROP TABLE mailbox_metadata;
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Server entries use the empty mailbox name. Server /shared entries belong to no user and use the empty username.
+CREATE TABLE IF NOT EXISTS mailbox_metadata (
+    username TEXT NOT NULL,
+    mailbox TEXT NOT NULL,
+    entry TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (username, mailbox, entry)
+);
@@ -0,0 +1,7 @@
+ALTER TABLE mailboxes DROP COLUMN mailbox_id;
+DROP INDEX mails_username_message_id;
+ALTER TABLE mails DROP COLUMN thread_id;
+ALTER TABLE mails DROP COLUMN message_id;
+ALTER TABLE mails DROP COLUMN username;
+ALTER TABLE mails DROP COLUMN email_id;
+DROP SEQUENCE object_ids;
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE SEQUENCE IF NOT EXISTS object_ids;
+ALTER TABLE mails ADD COLUMN IF NOT EXISTS email_id TEXT NOT NULL UNIQUE DEFAULT ('E' || nextval('object_ids'));
+ALTER TABLE mails ADD COLUMN IF NOT EXISTS username TEXT;
+ALTER TABLE mails ADD COLUMN IF NOT EXISTS message_id TEXT;
+ALTER TABLE mails ADD COLUMN IF NOT EXISTS thread_id TEXT;
+UPDATE mails SET thread_id = 'T' || nextval('object_ids') WHERE thread_id IS NULL;
+ALTER TABLE mails ALTER COLUMN thread_id SET NOT NULL;
+CREATE INDEX IF NOT EXISTS mails_username_message_id ON mails (username, message_id);
+ALTER TABLE mailboxes ADD COLUMN IF NOT EXISTS mailbox_id TEXT NOT NULL UNIQUE DEFAULT ('M' || nextval('object_ids'));
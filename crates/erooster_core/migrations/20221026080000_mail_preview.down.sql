@@ -0,0 +1 @@
+ALTER TABLE mails DROP COLUMN preview;
\ No newline at end of file
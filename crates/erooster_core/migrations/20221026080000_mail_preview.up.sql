@@ -0,0 +1 @@
+ALTER TABLE mails ADD COLUMN IF NOT EXISTS preview TEXT;
@@ -0,0 +1 @@
+DROP TABLE aliases;
\ No newline at end of file
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS aliases (
+    address TEXT NOT NULL PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS aliases_username ON aliases (username);
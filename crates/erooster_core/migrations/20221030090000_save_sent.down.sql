@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN save_sent;
\ No newline at end of file
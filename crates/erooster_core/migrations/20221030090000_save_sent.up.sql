@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS save_sent BOOLEAN NOT NULL DEFAULT FALSE;
\ No newline at end of file
@@ -0,0 +1 @@
+DROP INDEX mails_maildir_id;
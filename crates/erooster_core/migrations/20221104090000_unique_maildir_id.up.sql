@@ -0,0 +1,3 @@
+-- Every message file has exactly one row so all sessions agree on its uid
+DELETE FROM mails a USING mails b WHERE a.maildir_id = b.maildir_id AND a.id > b.id;
+CREATE UNIQUE INDEX IF NOT EXISTS mails_maildir_id ON mails (maildir_id);
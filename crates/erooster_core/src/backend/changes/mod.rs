@@ -0,0 +1,82 @@
+use std::{collections::HashMap, sync::Mutex};
+use tokio::sync::broadcast;
+use tracing::{debug, error, instrument};
+
+/// How many changes a slow session may lag behind before it misses some
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// The kind of change that happened to a mailbox
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// A message was added to the mailbox
+    MessageNew,
+    /// A message was removed from the mailbox
+    MessageExpunge,
+    /// The flags of a message changed
+    FlagChange,
+    /// The mailbox was created
+    MailboxCreated,
+    /// The mailbox was deleted
+    MailboxDeleted,
+    /// The mailbox got renamed. Contains the old name of the mailbox.
+    MailboxRenamed(String),
+    /// The mailbox got subscribed or unsubscribed
+    SubscriptionChange,
+}
+
+/// A change to one of the mailboxes of a user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxChange {
+    /// The imap name of the mailbox
+    pub mailbox: String,
+    /// What happened
+    pub kind: ChangeKind,
+}
+
+/// Per user broadcast streams of mailbox changes.
+///
+/// Every mutation of the storage gets published here so that all sessions of a user can be told about it.
+#[derive(Debug, Default)]
+pub struct ChangeStreams {
+    senders: Mutex<HashMap<String, broadcast::Sender<MailboxChange>>>,
+}
+
+impl ChangeStreams {
+    /// Create a new set of change streams
+    #[must_use]
+    pub fn new() -> Self {
+        ChangeStreams::default()
+    }
+
+    /// Get a receiver for all future changes of the user
+    #[instrument(skip(self))]
+    pub fn subscribe(&self, username: &str) -> broadcast::Receiver<MailboxChange> {
+        let mut senders = match self.senders.lock() {
+            Ok(senders) => senders,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        senders
+            .entry(username.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Tell all sessions of the user about a change
+    #[instrument(skip(self))]
+    pub fn publish(&self, username: &str, change: MailboxChange) {
+        let mut senders = match self.senders.lock() {
+            Ok(senders) => senders,
+            Err(poisoned) => {
+                error!("[Changes] Change streams lock was poisoned");
+                poisoned.into_inner()
+            }
+        };
+        if let Some(sender) = senders.get(username) {
+            if sender.send(change).is_err() {
+                // Nobody is listening anymore
+                debug!("[Changes] Dropping change stream of {}", username);
+                senders.remove(username);
+            }
+        }
+    }
+}
@@ -37,6 +37,26 @@ pub trait Database<S: sqlx::Database> {
 
     /// Adds a new user without password
     async fn add_user(&self, username: &str) -> color_eyre::eyre::Result<()>;
+
+    /// Lets the user send mail using another address
+    async fn add_alias(&self, username: &str, alias: &str) -> color_eyre::eyre::Result<()>;
+
+    /// The addresses the user may send mail as. These are the username and the aliases of the user.
+    async fn sender_addresses(&self, username: &str) -> color_eyre::eyre::Result<Vec<String>>;
+
+    /// The user whose mailbox receives mail for the address. The address is either the username
+    /// itself or one of the aliases of the user.
+    async fn mailbox_owner(&self, address: &str) -> color_eyre::eyre::Result<Option<String>>;
+
+    /// Whether a copy of the mail the user submits gets saved in the Sent mailbox
+    async fn saves_sent_mail(&self, username: &str) -> color_eyre::eyre::Result<bool>;
+
+    /// Turns saving a copy of submitted mail on or off for the user
+    async fn set_save_sent_mail(
+        &self,
+        username: &str,
+        enabled: bool,
+    ) -> color_eyre::eyre::Result<()>;
 }
 
 /// Get a postgres database connection pool and the higher level wrapper
@@ -91,6 +91,64 @@ impl Database<sqlx::Postgres> for Postgres {
         Ok(())
     }
 
+    #[instrument(skip(self, username, alias))]
+    async fn add_alias(&self, username: &str, alias: &str) -> color_eyre::eyre::Result<()> {
+        sqlx::query("INSERT INTO aliases (address, username) VALUES ($1, $2)")
+            .bind(alias.to_lowercase())
+            .bind(username)
+            .execute(self.get_pool())
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self, username))]
+    async fn sender_addresses(&self, username: &str) -> color_eyre::eyre::Result<Vec<String>> {
+        let aliases: Vec<(String,)> =
+            sqlx::query_as("SELECT address FROM aliases WHERE username = $1")
+                .bind(username)
+                .fetch_all(self.get_pool())
+                .await?;
+        Ok(std::iter::once(username.to_lowercase())
+            .chain(aliases.into_iter().map(|(address,)| address))
+            .collect())
+    }
+
+    #[instrument(skip(self, address))]
+    async fn mailbox_owner(&self, address: &str) -> color_eyre::eyre::Result<Option<String>> {
+        let owner: Option<(String,)> = sqlx::query_as(
+            "SELECT username FROM users WHERE username = $1 \
+             UNION SELECT username FROM aliases WHERE address = $1",
+        )
+        .bind(address.to_lowercase())
+        .fetch_optional(self.get_pool())
+        .await?;
+        Ok(owner.map(|(username,)| username))
+    }
+
+    #[instrument(skip(self, username))]
+    async fn saves_sent_mail(&self, username: &str) -> color_eyre::eyre::Result<bool> {
+        let save_sent: Option<(bool,)> =
+            sqlx::query_as("SELECT save_sent FROM users WHERE username = $1")
+                .bind(username)
+                .fetch_optional(self.get_pool())
+                .await?;
+        Ok(save_sent.map_or(false, |(save_sent,)| save_sent))
+    }
+
+    #[instrument(skip(self, username, enabled))]
+    async fn set_save_sent_mail(
+        &self,
+        username: &str,
+        enabled: bool,
+    ) -> color_eyre::eyre::Result<()> {
+        sqlx::query("UPDATE users SET save_sent = $1 WHERE username = $2")
+            .bind(enabled)
+            .bind(username)
+            .execute(self.get_pool())
+            .await?;
+        Ok(())
+    }
+
     #[instrument(skip(self, username))]
     async fn user_exists(&self, username: &str) -> bool {
         let exists = sqlx::query("SELECT 1 FROM users WHERE username = $1")
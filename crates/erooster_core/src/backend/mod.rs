@@ -1,5 +1,11 @@
+/// Per user streams of mailbox changes
+pub mod changes;
+
 /// The database logic of the server
 pub mod database;
 
+/// The full-text search index for message contents
+pub mod search;
+
 /// The logic for the mail storages
 pub mod storage;
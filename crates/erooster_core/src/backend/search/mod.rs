@@ -0,0 +1,635 @@
+use crate::config::Config;
+use color_eyre::eyre::eyre;
+use mailparse::{DispositionType, ParsedMail};
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tantivy::{
+    collector::DocSetCollector,
+    directory::MmapDirectory,
+    doc,
+    query::{AllQuery, BooleanQuery, Occur, Query, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, STORED, STRING},
+    tokenizer::{LowerCaser, NgramTokenizer, TextAnalyzer, TokenStream},
+    Document, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyError, Term,
+};
+use tracing::{debug, error, instrument};
+
+/// The name of the folder inside of the users storage directory holding the index.
+///
+/// This intentionally does not start with a dot so that it is never picked up as a maildir subfolder.
+pub const SEARCH_INDEX_FOLDER: &str = "erooster_search_index";
+
+/// The name of the tokenizer the text fields are indexed with
+const TRIGRAMS: &str = "trigrams";
+
+/// Memory budget for a single index writer
+const WRITER_HEAP_SIZE: usize = 15_000_000;
+
+/// How long a change waits for another writer of the same index, like a rebuild, to finish
+const WRITER_WAIT: Duration = Duration::from_secs(30);
+
+/// How often a waiting change checks whether the index is free again
+const WRITER_RETRY: Duration = Duration::from_millis(25);
+
+/// How many messages a rebuild indexes before it lets waiting changes in
+const REBUILD_BATCH: usize = 500;
+
+/// The parts of a message a text search may be run against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SearchField {
+    /// The decoded text parts and the attachment filenames
+    Body,
+    /// The headers, the decoded text parts and the attachment filenames
+    Text,
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    maildir_id: Field,
+    mailbox: Field,
+    headers: Field,
+    body: Field,
+    filenames: Field,
+}
+
+/// A per user index of the message contents used to narrow down text based searches.
+///
+/// The texts are indexed as lowercase trigrams. A message containing the searched string
+/// contains all of its trigrams, so the index finds every match of the substring search of
+/// RFC 9051. It may find messages containing the trigrams in other places as well, which
+/// [`text_matches`] sorts out.
+///
+/// Writers are only open while changing the index and every change is committed before it
+/// returns. Nothing gets lost if the server stops and `eroosterctl rebuild-search-index` works
+/// while the server is running.
+/// All work on the index runs on the blocking thread pool as it does disk IO.
+#[derive(Clone)]
+pub struct SearchIndex {
+    config: Arc<Config>,
+    schema: Schema,
+    fields: Fields,
+}
+
+impl SearchIndex {
+    /// Create a new search index handler
+    #[must_use]
+    #[instrument(skip(config))]
+    pub fn new(config: Arc<Config>) -> Self {
+        let text = TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer(TRIGRAMS)
+                .set_index_option(IndexRecordOption::Basic),
+        );
+        let mut schema_builder = Schema::builder();
+        let fields = Fields {
+            maildir_id: schema_builder.add_text_field("maildir_id", STRING | STORED),
+            mailbox: schema_builder.add_text_field("mailbox", STRING),
+            headers: schema_builder.add_text_field("headers", text.clone()),
+            body: schema_builder.add_text_field("body", text.clone()),
+            filenames: schema_builder.add_text_field("filenames", text),
+        };
+        SearchIndex {
+            config,
+            schema: schema_builder.build(),
+            fields,
+        }
+    }
+
+    /// The on disk location of the index of a user
+    #[must_use]
+    pub fn index_path(&self, username: &str) -> PathBuf {
+        Path::new(&self.config.mail.maildir_folders)
+            .join(username)
+            .join(SEARCH_INDEX_FOLDER)
+    }
+
+    fn open(&self, username: &str) -> color_eyre::eyre::Result<Index> {
+        let index_path = self.index_path(username);
+        std::fs::create_dir_all(&index_path)?;
+        let directory = MmapDirectory::open(&index_path)?;
+        let index = Index::open_or_create(directory, self.schema.clone())?;
+        index.tokenizers().register(TRIGRAMS, trigram_analyzer());
+        Ok(index)
+    }
+
+    fn reader(&self, username: &str) -> color_eyre::eyre::Result<IndexReader> {
+        Ok(self
+            .open(username)?
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?)
+    }
+
+    /// Runs `f` with a writer of the index of the user and commits the changes.
+    ///
+    /// Only one writer may be open at a time, so this waits for other ones to be done.
+    fn write<T>(
+        &self,
+        username: &str,
+        f: impl FnOnce(&mut IndexWriter, &Fields) -> color_eyre::eyre::Result<T>,
+    ) -> color_eyre::eyre::Result<T> {
+        let index = self.open(username)?;
+        let started = Instant::now();
+        let mut writer = loop {
+            match index.writer_with_num_threads(1, WRITER_HEAP_SIZE) {
+                Ok(writer) => break writer,
+                Err(TantivyError::LockFailure(..)) if started.elapsed() < WRITER_WAIT => {
+                    std::thread::sleep(WRITER_RETRY);
+                }
+                Err(TantivyError::LockFailure(..)) => {
+                    return Err(eyre!(
+                        "The search index of {} is busy for too long",
+                        username
+                    ));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let result = f(&mut writer, &self.fields)?;
+        writer.commit()?;
+        writer.wait_merging_threads()?;
+        Ok(result)
+    }
+
+    /// Runs `f` on the blocking thread pool
+    async fn blocking<T, F>(&self, f: F) -> color_eyre::eyre::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self) -> color_eyre::eyre::Result<T> + Send + 'static,
+    {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || f(&this)).await?
+    }
+
+    /// Adds a message to the index of the user
+    #[instrument(skip(self, data))]
+    pub async fn add_mail(
+        &self,
+        username: &str,
+        mailbox: &str,
+        maildir_id: &str,
+        data: &[u8],
+    ) -> color_eyre::eyre::Result<()> {
+        self.add_mails(
+            username,
+            vec![(mailbox.to_string(), maildir_id.to_string(), data.to_vec())],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Adds many messages to the index of the user.
+    ///
+    /// The messages are given as `(mailbox, maildir_id, data)`. Returns the number of indexed messages.
+    #[instrument(skip(self, mails))]
+    pub async fn add_mails(
+        &self,
+        username: &str,
+        mails: Vec<(String, String, Vec<u8>)>,
+    ) -> color_eyre::eyre::Result<usize> {
+        if mails.is_empty() {
+            return Ok(0);
+        }
+        let username = username.to_string();
+        self.blocking(move |this| {
+            this.write(&username, |writer, fields| {
+                let mut count = 0;
+                for (mailbox, maildir_id, data) in mails {
+                    if this.replace_document(writer, fields, &mailbox, &maildir_id, &data)? {
+                        count += 1;
+                    }
+                }
+                Ok(count)
+            })
+        })
+        .await
+    }
+
+    /// Indexes the message in place of the one with the same maildir id.
+    ///
+    /// Returns false if the message could not be parsed.
+    fn replace_document(
+        &self,
+        writer: &mut IndexWriter,
+        fields: &Fields,
+        mailbox: &str,
+        maildir_id: &str,
+        data: &[u8],
+    ) -> color_eyre::eyre::Result<bool> {
+        let document = match self.document(mailbox, maildir_id, data) {
+            Ok(document) => document,
+            Err(e) => {
+                error!("[Search] Failed to index message {}: {}", maildir_id, e);
+                return Ok(false);
+            }
+        };
+        // Make sure we never have the same message twice in the index
+        writer.delete_term(Term::from_field_text(fields.maildir_id, maildir_id));
+        writer.add_document(document)?;
+        Ok(true)
+    }
+
+    /// Indexes all messages of the user again.
+    ///
+    /// The messages are given as `(mailbox, maildir_id, data)`. Returns the number of indexed messages.
+    /// They are indexed in batches so changes of a running server don't have to wait for all of them.
+    /// Afterwards every message not returned by `current` anymore is removed. It is called while
+    /// no other changes can happen, so messages delivered during the rebuild are kept.
+    #[instrument(skip(self, mails, current))]
+    pub async fn rebuild<I, C>(
+        &self,
+        username: &str,
+        mails: I,
+        current: C,
+    ) -> color_eyre::eyre::Result<usize>
+    where
+        I: IntoIterator<Item = (String, String, Vec<u8>)> + Send + 'static,
+        I::IntoIter: Send,
+        C: FnOnce() -> color_eyre::eyre::Result<HashSet<String>> + Send + 'static,
+    {
+        let username = username.to_string();
+        self.blocking(move |this| {
+            let mut mails = mails.into_iter().peekable();
+            let mut count = 0;
+            while mails.peek().is_some() {
+                count += this.write(&username, |writer, fields| {
+                    let mut count = 0;
+                    for (mailbox, maildir_id, data) in mails.by_ref().take(REBUILD_BATCH) {
+                        if this.replace_document(writer, fields, &mailbox, &maildir_id, &data)? {
+                            count += 1;
+                        }
+                    }
+                    Ok(count)
+                })?;
+                // Gives waiting changes a chance to get the writer
+                std::thread::sleep(WRITER_RETRY * 4);
+            }
+
+            this.write(&username, |writer, fields| {
+                let current = current()?;
+                let reader: IndexReader = writer
+                    .index()
+                    .reader_builder()
+                    .reload_policy(ReloadPolicy::Manual)
+                    .try_into()?;
+                let searcher = reader.searcher();
+                for doc_address in searcher.search(&AllQuery, &DocSetCollector)? {
+                    let document = searcher.doc(doc_address)?;
+                    if let Some(maildir_id) = document
+                        .get_first(fields.maildir_id)
+                        .and_then(|value| value.as_text())
+                    {
+                        if !current.contains(maildir_id) {
+                            writer
+                                .delete_term(Term::from_field_text(fields.maildir_id, maildir_id));
+                        }
+                    }
+                }
+                Ok(())
+            })?;
+            Ok(count)
+        })
+        .await
+    }
+
+    /// Removes messages from the index of the user
+    #[instrument(skip(self, maildir_ids))]
+    pub async fn remove_mails(
+        &self,
+        username: &str,
+        maildir_ids: Vec<String>,
+    ) -> color_eyre::eyre::Result<()> {
+        if maildir_ids.is_empty() {
+            return Ok(());
+        }
+        let username = username.to_string();
+        self.blocking(move |this| {
+            this.write(&username, |writer, fields| {
+                for maildir_id in maildir_ids {
+                    writer.delete_term(Term::from_field_text(fields.maildir_id, &maildir_id));
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    /// Returns the maildir ids of the messages in the mailbox which may match the query.
+    ///
+    /// Every message containing the query is part of the result, but not every message in the
+    /// result contains it. Use [`text_matches`] to check them.
+    /// Returns `None` if the query is too short to use the index, in which case every message
+    /// has to be checked.
+    #[instrument(skip(self))]
+    pub async fn search(
+        &self,
+        username: &str,
+        mailbox: &str,
+        field: SearchField,
+        query: &str,
+    ) -> color_eyre::eyre::Result<Option<HashSet<String>>> {
+        let trigrams = trigrams(query);
+        if trigrams.is_empty() {
+            return Ok(None);
+        }
+        let username = username.to_string();
+        let mailbox = mailbox.to_string();
+        self.blocking(move |this| {
+            let fields = &this.fields;
+            let searched_fields = match field {
+                SearchField::Body => vec![fields.body, fields.filenames],
+                SearchField::Text => vec![fields.headers, fields.body, fields.filenames],
+            };
+            let field_queries: Vec<(Occur, Box<dyn Query>)> = searched_fields
+                .into_iter()
+                .map(|searched_field| {
+                    let trigram_queries: Vec<(Occur, Box<dyn Query>)> = trigrams
+                        .iter()
+                        .map(|trigram| {
+                            let query: Box<dyn Query> = Box::new(TermQuery::new(
+                                Term::from_field_text(searched_field, trigram),
+                                IndexRecordOption::Basic,
+                            ));
+                            (Occur::Must, query)
+                        })
+                        .collect();
+                    let query: Box<dyn Query> = Box::new(BooleanQuery::new(trigram_queries));
+                    (Occur::Should, query)
+                })
+                .collect();
+
+            let query = BooleanQuery::new(vec![
+                (
+                    Occur::Must,
+                    Box::new(TermQuery::new(
+                        Term::from_field_text(fields.mailbox, &mailbox),
+                        IndexRecordOption::Basic,
+                    )) as Box<dyn Query>,
+                ),
+                (Occur::Must, Box::new(BooleanQuery::new(field_queries))),
+            ]);
+
+            let searcher = this.reader(&username)?.searcher();
+            let doc_addresses = searcher.search(&query, &DocSetCollector)?;
+            let mut maildir_ids = HashSet::with_capacity(doc_addresses.len());
+            for doc_address in doc_addresses {
+                let document = searcher.doc(doc_address)?;
+                if let Some(maildir_id) = document
+                    .get_first(fields.maildir_id)
+                    .and_then(|value| value.as_text())
+                {
+                    maildir_ids.insert(maildir_id.to_string());
+                }
+            }
+            debug!("[Search] Index found {} candidates", maildir_ids.len());
+            Ok(Some(maildir_ids))
+        })
+        .await
+    }
+
+    fn document(
+        &self,
+        mailbox: &str,
+        maildir_id: &str,
+        data: &[u8],
+    ) -> color_eyre::eyre::Result<Document> {
+        let parsed = mailparse::parse_mail(data)?;
+        let texts = Texts::new(&parsed);
+        Ok(doc!(
+            self.fields.maildir_id => maildir_id,
+            self.fields.mailbox => mailbox,
+            self.fields.headers => texts.headers,
+            self.fields.body => texts.body,
+            self.fields.filenames => texts.filenames,
+        ))
+    }
+}
+
+/// Whether one of the searched texts of the message contains the query, ignoring case.
+///
+/// This is the substring match RFC 9051 asks for. It checks the candidates of the index and
+/// messages the index can not answer for.
+#[must_use]
+pub fn text_matches(mail: &ParsedMail, field: SearchField, query: &str) -> bool {
+    let query = query.to_lowercase();
+    let texts = Texts::new(mail);
+    let searched_texts = match field {
+        SearchField::Body => vec![texts.body, texts.filenames],
+        SearchField::Text => vec![texts.headers, texts.body, texts.filenames],
+    };
+    searched_texts
+        .iter()
+        .any(|text| text.to_lowercase().contains(&query))
+}
+
+/// Splits texts into lowercase trigrams. Texts shorter than three characters have none.
+fn trigram_analyzer() -> TextAnalyzer {
+    TextAnalyzer::from(NgramTokenizer::new(3, 3, false)).filter(LowerCaser)
+}
+
+/// The distinct trigrams of the text the same way the text fields of the index have them
+fn trigrams(text: &str) -> BTreeSet<String> {
+    let mut trigrams = BTreeSet::new();
+    trigram_analyzer().token_stream(text).process(&mut |token| {
+        trigrams.insert(token.text.clone());
+    });
+    trigrams
+}
+
+/// The searchable texts of a message
+struct Texts {
+    headers: String,
+    body: String,
+    filenames: String,
+}
+
+impl Texts {
+    fn new(parsed: &ParsedMail) -> Self {
+        let headers = parsed
+            .get_headers()
+            .into_iter()
+            .map(|header| format!("{}: {}", header.get_key(), header.get_value()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut body = String::new();
+        let mut filenames = Vec::new();
+        collect_text(parsed, &mut body, &mut filenames);
+        Texts {
+            headers,
+            body,
+            filenames: filenames.join(" "),
+        }
+    }
+}
+
+/// Collects the decoded text parts and the attachment filenames of a message
+fn collect_text(part: &ParsedMail, body: &mut String, filenames: &mut Vec<String>) {
+    let disposition = part.get_content_disposition();
+    if let Some(filename) = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+    {
+        filenames.push(filename.clone());
+    }
+
+    if part.subparts.is_empty() {
+        if part.ctype.mimetype.starts_with("text/")
+            && disposition.disposition != DispositionType::Attachment
+        {
+            if let Ok(text) = part.get_body() {
+                body.push_str(&text);
+                body.push('\n');
+            }
+        }
+    } else {
+        for subpart in &part.subparts {
+            collect_text(subpart, body, filenames);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    const MAIL: &[u8] = b"Subject: Quarterly Report\r\n\
+        Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+        --b\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        The meeting MOVED to Friday.\r\n\
+        --b\r\n\
+        Content-Type: application/pdf\r\n\
+        Content-Disposition: attachment; filename=\"budget-2022.pdf\"\r\n\r\n\
+        pdf\r\n\
+        --b--\r\n";
+
+    /// Queries and whether they match the body of `MAIL`
+    const BODY_QUERIES: [(&str, bool); 8] = [
+        ("meeting moved", true),
+        ("moved meeting", false),
+        ("Friday", true),
+        ("meet", true),
+        ("ting MOV", true),
+        ("budget", true),
+        ("2022.p", true),
+        ("quarterly", false),
+    ];
+
+    #[test]
+    fn test_text_matches() {
+        let mail = mailparse::parse_mail(MAIL).unwrap();
+        for (query, expected) in BODY_QUERIES {
+            assert_eq!(
+                text_matches(&mail, SearchField::Body, query),
+                expected,
+                "{query}"
+            );
+        }
+        assert!(text_matches(&mail, SearchField::Text, "quarterly report"));
+        assert!(text_matches(&mail, SearchField::Body, "fr"));
+        assert!(text_matches(&mail, SearchField::Body, ""));
+        assert!(!text_matches(&mail, SearchField::Body, " - "));
+    }
+
+    #[tokio::test]
+    async fn test_index_matches_fallback() {
+        let config = crate::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let username = format!("search-{nanos}@localhost");
+        let index = SearchIndex::new(config);
+        index
+            .add_mail(&username, "INBOX", "mail", MAIL)
+            .await
+            .unwrap();
+
+        let mail = mailparse::parse_mail(MAIL).unwrap();
+        for (query, expected) in BODY_QUERIES {
+            let matches = index
+                .search(&username, "INBOX", SearchField::Body, query)
+                .await
+                .unwrap()
+                .unwrap();
+            // The index only narrows the messages down
+            assert_eq!(
+                matches.contains("mail") && text_matches(&mail, SearchField::Body, query),
+                expected,
+                "{query}"
+            );
+        }
+        assert!(index
+            .search(&username, "INBOX", SearchField::Body, "fr")
+            .await
+            .unwrap()
+            .is_none());
+
+        index
+            .remove_mails(&username, vec![String::from("mail")])
+            .await
+            .unwrap();
+        let matches = index
+            .search(&username, "INBOX", SearchField::Body, "friday")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches.is_empty());
+        std::fs::remove_dir_all(index.index_path(&username).parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_next_to_server() {
+        let config = crate::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let username = format!("rebuild-{nanos}@localhost");
+        let server = SearchIndex::new(Arc::clone(&config));
+        server
+            .add_mail(&username, "INBOX", "gone", MAIL)
+            .await
+            .unwrap();
+
+        // Like eroosterctl while the server keeps running
+        let rebuilt = SearchIndex::new(config)
+            .rebuild(
+                &username,
+                vec![(String::from("INBOX"), String::from("mail"), MAIL.to_vec())],
+                || Ok(HashSet::from([String::from("mail")])),
+            )
+            .await
+            .unwrap();
+        assert_eq!(rebuilt, 1);
+
+        // The server sees the rebuilt index and can still change it
+        let matches = server
+            .search(&username, "INBOX", SearchField::Body, "friday")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(matches, HashSet::from([String::from("mail")]));
+        server
+            .add_mail(&username, "INBOX", "new", MAIL)
+            .await
+            .unwrap();
+        let matches = server
+            .search(&username, "INBOX", SearchField::Body, "friday")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(matches.len(), 2);
+        std::fs::remove_dir_all(server.index_path(&username).parent().unwrap()).unwrap();
+    }
+}
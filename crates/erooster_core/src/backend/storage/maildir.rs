@@ -1,27 +1,37 @@
 use crate::{
     backend::{
+        changes::{ChangeKind, ChangeStreams, MailboxChange},
         database::{Database, DB},
-        storage::{MailEntry, MailState, MailStorage},
+        search::{SearchField, SearchIndex, SEARCH_INDEX_FOLDER},
+        storage::{preview::preview, MailEntry, MailState, MailStorage},
     },
     config::Config,
 };
-use futures::{StreamExt, TryStreamExt};
+use color_eyre::eyre::ContextCompat;
+use futures::StreamExt;
 use maildir::Maildir;
 use mailparse::ParsedMail;
 use std::{
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
     sync::Arc,
 };
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::{fs::OpenOptions, io::AsyncWriteExt};
-use tokio_stream::wrappers::LinesStream;
-use tracing::{debug, instrument};
+use tokio::sync::broadcast;
+use tracing::{debug, error, instrument};
+
+/// Folder flag of a mailbox which only exists to hold its children
+const NOSELECT: &str = "\\Noselect";
+/// The subscription state is stored as a flag of the folder
+const SUBSCRIBED: &str = "\\Subscribed";
+/// The file older versions kept the folder flags in
+const LEGACY_FLAGS_FILE: &str = ".erooster_folder_flags";
 
 /// The Storage handler for the maildir format
 pub struct MaildirStorage {
     db: DB,
     config: Arc<Config>,
+    search: SearchIndex,
+    changes: ChangeStreams,
 }
 
 impl MaildirStorage {
@@ -29,10 +39,293 @@ impl MaildirStorage {
     #[must_use]
     #[instrument(skip(db))]
     pub fn new(db: DB, config: Arc<Config>) -> Self {
-        MaildirStorage { db, config }
+        let search = SearchIndex::new(Arc::clone(&config));
+        MaildirStorage {
+            db,
+            config,
+            search,
+            changes: ChangeStreams::new(),
+        }
+    }
+
+    /// Wraps the entries of a maildir and looks up their uids.
+    ///
+    /// Only the rows of the listed messages are queried instead of the whole table.
+    /// Messages put into the maildir by other programs get their row and uid here.
+    #[instrument(skip(self, entries, mail_state))]
+    async fn with_uids(
+        &self,
+        entries: Vec<maildir::MailEntry>,
+        mail_state: impl Fn(&maildir::MailEntry) -> MailState,
+    ) -> Vec<MaildirMailEntry> {
+        let maildir_ids: Vec<String> = entries.iter().map(|entry| entry.id().to_string()).collect();
+        let mut rows = self.mail_rows(maildir_ids).await;
+        let unknown: Vec<&maildir::MailEntry> = entries
+            .iter()
+            .filter(|entry| !rows.contains_key(entry.id()))
+            .collect();
+        if !unknown.is_empty() {
+            let mut added = Vec::new();
+            for entry in unknown {
+                match self.insert_foreign_mail(entry).await {
+                    Ok(()) => added.push(entry.id().to_string()),
+                    Err(e) => error!(
+                        "Failed to add message {} to the database: {}",
+                        entry.id(),
+                        e
+                    ),
+                }
+            }
+            rows.extend(self.mail_rows(added).await);
+        }
+        entries
+            .into_iter()
+            .map(|entry| {
+                let row = rows.remove(entry.id());
+                MaildirMailEntry::new(entry, row, mail_state)
+            })
+            .collect()
+    }
+
+    /// The database rows of the given messages keyed by their maildir id
+    async fn mail_rows(&self, maildir_ids: Vec<String>) -> HashMap<String, DbMails> {
+        sqlx::query_as::<_, DbMails>("SELECT * FROM mails WHERE maildir_id = ANY($1)")
+            .bind(maildir_ids)
+            .fetch(self.db.get_pool())
+            .filter_map(|x| async move { x.ok() })
+            .map(|row| (row.maildir_id.clone(), row))
+            .collect()
+            .await
+    }
+
+    /// Adds the database row of a message which got into the maildir without us
+    async fn insert_foreign_mail(
+        &self,
+        entry: &maildir::MailEntry,
+    ) -> color_eyre::eyre::Result<()> {
+        // Messages live in the new or cur folder of the mailbox
+        let mailbox_path = entry
+            .path()
+            .parent()
+            .and_then(Path::parent)
+            .context("Message outside of a maildir")?;
+        let data = tokio::fs::read(entry.path()).await?;
+        self.insert_mail(mailbox_path, entry.id(), &data, None)
+            .await
+    }
+
+    /// Adds the database row of a freshly stored message.
+    ///
+    /// `delivered` is the unix timestamp used as the internal date. `None` means now.
+    /// The preview gets generated right away so fetching it never needs to parse the message.
+    /// The message joins the thread of the oldest message of the same user it references or starts a new one.
+    #[instrument(skip(self, path, data))]
+    async fn insert_mail(
+        &self,
+        path: &Path,
+        maildir_id: &str,
+        data: &[u8],
+        delivered: Option<i64>,
+    ) -> color_eyre::eyre::Result<()> {
+        let (username, _) = user_and_mailbox(path).context("Invalid mailbox path")?;
+        let (message_id, references) = thread_headers(data);
+        let preview = mailparse::parse_mail(data)
+            .map(|parsed| preview(&parsed))
+            .unwrap_or_default();
+        // Only messages of the same user are considered so nobody can join the threads of others
+        let thread_id: Option<(String,)> = if references.is_empty() {
+            None
+        } else {
+            sqlx::query_as(
+                "SELECT thread_id FROM mails WHERE message_id = ANY($1) AND username = $2
+                ORDER BY id LIMIT 1",
+            )
+            .bind(references)
+            .bind(&username)
+            .fetch_optional(self.db.get_pool())
+            .await?
+        };
+        let delivered: i64 = match delivered {
+            Some(delivered) => delivered,
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs()
+                .try_into()?,
+        };
+        let size: i64 = data.len().try_into()?;
+        sqlx::query(
+            "INSERT INTO mails (maildir_id, username, message_id, thread_id, preview, size, delivered)
+            VALUES ($1, $2, $3, COALESCE($4, 'T' || nextval('object_ids')), $5, $6, $7)
+            ON CONFLICT (maildir_id) DO NOTHING",
+        )
+        .bind(maildir_id)
+        .bind(username)
+        .bind(message_id)
+        .bind(thread_id.map(|(thread_id,)| thread_id))
+        .bind(preview)
+        .bind(size)
+        .bind(delivered)
+        .execute(self.db.get_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Moves the subscription, attributes and metadata of a mailbox to its new name
+    #[instrument(skip(self, path, new_path))]
+    async fn rename_mailbox_row(
+        &self,
+        path: &Path,
+        new_path: &Path,
+    ) -> color_eyre::eyre::Result<()> {
+        let (username, mailbox) = mailbox_key(path)?;
+        let (_, new_mailbox) = mailbox_key(new_path)?;
+        sqlx::query("UPDATE mailboxes SET mailbox = $3 WHERE username = $1 AND mailbox = $2")
+            .bind(&username)
+            .bind(&mailbox)
+            .bind(&new_mailbox)
+            .execute(self.db.get_pool())
+            .await?;
+        sqlx::query(
+            "UPDATE mailbox_metadata SET mailbox = $3 WHERE username = $1 AND mailbox = $2",
+        )
+        .bind(username)
+        .bind(mailbox)
+        .bind(new_mailbox)
+        .execute(self.db.get_pool())
+        .await?;
+        Ok(())
+    }
+
+    /// Indexes all messages of a mailbox again after they moved to it.
+    ///
+    /// Failures are only logged like in `index_mail`.
+    #[instrument(skip(self, path))]
+    async fn reindex_mailbox(&self, path: &Path) {
+        let Some((username, mailbox)) = user_and_mailbox(path) else {
+            error!("[Search] Unable to get user and mailbox from {:?}", path);
+            return;
+        };
+        let maildir = Maildir::from(path.to_path_buf());
+        let mut mails = Vec::new();
+        for mail in maildir
+            .list_new()
+            .chain(maildir.list_cur())
+            .filter_map(Result::ok)
+        {
+            match tokio::fs::read(mail.path()).await {
+                Ok(data) => mails.push((mailbox.clone(), mail.id().to_string(), data)),
+                Err(e) => error!("[Search] Failed to read message {}: {}", mail.id(), e),
+            }
+        }
+        if let Err(e) = self.search.add_mails(&username, mails).await {
+            error!("[Search] Failed to index the messages of {:?}: {}", path, e);
+        }
+    }
+
+    /// Adds a freshly stored message to the search index.
+    ///
+    /// Indexing failures are only logged as the message itself is already safely stored.
+    #[instrument(skip(self, path, data))]
+    async fn index_mail(&self, path: &Path, maildir_id: &str, data: &[u8]) {
+        let Some((username, mailbox)) = user_and_mailbox(path) else {
+            error!("[Search] Unable to get user and mailbox from {:?}", path);
+            return;
+        };
+        if let Err(e) = self
+            .search
+            .add_mail(&username, &mailbox, maildir_id, data)
+            .await
+        {
+            error!("[Search] Failed to index message {}: {}", maildir_id, e);
+        }
+    }
+}
+
+/// The mailbox, maildir id and path of every message of the user
+fn mail_files(user_path: &Path) -> std::io::Result<Vec<(String, String, PathBuf)>> {
+    let mut mail_paths = Vec::new();
+    for entry in std::fs::read_dir(user_path)? {
+        let entry = entry?;
+        let mailbox_path = entry.path();
+        // Every folder with a cur folder is a mailbox
+        if entry.file_name() == SEARCH_INDEX_FOLDER || !mailbox_path.join("cur").is_dir() {
+            continue;
+        }
+        let mailbox = entry.file_name().to_string_lossy().to_string();
+        let maildir = Maildir::from(mailbox_path);
+        for mail in maildir.list_new().chain(maildir.list_cur()).flatten() {
+            mail_paths.push((mailbox.clone(), mail.id().to_string(), mail.path().clone()));
+        }
+    }
+    Ok(mail_paths)
+}
+
+/// The Message-ID of a message and the ids of the messages it refers to
+fn thread_headers(data: &[u8]) -> (Option<String>, Vec<String>) {
+    let Ok((headers, _)) = mailparse::parse_headers(data) else {
+        return (None, vec![]);
+    };
+    let message_id = headers
+        .get_first_value("Message-ID")
+        .and_then(|value| message_ids(&value).into_iter().next());
+    let mut references = headers
+        .get_first_value("References")
+        .map(|value| message_ids(&value))
+        .unwrap_or_default();
+    if let Some(in_reply_to) = headers.get_first_value("In-Reply-To") {
+        references.extend(message_ids(&in_reply_to));
+    }
+    (message_id, references)
+}
+
+/// The `<...>` message ids in a header value
+fn message_ids(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>'))
+        .map(|(id, _)| format!("<{}>", id.trim()))
+        .collect()
+}
+
+/// Converts the name of a mailbox folder into the name of the mailbox used in imap
+fn imap_mailbox_name(folder: &str) -> String {
+    folder.trim_start_matches('.').to_string()
+}
+
+/// The username and imap name identifying the mailbox in the database
+fn mailbox_key(path: &Path) -> color_eyre::eyre::Result<(String, String)> {
+    let (username, folder) = user_and_mailbox(path).context("Invalid mailbox path")?;
+    Ok((username, imap_mailbox_name(&folder)))
+}
+
+/// The mailbox name metadata is stored under. Server metadata uses the empty name.
+fn metadata_mailbox(path: Option<&Path>) -> color_eyre::eyre::Result<String> {
+    path.map_or_else(
+        || Ok(String::new()),
+        |path| mailbox_key(path).map(|(_, mailbox)| mailbox),
+    )
+}
+
+/// The user a metadata entry is stored for.
+///
+/// Mailboxes belong to a single user. Shared entries of the server are the same for all users
+/// and are stored for the empty username.
+fn metadata_owner<'a>(username: &'a str, path: Option<&Path>, entry: &str) -> &'a str {
+    if path.is_none() && entry.starts_with("/shared") {
+        ""
+    } else {
+        username
     }
 }
 
+/// Splits a mailbox path into the username and the name of the mailbox folder
+fn user_and_mailbox(path: &Path) -> Option<(String, String)> {
+    let mailbox = path.file_name()?.to_string_lossy().to_string();
+    let username = path.parent()?.file_name()?.to_string_lossy().to_string();
+    Some((username, mailbox))
+}
+
 #[async_trait::async_trait]
 impl MailStorage<MaildirMailEntry> for MaildirStorage {
     #[instrument(skip(self, mailbox_path))]
@@ -45,80 +338,271 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
 
     async fn find(&self, path: &Path, id: &str) -> Option<MaildirMailEntry> {
         let maildir = Maildir::from(path.to_path_buf());
-        let mail_rows: Vec<DbMails> = sqlx::query_as::<_, DbMails>("SELECT * FROM mails")
-            .fetch(self.db.get_pool())
-            .filter_map(|x| async move { x.ok() })
-            .collect()
-            .await;
-        maildir.find(id).map(|entry| {
-            let uid = mail_rows
-                .iter()
-                .find(|x| x.maildir_id == id)
-                .map_or(0, |x| x.id);
-            let mail_state = if entry.is_seen() {
+        let entry = maildir.find(id)?;
+        let row = sqlx::query_as::<_, DbMails>("SELECT * FROM mails WHERE maildir_id = $1")
+            .bind(id)
+            .fetch_optional(self.db.get_pool())
+            .await
+            .ok()
+            .flatten();
+        Some(MaildirMailEntry::new(entry, row, |entry| {
+            if entry.is_seen() {
                 MailState::Read
             } else {
                 MailState::New
-            };
-            MaildirMailEntry {
-                uid,
-                entry,
-                mail_state,
-                sequence_number: None,
-                date: None,
             }
-        })
+        }))
     }
 
     #[instrument(skip(self, path))]
-    async fn get_flags(&self, path: &Path) -> std::io::Result<Vec<String>> {
-        let flags_file = path.join(".erooster_folder_flags");
-        if flags_file.exists() {
-            let file = File::open(flags_file).await?;
-            let buf = BufReader::new(file);
-            let flags = LinesStream::new(buf.lines()).try_collect().await;
-            return flags;
+    async fn get_flags(&self, path: &Path) -> color_eyre::eyre::Result<Vec<String>> {
+        let (username, mailbox) = mailbox_key(path)?;
+        let row: Option<(bool, Vec<String>)> = sqlx::query_as(
+            "SELECT subscribed, attributes FROM mailboxes WHERE username = $1 AND mailbox = $2",
+        )
+        .bind(username)
+        .bind(mailbox)
+        .fetch_optional(self.db.get_pool())
+        .await?;
+        let Some((subscribed, mut flags)) = row else {
+            return Ok(vec![]);
+        };
+        if subscribed {
+            flags.push(String::from(SUBSCRIBED));
         }
-        Ok(vec![])
+        Ok(flags)
     }
 
     #[instrument(skip(self, path))]
     async fn add_flag(&self, path: &Path, flag: &str) -> color_eyre::eyre::Result<()> {
-        let flags_file = path.join(".erooster_folder_flags");
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(flags_file)
+        let (username, mailbox) = mailbox_key(path)?;
+        // Both are single upserts so concurrent sessions can not lose each others changes
+        if flag == SUBSCRIBED {
+            sqlx::query(
+                "INSERT INTO mailboxes (username, mailbox, subscribed) VALUES ($1, $2, TRUE)
+                ON CONFLICT (username, mailbox) DO UPDATE SET subscribed = TRUE",
+            )
+            .bind(username)
+            .bind(mailbox)
+            .execute(self.db.get_pool())
+            .await?;
+            self.publish_change(path, ChangeKind::SubscriptionChange);
+        } else {
+            sqlx::query(
+                "INSERT INTO mailboxes (username, mailbox, attributes) VALUES ($1, $2, ARRAY[$3])
+                ON CONFLICT (username, mailbox) DO UPDATE
+                SET attributes = array_append(mailboxes.attributes, $3)
+                WHERE NOT ($3 = ANY(mailboxes.attributes))",
+            )
+            .bind(username)
+            .bind(mailbox)
+            .bind(flag)
+            .execute(self.db.get_pool())
             .await?;
-        file.write_all(flag.as_bytes()).await?;
+        }
         Ok(())
     }
 
     #[instrument(skip(self, path))]
     async fn remove_flag(&self, path: &Path, flag: &str) -> color_eyre::eyre::Result<()> {
-        let flags_file = path.join(".erooster_folder_flags");
-        let mut lines = lines_from_file(&flags_file).await?;
+        let (username, mailbox) = mailbox_key(path)?;
+        if flag == SUBSCRIBED {
+            sqlx::query(
+                "UPDATE mailboxes SET subscribed = FALSE WHERE username = $1 AND mailbox = $2",
+            )
+            .bind(username)
+            .bind(mailbox)
+            .execute(self.db.get_pool())
+            .await?;
+            self.publish_change(path, ChangeKind::SubscriptionChange);
+        } else {
+            sqlx::query(
+                "UPDATE mailboxes SET attributes = array_remove(attributes, $3)
+                WHERE username = $1 AND mailbox = $2",
+            )
+            .bind(username)
+            .bind(mailbox)
+            .bind(flag)
+            .execute(self.db.get_pool())
+            .await?;
+        }
+        Ok(())
+    }
 
-        lines.retain(|x| x != flag);
+    #[instrument(skip(self))]
+    async fn import_folder_flags(&self, username: &str) -> color_eyre::eyre::Result<usize> {
+        let user_path = Path::new(&self.config.mail.maildir_folders).join(username);
+        let mut count = 0;
+        let mut entries = tokio::fs::read_dir(&user_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let mailbox_path = entry.path();
+            let flags_file = mailbox_path.join(LEGACY_FLAGS_FILE);
+            if !tokio::fs::try_exists(&flags_file).await? {
+                continue;
+            }
+            let content = tokio::fs::read_to_string(&flags_file).await?;
+            // Older versions did not always terminate the flags with a newline
+            let flags = content
+                .split(|c: char| c == '\\' || c.is_whitespace())
+                .filter(|flag| !flag.is_empty())
+                .map(|flag| format!("\\{flag}"));
+            for flag in flags {
+                self.add_flag(&mailbox_path, &flag).await?;
+            }
+            tokio::fs::remove_file(&flags_file).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(flags_file)
-            .await?;
+    #[instrument(skip(self, path))]
+    async fn mailbox_id(&self, path: &Path) -> color_eyre::eyre::Result<String> {
+        let (username, mailbox) = mailbox_key(path)?;
+        let row: Option<(String,)> =
+            sqlx::query_as("SELECT mailbox_id FROM mailboxes WHERE username = $1 AND mailbox = $2")
+                .bind(&username)
+                .bind(&mailbox)
+                .fetch_optional(self.db.get_pool())
+                .await?;
+        if let Some((mailbox_id,)) = row {
+            return Ok(mailbox_id);
+        }
+        // Mailboxes get their row when they are created. Older ones and the INBOX get it on first use.
+        sqlx::query(
+            "INSERT INTO mailboxes (username, mailbox) VALUES ($1, $2)
+            ON CONFLICT (username, mailbox) DO NOTHING",
+        )
+        .bind(&username)
+        .bind(&mailbox)
+        .execute(self.db.get_pool())
+        .await?;
+        let (mailbox_id,): (String,) =
+            sqlx::query_as("SELECT mailbox_id FROM mailboxes WHERE username = $1 AND mailbox = $2")
+                .bind(username)
+                .bind(mailbox)
+                .fetch_one(self.db.get_pool())
+                .await?;
+        Ok(mailbox_id)
+    }
 
-        for line in lines {
-            let line = format!("{line}\n");
-            file.write_all(line.as_bytes()).await?;
+    #[instrument(skip(self))]
+    async fn backfill_message_ids(&self, username: &str) -> color_eyre::eyre::Result<usize> {
+        let user_path = Path::new(&self.config.mail.maildir_folders).join(username);
+        let mut count = 0;
+        let mut entries = tokio::fs::read_dir(&user_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() || entry.file_name() == SEARCH_INDEX_FOLDER {
+                continue;
+            }
+            let maildir = Maildir::from(entry.path());
+            for mail in maildir
+                .list_new()
+                .chain(maildir.list_cur())
+                .filter_map(Result::ok)
+            {
+                let data = tokio::fs::read(mail.path()).await?;
+                let (message_id, _) = thread_headers(&data);
+                let preview = mailparse::parse_mail(&data)
+                    .map(|parsed| preview(&parsed))
+                    .ok();
+                let result = sqlx::query(
+                    "UPDATE mails SET username = $2, message_id = COALESCE(message_id, $3), preview = COALESCE(preview, $4)
+                    WHERE maildir_id = $1 AND (username IS NULL OR message_id IS NULL OR preview IS NULL)",
+                )
+                .bind(mail.id())
+                .bind(username)
+                .bind(message_id)
+                .bind(preview)
+                .execute(self.db.get_pool())
+                .await?;
+                if result.rows_affected() > 0 {
+                    count += 1;
+                }
+            }
         }
+        Ok(count)
+    }
+
+    #[instrument(skip(self, path))]
+    async fn get_metadata(
+        &self,
+        username: &str,
+        path: Option<&Path>,
+        entries: &[String],
+    ) -> color_eyre::eyre::Result<Vec<(String, String)>> {
+        let mailbox = metadata_mailbox(path)?;
+        // There are only few entries per mailbox so the hierarchy is matched here
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT entry, value FROM mailbox_metadata
+            WHERE mailbox = $2
+            AND ((username = $1 AND entry LIKE '/private/%') OR (username = $3 AND entry LIKE '/shared/%'))
+            ORDER BY entry",
+        )
+        .bind(username)
+        .bind(mailbox)
+        .bind(metadata_owner(username, path, "/shared"))
+        .fetch_all(self.db.get_pool())
+        .await?;
+        Ok(rows
+            .into_iter()
+            .filter(|(entry, _)| {
+                entries.iter().any(|requested| {
+                    let requested = requested.to_lowercase();
+                    *entry == requested || entry.starts_with(&format!("{requested}/"))
+                })
+            })
+            .collect())
+    }
 
+    #[instrument(skip(self, path, entries))]
+    async fn set_metadata(
+        &self,
+        username: &str,
+        path: Option<&Path>,
+        entries: &[(String, Option<String>)],
+    ) -> color_eyre::eyre::Result<()> {
+        let mailbox = metadata_mailbox(path)?;
+        // Either all entries are set or none of them (RFC 5464 4.3)
+        let mut transaction = self.db.get_pool().begin().await?;
+        for (entry, value) in entries {
+            // Entry names are case insensitive
+            let entry = entry.to_lowercase();
+            let owner = metadata_owner(username, path, &entry);
+            if let Some(value) = value {
+                sqlx::query(
+                    "INSERT INTO mailbox_metadata (username, mailbox, entry, value) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (username, mailbox, entry) DO UPDATE SET value = $4",
+                )
+                .bind(owner)
+                .bind(&mailbox)
+                .bind(entry)
+                .bind(value)
+                .execute(&mut transaction)
+                .await?;
+            } else {
+                sqlx::query(
+                    "DELETE FROM mailbox_metadata WHERE username = $1 AND mailbox = $2 AND entry = $3",
+                )
+                .bind(owner)
+                .bind(&mailbox)
+                .bind(entry)
+                .execute(&mut transaction)
+                .await?;
+            }
+        }
+        transaction.commit().await?;
         Ok(())
     }
 
     #[instrument(skip(self, mailbox_path))]
     fn create_dirs(&self, mailbox_path: &Path) -> color_eyre::eyre::Result<()> {
+        let existed = mailbox_path.exists();
         let maildir = Maildir::from(mailbox_path.to_path_buf());
-        maildir.create_dirs().map_err(Into::into)
+        maildir.create_dirs()?;
+        if !existed {
+            self.publish_change(mailbox_path, ChangeKind::MailboxCreated);
+        }
+        Ok(())
     }
 
     #[instrument(skip(self, path, data))]
@@ -127,6 +611,7 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
         path: &Path,
         data: &[u8],
         imap_flags: Vec<String>,
+        internal_date: Option<i64>,
     ) -> color_eyre::eyre::Result<String> {
         let maildir = Maildir::from(path.to_path_buf());
         let maildir_flags = imap_flags
@@ -150,10 +635,10 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
             .collect::<Vec<_>>()
             .join("");
         let maildir_id = maildir.store_cur_with_flags(data, &maildir_flags)?;
-        sqlx::query("INSERT INTO mails (maildir_id) VALUES ($1)")
-            .bind(maildir_id.clone())
-            .execute(self.db.get_pool())
+        self.insert_mail(path, &maildir_id, data, internal_date)
             .await?;
+        self.index_mail(path, &maildir_id, data).await;
+        self.publish_change(path, ChangeKind::MessageNew);
         Ok(maildir_id)
     }
 
@@ -161,10 +646,9 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
     async fn store_new(&self, path: &Path, data: &[u8]) -> color_eyre::eyre::Result<String> {
         let maildir = Maildir::from(path.to_path_buf());
         let maildir_id = maildir.store_new(data)?;
-        sqlx::query("INSERT INTO mails (maildir_id) VALUES ($1)")
-            .bind(maildir_id.clone())
-            .execute(self.db.get_pool())
-            .await?;
+        self.insert_mail(path, &maildir_id, data, None).await?;
+        self.index_mail(path, &maildir_id, data).await;
+        self.publish_change(path, ChangeKind::MessageNew);
         Ok(maildir_id)
     }
 
@@ -180,6 +664,132 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
             .collect())
     }
 
+    #[instrument(skip(self, path))]
+    fn list_children(&self, path: &Path) -> color_eyre::eyre::Result<Vec<PathBuf>> {
+        let user_path = path.parent().context("Mailbox without a user folder")?;
+        let name = path
+            .file_name()
+            .context("Unable to get mailbox name")?
+            .to_string_lossy();
+        // The hierarchy is flat on disk. Children are named `<parent>.<child>`.
+        let prefix = format!("{name}.");
+        let mut children = Vec::new();
+        for entry in std::fs::read_dir(user_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir()
+                && entry.file_name().to_string_lossy().starts_with(&prefix)
+            {
+                children.push(entry.path());
+            }
+        }
+        children.sort();
+        Ok(children)
+    }
+
+    #[instrument(skip(self, path))]
+    async fn delete_mailbox(&self, path: &Path) -> color_eyre::eyre::Result<()> {
+        let (username, _) = user_and_mailbox(path).context("Invalid mailbox path")?;
+        let maildir = Maildir::from(path.to_path_buf());
+        let ids: Vec<String> = maildir
+            .list_new()
+            .chain(maildir.list_cur())
+            .filter_map(Result::ok)
+            .map(|mail| mail.id().to_string())
+            .collect();
+        sqlx::query("DELETE FROM mails WHERE maildir_id = ANY($1)")
+            .bind(&ids)
+            .execute(self.db.get_pool())
+            .await?;
+        self.search.remove_mails(&username, ids).await?;
+
+        if self.list_children(path)?.is_empty() {
+            tokio::fs::remove_dir_all(path).await?;
+            let (username, mailbox) = mailbox_key(path)?;
+            // The subscription outlives the mailbox (RFC 9051 6.3.9). A mailbox created again
+            // with the same name is a new mailbox and gets a new id.
+            sqlx::query(
+                "UPDATE mailboxes SET attributes = '{}', mailbox_id = DEFAULT
+                WHERE username = $1 AND mailbox = $2",
+            )
+            .bind(&username)
+            .bind(&mailbox)
+            .execute(self.db.get_pool())
+            .await?;
+            sqlx::query("DELETE FROM mailbox_metadata WHERE username = $1 AND mailbox = $2")
+                .bind(username)
+                .bind(mailbox)
+                .execute(self.db.get_pool())
+                .await?;
+        } else {
+            // The children still need their parent in the hierarchy
+            for id in &ids {
+                maildir.delete(id)?;
+            }
+            self.add_flag(path, NOSELECT).await?;
+        }
+        self.publish_change(path, ChangeKind::MailboxDeleted);
+        Ok(())
+    }
+
+    #[instrument(skip(self, path, new_path))]
+    async fn rename_mailbox(&self, path: &Path, new_path: &Path) -> color_eyre::eyre::Result<()> {
+        let (_, old_name) = user_and_mailbox(path).context("Invalid mailbox path")?;
+        let (_, new_name) = user_and_mailbox(new_path).context("Invalid mailbox path")?;
+
+        if old_name == "INBOX" {
+            // INBOX itself always stays. Only its messages move, keeping their maildir ids and uids.
+            self.create_dirs(new_path)?;
+            let maildir = Maildir::from(path.to_path_buf());
+            for mail in maildir
+                .list_new()
+                .chain(maildir.list_cur())
+                .filter_map(Result::ok)
+            {
+                let subfolder = mail
+                    .path()
+                    .parent()
+                    .and_then(Path::file_name)
+                    .context("Message outside of a maildir folder")?;
+                let file_name = mail
+                    .path()
+                    .file_name()
+                    .context("Message without file name")?;
+                let target = new_path.join(subfolder).join(file_name);
+                tokio::fs::rename(mail.path(), &target).await?;
+            }
+            self.reindex_mailbox(new_path).await;
+            self.publish_change(path, ChangeKind::MessageExpunge);
+            self.publish_change(new_path, ChangeKind::MessageNew);
+            return Ok(());
+        }
+
+        let children = self.list_children(path)?;
+        tokio::fs::rename(path, new_path).await?;
+        self.rename_mailbox_row(path, new_path).await?;
+        self.reindex_mailbox(new_path).await;
+        self.publish_change(
+            new_path,
+            ChangeKind::MailboxRenamed(imap_mailbox_name(&old_name)),
+        );
+        for child in children {
+            let child_name = child
+                .file_name()
+                .context("Unable to get mailbox name")?
+                .to_string_lossy()
+                .to_string();
+            let new_child =
+                new_path.with_file_name(format!("{new_name}{}", &child_name[old_name.len()..]));
+            tokio::fs::rename(&child, &new_child).await?;
+            self.rename_mailbox_row(&child, &new_child).await?;
+            self.reindex_mailbox(&new_child).await;
+            self.publish_change(
+                &new_child,
+                ChangeKind::MailboxRenamed(imap_mailbox_name(&child_name)),
+            );
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self, path))]
     fn count_cur(&self, path: &Path) -> usize {
         let maildir = Maildir::from(path.to_path_buf());
@@ -195,100 +805,33 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
     #[instrument(skip(self, path))]
     async fn list_cur(&self, path: &Path) -> Vec<MaildirMailEntry> {
         let maildir = Maildir::from(path.to_path_buf());
-        let mail_rows: Vec<DbMails> = sqlx::query_as::<_, DbMails>("SELECT * FROM mails")
-            .fetch(self.db.get_pool())
-            .filter_map(|x| async move { x.ok() })
-            .collect()
-            .await;
-        maildir
-            .list_cur()
-            .filter_map(|x| match x {
-                Ok(x) => {
-                    let maildir_id = x.id();
-                    let uid = mail_rows
-                        .iter()
-                        .find(|y| y.maildir_id == maildir_id)
-                        .map_or(0, |y| y.id);
-                    Some(MaildirMailEntry {
-                        uid,
-                        entry: x,
-                        mail_state: MailState::Read,
-                        sequence_number: None,
-                        date: None,
-                    })
-                }
-                Err(_) => None,
-            })
-            .collect()
+        let entries: Vec<maildir::MailEntry> = maildir.list_cur().filter_map(Result::ok).collect();
+        self.with_uids(entries, |_| MailState::Read).await
     }
 
     #[instrument(skip(self, path))]
     async fn list_new(&self, path: &Path) -> Vec<MaildirMailEntry> {
         let maildir = Maildir::from(path.to_path_buf());
-        let mail_rows: Vec<DbMails> = sqlx::query_as::<_, DbMails>("SELECT * FROM mails")
-            .fetch(self.db.get_pool())
-            .filter_map(|x| async move { x.ok() })
-            .collect()
-            .await;
-
-        maildir
-            .list_new()
-            .filter_map(|x| match x {
-                Ok(x) => {
-                    let maildir_id = x.id();
-                    let uid = mail_rows
-                        .iter()
-                        .find(|y| y.maildir_id == maildir_id)
-                        .map_or(0, |y| y.id);
-                    Some(MaildirMailEntry {
-                        uid,
-                        entry: x,
-                        mail_state: MailState::New,
-                        sequence_number: None,
-                        date: None,
-                    })
-                }
-                Err(_) => None,
-            })
-            .collect()
+        let entries: Vec<maildir::MailEntry> = maildir.list_new().filter_map(Result::ok).collect();
+        self.with_uids(entries, |_| MailState::New).await
     }
 
     #[instrument(skip(self, path))]
     async fn list_all(&self, path: &Path) -> Vec<MaildirMailEntry> {
         let maildir = Maildir::from(path.to_path_buf());
-        let mail_rows: Vec<DbMails> = sqlx::query_as::<_, DbMails>("SELECT * FROM mails")
-            .fetch(self.db.get_pool())
-            .filter_map(|x| async move { x.ok() })
-            .collect()
-            .await;
-
-        maildir
+        let entries: Vec<maildir::MailEntry> = maildir
             .list_new()
             .chain(maildir.list_cur())
-            .filter(std::result::Result::is_ok)
-            .filter_map(|x| match x {
-                Ok(x) => {
-                    let maildir_id = x.id();
-                    let uid = mail_rows
-                        .iter()
-                        .find(|y| y.maildir_id == maildir_id)
-                        .map_or(0, |y| y.id);
-                    let state = if x.is_seen() {
-                        MailState::Read
-                    } else {
-                        MailState::New
-                    };
-                    Some(MaildirMailEntry {
-                        uid,
-                        entry: x,
-                        mail_state: state,
-                        sequence_number: None,
-                        date: None,
-                    })
-                }
-                Err(_) => None,
-            })
-            .collect()
+            .filter_map(Result::ok)
+            .collect();
+        self.with_uids(entries, |entry| {
+            if entry.is_seen() {
+                MailState::Read
+            } else {
+                MailState::New
+            }
+        })
+        .await
     }
 
     #[instrument(skip(self, path))]
@@ -320,6 +863,7 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
             .collect::<Vec<_>>()
             .join("");
         maildir.move_new_to_cur_with_flags(id, &maildir_flags)?;
+        self.publish_change(path, ChangeKind::FlagChange);
         Ok(())
     }
 
@@ -353,6 +897,7 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
             .join("");
         debug!("flags: {:?}", maildir_flags);
         maildir.add_flags(id, &maildir_flags)?;
+        self.publish_change(path, ChangeKind::FlagChange);
         Ok(())
     }
 
@@ -385,6 +930,7 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
             .collect::<Vec<_>>()
             .join("");
         maildir.set_flags(id, &maildir_flags)?;
+        self.publish_change(path, ChangeKind::FlagChange);
         Ok(())
     }
 
@@ -417,9 +963,81 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
             .collect::<Vec<_>>()
             .join("");
         maildir.set_flags(id, &maildir_flags)?;
+        self.publish_change(path, ChangeKind::FlagChange);
+        Ok(())
+    }
+
+    #[instrument(skip(self, path))]
+    async fn expunge(&self, path: &Path, id: &str) -> color_eyre::eyre::Result<()> {
+        let maildir = Maildir::from(path.to_path_buf());
+        maildir.delete(id)?;
+        sqlx::query("DELETE FROM mails WHERE maildir_id = $1")
+            .bind(id)
+            .execute(self.db.get_pool())
+            .await?;
+        let (username, _) = user_and_mailbox(path).context("Invalid mailbox path")?;
+        self.search
+            .remove_mails(&username, vec![id.to_string()])
+            .await?;
+        self.publish_change(path, ChangeKind::MessageExpunge);
         Ok(())
     }
 
+    #[instrument(skip(self, path))]
+    async fn search_text(
+        &self,
+        path: &Path,
+        field: SearchField,
+        query: &str,
+    ) -> color_eyre::eyre::Result<Option<HashSet<String>>> {
+        let (username, mailbox) = user_and_mailbox(path).context("Invalid mailbox path")?;
+        self.search.search(&username, &mailbox, field, query).await
+    }
+
+    #[instrument(skip(self))]
+    async fn rebuild_search_index(&self, username: &str) -> color_eyre::eyre::Result<usize> {
+        let user_path = Path::new(&self.config.mail.maildir_folders).join(username);
+        let mail_paths = mail_files(&user_path)?;
+        // The files are only read while indexing to not keep whole mailboxes in memory
+        let mails =
+            mail_paths
+                .into_iter()
+                .filter_map(|(mailbox, id, path)| match std::fs::read(path) {
+                    Ok(data) => Some((mailbox, id, data)),
+                    Err(e) => {
+                        error!("[Search] Failed to read message {}: {}", id, e);
+                        None
+                    }
+                });
+        // Listed again at the end to keep what got delivered in the meantime
+        let current = move || -> color_eyre::eyre::Result<HashSet<String>> {
+            Ok(mail_files(&user_path)?
+                .into_iter()
+                .map(|(_, id, _)| id)
+                .collect())
+        };
+        self.search.rebuild(username, mails, current).await
+    }
+
+    fn subscribe_changes(&self, username: &str) -> broadcast::Receiver<MailboxChange> {
+        self.changes.subscribe(username)
+    }
+
+    #[instrument(skip(self, path))]
+    fn publish_change(&self, path: &Path, kind: ChangeKind) {
+        let Some((username, folder)) = user_and_mailbox(path) else {
+            error!("[Changes] Unable to get user and mailbox from {:?}", path);
+            return;
+        };
+        self.changes.publish(
+            &username,
+            MailboxChange {
+                mailbox: imap_mailbox_name(&folder),
+                kind,
+            },
+        );
+    }
+
     fn to_ondisk_path(&self, path: String, username: String) -> color_eyre::eyre::Result<PathBuf> {
         let folder = self.to_ondisk_path_name(path)?;
         let mailbox_path = Path::new(&self.config.mail.maildir_folders)
@@ -440,21 +1058,61 @@ impl MailStorage<MaildirMailEntry> for MaildirStorage {
 #[derive(sqlx::FromRow)]
 struct DbMails {
     id: i64,
-    #[allow(dead_code)]
     maildir_id: String,
+    email_id: String,
+    thread_id: String,
+    preview: Option<String>,
+    size: Option<i64>,
+    delivered: Option<i64>,
 }
 
 /// Wrapper for the mailentries from the Maildir crate
 pub struct MaildirMailEntry {
     entry: maildir::MailEntry,
     uid: i64,
+    email_id: Option<String>,
+    thread_id: Option<String>,
+    preview: Option<String>,
     /// The sequence number. It is None until used
     pub sequence_number: Option<i64>,
     date: Option<i64>,
+    internal_date: Option<i64>,
+    size: Option<u64>,
     mail_state: MailState,
 }
 
 impl MaildirMailEntry {
+    fn new(
+        entry: maildir::MailEntry,
+        row: Option<DbMails>,
+        mail_state: impl Fn(&maildir::MailEntry) -> MailState,
+    ) -> Self {
+        let mail_state = mail_state(&entry);
+        let (uid, email_id, thread_id, preview, size, internal_date) =
+            row.map_or((0, None, None, None, None, None), |row| {
+                (
+                    row.id,
+                    Some(row.email_id),
+                    Some(row.thread_id),
+                    row.preview,
+                    row.size.and_then(|size| size.try_into().ok()),
+                    row.delivered,
+                )
+            });
+        MaildirMailEntry {
+            entry,
+            uid,
+            email_id,
+            thread_id,
+            preview,
+            sequence_number: None,
+            date: None,
+            internal_date,
+            size,
+            mail_state,
+        }
+    }
+
     /// Loads async data in memory for non mut usage
     /// FIXME: This should probably return the error somewhere
     pub fn load(&mut self) {
@@ -507,6 +1165,41 @@ impl MailEntry for MaildirMailEntry {
         self.date
     }
 
+    #[instrument(skip(self))]
+    fn internal_date(&self) -> Option<i64> {
+        // Messages stored by older versions have no delivery time in the database.
+        // Their file was written on delivery and keeps its modification time when its flags change.
+        self.internal_date.or_else(|| {
+            let modified = std::fs::metadata(self.path()).ok()?.modified().ok()?;
+            let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+            since_epoch.as_secs().try_into().ok()
+        })
+    }
+
+    #[instrument(skip(self))]
+    fn size(&self) -> Option<u64> {
+        self.size.or_else(|| {
+            std::fs::metadata(self.path())
+                .ok()
+                .map(|metadata| metadata.len())
+        })
+    }
+
+    #[instrument(skip(self))]
+    fn email_id(&self) -> Option<&str> {
+        self.email_id.as_deref()
+    }
+
+    #[instrument(skip(self))]
+    fn thread_id(&self) -> Option<&str> {
+        self.thread_id.as_deref()
+    }
+
+    #[instrument(skip(self))]
+    fn preview(&self) -> Option<&str> {
+        self.preview.as_deref()
+    }
+
     #[instrument(skip(self))]
     fn flags(&self) -> &str {
         self.entry.flags()
@@ -548,9 +1241,259 @@ impl MailEntry for MaildirMailEntry {
     }
 }
 
-#[instrument(skip(filename))]
-async fn lines_from_file(filename: impl AsRef<Path>) -> std::io::Result<Vec<String>> {
-    LinesStream::new(BufReader::new(File::open(filename).await?).lines())
-        .try_collect::<Vec<String>>()
-        .await
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::backend::database::get_database;
+
+    async fn storage() -> MaildirStorage {
+        let config = crate::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(get_database(Arc::clone(&config)).await.unwrap());
+        MaildirStorage::new(database, config)
+    }
+
+    /// A mailbox of a user no other test uses
+    fn mailbox(storage: &MaildirStorage, name: &str) -> PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        Path::new(&storage.config.mail.maildir_folders)
+            .join(format!("folders-{nanos}@localhost"))
+            .join(name)
+    }
+
+    #[tokio::test]
+    async fn test_subscription_and_attributes() {
+        let storage = storage().await;
+        let path = mailbox(&storage, ".Archive");
+        assert!(storage.get_flags(&path).await.unwrap().is_empty());
+
+        storage.add_flag(&path, SUBSCRIBED).await.unwrap();
+        storage.add_flag(&path, "\\Archive").await.unwrap();
+        storage.add_flag(&path, "\\Archive").await.unwrap();
+        assert_eq!(
+            storage.get_flags(&path).await.unwrap(),
+            vec![String::from("\\Archive"), String::from(SUBSCRIBED)]
+        );
+
+        storage.remove_flag(&path, SUBSCRIBED).await.unwrap();
+        assert_eq!(
+            storage.get_flags(&path).await.unwrap(),
+            vec![String::from("\\Archive")]
+        );
+        storage.remove_flag(&path, "\\Archive").await.unwrap();
+        assert!(storage.get_flags(&path).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_keeps_subscription() {
+        let storage = storage().await;
+        let path = mailbox(&storage, ".Archive");
+        storage.create_dirs(&path).unwrap();
+        storage.add_flag(&path, SUBSCRIBED).await.unwrap();
+        storage.add_flag(&path, "\\Archive").await.unwrap();
+        let mailbox_id = storage.mailbox_id(&path).await.unwrap();
+
+        storage.delete_mailbox(&path).await.unwrap();
+        assert!(!path.exists());
+        assert_eq!(
+            storage.get_flags(&path).await.unwrap(),
+            vec![String::from(SUBSCRIBED)]
+        );
+
+        storage.create_dirs(&path).unwrap();
+        assert_ne!(storage.mailbox_id(&path).await.unwrap(), mailbox_id);
+    }
+
+    #[tokio::test]
+    async fn test_import_folder_flags() {
+        let storage = storage().await;
+        let path = mailbox(&storage, ".Sent");
+        let other = path.with_file_name(".Drafts");
+        storage.create_dirs(&path).unwrap();
+        storage.create_dirs(&other).unwrap();
+        // Older versions did not terminate the last flag with a newline
+        tokio::fs::write(path.join(LEGACY_FLAGS_FILE), "\\Subscribed\n\\Sent")
+            .await
+            .unwrap();
+
+        let username = path
+            .parent()
+            .and_then(Path::file_name)
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        assert_eq!(storage.import_folder_flags(&username).await.unwrap(), 1);
+        assert!(!path.join(LEGACY_FLAGS_FILE).exists());
+        assert_eq!(
+            storage.get_flags(&path).await.unwrap(),
+            vec![String::from("\\Sent"), String::from(SUBSCRIBED)]
+        );
+        assert!(storage.get_flags(&other).await.unwrap().is_empty());
+
+        // The flags are only imported once
+        assert_eq!(storage.import_folder_flags(&username).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shared_server_metadata() {
+        let storage = storage().await;
+        let first = mailbox(&storage, "INBOX");
+        let second = mailbox(&storage, "INBOX");
+        let first = first
+            .parent()
+            .and_then(Path::file_name)
+            .unwrap()
+            .to_string_lossy();
+        let second = second
+            .parent()
+            .and_then(Path::file_name)
+            .unwrap()
+            .to_string_lossy();
+        let entry = format!("/shared/vendor/{first}");
+        storage
+            .set_metadata(
+                &first,
+                None,
+                &[
+                    (entry.clone(), Some(String::from("shared"))),
+                    (
+                        String::from("/private/comment"),
+                        Some(String::from("private")),
+                    ),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let requested = [String::from("/shared"), String::from("/private")];
+        let found = storage
+            .get_metadata(&second, None, &requested)
+            .await
+            .unwrap();
+        assert!(found.contains(&(entry.clone(), String::from("shared"))));
+        assert!(!found.iter().any(|(_, value)| value == "private"));
+        assert!(storage
+            .get_metadata(&first, None, &requested)
+            .await
+            .unwrap()
+            .contains(&(String::from("/private/comment"), String::from("private"))));
+
+        storage
+            .set_metadata(&second, None, &[(entry.clone(), None)])
+            .await
+            .unwrap();
+        assert!(!storage
+            .get_metadata(&first, None, &requested)
+            .await
+            .unwrap()
+            .iter()
+            .any(|(found, _)| *found == entry));
+    }
+
+    async fn thread_id(storage: &MaildirStorage, path: &Path, id: &str) -> String {
+        storage
+            .find(path, id)
+            .await
+            .unwrap()
+            .thread_id()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_threads_stay_per_user() {
+        let storage = storage().await;
+        let first = mailbox(&storage, "INBOX");
+        let second = mailbox(&storage, "INBOX");
+        storage.create_dirs(&first).unwrap();
+        storage.create_dirs(&second).unwrap();
+        let message_id = format!(
+            "<{}>",
+            first
+                .parent()
+                .and_then(Path::file_name)
+                .unwrap()
+                .to_string_lossy()
+        );
+        let original = format!("Message-ID: {message_id}\r\nSubject: Test\r\n\r\nHello\r\n");
+        let reply = format!("In-Reply-To: {message_id}\r\nSubject: Re: Test\r\n\r\nHi\r\n");
+
+        let original_id = storage
+            .store_new(&first, original.as_bytes())
+            .await
+            .unwrap();
+        let reply_id = storage.store_new(&first, reply.as_bytes()).await.unwrap();
+        let foreign_id = storage.store_new(&second, reply.as_bytes()).await.unwrap();
+
+        let original_thread = thread_id(&storage, &first, &original_id).await;
+        assert_eq!(
+            thread_id(&storage, &first, &reply_id).await,
+            original_thread
+        );
+        assert_ne!(
+            thread_id(&storage, &second, &foreign_id).await,
+            original_thread
+        );
+    }
+
+    #[tokio::test]
+    async fn test_size_and_internal_date() {
+        let storage = storage().await;
+        let path = mailbox(&storage, "INBOX");
+        storage.create_dirs(&path).unwrap();
+        // The Date header is not the time the message arrived
+        let data = b"Date: Mon, 1 Jan 2001 00:00:00 +0000\r\nSubject: Test\r\n\r\nHello\r\n";
+        let id = storage.store_new(&path, data).await.unwrap();
+
+        let mut mail = storage.find(&path, &id).await.unwrap();
+        assert_eq!(mail.size(), Some(data.len() as u64));
+        let now: i64 = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .try_into()
+            .unwrap();
+        let internal_date = mail.internal_date().unwrap();
+        assert!((now - 60..=now).contains(&internal_date));
+        mail.load();
+        assert_eq!(mail.date(), Some(978_307_200));
+
+        // APPEND may give the internal date explicitly
+        let id = storage
+            .store_cur_with_flags(&path, data, vec![], Some(837_596_665))
+            .await
+            .unwrap();
+        let mail = storage.find(&path, &id).await.unwrap();
+        assert_eq!(mail.internal_date(), Some(837_596_665));
+    }
+
+    #[tokio::test]
+    async fn test_foreign_mail_gets_uid() {
+        let storage = storage().await;
+        let path = mailbox(&storage, "INBOX");
+        storage.create_dirs(&path).unwrap();
+        let known = storage
+            .store_new(&path, b"Subject: Known\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+        // Delivered by another program without a database row
+        let foreign = Maildir::from(path.clone())
+            .store_new(b"Subject: Foreign\r\n\r\nHello\r\n")
+            .unwrap();
+
+        let mails = storage.list_all(&path).await;
+        let uid = |id: &str| mails.iter().find(|mail| mail.id() == id).unwrap().uid();
+        assert_ne!(uid(&foreign), 0);
+        assert!(uid(&foreign) > uid(&known));
+
+        // Listing again keeps the uid
+        let again = storage.list_all(&path).await;
+        let foreign_again = again.iter().find(|mail| mail.id() == foreign).unwrap();
+        assert_eq!(foreign_again.uid(), uid(&foreign));
+    }
 }
@@ -1,19 +1,26 @@
 use crate::{
     backend::{
+        changes::{ChangeKind, MailboxChange},
         database::DB,
+        search::SearchField,
         storage::maildir::{MaildirMailEntry, MaildirStorage},
     },
     config::Config,
 };
 use mailparse::{MailHeader, ParsedMail};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::sync::broadcast;
 use tracing::instrument;
 
 /// The maildir format
 #[cfg(feature = "maildir")]
 pub mod maildir;
 
+/// Plaintext previews of messages
+pub mod preview;
+
 /// The current storage type
 #[cfg(feature = "maildir")]
 pub type Storage = MaildirStorage;
@@ -50,6 +57,16 @@ pub trait MailEntry {
     fn received(&mut self) -> color_eyre::eyre::Result<i64>;
     /// The date of the email
     fn date(&self) -> Option<i64>;
+    /// The time the email was delivered to the mailbox as unix timestamp
+    fn internal_date(&self) -> Option<i64>;
+    /// The size of the email in octets
+    fn size(&self) -> Option<u64>;
+    /// The immutable object id of the email. `None` if the email is not known to the database.
+    fn email_id(&self) -> Option<&str>;
+    /// The object id of the thread the email belongs to
+    fn thread_id(&self) -> Option<&str>;
+    /// The cached plaintext preview of the email. `None` if it was not generated yet.
+    fn preview(&self) -> Option<&str>;
     /// The flags of the email
     fn flags(&self) -> &str;
     /// Whether the email is a draft
@@ -77,24 +94,65 @@ pub trait MailStorage<M: MailEntry> {
     /// Get the current UID for the folder
     fn get_uid_for_folder(&self, path: &Path) -> color_eyre::eyre::Result<u32>;
     /// Get the current flags for the folder
-    async fn get_flags(&self, path: &Path) -> std::io::Result<Vec<String>>;
+    async fn get_flags(&self, path: &Path) -> color_eyre::eyre::Result<Vec<String>>;
     /// Set a new flag for the folder
     async fn add_flag(&self, path: &Path, flag: &str) -> color_eyre::eyre::Result<()>;
     /// Remove a flag from the folder
     async fn remove_flag(&self, path: &Path, flag: &str) -> color_eyre::eyre::Result<()>;
+    /// Import the flag files older versions kept in the folders of the user into the database.
+    ///
+    /// Returns the number of imported folders.
+    async fn import_folder_flags(&self, username: &str) -> color_eyre::eyre::Result<usize>;
+    /// Record the Message-ID, the owner and the preview of the messages of the user stored before they were recorded.
+    ///
+    /// Without them replies can not join the threads of those messages. Returns the number of updated messages.
+    async fn backfill_message_ids(&self, username: &str) -> color_eyre::eyre::Result<usize>;
+    /// Get the immutable object id of the mailbox. It stays the same when the mailbox is renamed.
+    async fn mailbox_id(&self, path: &Path) -> color_eyre::eyre::Result<String>;
+    /// Get the metadata of a mailbox or of the server if no mailbox is given.
+    ///
+    /// Returns the entries which are one of the requested ones or lie below them.
+    async fn get_metadata(
+        &self,
+        username: &str,
+        path: Option<&Path>,
+        entries: &[String],
+    ) -> color_eyre::eyre::Result<Vec<(String, String)>>;
+    /// Set metadata entries of a mailbox or of the server. A value of `None` removes the entry.
+    ///
+    /// Either all entries are changed or none of them.
+    async fn set_metadata(
+        &self,
+        username: &str,
+        path: Option<&Path>,
+        entries: &[(String, Option<String>)],
+    ) -> color_eyre::eyre::Result<()>;
     /// Creates the required folder structure
     fn create_dirs(&self, path: &Path) -> color_eyre::eyre::Result<()>;
     /// Store new message
     async fn store_new(&self, path: &Path, data: &[u8]) -> color_eyre::eyre::Result<String>;
-    /// Store a message
+    /// Store a message.
+    ///
+    /// `internal_date` is the unix timestamp the message counts as delivered at. `None` means now.
     async fn store_cur_with_flags(
         &self,
         path: &Path,
         data: &[u8],
         flags: Vec<String>,
+        internal_date: Option<i64>,
     ) -> color_eyre::eyre::Result<String>;
     /// List the subfolders
     fn list_subdirs(&self, path: &Path) -> color_eyre::eyre::Result<Vec<PathBuf>>;
+    /// List the mailboxes below the mailbox in the hierarchy
+    fn list_children(&self, path: &Path) -> color_eyre::eyre::Result<Vec<PathBuf>>;
+    /// Delete a mailbox and all of its messages.
+    ///
+    /// A mailbox with children only loses its messages and gets the `\Noselect` flag.
+    async fn delete_mailbox(&self, path: &Path) -> color_eyre::eyre::Result<()>;
+    /// Rename a mailbox together with its children.
+    ///
+    /// Renaming INBOX moves its messages into the new mailbox and leaves INBOX empty.
+    async fn rename_mailbox(&self, path: &Path, new_path: &Path) -> color_eyre::eyre::Result<()>;
     /// Count of current messages
     fn count_cur(&self, path: &Path) -> usize;
     /// Count of new messages
@@ -127,6 +185,26 @@ pub trait MailStorage<M: MailEntry> {
         id: &str,
         imap_flags: &[&str],
     ) -> color_eyre::eyre::Result<()>;
+    /// Permanently remove a message from the folder
+    async fn expunge(&self, path: &Path, id: &str) -> color_eyre::eyre::Result<()>;
+    /// Get the ids of the messages in the folder whose text may match the query.
+    ///
+    /// Every matching message is part of the result, but the result may contain messages which
+    /// don't match. Returns `None` if the query does not narrow down the messages.
+    async fn search_text(
+        &self,
+        path: &Path,
+        field: SearchField,
+        query: &str,
+    ) -> color_eyre::eyre::Result<Option<HashSet<String>>>;
+    /// Rebuild the search index of a user from the stored messages.
+    ///
+    /// This works while the server is running.
+    async fn rebuild_search_index(&self, username: &str) -> color_eyre::eyre::Result<usize>;
+    /// Get a stream of all future changes to the mailboxes of the user
+    fn subscribe_changes(&self, username: &str) -> broadcast::Receiver<MailboxChange>;
+    /// Tell all sessions of the owner of the mailbox about a change which did not happen through the storage
+    fn publish_change(&self, path: &Path, kind: ChangeKind);
     /// Converts the imap path to a local path
     fn to_ondisk_path(&self, path: String, username: String) -> color_eyre::eyre::Result<PathBuf>;
     /// Converts the imap path to a local path name
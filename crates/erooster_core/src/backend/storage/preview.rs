@@ -0,0 +1,168 @@
+use mailparse::{DispositionType, ParsedMail};
+
+/// The maximum length of a preview in characters
+pub const PREVIEW_LENGTH: usize = 256;
+
+/// Elements whose content is never shown as text
+const HIDDEN_ELEMENTS: [&str; 3] = ["head", "script", "style"];
+
+/// Builds the plaintext preview of a message from its first text part.
+///
+/// HTML gets stripped and whitespace collapsed. Messages without a text part have an empty preview.
+#[must_use]
+pub fn preview(mail: &ParsedMail) -> String {
+    let Some(part) = first_text_part(mail) else {
+        return String::new();
+    };
+    let Ok(body) = part.get_body() else {
+        return String::new();
+    };
+    let text = if part.ctype.mimetype.eq_ignore_ascii_case("text/html") {
+        strip_html(&body)
+    } else {
+        body
+    };
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(PREVIEW_LENGTH)
+        .collect()
+}
+
+/// The first part of the message which is meant to be read as text
+fn first_text_part<'a, 'b>(part: &'b ParsedMail<'a>) -> Option<&'b ParsedMail<'a>> {
+    if part.subparts.is_empty() {
+        let mimetype = part.ctype.mimetype.to_lowercase();
+        let attachment = part.get_content_disposition().disposition == DispositionType::Attachment;
+        return ((mimetype == "text/plain" || mimetype == "text/html") && !attachment)
+            .then_some(part);
+    }
+    part.subparts.iter().find_map(first_text_part)
+}
+
+/// Removes all tags from the html and decodes the common entities
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        // Tags separate words. The whitespace gets collapsed later anyway.
+        text.push(' ');
+        let tag = &rest[start..];
+        // ASCII lowercasing keeps the byte offsets intact
+        let lowercase_tag = tag.to_ascii_lowercase();
+        let end = HIDDEN_ELEMENTS
+            .iter()
+            .find(|element| {
+                lowercase_tag[1..].starts_with(*element)
+                    && lowercase_tag[1 + element.len()..]
+                        .starts_with(|c: char| c == '>' || c.is_whitespace())
+            })
+            .map_or_else(
+                || tag.find('>').map(|end| end + 1),
+                |element| {
+                    let closing = format!("</{element}>");
+                    lowercase_tag.find(&closing).map(|end| end + closing.len())
+                },
+            );
+        let Some(end) = end else {
+            // An unterminated tag hides the rest of the document
+            rest = "";
+            break;
+        };
+        rest = &tag[end..];
+    }
+    text.push_str(rest);
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview_empty_body() {
+        let mail = mailparse::parse_mail(b"Subject: Empty\r\n\r\n").unwrap();
+        assert_eq!(preview(&mail), "");
+    }
+
+    #[test]
+    fn test_preview_html_only() {
+        let mail = mailparse::parse_mail(
+            b"Content-Type: text/html; charset=utf-8\r\n\r\n\
+            <html><head><title>Title</title></head>\r\n\
+            <body><p>Hello&nbsp;<b>world</b>\r\n &amp; you</p><script>alert(1)</script></body></html>\r\n",
+        )
+        .unwrap();
+        assert_eq!(preview(&mail), "Hello world & you");
+    }
+
+    #[test]
+    fn test_preview_multipart_alternative() {
+        let mail = mailparse::parse_mail(
+            b"Content-Type: multipart/alternative; boundary=\"b\"\r\n\r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            Plain  text\r\n\
+            --b\r\n\
+            Content-Type: text/html\r\n\r\n\
+            <p>HTML text</p>\r\n\
+            --b--\r\n",
+        )
+        .unwrap();
+        assert_eq!(preview(&mail), "Plain text");
+    }
+
+    #[test]
+    fn test_preview_truncates_at_char_boundary() {
+        let body = "ä".repeat(PREVIEW_LENGTH + 10);
+        let data = format!("Content-Type: text/plain; charset=utf-8\r\n\r\n{body}\r\n");
+        let mail = mailparse::parse_mail(data.as_bytes()).unwrap();
+        let preview = preview(&mail);
+        assert_eq!(preview.chars().count(), PREVIEW_LENGTH);
+        assert!(preview.chars().all(|c| c == 'ä'));
+    }
+
+    #[test]
+    fn test_first_text_part() {
+        let mail = mailparse::parse_mail(
+            b"Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+            --b\r\n\
+            Content-Type: image/png\r\n\r\n\
+            png\r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Disposition: attachment; filename=\"notes.txt\"\r\n\r\n\
+            Attached\r\n\
+            --b\r\n\
+            Content-Type: text/html\r\n\r\n\
+            <p>Body</p>\r\n\
+            --b--\r\n",
+        )
+        .unwrap();
+        let part = first_text_part(&mail).unwrap();
+        assert_eq!(part.ctype.mimetype, "text/html");
+
+        let image = mailparse::parse_mail(b"Content-Type: image/png\r\n\r\npng\r\n").unwrap();
+        assert!(first_text_part(&image).is_none());
+    }
+
+    #[test]
+    fn test_strip_html() {
+        assert_eq!(
+            strip_html("<STYLE type=\"text/css\">p { color: red; }</style>a&lt;b&gt;"),
+            " a<b>"
+        );
+        assert_eq!(strip_html("<header>Title</header>"), " Title ");
+        assert_eq!(strip_html("Text <a href=\"x"), "Text  ");
+        assert_eq!(strip_html("&amp;lt;"), "&lt;");
+    }
+}
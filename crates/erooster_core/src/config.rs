@@ -4,6 +4,55 @@ const fn default_webserver_port() -> u16 {
     8080
 }
 
+const fn default_max_message_size() -> usize {
+    // 25 MiB
+    26_214_400
+}
+
+const fn default_max_connections() -> usize {
+    1024
+}
+
+const fn default_max_connections_per_ip() -> usize {
+    16
+}
+
+const fn default_idle_timeout() -> u64 {
+    300
+}
+
+const fn default_session_timeout() -> u64 {
+    3600
+}
+
+const fn default_queue_lifetime() -> u64 {
+    432_000
+}
+
+const fn default_delay_warning() -> u64 {
+    14_400
+}
+
+fn default_listeners() -> Vec<Listener> {
+    vec![
+        Listener {
+            port: 25,
+            role: ListenerRole::Mx,
+            tls: false,
+        },
+        Listener {
+            port: 587,
+            role: ListenerRole::Submission,
+            tls: false,
+        },
+        Listener {
+            port: 465,
+            role: ListenerRole::Submission,
+            tls: true,
+        },
+    ]
+}
+
 /// The config for the mailserver
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
@@ -22,6 +71,92 @@ pub struct Config {
     pub webserver: Webserver,
     /// The config related to the optional rspamd integration
     pub rspamd: Option<Rspamd>,
+    /// Ports and limits of the SMTP server
+    #[serde(default)]
+    pub smtp: Smtp,
+}
+
+/// Ports and limits of the SMTP server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Smtp {
+    /// The ports the SMTP server listens on and what they are used for
+    #[serde(default = "default_listeners")]
+    pub listeners: Vec<Listener>,
+    /// How many sessions may be open at the same time across all ports.
+    /// New connections are not accepted while the limit is reached.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// How many sessions a single IP may have open at the same time
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_connections_per_ip: usize,
+    /// Seconds to wait for the next command before closing the session.
+    /// RFC 5321 asks for at least 5 minutes.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Seconds a session may last in total
+    #[serde(default = "default_session_timeout")]
+    pub session_timeout: u64,
+    /// What happens to mail of users sending as an address they don't own
+    #[serde(default)]
+    pub sender_policy: SenderPolicy,
+    /// Seconds to keep retrying mail for other servers before it gets bounced.
+    /// RFC 5321 asks for at least 4 to 5 days.
+    #[serde(default = "default_queue_lifetime")]
+    pub queue_lifetime: u64,
+    /// Seconds after which the sender is told that the mail is still being retried
+    #[serde(default = "default_delay_warning")]
+    pub delay_warning: u64,
+}
+
+impl Default for Smtp {
+    fn default() -> Self {
+        Self {
+            listeners: default_listeners(),
+            max_connections: default_max_connections(),
+            max_connections_per_ip: default_max_connections_per_ip(),
+            idle_timeout: default_idle_timeout(),
+            session_timeout: default_session_timeout(),
+            sender_policy: SenderPolicy::default(),
+            queue_lifetime: default_queue_lifetime(),
+            delay_warning: default_delay_warning(),
+        }
+    }
+}
+
+/// A port the SMTP server listens on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Listener {
+    /// The port to listen on
+    pub port: u16,
+    /// What the port is used for
+    pub role: ListenerRole,
+    /// If enabled TLS starts right away as described in RFC 8314 instead of using STARTTLS
+    #[serde(default)]
+    pub tls: bool,
+}
+
+/// What an SMTP listener is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerRole {
+    /// Receives mail for local users from other servers. Authentication is never offered.
+    Mx,
+    /// Users submit their mail here as described in RFC 6409.
+    /// STARTTLS and authentication are required before MAIL FROM.
+    Submission,
+}
+
+/// What happens to mail of users sending as an address they don't own.
+///
+/// Applies to both the envelope sender and the From header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SenderPolicy {
+    /// The mail gets refused
+    #[default]
+    Reject,
+    /// The address of the user is put in place of the one given
+    Rewrite,
 }
 
 /// The config for the webserver
@@ -79,6 +214,9 @@ pub struct Mail {
     pub dkim_key_path: String,
     /// The selector to be used in the dkim header
     pub dkim_key_selector: String,
+    /// The largest message in bytes that gets accepted over SMTP or IMAP APPEND
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
 }
 
 impl Config {
@@ -1,4 +1,4 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use simdutf8::compat::from_utf8;
 use std::{cmp, fmt, io, str, usize};
 use tokio_util::codec::{Decoder, Encoder};
@@ -201,6 +201,18 @@ impl Encoder<String> for LinesCodec {
     }
 }
 
+/// Raw data like IMAP literals is written as is without adding a line ending.
+impl Encoder<Bytes> for LinesCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, data: Bytes, buf: &mut BytesMut) -> Result<(), LinesCodecError> {
+        buf.reserve(data.len());
+        buf.put(data);
+        debug!("sending {} raw bytes", buf.len());
+        Ok(())
+    }
+}
+
 impl Default for LinesCodec {
     fn default() -> Self {
         Self::new()
@@ -1,5 +1,8 @@
 use crate::{
-    commands::{parsers::append_arguments, CommandData, Data},
+    commands::{
+        parsers::{append_arguments, DateTime},
+        CommandData, Data,
+    },
     servers::state::{AppendingState, State},
 };
 use color_eyre::eyre::ContextCompat;
@@ -18,11 +21,12 @@ pub struct Append<'a> {
 }
 
 impl Append<'_> {
-    #[instrument(skip(self, lines, storage, command_data))]
+    #[instrument(skip(self, lines, storage, append_limit, command_data))]
     pub async fn exec<S, E>(
         &self,
         lines: &mut S,
         storage: Arc<Storage>,
+        append_limit: usize,
         command_data: &CommandData<'_>,
     ) -> color_eyre::eyre::Result<()>
     where
@@ -85,6 +89,17 @@ impl Append<'_> {
             match append_arguments(append_args_borrow).finish() {
                 Ok((left, (flags, datetime, literal))) => {
                     debug!("[Append] leftover: {}", left);
+                    let too_big = literal.length > append_limit;
+                    // A synchronizing literal can be refused before the client sends it
+                    if too_big && !literal.continuation {
+                        lines
+                            .send(format!(
+                                "{} NO [TOOBIG] Message is larger than the APPENDLIMIT of {append_limit} bytes",
+                                command_data.tag
+                            ))
+                            .await?;
+                        return Ok(());
+                    }
                     write_lock.state = State::Appending(AppendingState {
                         folder: folder.to_string(),
                         flags: flags.map(|x| x.iter().map(ToString::to_string).collect::<Vec<_>>()),
@@ -92,6 +107,7 @@ impl Append<'_> {
                         data: None,
                         datalen: literal.length,
                         tag: command_data.tag.to_string(),
+                        too_big,
                     });
                     if !literal.continuation {
                         lines.send(String::from("+ Ready for literal data")).await?;
@@ -137,7 +153,19 @@ impl Append<'_> {
             .clone()
             .context("Username missing in internal State")?;
         if let State::Appending(state) = &mut write_lock.state {
-            if let Some(buffer) = &mut state.data {
+            if state.too_big {
+                // Nothing gets kept, the literal only has to be read up to its end
+                state.datalen = state.datalen.saturating_sub(append_data.len() + 2);
+                if state.datalen == 0 {
+                    write_lock.state = State::GotAppendData;
+                    lines
+                        .send(format!(
+                            "{tag} NO [TOOBIG] Message is larger than the APPENDLIMIT of {} bytes",
+                            config.mail.max_message_size
+                        ))
+                        .await?;
+                }
+            } else if let Some(buffer) = &mut state.data {
                 write!(buffer, "{append_data}\r\n")?;
                 debug!("Buffer length: {}", buffer.len());
                 debug!("expected: {}", state.datalen);
@@ -155,6 +183,7 @@ impl Append<'_> {
                             &mailbox_path,
                             buffer,
                             state.flags.clone().unwrap_or_default(),
+                            state.datetime.as_ref().and_then(DateTime::timestamp),
                         )
                         .await?;
                     debug!("Stored message via append: {}", message_id);
@@ -1,22 +1,39 @@
-use crate::commands::CommandData;
+use crate::{
+    commands::{CommandData, Data},
+    servers::state::State,
+};
 use futures::{Sink, SinkExt};
 use tracing::instrument;
 
-pub struct Capability;
+pub struct Capability<'a> {
+    pub data: &'a Data,
+}
 
-impl Capability {
-    #[instrument(skip(self, lines, command_data))]
+impl Capability<'_> {
+    #[instrument(skip(self, lines, append_limit, command_data))]
     pub async fn exec<S, E>(
         &self,
         lines: &mut S,
+        append_limit: usize,
         command_data: &CommandData<'_>,
     ) -> color_eyre::eyre::Result<()>
     where
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
         S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
     {
-        let capabilities = get_capabilities();
-        lines.feed(format!("* {capabilities}")).await?;
+        let authenticated = matches!(
+            self.data.con_state.read().await.state,
+            State::Authenticated | State::Selected(_, _)
+        );
+        let capabilities = get_capabilities(authenticated);
+        // The limit comes from the config so it can't be part of the constant list (RFC 7889)
+        if authenticated {
+            lines
+                .feed(format!("* {capabilities} APPENDLIMIT={append_limit}"))
+                .await?;
+        } else {
+            lines.feed(format!("* {capabilities}")).await?;
+        }
         lines
             .feed(format!("{} OK CAPABILITY completed", command_data.tag))
             .await?;
@@ -25,31 +42,68 @@ impl Capability {
     }
 }
 
-pub const fn get_capabilities() -> &'static str {
-    "CAPABILITY AUTH=PLAIN LOGINDISABLED UTF8=ONLY ENABLE IMAP4rev2 IMAP4rev1"
+/// The capabilities we offer. Some of them are only usable after authentication.
+///
+/// Literals are only read for the message of APPEND, which is why neither LITERAL+ nor LITERAL-
+/// is offered. Strings in all other commands, like SETMETADATA values and SEARCH keys, have to be
+/// quoted. Commands using a literal anyway get a tagged BAD response.
+pub const fn get_capabilities(authenticated: bool) -> &'static str {
+    if authenticated {
+        "CAPABILITY AUTH=PLAIN LOGINDISABLED UTF8=ONLY ENABLE IMAP4rev2 IMAP4rev1 ESEARCH SEARCHRES NOTIFY ID UNSELECT NAMESPACE METADATA OBJECTID PREVIEW COMPRESS=DEFLATE"
+    } else {
+        "CAPABILITY AUTH=PLAIN LOGINDISABLED UTF8=ONLY ENABLE IMAP4rev2 IMAP4rev1 ESEARCH SEARCHRES NOTIFY ID UNSELECT NAMESPACE METADATA OBJECTID PREVIEW"
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::commands::{CommandData, Commands};
+    use crate::servers::state::Connection;
     use futures::{channel::mpsc, StreamExt};
 
     #[tokio::test]
     async fn test_get_capabilities() {
-        let caps = Capability {};
+        let caps = Capability {
+            data: &Data {
+                con_state: Connection::new(true),
+            },
+        };
+        let cmd_data = CommandData {
+            tag: "",
+            command: Commands::Capability,
+            arguments: &[],
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        let res = caps.exec(&mut tx, 1024, &cmd_data).await;
+        assert!(res.is_ok());
+        assert_eq!(
+            rx.next().await,
+            Some(String::from(
+                "* CAPABILITY AUTH=PLAIN LOGINDISABLED UTF8=ONLY ENABLE IMAP4rev2 IMAP4rev1 ESEARCH SEARCHRES NOTIFY ID UNSELECT NAMESPACE METADATA OBJECTID PREVIEW"
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_authenticated_capabilities() {
+        let con_state = Connection::new(true);
+        con_state.write().await.state = State::Authenticated;
+        let caps = Capability {
+            data: &Data { con_state },
+        };
         let cmd_data = CommandData {
             tag: "",
             command: Commands::Capability,
             arguments: &[],
         };
         let (mut tx, mut rx) = mpsc::unbounded();
-        let res = caps.exec(&mut tx, &cmd_data).await;
+        let res = caps.exec(&mut tx, 1024, &cmd_data).await;
         assert!(res.is_ok());
         assert_eq!(
             rx.next().await,
             Some(String::from(
-                "* CAPABILITY AUTH=PLAIN LOGINDISABLED UTF8=ONLY ENABLE IMAP4rev2 IMAP4rev1"
+                "* CAPABILITY AUTH=PLAIN LOGINDISABLED UTF8=ONLY ENABLE IMAP4rev2 IMAP4rev1 ESEARCH SEARCHRES NOTIFY ID UNSELECT NAMESPACE METADATA OBJECTID PREVIEW COMPRESS=DEFLATE APPENDLIMIT=1024"
             ))
         );
     }
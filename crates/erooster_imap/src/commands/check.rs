@@ -1,10 +1,10 @@
 use std::sync::Arc;
 
 use crate::{
-    commands::{CommandData, Data},
+    commands::{updates::send_updates, CommandData, Data},
     servers::state::State,
 };
-use erooster_core::backend::storage::{MailEntryType, MailStorage, Storage};
+use erooster_core::backend::storage::Storage;
 use futures::{Sink, SinkExt};
 use tracing::instrument;
 
@@ -26,22 +26,9 @@ impl Check<'_> {
     {
         // This is an Imap4rev1 feature. It does the same as Noop for us as we have no memory gc.
         // It also only is allowed in selected state
-        if let State::Selected(folder, _) = &self.data.con_state.read().await.state {
-            let folder = folder.replace('/', ".");
-            let Some(username) = self.data.con_state.read().await.username.clone() else {
-                lines
-                    .send(format!("{} NO invalid state", command_data.tag))
-                    .await?;
-                return Ok(());
-            };
-            let mailbox_path = storage.to_ondisk_path(folder.clone(), username)?;
-            let mails: Vec<MailEntryType> = storage.list_new(&mailbox_path).await;
-            let got_new = !mails.is_empty();
-            if got_new {
-                let mails: Vec<MailEntryType> = storage.list_all(&mailbox_path).await;
-                lines.send(format!("* {} EXISTS", mails.len())).await?;
-            }
-
+        let state = self.data.con_state.read().await.state.clone();
+        if matches!(state, State::Selected(_, _)) {
+            send_updates(self.data, lines, &storage, true).await?;
             lines
                 .send(format!("{} OK CHECK completed", command_data.tag))
                 .await?;
@@ -61,7 +48,7 @@ mod tests {
     use crate::commands::{CommandData, Commands};
     use crate::servers::state::{Access, Connection};
     use futures::{channel::mpsc, StreamExt};
-    use std::sync::Arc;
+    use std::{collections::HashSet, sync::Arc};
     use tokio::sync::RwLock;
 
     #[tokio::test]
@@ -73,6 +60,11 @@ mod tests {
                     secure: true,
                     username: Some(String::from("test")),
                     active_capabilities: vec![],
+                    saved_search: HashSet::new(),
+                    mailbox_index: None,
+                    notify: vec![],
+                    compressed: false,
+                    session_span: tracing::Span::none(),
                 })),
             },
         };
@@ -108,6 +100,11 @@ mod tests {
                     secure: true,
                     username: None,
                     active_capabilities: vec![],
+                    saved_search: HashSet::new(),
+                    mailbox_index: None,
+                    notify: vec![],
+                    compressed: false,
+                    session_span: tracing::Span::none(),
                 })),
             },
         };
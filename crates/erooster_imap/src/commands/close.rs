@@ -6,7 +6,6 @@ use color_eyre::eyre::ContextCompat;
 use erooster_core::backend::storage::{MailEntry, MailStorage, Storage};
 use futures::{Sink, SinkExt};
 use std::sync::Arc;
-use tokio::fs;
 use tracing::{debug, instrument};
 
 pub struct Close<'a> {
@@ -52,14 +51,11 @@ impl Close<'_> {
             for mail in mails {
                 debug!("Checking mails");
                 if mail.is_trashed() {
-                    let path = mail.path();
-                    fs::remove_file(path).await?;
+                    storage.expunge(&mailbox_path, mail.id()).await?;
                 }
             }
 
-            {
-                write_lock.state = State::Authenticated;
-            };
+            write_lock.deselect();
             lines
                 .send(format!("{} OK CLOSE completed", command_data.tag))
                 .await?;
@@ -80,7 +76,7 @@ mod tests {
     use crate::commands::{CommandData, Commands};
     use crate::servers::state::{Access, Connection};
     use futures::{channel::mpsc, StreamExt};
-    use std::sync::Arc;
+    use std::{collections::HashSet, sync::Arc};
     use tokio::sync::RwLock;
 
     #[tokio::test]
@@ -92,6 +88,11 @@ mod tests {
                     secure: true,
                     username: Some(String::from("test")),
                     active_capabilities: vec![],
+                    saved_search: HashSet::new(),
+                    mailbox_index: None,
+                    notify: vec![],
+                    compressed: false,
+                    session_span: tracing::Span::none(),
                 })),
             },
         };
@@ -127,6 +128,11 @@ mod tests {
                     secure: true,
                     username: Some(String::from("test")),
                     active_capabilities: vec![],
+                    saved_search: HashSet::new(),
+                    mailbox_index: None,
+                    notify: vec![],
+                    compressed: false,
+                    session_span: tracing::Span::none(),
                 })),
             },
         };
@@ -165,6 +171,11 @@ mod tests {
                     secure: true,
                     username: None,
                     active_capabilities: vec![],
+                    saved_search: HashSet::new(),
+                    mailbox_index: None,
+                    notify: vec![],
+                    compressed: false,
+                    session_span: tracing::Span::none(),
                 })),
             },
         };
@@ -0,0 +1,56 @@
+use crate::{
+    commands::{CommandData, Data},
+    servers::state::State,
+};
+use futures::{Sink, SinkExt};
+use tracing::instrument;
+
+pub struct Compress<'a> {
+    pub data: &'a Data,
+}
+
+impl Compress<'_> {
+    #[instrument(skip(self, lines, command_data))]
+    pub async fn exec<S, E>(
+        &self,
+        lines: &mut S,
+        command_data: &CommandData<'_>,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let mut write_lock = self.data.con_state.write().await;
+        if !matches!(
+            write_lock.state,
+            State::Authenticated | State::Selected(_, _)
+        ) {
+            lines
+                .send(format!("{} NO invalid state", command_data.tag))
+                .await?;
+        } else if command_data.arguments.len() != 1
+            || !command_data.arguments[0].eq_ignore_ascii_case("DEFLATE")
+        {
+            lines
+                .send(format!(
+                    "{} BAD Unsupported compression mechanism",
+                    command_data.tag
+                ))
+                .await?;
+        } else if write_lock.compressed {
+            lines
+                .send(format!(
+                    "{} NO [COMPRESSIONACTIVE] DEFLATE active via COMPRESS",
+                    command_data.tag
+                ))
+                .await?;
+        } else {
+            // The OK still goes out uncompressed. The server wraps the stream once the command is done.
+            lines
+                .send(format!("{} OK DEFLATE active", command_data.tag))
+                .await?;
+            write_lock.compressed = true;
+        }
+        Ok(())
+    }
+}
@@ -55,8 +55,12 @@ impl Create<'_> {
                         storage.add_flag(&mailbox_path, "\\Trash").await?;
                         storage.add_flag(&mailbox_path, "\\Subscribed").await?;
                     }
+                    let mailbox_id = storage.mailbox_id(&mailbox_path).await?;
                     lines
-                        .send(format!("{} OK CREATE completed", command_data.tag))
+                        .send(format!(
+                            "{} OK [MAILBOXID ({mailbox_id})] CREATE completed",
+                            command_data.tag
+                        ))
                         .await?;
                 }
                 Err(e) => {
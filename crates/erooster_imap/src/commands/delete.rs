@@ -3,7 +3,6 @@ use color_eyre::eyre::ContextCompat;
 use erooster_core::backend::storage::{MailStorage, Storage};
 use futures::{Sink, SinkExt};
 use std::sync::Arc;
-use tokio::fs;
 use tracing::instrument;
 
 pub struct Delete<'a> {
@@ -26,6 +25,15 @@ impl Delete<'_> {
         assert!(arguments.len() == 1);
         if arguments.len() == 1 {
             let folder = arguments[0].replace('/', ".");
+            if folder.trim_matches('"').eq_ignore_ascii_case("INBOX") {
+                lines
+                    .send(format!(
+                        "{} NO [CANNOT] INBOX can not be deleted",
+                        command_data.tag
+                    ))
+                    .await?;
+                return Ok(());
+            }
             let mailbox_path = storage.to_ondisk_path(
                 folder.clone(),
                 self.data
@@ -36,9 +44,32 @@ impl Delete<'_> {
                     .clone()
                     .context("Username missing in internal State")?,
             )?;
-            // TODO error handling
-            // TODO all the extra rules when to not delete
-            fs::remove_dir_all(mailbox_path).await?;
+            if !mailbox_path.exists() {
+                lines
+                    .send(format!(
+                        "{} NO [NONEXISTENT] Mailbox does not exist",
+                        command_data.tag
+                    ))
+                    .await?;
+                return Ok(());
+            }
+            // A parent which already lost its messages can only go away once its children are gone
+            let has_children = !storage.list_children(&mailbox_path)?.is_empty();
+            let noselect = storage
+                .get_flags(&mailbox_path)
+                .await?
+                .iter()
+                .any(|flag| flag.eq_ignore_ascii_case("\\Noselect"));
+            if has_children && noselect {
+                lines
+                    .send(format!(
+                        "{} NO [HASCHILDREN] Mailbox has children",
+                        command_data.tag
+                    ))
+                    .await?;
+                return Ok(());
+            }
+            storage.delete_mailbox(&mailbox_path).await?;
             lines
                 .send(format!("{} OK DELETE completed", command_data.tag))
                 .await?;
@@ -53,3 +84,119 @@ impl Delete<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::commands::{CommandData, Commands};
+    use crate::servers::state::{Connection, State};
+    use futures::{channel::mpsc, StreamExt};
+
+    async fn setup() -> (Data, Arc<Storage>, String) {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(
+            erooster_core::backend::database::get_database(Arc::clone(&config))
+                .await
+                .unwrap(),
+        );
+        let storage = Arc::new(erooster_core::backend::storage::get_storage(
+            database,
+            Arc::clone(&config),
+        ));
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let username = format!("delete-{nanos}@localhost");
+        let con_state = Connection::new(true);
+        con_state.write().await.state = State::Authenticated;
+        con_state.write().await.username = Some(username.clone());
+        (Data { con_state }, storage, username)
+    }
+
+    async fn delete(data: &Data, storage: &Arc<Storage>, mailbox: &str) -> Option<String> {
+        let cmd_data = CommandData {
+            tag: "1",
+            command: Commands::Delete,
+            arguments: &[mailbox],
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        let res = Delete { data }
+            .exec(&mut tx, Arc::clone(storage), &cmd_data)
+            .await;
+        assert!(res.is_ok());
+        rx.next().await
+    }
+
+    #[tokio::test]
+    async fn test_delete_inbox() {
+        let (data, storage, username) = setup().await;
+        let inbox = storage
+            .to_ondisk_path(String::from("INBOX"), username)
+            .unwrap();
+        storage.create_dirs(&inbox).unwrap();
+        assert_eq!(
+            delete(&data, &storage, "INBOX").await,
+            Some(String::from("1 NO [CANNOT] INBOX can not be deleted"))
+        );
+        assert!(inbox.exists());
+    }
+
+    #[tokio::test]
+    async fn test_delete_nonexistent() {
+        let (data, storage, _) = setup().await;
+        assert_eq!(
+            delete(&data, &storage, "Missing").await,
+            Some(String::from("1 NO [NONEXISTENT] Mailbox does not exist"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_with_children() {
+        let (data, storage, username) = setup().await;
+        let parent = storage
+            .to_ondisk_path(String::from("Parent"), username.clone())
+            .unwrap();
+        let child = storage
+            .to_ondisk_path(String::from("Parent/Child"), username)
+            .unwrap();
+        storage.create_dirs(&parent).unwrap();
+        storage.create_dirs(&child).unwrap();
+        storage
+            .store_new(&parent, b"Subject: Test\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+
+        // The parent stays for its children but loses its messages
+        assert_eq!(
+            delete(&data, &storage, "Parent").await,
+            Some(String::from("1 OK DELETE completed"))
+        );
+        assert!(parent.exists());
+        assert_eq!(storage.count_new(&parent) + storage.count_cur(&parent), 0);
+        assert!(storage
+            .get_flags(&parent)
+            .await
+            .unwrap()
+            .contains(&String::from("\\Noselect")));
+
+        assert_eq!(
+            delete(&data, &storage, "Parent").await,
+            Some(String::from("1 NO [HASCHILDREN] Mailbox has children"))
+        );
+
+        assert_eq!(
+            delete(&data, &storage, "Parent/Child").await,
+            Some(String::from("1 OK DELETE completed"))
+        );
+        assert!(!child.exists());
+        assert_eq!(
+            delete(&data, &storage, "Parent").await,
+            Some(String::from("1 OK DELETE completed"))
+        );
+        assert!(!parent.exists());
+    }
+}
@@ -1,23 +1,33 @@
 use crate::{
     commands::{
         parsers::{
-            fetch_arguments, parse_selected_range, FetchArguments, FetchAttributes, Range,
-            RangeEnd, SectionText,
+            fetch_arguments, parse_selected_range, FetchArguments, FetchAttributes, SectionText,
         },
+        updates::{ensure_index, mail_entries},
         CommandData, Data,
     },
-    servers::state::State,
+    servers::state::{IndexedMail, State},
 };
+use bytes::{Bytes, BytesMut};
 use color_eyre::{
     eyre::{eyre, ContextCompat, WrapErr},
     Result,
 };
 use erooster_core::backend::storage::{
-    maildir::MaildirMailEntry, MailEntry, MailEntryType, MailStorage, Storage,
+    preview::preview, MailEntry, MailEntryType, MailStorage, Storage,
 };
 use futures::{Sink, SinkExt};
 use nom::{error::convert_error, Finish};
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
 use tracing::{debug, error, instrument};
 
 pub struct Fetch<'a> {
@@ -36,118 +46,87 @@ impl Fetch<'_> {
     ) -> color_eyre::eyre::Result<()>
     where
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
-        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+        S: Sink<String, Error = E>
+            + Sink<Bytes, Error = E>
+            + std::marker::Unpin
+            + std::marker::Send,
     {
         let offset = usize::from(is_uid);
         // TODO handle the various request types defined in https://www.rfc-editor.org/rfc/rfc9051.html#name-fetch-command
-        if let State::Selected(folder, _) = &self.data.con_state.read().await.state {
-            let folder = folder.replace('/', ".");
-            let mailbox_path = storage.to_ondisk_path(
-                folder.clone(),
-                self.data
-                    .con_state
-                    .read()
-                    .await
-                    .username
-                    .clone()
-                    .context("Username missing in internal State")?,
-            )?;
-            let mails: Vec<MailEntryType> = storage.list_all(&mailbox_path).await;
-            // Possibly slower or faster than before?
-            // This loads stuff in memory essentially
-            let mut mails: Vec<MailEntryType> = mails
-                .into_iter()
-                .map(|mut mail| {
-                    mail.load();
-                    mail
-                })
-                .collect();
-            mails.sort_by_cached_key(MaildirMailEntry::date);
+        let selected = {
+            let read_lock = self.data.con_state.read().await;
+            if let State::Selected(folder, _) = &read_lock.state {
+                Some((
+                    folder.replace('/', "."),
+                    read_lock
+                        .username
+                        .clone()
+                        .context("Username missing in internal State")?,
+                ))
+            } else {
+                None
+            }
+        };
+        if let Some((folder, username)) = selected {
+            let mailbox_path = storage.to_ondisk_path(folder, username)?;
 
             let arguments_borrow = command_data.arguments[offset];
             let range = parse_selected_range(arguments_borrow).finish();
             debug!("Range: {:?}", range);
             match range {
                 Ok((_, range)) => {
-                    let mut filtered_mails: Vec<MailEntryType> = mails
-                        .into_iter()
-                        .enumerate()
-                        .filter_map(|(index, mut mail)| {
-                            for range in &range {
-                                match range {
-                                    Range::Single(id) => {
-                                        if is_uid && &mail.uid() == id
-                                            || !is_uid && &(index as i64 + 1) == id
-                                        {
-                                            mail.sequence_number = Some(index as i64 + 1);
-                                            return Some(mail);
-                                        }
-                                    }
-                                    Range::Range(start, end) => match end {
-                                        RangeEnd::End(end_int) => {
-                                            if (is_uid
-                                                && &mail.uid() >= start
-                                                && &mail.uid() <= end_int)
-                                                || (!is_uid
-                                                    && &(index as i64) >= start
-                                                    && &(index as i64) <= end_int)
-                                            {
-                                                mail.sequence_number = Some(index as i64 + 1);
-                                                return Some(mail);
-                                            }
-                                        }
-                                        RangeEnd::All => {
-                                            if (is_uid && &mail.uid() >= start)
-                                                || (!is_uid && &(index as i64) >= start)
-                                            {
-                                                mail.sequence_number = Some(index as i64 + 1);
-                                                return Some(mail);
-                                            }
-                                        }
-                                    },
-                                }
-                            }
-                            None
-                        })
-                        .collect::<Vec<MailEntryType>>();
-
                     let fetch_args = command_data.arguments[1 + offset..].to_vec().join(" ");
                     let fetch_args_str = &fetch_args[1..fetch_args.len() - 1];
                     debug!("Fetch args: {}", fetch_args_str);
 
-                    filtered_mails.sort_by_cached_key(|x| {
-                        if let Some(number) = x.sequence_number() {
-                            number
-                        } else {
-                            0
-                        }
-                    });
                     match fetch_arguments(fetch_args_str).finish() {
                         Ok((_, args)) => {
                             debug!("Parsed Fetch args: {:?}", args);
-                            for mut mail in filtered_mails {
-                                let uid = mail.uid();
-                                let sequence =
-                                    mail.sequence_number().context("Sequence number missing")?;
-                                if let Some(resp) = generate_response(args.clone(), &mut mail)? {
-                                    if is_uid {
-                                        if resp.contains("UID") {
-                                            lines
-                                                .feed(format!("* {sequence} FETCH ({resp})"))
-                                                .await?;
-                                        } else {
-                                            lines
-                                                .feed(format!(
-                                                    "* {} FETCH (UID {} {})",
-                                                    sequence, uid, resp
-                                                ))
-                                                .await?;
-                                        }
-                                    } else {
-                                        lines.feed(format!("* {sequence} FETCH ({resp})")).await?;
+                            let uid_requested = requests_uid(&args);
+
+                            // The lock is only held while taking a snapshot of the selection.
+                            // Other commands may run while the messages get read and sent.
+                            ensure_index(self.data, &storage, &mailbox_path).await?;
+                            let selected: Vec<(i64, IndexedMail)> = {
+                                let read_lock = self.data.con_state.read().await;
+                                let index = read_lock
+                                    .mailbox_index
+                                    .as_ref()
+                                    .context("Mailbox index missing")?;
+                                index
+                                    .selection(&range, is_uid, &read_lock.saved_search)
+                                    .map(|(sequence, indexed)| (sequence, indexed.clone()))
+                                    .collect()
+                            };
+                            // Only the directory listing is needed. Everything else comes from the index.
+                            let mut entries = mail_entries(&storage, &mailbox_path).await;
+                            let mut parsed_headers = HashMap::new();
+                            for (sequence, mut indexed) in selected {
+                                // Expunged by another session but not yet reported to the client
+                                let Some(mut mail) = entries.remove(&indexed.maildir_id) else {
+                                    continue;
+                                };
+                                mail.sequence_number = Some(sequence);
+                                let had_headers = indexed.headers.is_some();
+                                let response = generate_response(
+                                    args.clone(),
+                                    &mut mail,
+                                    &mut indexed.headers,
+                                )?;
+                                if let (false, Some(headers)) = (had_headers, indexed.headers) {
+                                    parsed_headers.insert(indexed.maildir_id, headers);
+                                }
+                                if let Some(mut resp) = response {
+                                    if is_uid && !uid_requested {
+                                        resp.insert(
+                                            0,
+                                            FetchData::Text(format!("UID {} ", indexed.uid)),
+                                        );
                                     }
+                                    send_fetch_response(lines, sequence, resp).await?;
                                 }
                             }
+                            cache_headers(self.data, parsed_headers).await;
 
                             if is_uid {
                                 lines
@@ -158,7 +137,7 @@ impl Fetch<'_> {
                                     .feed(format!("{} Ok FETCH completed", command_data.tag))
                                     .await?;
                             }
-                            lines.flush().await?;
+                            SinkExt::<String>::flush(lines).await?;
                         }
                         Err(e) => {
                             error!(
@@ -188,171 +167,325 @@ impl Fetch<'_> {
                     command_data.tag
                 ))
                 .await?;
-            lines.flush().await?;
+            SinkExt::<String>::flush(lines).await?;
         }
         Ok(())
     }
 }
 
-#[instrument(skip(arg, mail))]
-pub fn generate_response(arg: FetchArguments, mail: &mut MailEntryType) -> Result<Option<String>> {
+/// Keeps the headers parsed while answering in the mailbox index for later FETCH commands
+async fn cache_headers(data: &Data, mut parsed_headers: HashMap<String, Vec<(String, String)>>) {
+    if parsed_headers.is_empty() {
+        return;
+    }
+    let mut write_lock = data.con_state.write().await;
+    if let Some(index) = write_lock.mailbox_index.as_mut() {
+        for mail in &mut index.messages {
+            if mail.headers.is_none() {
+                mail.headers = parsed_headers.remove(&mail.maildir_id);
+            }
+        }
+    }
+}
+
+/// Size of the chunks a message file is streamed to the client with
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// A part of a FETCH response
+#[derive(Debug)]
+pub enum FetchData {
+    /// Text which is part of the response line
+    Text(String),
+    /// A literal which is already in memory
+    Literal(Bytes),
+    /// A literal which is streamed from the message file
+    File {
+        path: PathBuf,
+        offset: u64,
+        length: u64,
+    },
+}
+
+/// Whether the client asked for the UID itself
+fn requests_uid(args: &FetchArguments) -> bool {
+    match args {
+        FetchArguments::Single(FetchAttributes::Uid) => true,
+        FetchArguments::List(attributes) => attributes
+            .iter()
+            .any(|attribute| matches!(attribute, FetchAttributes::Uid)),
+        _ => false,
+    }
+}
+
+/// Writes a single `* n FETCH (...)` response.
+///
+/// Literals are sent as raw bytes so their octet count always matches what the client receives.
+#[instrument(skip(lines, parts))]
+async fn send_fetch_response<S, E>(
+    lines: &mut S,
+    sequence: i64,
+    parts: Vec<FetchData>,
+) -> Result<()>
+where
+    E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+    S: Sink<String, Error = E> + Sink<Bytes, Error = E> + std::marker::Unpin + std::marker::Send,
+{
+    let mut line = format!("* {sequence} FETCH (");
+    for part in parts {
+        match part {
+            FetchData::Text(text) => line.push_str(&text),
+            FetchData::Literal(data) => {
+                // The line codec terminates the line right after the literal size
+                lines.feed(format!("{line}{{{}}}", data.len())).await?;
+                line.clear();
+                lines.feed(data).await?;
+            }
+            FetchData::File {
+                path,
+                offset,
+                length,
+            } => {
+                lines.feed(format!("{line}{{{length}}}")).await?;
+                line.clear();
+                stream_file(lines, &path, offset, length).await?;
+            }
+        }
+    }
+    line.push(')');
+    lines.feed(line).await?;
+    Ok(())
+}
+
+/// Sends `length` bytes of the file starting at `offset` in chunks instead of loading it into memory
+#[instrument(skip(lines))]
+async fn stream_file<S, E>(lines: &mut S, path: &Path, offset: u64, length: u64) -> Result<()>
+where
+    E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+    S: Sink<Bytes, Error = E> + std::marker::Unpin + std::marker::Send,
+{
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk_size = remaining.min(CHUNK_SIZE);
+        let mut chunk = BytesMut::zeroed(usize::try_from(chunk_size)?);
+        // The promised octet count has to be honoured. A file which got shorter is an error.
+        file.read_exact(&mut chunk).await?;
+        lines.feed(chunk.freeze()).await?;
+        remaining -= chunk_size;
+    }
+    Ok(())
+}
+
+/// Applies a `<origin.octets>` partial range to data of the given size.
+///
+/// Returns the offset, the length and the origin suffix for the response name.
+fn partial(size: u64, range: Option<(u64, u64)>) -> (u64, u64, String) {
+    if let Some((origin, octets)) = range {
+        let offset = origin.min(size);
+        (offset, octets.min(size - offset), format!("<{origin}>"))
+    } else {
+        (0, size, String::new())
+    }
+}
+
+fn partial_literal(data: &[u8], range: Option<(u64, u64)>) -> (Bytes, String) {
+    let (offset, length, origin) = partial(data.len() as u64, range);
+    #[allow(clippy::cast_possible_truncation)]
+    let data = &data[offset as usize..(offset + length) as usize];
+    (Bytes::copy_from_slice(data), origin)
+}
+
+/// The headers of the message. They are parsed only once and then kept in the mailbox index.
+fn cached_headers<'a>(
+    mail: &mut MailEntryType,
+    cache: &'a mut Option<Vec<(String, String)>>,
+) -> Option<&'a [(String, String)]> {
+    if cache.is_none() {
+        let headers = mail.headers().ok()?;
+        *cache = Some(
+            headers
+                .iter()
+                .map(|header| (header.get_key(), header.get_value()))
+                .collect(),
+        );
+    }
+    cache.as_deref()
+}
+
+#[instrument(skip(arg, mail, headers))]
+pub fn generate_response(
+    arg: FetchArguments,
+    mail: &mut MailEntryType,
+    headers: &mut Option<Vec<(String, String)>>,
+) -> Result<Option<Vec<FetchData>>> {
     match arg {
         FetchArguments::Single(single_arg) => {
-            Ok(generate_response_for_attributes(single_arg, mail)?)
+            Ok(generate_response_for_attributes(single_arg, mail, headers)?)
         }
         FetchArguments::List(args) => {
-            let mut resp = String::new();
+            let mut resp = Vec::new();
             for arg in args {
-                if let Some(extra_resp) = generate_response_for_attributes(arg, mail)? {
-                    if resp.is_empty() {
-                        resp = extra_resp;
-                    } else {
-                        resp = format!("{resp} {extra_resp}");
+                if let Some(extra_resp) = generate_response_for_attributes(arg, mail, headers)? {
+                    if !resp.is_empty() {
+                        resp.push(FetchData::Text(String::from(" ")));
                     }
+                    resp.extend(extra_resp);
                 }
             }
-            debug!("[Fetch] List Response: {}", resp);
+            debug!("[Fetch] List Response: {:?}", resp);
             Ok(Some(resp))
         }
         _ => Ok(None),
     }
 }
 
-#[allow(clippy::too_many_lines)]
-#[instrument(skip(attr, mail))]
+/// The FLAGS of a message in the form used in FETCH responses
+#[instrument(skip(mail))]
+pub fn flags_response(mail: &MailEntryType) -> Result<String> {
+    let mut flags = Vec::new();
+    if mail
+        .path()
+        .clone()
+        .into_os_string()
+        .into_string()
+        .map_err(|e| eyre!(e.to_string_lossy().to_string()))
+        .wrap_err("Failed to convert OS String into String type")?
+        .contains("new")
+    {
+        flags.push("\\Recent");
+    }
+    if mail.is_draft() {
+        flags.push("\\Draft");
+    }
+    if mail.is_flagged() {
+        flags.push("\\Flagged");
+    }
+    if mail.is_seen() {
+        flags.push("\\Seen");
+    }
+    if mail.is_replied() {
+        flags.push("\\Answered");
+    }
+    if mail.is_trashed() {
+        flags.push("\\Deleted");
+    }
+
+    Ok(format!("FLAGS ({})", flags.join(" ")))
+}
+
+/// The value of the PREVIEW data item.
+///
+/// Previews are generated on delivery.
+/// Mail stored by older versions only has one after `eroosterctl backfill-message-ids`.
+/// A message that can't be parsed gets an empty preview instead of failing the whole FETCH.
+fn preview_response(
+    stored: Option<String>,
+    lazy: bool,
+    generate: impl FnOnce() -> Result<String>,
+) -> String {
+    let preview = match stored {
+        Some(stored) => Some(stored),
+        None if lazy => None,
+        None => Some(generate().unwrap_or_else(|e| {
+            error!("[Fetch] Unable to generate the preview: {}", e);
+            String::new()
+        })),
+    };
+    preview.map_or_else(
+        || String::from("NIL"),
+        |preview| format!("\"{}\"", preview.replace('\\', "\\\\").replace('"', "\\\"")),
+    )
+}
+
+#[instrument(skip(attr, mail, headers))]
 fn generate_response_for_attributes(
     attr: FetchAttributes,
     mail: &mut MailEntryType,
-) -> Result<Option<String>> {
+    headers: &mut Option<Vec<(String, String)>>,
+) -> Result<Option<Vec<FetchData>>> {
     match attr {
         FetchAttributes::RFC822Header => {
-            if let Ok(headers_vec) = mail.headers() {
+            if let Some(headers_vec) = cached_headers(mail, headers) {
                 let headers = headers_vec
                     .iter()
-                    .map(|header| format!("{}: {}", header.get_key(), header.get_value()))
-                    .collect::<Vec<_>>()
-                    .join("\r\n");
+                    .map(|(key, value)| format!("{key}: {value}\r\n"))
+                    .collect::<String>();
 
-                Ok(Some(format!("RFC822.HEADER{headers}")))
+                Ok(Some(vec![
+                    FetchData::Text(String::from("RFC822.HEADER ")),
+                    FetchData::Literal(Bytes::from(format!("{headers}\r\n"))),
+                ]))
             } else {
-                Ok(Some(String::from("RFC822.HEADER\r\n")))
-            }
-        }
-        FetchAttributes::Flags => {
-            let mut flags = String::new();
-            if mail
-                .path()
-                .clone()
-                .into_os_string()
-                .into_string()
-                .map_err(|e| eyre!(e.to_string_lossy().to_string()))
-                .wrap_err("Failed to convert OS String into String type")?
-                .contains("new")
-            {
-                flags.push_str("\\Recent");
-            }
-            if mail.is_draft() {
-                if flags.is_empty() {
-                    flags = String::from("\\Draft");
-                } else {
-                    flags.push_str(" \\Draft");
-                }
+                Ok(Some(vec![FetchData::Text(String::from(
+                    "RFC822.HEADER NIL",
+                ))]))
             }
-            if mail.is_flagged() {
-                if flags.is_empty() {
-                    flags = String::from("\\Flagged");
-                } else {
-                    flags.push_str(" \\Flagged");
-                }
-            }
-            if mail.is_seen() {
-                if flags.is_empty() {
-                    flags = String::from("\\Seen");
-                } else {
-                    flags.push_str(" \\Seen");
-                }
-            }
-            if mail.is_replied() {
-                if flags.is_empty() {
-                    flags = String::from("\\Answered");
-                } else {
-                    flags.push_str(" \\Answered");
-                }
-            }
-            if mail.is_trashed() {
-                if flags.is_empty() {
-                    flags = String::from("\\Deleted");
-                } else {
-                    flags.push_str(" \\Deleted");
-                }
-            }
-
-            Ok(Some(format!("FLAGS ({flags})")))
         }
+        FetchAttributes::Flags => Ok(Some(vec![FetchData::Text(flags_response(mail)?)])),
         FetchAttributes::RFC822Size => {
-            if let Ok(parsed) = mail.parsed() {
-                let size = parsed.raw_bytes.len();
-                Ok(Some(format!("RFC822.SIZE {size}")))
-            } else {
-                Ok(Some(String::from("RFC822.SIZE 0")))
-            }
+            let size = mail.size().unwrap_or_default();
+            Ok(Some(vec![FetchData::Text(format!("RFC822.SIZE {size}"))]))
+        }
+        FetchAttributes::Uid => Ok(Some(vec![FetchData::Text(format!("UID {}", mail.uid()))])),
+        FetchAttributes::EmailId => Ok(mail
+            .email_id()
+            .map(|email_id| vec![FetchData::Text(format!("EMAILID ({email_id})"))])),
+        FetchAttributes::Preview(lazy) => {
+            let stored = mail.preview().map(ToString::to_string);
+            let preview = preview_response(stored, lazy, || {
+                mail.parsed().map(|parsed| preview(&parsed))
+            });
+            Ok(Some(vec![FetchData::Text(format!("PREVIEW {preview}"))]))
+        }
+        FetchAttributes::ThreadId => {
+            let thread_id = mail
+                .thread_id()
+                .map_or_else(|| String::from("NIL"), |thread_id| format!("({thread_id})"));
+            Ok(Some(vec![FetchData::Text(format!("THREADID {thread_id}"))]))
         }
-        FetchAttributes::Uid => Ok(Some(format!("UID {}", mail.uid()))),
         FetchAttributes::BodySection(section_text, range) => {
-            Ok(Some(body(section_text, range, mail, true)))
+            Ok(Some(body(section_text, range, mail, headers, true)))
         }
         FetchAttributes::BodyPeek(section_text, range) => {
-            Ok(Some(body(section_text, range, mail, false)))
+            Ok(Some(body(section_text, range, mail, headers, false)))
         }
         _ => Ok(None),
     }
 }
 
-#[allow(clippy::cast_possible_truncation)]
-#[instrument(skip(section_text, range, mail))]
+#[allow(clippy::too_many_lines)]
+#[instrument(skip(section_text, range, mail, headers))]
 fn body(
     section_text: Option<SectionText>,
     range: Option<(u64, u64)>,
     mail: &mut MailEntryType,
+    headers: &mut Option<Vec<(String, String)>>,
     seen: bool,
-) -> String {
+) -> Vec<FetchData> {
     if let Some(section_text) = section_text {
         match section_text {
             super::parsers::SectionText::Header => {
                 // TODO implement
-                String::from("BODY[HEADER] NIL\r\n")
+                vec![FetchData::Text(String::from("BODY[HEADER] NIL"))]
             }
             super::parsers::SectionText::Text => {
-                if let Some((start, end)) = range {
-                    if let Ok(body) = mail.parsed() {
-                        if let Ok(body_text) = body.get_body_raw() {
-                            let end = if body_text.len() < (end as usize) {
-                                body_text.len()
-                            } else {
-                                end as usize
-                            };
-                            let body_text = body_text.get((start as usize)..end).unwrap_or(&[]);
-                            let body_text = String::from_utf8_lossy(body_text);
-                            format!("BODY[] {{{}}}\r\n{body_text}", body_text.as_bytes().len())
-                        } else {
-                            String::from("BODY[TEXT] NIL\r\n")
-                        }
-                    } else {
-                        String::from("BODY[TEXT] NIL\r\n")
-                    }
-                } else if let Ok(body) = mail.parsed() {
+                if let Ok(body) = mail.parsed() {
                     if let Ok(body_text) = body.get_body_raw() {
-                        let body_text = String::from_utf8_lossy(&body_text);
-                        format!("BODY[] {{{}}}\r\n{body_text}", body_text.as_bytes().len())
+                        let (data, origin) = partial_literal(&body_text, range);
+                        vec![
+                            FetchData::Text(format!("BODY[TEXT]{origin} ")),
+                            FetchData::Literal(data),
+                        ]
                     } else {
-                        String::from("BODY[TEXT] NIL\r\n")
+                        vec![FetchData::Text(String::from("BODY[TEXT] NIL"))]
                     }
                 } else {
-                    String::from("BODY[TEXT] NIL\r\n")
+                    vec![FetchData::Text(String::from("BODY[TEXT] NIL"))]
                 }
             }
             super::parsers::SectionText::HeaderFields(headers_requested_vec) => {
-                if let Ok(headers_vec) = mail.headers() {
+                if let Some(headers_vec) = cached_headers(mail, headers) {
                     let lower_headers_requested_vec: Vec<_> = headers_requested_vec
                         .iter()
                         .map(|header| header.to_lowercase())
@@ -360,26 +493,28 @@ fn body(
                     let headers = headers_vec
                         .iter()
                         .filter(|header| {
-                            lower_headers_requested_vec.contains(&header.get_key().to_lowercase())
+                            lower_headers_requested_vec.contains(&header.0.to_lowercase())
                         })
-                        .map(|header| format!("{}: {}", header.get_key(), header.get_value()))
-                        .collect::<Vec<_>>()
-                        .join("\r\n");
-                    format!(
-                        "BODY[HEADER.FIELDS ({})] {{{}}}\r\n{}\r\n",
-                        headers_requested_vec.join(" "),
-                        headers.as_bytes().len() + 2,
-                        headers
-                    )
+                        .map(|(key, value)| format!("{key}: {value}\r\n"))
+                        .collect::<String>();
+                    let (data, origin) =
+                        partial_literal(format!("{headers}\r\n").as_bytes(), range);
+                    vec![
+                        FetchData::Text(format!(
+                            "BODY[HEADER.FIELDS ({})]{origin} ",
+                            headers_requested_vec.join(" ")
+                        )),
+                        FetchData::Literal(data),
+                    ]
                 } else {
-                    format!(
-                        "BODY[HEADER.FIELDS ({})] NIL\r\n",
+                    vec![FetchData::Text(format!(
+                        "BODY[HEADER.FIELDS ({})] NIL",
                         headers_requested_vec.join(" "),
-                    )
+                    ))]
                 }
             }
             super::parsers::SectionText::HeaderFieldsNot(headers_requested_vec) => {
-                if let Ok(headers_vec) = mail.headers() {
+                if let Some(headers_vec) = cached_headers(mail, headers) {
                     let lower_headers_requested_vec: Vec<_> = headers_requested_vec
                         .iter()
                         .map(|header| header.to_lowercase())
@@ -388,23 +523,124 @@ fn body(
                     let headers = headers_vec
                         .iter()
                         .filter(|header| {
-                            !lower_headers_requested_vec.contains(&header.get_key().to_lowercase())
+                            !lower_headers_requested_vec.contains(&header.0.to_lowercase())
                         })
-                        .map(|header| format!("{}: {}", header.get_key(), header.get_value()))
-                        .collect::<Vec<_>>()
-                        .join("\r\n");
-                    let data = format!("{headers}\r\n");
-                    format!("BODY[HEADER] {{{}}}\r\n{data}", data.as_bytes().len())
+                        .map(|(key, value)| format!("{key}: {value}\r\n"))
+                        .collect::<String>();
+                    let (data, origin) =
+                        partial_literal(format!("{headers}\r\n").as_bytes(), range);
+                    vec![
+                        FetchData::Text(format!(
+                            "BODY[HEADER.FIELDS.NOT ({})]{origin} ",
+                            headers_requested_vec.join(" ")
+                        )),
+                        FetchData::Literal(data),
+                    ]
                 } else {
-                    String::from("BODY[HEADER] NIL\r\n")
+                    vec![FetchData::Text(format!(
+                        "BODY[HEADER.FIELDS.NOT ({})] NIL",
+                        headers_requested_vec.join(" "),
+                    ))]
                 }
             }
         }
-    } else if let Ok(mail) = mail.parsed() {
-        let mail = String::from_utf8_lossy(mail.raw_bytes);
-
-        format!("BODY[] {{{}}}\r\n{mail}", mail.as_bytes().len())
+    } else if let Ok(metadata) = std::fs::metadata(mail.path()) {
+        // The whole message is sent straight from disk exactly as it is stored
+        let (offset, length, origin) = partial(metadata.len(), range);
+        vec![
+            FetchData::Text(format!("BODY[]{origin} ")),
+            FetchData::File {
+                path: mail.path().clone(),
+                offset,
+                length,
+            },
+        ]
     } else {
-        String::from("BODY[] NIL\r\n")
+        vec![FetchData::Text(String::from("BODY[] NIL"))]
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use erooster_core::line_codec::LinesCodec;
+    use tokio_util::codec::FramedWrite;
+
+    #[tokio::test]
+    async fn test_binary_literal() {
+        let mut sink = FramedWrite::new(Vec::new(), LinesCodec::new());
+        let data: &[u8] = b"Subject: caf\xe9\r\n\r\n\xff\x00body";
+        send_fetch_response(
+            &mut sink,
+            1,
+            vec![
+                FetchData::Text(String::from("UID 4 BODY[] ")),
+                FetchData::Literal(Bytes::from_static(data)),
+            ],
+        )
+        .await
+        .expect("sending the response works");
+        SinkExt::<String>::flush(&mut sink)
+            .await
+            .expect("flushing works");
+
+        let mut expected = format!("* 1 FETCH (UID 4 BODY[] {{{}}}\r\n", data.len()).into_bytes();
+        expected.extend_from_slice(data);
+        expected.extend_from_slice(b")\r\n");
+        assert_eq!(sink.into_inner(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_preview_of_unreadable_message() {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(
+            erooster_core::backend::database::get_database(Arc::clone(&config))
+                .await
+                .unwrap(),
+        );
+        let storage = erooster_core::backend::storage::get_storage(database, Arc::clone(&config));
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = storage
+            .to_ondisk_path(String::from("INBOX"), format!("preview-{nanos}@localhost"))
+            .unwrap();
+        storage.create_dirs(&path).unwrap();
+        let id = storage
+            .store_new(&path, b"Subject: Test\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+        let mut mail = storage.find(&path, &id).await.unwrap();
+        // The message can't be read anymore after it was listed
+        std::fs::remove_file(mail.path()).unwrap();
+
+        let generate = || mail.parsed().map(|parsed| preview(&parsed));
+        assert_eq!(preview_response(None, false, generate), "\"\"");
+    }
+
+    #[test]
+    fn test_preview_response() {
+        let generate = || Ok(String::from("Hello"));
+        assert_eq!(preview_response(None, false, generate), "\"Hello\"");
+        assert_eq!(preview_response(None, true, generate), "NIL");
+        assert_eq!(
+            preview_response(Some(String::from("Say \"hi\"")), false, generate),
+            "\"Say \\\"hi\\\"\""
+        );
+    }
+
+    #[test]
+    fn test_partial() {
+        assert_eq!(partial(100, None), (0, 100, String::new()));
+        assert_eq!(partial(100, Some((10, 20))), (10, 20, String::from("<10>")));
+        assert_eq!(partial(100, Some((90, 20))), (90, 10, String::from("<90>")));
+        assert_eq!(
+            partial(100, Some((200, 20))),
+            (100, 0, String::from("<200>"))
+        );
     }
 }
@@ -0,0 +1,119 @@
+use crate::commands::{parsers::id_arguments, CommandData, Data};
+use futures::{Sink, SinkExt};
+use nom::{error::convert_error, Finish};
+use tracing::{error, info, instrument};
+
+pub struct Id<'a> {
+    pub data: &'a Data,
+}
+
+impl Id<'_> {
+    #[instrument(skip(self, lines, command_data))]
+    pub async fn exec<S, E>(
+        &self,
+        lines: &mut S,
+        command_data: &CommandData<'_>,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let arguments = command_data.arguments.join(" ");
+        let arguments_borrow: &str = &arguments;
+        let client_fields = match id_arguments(arguments_borrow).finish() {
+            Ok(("", client_fields)) => client_fields.unwrap_or_default(),
+            Ok((left, _)) => {
+                error!("Failed to parse id arguments. Leftover: {}", left);
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+            Err(e) => {
+                error!(
+                    "Failed to parse id arguments: {}",
+                    convert_error(arguments_borrow, e)
+                );
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let client_field = |name: &str| {
+            client_fields
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .and_then(|(_, value)| value.as_deref())
+        };
+        let client_name = client_field("name");
+        let client_version = client_field("version");
+        {
+            let read_lock = self.data.con_state.read().await;
+            if let Some(client_name) = client_name {
+                read_lock.session_span.record("client_name", client_name);
+            }
+            if let Some(client_version) = client_version {
+                read_lock
+                    .session_span
+                    .record("client_version", client_version);
+            }
+        };
+        info!(
+            "[IMAP] Client identified as {} {}",
+            client_name.unwrap_or("unknown"),
+            client_version.unwrap_or("unknown")
+        );
+
+        lines
+            .feed(format!(
+                "* ID (\"name\" \"erooster\" \"version\" \"{}\")",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .await?;
+        lines
+            .feed(format!("{} OK ID completed", command_data.tag))
+            .await?;
+        lines.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{CommandData, Commands};
+    use crate::servers::state::Connection;
+    use futures::{channel::mpsc, StreamExt};
+
+    #[tokio::test]
+    async fn test_id() {
+        let id = Id {
+            data: &Data {
+                con_state: Connection::new(true),
+            },
+        };
+        let cmd_data = CommandData {
+            tag: "1",
+            command: Commands::Id,
+            arguments: &[
+                "(\"name\"",
+                "\"Thunderbird\"",
+                "\"version\"",
+                "\"102.5.0\")",
+            ],
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        let res = id.exec(&mut tx, &cmd_data).await;
+        assert!(res.is_ok());
+        assert_eq!(
+            rx.next().await,
+            Some(format!(
+                "* ID (\"name\" \"erooster\" \"version\" \"{}\")",
+                env!("CARGO_PKG_VERSION")
+            ))
+        );
+        assert_eq!(rx.next().await, Some(String::from("1 OK ID completed")));
+    }
+}
@@ -0,0 +1,393 @@
+use crate::{
+    commands::{
+        parsers::{getmetadata_arguments, setmetadata_arguments, MetadataDepth},
+        CommandData, Data,
+    },
+    servers::state::State,
+};
+use erooster_core::backend::storage::{MailStorage, Storage};
+use futures::{Sink, SinkExt};
+use nom::{error::convert_error, Finish};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{error, instrument};
+
+/// The largest value we accept for a single entry in octets
+const MAX_VALUE_SIZE: usize = 64 * 1024;
+/// How many entries a single mailbox or the server may have
+const MAX_ENTRIES: usize = 128;
+
+pub struct GetMetadata<'a> {
+    pub data: &'a Data,
+}
+
+impl GetMetadata<'_> {
+    #[instrument(skip(self, lines, storage, command_data))]
+    pub async fn exec<S, E>(
+        &self,
+        lines: &mut S,
+        storage: Arc<Storage>,
+        command_data: &CommandData<'_>,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let Some(username) = authenticated_user(self.data).await else {
+            lines
+                .send(format!("{} NO invalid state", command_data.tag))
+                .await?;
+            return Ok(());
+        };
+        let arguments = command_data.arguments.join(" ");
+        let arguments_borrow: &str = &arguments;
+        let arguments = match getmetadata_arguments(arguments_borrow).finish() {
+            Ok(("", arguments)) => arguments,
+            Ok((left, _)) => {
+                error!("Failed to parse getmetadata arguments. Leftover: {}", left);
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+            Err(e) => {
+                error!(
+                    "Failed to parse getmetadata arguments: {}",
+                    convert_error(arguments_borrow, e)
+                );
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+        };
+        if !arguments
+            .entries
+            .iter()
+            .all(|entry| valid_entry(entry, true))
+        {
+            lines
+                .send(format!("{} BAD Invalid entry name", command_data.tag))
+                .await?;
+            return Ok(());
+        }
+        let Some(target) = resolve_target(&storage, &arguments.mailbox, username.clone()).await? else {
+            lines
+                .send(format!(
+                    "{} NO [NONEXISTENT] Mailbox does not exist",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        };
+
+        let found = storage
+            .get_metadata(&username, target.path(), &arguments.entries)
+            .await?;
+        let mut longest_skipped = None;
+        let values: Vec<String> = found
+            .into_iter()
+            .filter(|(entry, _)| within_depth(entry, &arguments.entries, arguments.depth))
+            .filter(|(_, value)| match arguments.max_size {
+                Some(max_size) if value.len() > max_size => {
+                    longest_skipped = longest_skipped.max(Some(value.len()));
+                    false
+                }
+                _ => true,
+            })
+            .map(|(entry, value)| format!("{entry} {}", string_value(&value)))
+            .collect();
+
+        if !values.is_empty() {
+            lines
+                .feed(format!(
+                    "* METADATA {} ({})",
+                    quote(&arguments.mailbox),
+                    values.join(" ")
+                ))
+                .await?;
+        }
+        if let Some(longest) = longest_skipped {
+            lines
+                .feed(format!(
+                    "{} OK [METADATA LONGENTRIES {longest}] GETMETADATA completed",
+                    command_data.tag
+                ))
+                .await?;
+        } else {
+            lines
+                .feed(format!("{} OK GETMETADATA completed", command_data.tag))
+                .await?;
+        }
+        lines.flush().await?;
+        Ok(())
+    }
+}
+
+pub struct SetMetadata<'a> {
+    pub data: &'a Data,
+}
+
+impl SetMetadata<'_> {
+    #[instrument(skip(self, lines, storage, command_data))]
+    pub async fn exec<S, E>(
+        &self,
+        lines: &mut S,
+        storage: Arc<Storage>,
+        command_data: &CommandData<'_>,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let Some(username) = authenticated_user(self.data).await else {
+            lines
+                .send(format!("{} NO invalid state", command_data.tag))
+                .await?;
+            return Ok(());
+        };
+        let arguments = command_data.arguments.join(" ");
+        let arguments_borrow: &str = &arguments;
+        let (mailbox, entries) = match setmetadata_arguments(arguments_borrow).finish() {
+            Ok(("", arguments)) => arguments,
+            Ok((left, _)) => {
+                error!("Failed to parse setmetadata arguments. Leftover: {}", left);
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+            Err(e) => {
+                error!(
+                    "Failed to parse setmetadata arguments: {}",
+                    convert_error(arguments_borrow, e)
+                );
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+        };
+        if !entries.iter().all(|(entry, _)| valid_entry(entry, false)) {
+            lines
+                .send(format!("{} BAD Invalid entry name", command_data.tag))
+                .await?;
+            return Ok(());
+        }
+        let Some(target) = resolve_target(&storage, &mailbox, username.clone()).await? else {
+            lines
+                .send(format!(
+                    "{} NO [NONEXISTENT] Mailbox does not exist",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        };
+
+        if entries
+            .iter()
+            .any(|(_, value)| value.as_ref().map_or(0, String::len) > MAX_VALUE_SIZE)
+        {
+            lines
+                .send(format!(
+                    "{} NO [METADATA MAXSIZE {MAX_VALUE_SIZE}] Value too large",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        }
+        let existing: Vec<String> = storage
+            .get_metadata(
+                &username,
+                target.path(),
+                &[String::from("/private"), String::from("/shared")],
+            )
+            .await?
+            .into_iter()
+            .map(|(entry, _)| entry)
+            .collect();
+        let mut remaining = existing.clone();
+        for (entry, value) in &entries {
+            let entry = entry.to_lowercase();
+            remaining.retain(|existing| *existing != entry);
+            if value.is_some() {
+                remaining.push(entry);
+            }
+        }
+        if remaining.len() > MAX_ENTRIES && remaining.len() > existing.len() {
+            lines
+                .send(format!(
+                    "{} NO [METADATA TOOMANY] Too many entries",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        }
+
+        storage
+            .set_metadata(&username, target.path(), &entries)
+            .await?;
+        lines
+            .send(format!("{} OK SETMETADATA completed", command_data.tag))
+            .await?;
+        Ok(())
+    }
+}
+
+/// The user of the session if it is allowed to use metadata
+async fn authenticated_user(data: &Data) -> Option<String> {
+    let read_lock = data.con_state.read().await;
+    if matches!(
+        read_lock.state,
+        State::Authenticated | State::Selected(_, _)
+    ) {
+        read_lock.username.clone()
+    } else {
+        None
+    }
+}
+
+/// What the metadata is attached to
+enum Target {
+    Server,
+    Mailbox(PathBuf),
+}
+
+impl Target {
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Target::Server => None,
+            Target::Mailbox(path) => Some(path),
+        }
+    }
+}
+
+/// Resolves the mailbox argument. The empty name refers to the server.
+///
+/// Returns `None` if the mailbox does not exist.
+async fn resolve_target(
+    storage: &Storage,
+    mailbox: &str,
+    username: String,
+) -> color_eyre::eyre::Result<Option<Target>> {
+    if mailbox.is_empty() {
+        return Ok(Some(Target::Server));
+    }
+    let path = storage.to_ondisk_path(mailbox.replace('/', "."), username)?;
+    if tokio::fs::metadata(&path).await.is_ok() {
+        Ok(Some(Target::Mailbox(path)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Whether the entry name is allowed by RFC 5464.
+///
+/// The bare `/private` and `/shared` prefixes can only be read.
+fn valid_entry(entry: &str, read: bool) -> bool {
+    let lowercase = entry.to_lowercase();
+    if read && (lowercase == "/private" || lowercase == "/shared") {
+        return true;
+    }
+    (lowercase.starts_with("/private/") || lowercase.starts_with("/shared/"))
+        && !entry.ends_with('/')
+        && !entry.contains("//")
+        && !entry.contains(['*', '%'])
+        && entry.chars().all(|c| !c.is_control())
+}
+
+/// Whether the entry is within the requested depth below one of the requested entries
+fn within_depth(entry: &str, requested: &[String], depth: MetadataDepth) -> bool {
+    requested.iter().any(|requested| {
+        let requested = requested.to_lowercase();
+        if entry == requested {
+            return true;
+        }
+        let Some(rest) = entry.strip_prefix(&format!("{requested}/")) else {
+            return false;
+        };
+        match depth {
+            MetadataDepth::Zero => false,
+            MetadataDepth::One => !rest.contains('/'),
+            MetadataDepth::Infinity => true,
+        }
+    })
+}
+
+/// Formats a value as an imap quoted string
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Formats a value as a quoted string or as a literal if it can not be quoted
+fn string_value(value: &str) -> String {
+    if value.contains(['\r', '\n']) {
+        format!("{{{}}}\r\n{value}", value.len())
+    } else {
+        quote(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_entry() {
+        assert!(valid_entry("/private/comment", false));
+        assert!(valid_entry("/shared/vendor/erooster/color", false));
+        assert!(valid_entry("/shared", true));
+        assert!(!valid_entry("/shared", false));
+        assert!(!valid_entry("/other/comment", false));
+        assert!(!valid_entry("/private/comment/", false));
+        assert!(!valid_entry("/private//comment", false));
+        assert!(!valid_entry("/private/*", true));
+    }
+
+    #[test]
+    fn test_string_value() {
+        assert_eq!(string_value("plain"), "\"plain\"");
+        assert_eq!(string_value("a \"b\" \\c"), "\"a \\\"b\\\" \\\\c\"");
+        assert_eq!(
+            string_value("first line\r\nsecond line"),
+            "{23}\r\nfirst line\r\nsecond line"
+        );
+    }
+
+    #[test]
+    fn test_within_depth() {
+        let requested = vec![String::from("/private/vendor")];
+        assert!(within_depth(
+            "/private/vendor",
+            &requested,
+            MetadataDepth::Zero
+        ));
+        assert!(!within_depth(
+            "/private/vendor/a",
+            &requested,
+            MetadataDepth::Zero
+        ));
+        assert!(within_depth(
+            "/private/vendor/a",
+            &requested,
+            MetadataDepth::One
+        ));
+        assert!(!within_depth(
+            "/private/vendor/a/b",
+            &requested,
+            MetadataDepth::One
+        ));
+        assert!(within_depth(
+            "/private/vendor/a/b",
+            &requested,
+            MetadataDepth::Infinity
+        ));
+        assert!(!within_depth(
+            "/private/vendorx",
+            &requested,
+            MetadataDepth::Infinity
+        ));
+    }
+}
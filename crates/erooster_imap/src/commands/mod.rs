@@ -5,14 +5,20 @@ use crate::{
         capability::Capability,
         check::Check,
         close::Close,
+        compress::Compress,
         create::Create,
         delete::Delete,
         enable::Enable,
         fetch::Fetch,
+        id::Id,
         list::{LSub, List},
         login::Login,
         logout::Logout,
+        metadata::{GetMetadata, SetMetadata},
+        namespace::Namespace,
         noop::Noop,
+        notify::Notify,
+        parsers::literal_size,
         rename::Rename,
         search::Search,
         select::{Examine, Select},
@@ -20,10 +26,13 @@ use crate::{
         store::Store,
         subscribe::Subscribe,
         uid::Uid,
+        unselect::Unselect,
         unsubscribe::Unsubscribe,
+        updates::send_updates,
     },
     servers::state::{Connection, State},
 };
+use bytes::Bytes;
 use erooster_core::{
     backend::{database::DB, storage::Storage},
     config::Config,
@@ -50,14 +59,19 @@ pub mod auth;
 pub mod capability;
 mod check;
 mod close;
+mod compress;
 mod create;
 mod delete;
 mod enable;
 mod fetch;
+mod id;
 mod list;
 mod login;
 mod logout;
+mod metadata;
+mod namespace;
 mod noop;
+pub mod notify;
 pub mod parsers;
 mod rename;
 mod search;
@@ -66,7 +80,9 @@ mod status;
 mod store;
 mod subscribe;
 mod uid;
+mod unselect;
 mod unsubscribe;
+mod updates;
 
 #[derive(Debug)]
 pub struct Data {
@@ -98,22 +114,29 @@ pub enum Commands {
     Capability,
     Check,
     Close,
+    Compress,
     Create,
     Delete,
     Enable,
     Examine,
     Fetch,
+    GetMetadata,
+    Id,
     List,
     Login,
     Logout,
     LSub,
+    Namespace,
     Noop,
+    Notify,
     Rename,
     Search,
     Select,
+    SetMetadata,
     Status,
     Store,
     Subscribe,
+    Unselect,
     Unsubscribe,
     Uid,
 }
@@ -147,6 +170,13 @@ impl TryFrom<&str> for Commands {
             "enable" => Ok(Commands::Enable),
             "status" => Ok(Commands::Status),
             "search" => Ok(Commands::Search),
+            "notify" => Ok(Commands::Notify),
+            "compress" => Ok(Commands::Compress),
+            "id" => Ok(Commands::Id),
+            "unselect" => Ok(Commands::Unselect),
+            "namespace" => Ok(Commands::Namespace),
+            "getmetadata" => Ok(Commands::GetMetadata),
+            "setmetadata" => Ok(Commands::SetMetadata),
             _ => {
                 warn!("[IMAP] Got unknown command: {}", i);
                 Err(String::from("no other commands supported"))
@@ -203,12 +233,64 @@ fn arguments(input: &str) -> Res<Vec<&str>> {
     .map(|(x, y)| (x, y))
 }
 
+/// Whether the command line ends by announcing a literal.
+///
+/// Only APPEND reads literals. Everywhere else strings have to be quoted (see `get_capabilities`).
+fn announces_literal(command_data: &CommandData<'_>) -> bool {
+    command_data.command != Commands::Append
+        && command_data.arguments.last().map_or(false, |argument| {
+            matches!(literal_size(argument).finish(), Ok(("", _)))
+        })
+}
+
 impl Data {
     #[instrument(skip(line))]
     fn parse_internal(line: &str) -> Res<(&str, Result<Commands, String>, Vec<&str>)> {
         context("parse_internal", tuple((imaptag, command, arguments)))(line)
     }
 
+    /// Whether the command in the line may run while earlier commands are still in progress.
+    ///
+    /// Only commands which neither change the state of the session nor the messages of the
+    /// selected mailbox qualify. Everything else waits for the commands before it to complete
+    /// and holds back the ones after it, so there is never any ambiguity as described in
+    /// RFC 9051 section 5.5.
+    #[instrument(skip(self, line))]
+    pub async fn runs_concurrently(&self, line: &str) -> bool {
+        // Lines sent during AUTHENTICATE and APPEND are no commands on their own
+        if !matches!(
+            self.con_state.read().await.state,
+            State::Authenticated | State::Selected(_, _)
+        ) {
+            return false;
+        }
+        let Ok((_, (_, Ok(command), arguments))) = Data::parse_internal(line).finish() else {
+            return false;
+        };
+        // A search result saved using SAVE has to be in place before it gets used as `$`
+        let uses_saved_search = arguments
+            .iter()
+            .any(|argument| argument.contains('$') || argument.to_uppercase().contains("SAVE"));
+        match command {
+            Commands::Fetch | Commands::Search => !uses_saved_search,
+            Commands::Uid => {
+                !uses_saved_search
+                    && arguments.first().map_or(false, |subcommand| {
+                        subcommand.eq_ignore_ascii_case("fetch")
+                            || subcommand.eq_ignore_ascii_case("search")
+                    })
+            }
+            Commands::Capability
+            | Commands::GetMetadata
+            | Commands::Id
+            | Commands::List
+            | Commands::LSub
+            | Commands::Namespace
+            | Commands::Status => true,
+            _ => false,
+        }
+    }
+
     #[allow(clippy::too_many_lines)]
     #[instrument(skip(self, lines, config, database, storage, line))]
     pub async fn parse<S, E>(
@@ -221,7 +303,10 @@ impl Data {
     ) -> color_eyre::eyre::Result<bool>
     where
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
-        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+        S: Sink<String, Error = E>
+            + Sink<Bytes, Error = E>
+            + std::marker::Unpin
+            + std::marker::Send,
     {
         debug!("Current state: {:?}", self.con_state.read().await.state);
 
@@ -275,12 +360,44 @@ impl Data {
                     }
                 };
                 debug!("Command data: {:?}", command_data);
+                // The client would wait for a continuation request or send the literal as commands
+                if announces_literal(&command_data) {
+                    lines
+                        .send(format!(
+                            "{} BAD Literals are only supported in APPEND. Use a quoted string.",
+                            command_data.tag
+                        ))
+                        .await?;
+                    return Ok(false);
+                }
+                // Tell the client about changes other sessions made to the selected mailbox.
+                // Commands running alongside others must not renumber the messages those are
+                // working with (RFC 9051 5.5). The next command running on its own reports them.
+                if !self.runs_concurrently(line_borrow).await {
+                    match command_data.command {
+                        Commands::Fetch | Commands::Store | Commands::Search => {
+                            send_updates(self, lines, &storage, false).await?;
+                        }
+                        Commands::Select
+                        | Commands::Examine
+                        | Commands::Close
+                        | Commands::Unselect
+                        | Commands::Logout
+                        | Commands::Noop
+                        | Commands::Check => {}
+                        _ => {
+                            send_updates(self, lines, &storage, true).await?;
+                        }
+                    }
+                }
                 match command_data.command {
                     Commands::Enable => {
                         Enable { data: self }.exec(lines, &command_data).await?;
                     }
                     Commands::Capability => {
-                        Capability.exec(lines, &command_data).await?;
+                        Capability { data: self }
+                            .exec(lines, config.mail.max_message_size, &command_data)
+                            .await?;
                     }
                     Commands::Login => {
                         Login.exec(lines, &command_data).await?;
@@ -376,7 +493,7 @@ impl Data {
                     }
                     Commands::Append => {
                         Append { data: self }
-                            .exec(lines, storage, &command_data)
+                            .exec(lines, storage, config.mail.max_message_size, &command_data)
                             .await?;
                     }
                     Commands::Status => {
@@ -386,6 +503,33 @@ impl Data {
                     }
                     Commands::Search => {
                         Search { data: self }
+                            .exec(lines, storage, &command_data, false)
+                            .await?;
+                    }
+                    Commands::Compress => {
+                        Compress { data: self }.exec(lines, &command_data).await?;
+                    }
+                    Commands::Id => {
+                        Id { data: self }.exec(lines, &command_data).await?;
+                    }
+                    Commands::Unselect => {
+                        Unselect { data: self }.exec(lines, &command_data).await?;
+                    }
+                    Commands::Namespace => {
+                        Namespace { data: self }.exec(lines, &command_data).await?;
+                    }
+                    Commands::Notify => {
+                        Notify { data: self }
+                            .exec(lines, storage, &command_data)
+                            .await?;
+                    }
+                    Commands::GetMetadata => {
+                        GetMetadata { data: self }
+                            .exec(lines, storage, &command_data)
+                            .await?;
+                    }
+                    Commands::SetMetadata => {
+                        SetMetadata { data: self }
                             .exec(lines, storage, &command_data)
                             .await?;
                     }
@@ -411,6 +555,7 @@ impl Data {
 #[allow(clippy::unwrap_used)]
 mod tests {
     use super::*;
+    use crate::servers::state::Access;
     use convert_case::{Case, Casing};
     use enum_iterator::all;
 
@@ -484,6 +629,53 @@ mod tests {
         assert!(arguments.is_empty());
     }
 
+    #[test]
+    fn test_announces_literal() {
+        let literal = |line: &str| {
+            let (_, (tag, command, arguments)) = Data::parse_internal(line).unwrap();
+            announces_literal(&CommandData {
+                tag,
+                command: command.unwrap(),
+                arguments: &arguments,
+            })
+        };
+        assert!(literal("a SETMETADATA INBOX (/private/comment {5}"));
+        assert!(literal("a SEARCH BODY {4+}"));
+        assert!(!literal("a SETMETADATA INBOX (/private/comment \"hello\")"));
+        assert!(!literal("a SEARCH BODY \"{4}\""));
+        assert!(!literal("a APPEND INBOX {310}"));
+    }
+
+    #[tokio::test]
+    async fn test_runs_concurrently() {
+        let data = Data {
+            con_state: Connection::new(true),
+        };
+        assert!(!data.runs_concurrently("a FETCH 1:* (FLAGS)").await);
+
+        data.con_state.write().await.state =
+            State::Selected(String::from("INBOX"), Access::ReadWrite);
+        assert!(data.runs_concurrently("a FETCH 1:* (FLAGS)").await);
+        assert!(data.runs_concurrently("a UID FETCH 1:* (FLAGS)").await);
+        assert!(data.runs_concurrently("a UID SEARCH UNSEEN").await);
+        assert!(data.runs_concurrently("a STATUS Sent (MESSAGES)").await);
+        assert!(!data.runs_concurrently("a FETCH $ (FLAGS)").await);
+        assert!(
+            !data
+                .runs_concurrently("a SEARCH RETURN (SAVE) UNSEEN")
+                .await
+        );
+        assert!(
+            !data
+                .runs_concurrently("a UID STORE 1 +FLAGS (\\Seen)")
+                .await
+        );
+        assert!(!data.runs_concurrently("a EXPUNGE").await);
+        assert!(!data.runs_concurrently("a NOOP").await);
+        assert!(!data.runs_concurrently("a SELECT INBOX").await);
+        assert!(!data.runs_concurrently("a COMPRESS DEFLATE").await);
+    }
+
     #[test]
     fn test_parsing_list_command() {
         let result = Data::parse_internal("18 list \"\" \"*\"");
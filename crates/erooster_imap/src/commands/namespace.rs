@@ -0,0 +1,41 @@
+use crate::{
+    commands::{CommandData, Data},
+    servers::state::State,
+};
+use futures::{Sink, SinkExt};
+use tracing::instrument;
+
+pub struct Namespace<'a> {
+    pub data: &'a Data,
+}
+
+impl Namespace<'_> {
+    #[instrument(skip(self, lines, command_data))]
+    pub async fn exec<S, E>(
+        &self,
+        lines: &mut S,
+        command_data: &CommandData<'_>,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let state = self.data.con_state.read().await.state.clone();
+        if !matches!(state, State::Authenticated | State::Selected(_, _)) {
+            lines
+                .send(format!("{} NO invalid state", command_data.tag))
+                .await?;
+            return Ok(());
+        }
+
+        // Every mailbox is personal. There are no other users or shared namespaces.
+        lines
+            .feed(String::from("* NAMESPACE ((\"\" \".\")) NIL NIL"))
+            .await?;
+        lines
+            .feed(format!("{} OK NAMESPACE completed", command_data.tag))
+            .await?;
+        lines.flush().await?;
+        Ok(())
+    }
+}
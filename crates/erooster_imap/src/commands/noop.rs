@@ -1,11 +1,7 @@
 use std::sync::Arc;
 
-use crate::{
-    commands::{CommandData, Data},
-    servers::state::State,
-};
-use color_eyre::eyre::ContextCompat;
-use erooster_core::backend::storage::{MailEntryType, MailStorage, Storage};
+use crate::commands::{updates::send_updates, CommandData, Data};
+use erooster_core::backend::storage::Storage;
 use futures::{Sink, SinkExt};
 use tracing::instrument;
 
@@ -25,26 +21,7 @@ impl Noop<'_> {
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
         S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
     {
-        // TODO return status as suggested in https://www.rfc-editor.org/rfc/rfc9051.html#name-noop-command
-        if let State::Selected(folder, _) = &self.data.con_state.read().await.state {
-            let folder = folder.replace('/', ".");
-            let mailbox_path = storage.to_ondisk_path(
-                folder.clone(),
-                self.data
-                    .con_state
-                    .read()
-                    .await
-                    .username
-                    .clone()
-                    .context("Username missing in internal State")?,
-            )?;
-            let mails: Vec<MailEntryType> = storage.list_new(&mailbox_path).await;
-            let got_new = !mails.is_empty();
-            if got_new {
-                let mails: Vec<MailEntryType> = storage.list_all(&mailbox_path).await;
-                lines.send(format!("* {} EXISTS", mails.len())).await?;
-            }
-        }
+        send_updates(self.data, lines, &storage, true).await?;
         lines
             .send(format!("{} OK NOOP completed", command_data.tag))
             .await?;
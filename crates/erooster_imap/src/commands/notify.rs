@@ -0,0 +1,383 @@
+use crate::{
+    commands::{
+        parsers::{notify_arguments, NotifyArguments, NotifyEvent, NotifyEventGroup, NotifyFilter},
+        status::status_values,
+        updates::send_updates,
+        CommandData, Data,
+    },
+    servers::state::State,
+};
+use color_eyre::eyre::ContextCompat;
+use erooster_core::backend::{
+    changes::{ChangeKind, MailboxChange},
+    storage::{MailStorage, Storage},
+};
+use futures::{Sink, SinkExt};
+use nom::{error::convert_error, Finish};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, instrument};
+
+/// The events we are able to send notifications for
+const SUPPORTED_EVENTS: [NotifyEvent; 5] = [
+    NotifyEvent::MessageNew,
+    NotifyEvent::MessageExpunge,
+    NotifyEvent::FlagChange,
+    NotifyEvent::MailboxName,
+    NotifyEvent::SubscriptionChange,
+];
+
+/// The status items sent for changes in mailboxes which are not selected
+const STATUS_ITEMS: [&str; 3] = ["MESSAGES", "UIDNEXT", "UNSEEN"];
+
+pub struct Notify<'a> {
+    pub data: &'a Data,
+}
+
+impl Notify<'_> {
+    #[instrument(skip(self, lines, storage, command_data))]
+    pub async fn exec<S, E>(
+        &self,
+        lines: &mut S,
+        storage: Arc<Storage>,
+        command_data: &CommandData<'_>,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let state = self.data.con_state.read().await.state.clone();
+        if !matches!(state, State::Authenticated | State::Selected(_, _)) {
+            lines
+                .send(format!("{} NO invalid state", command_data.tag))
+                .await?;
+            return Ok(());
+        }
+
+        let arguments = command_data.arguments.join(" ");
+        let arguments_borrow: &str = &arguments;
+        let notify_args = match notify_arguments(arguments_borrow).finish() {
+            Ok(("", notify_args)) => notify_args,
+            Ok((left, _)) => {
+                error!("Failed to parse notify arguments. Leftover: {}", left);
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+            Err(e) => {
+                error!(
+                    "Failed to parse notify arguments: {}",
+                    convert_error(arguments_borrow, e)
+                );
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        match notify_args {
+            NotifyArguments::None => {
+                self.data.con_state.write().await.notify = vec![];
+            }
+            NotifyArguments::Set { status, groups } => {
+                if groups
+                    .iter()
+                    .flat_map(|group| &group.events)
+                    .any(|event| !SUPPORTED_EVENTS.contains(event))
+                {
+                    lines
+                        .send(format!(
+                            "{} NO [BADEVENT (MessageNew MessageExpunge FlagChange MailboxName SubscriptionChange)] Unsupported event",
+                            command_data.tag
+                        ))
+                        .await?;
+                    return Ok(());
+                }
+
+                if status {
+                    self.send_initial_status(lines, &storage, &groups).await?;
+                }
+                self.data.con_state.write().await.notify = groups;
+            }
+        }
+
+        lines
+            .feed(format!("{} OK NOTIFY completed", command_data.tag))
+            .await?;
+        lines.flush().await?;
+        Ok(())
+    }
+
+    /// Sends the STATUS of all mailboxes which have message events requested
+    async fn send_initial_status<S, E>(
+        &self,
+        lines: &mut S,
+        storage: &Storage,
+        groups: &[NotifyEventGroup],
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let (username, selected) = {
+            let con_state = self.data.con_state.read().await;
+            let username = con_state
+                .username
+                .clone()
+                .context("Username missing in internal State")?;
+            let selected = selected_mailbox(&con_state.state);
+            (username, selected)
+        };
+
+        let inbox_path = storage.to_ondisk_path(String::from("INBOX"), username.clone())?;
+        let user_path = inbox_path
+            .parent()
+            .context("Unable to get the users storage folder")?;
+        let mut mailboxes = vec![String::from("INBOX")];
+        for sub_folder in storage.list_subdirs(user_path)? {
+            let folder_name = sub_folder
+                .file_name()
+                .context("Unable to get file name")?
+                .to_string_lossy()
+                .trim_start_matches('.')
+                .to_string();
+            mailboxes.push(folder_name);
+        }
+
+        for mailbox in mailboxes {
+            if selected.as_ref() == Some(&mailbox) {
+                continue;
+            }
+            let wants_status = find_group(groups, &mailbox, false).map_or(false, |group| {
+                group.events.iter().any(|event| {
+                    matches!(
+                        event,
+                        NotifyEvent::MessageNew
+                            | NotifyEvent::MessageExpunge
+                            | NotifyEvent::FlagChange
+                    )
+                })
+            });
+            if wants_status {
+                let mailbox_path = storage.to_ondisk_path(mailbox.clone(), username.clone())?;
+                let values = status_values(storage, &mailbox_path, &STATUS_ITEMS).await?;
+                lines
+                    .feed(format!("* STATUS \"{mailbox}\" ({values})"))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The imap name of the selected mailbox
+fn selected_mailbox(state: &State) -> Option<String> {
+    if let State::Selected(folder, _) = state {
+        Some(folder.replace('/', ".").replace('"', ""))
+    } else {
+        None
+    }
+}
+
+/// Finds the event group responsible for a mailbox.
+///
+/// The selected mailbox is covered by SELECTED and SELECTED-DELAYED if the client asked for it.
+fn find_group<'a>(
+    groups: &'a [NotifyEventGroup],
+    mailbox: &str,
+    is_selected: bool,
+) -> Option<&'a NotifyEventGroup> {
+    if is_selected {
+        if let Some(group) = groups.iter().find(|group| {
+            matches!(
+                group.filter,
+                NotifyFilter::Selected | NotifyFilter::SelectedDelayed
+            )
+        }) {
+            return Some(group);
+        }
+    }
+    groups.iter().find(|group| match &group.filter {
+        NotifyFilter::Selected | NotifyFilter::SelectedDelayed => false,
+        NotifyFilter::Inboxes => mailbox == "INBOX",
+        NotifyFilter::Personal => true,
+        NotifyFilter::Subtree(roots) => roots.iter().any(|root| {
+            let root = root.replace('/', ".");
+            mailbox == root || mailbox.starts_with(&format!("{root}."))
+        }),
+        NotifyFilter::Mailboxes(names) => {
+            names.iter().any(|name| name.replace('/', ".") == mailbox)
+        }
+    })
+}
+
+const fn event_for_change(kind: &ChangeKind) -> NotifyEvent {
+    match kind {
+        ChangeKind::MessageNew => NotifyEvent::MessageNew,
+        ChangeKind::MessageExpunge => NotifyEvent::MessageExpunge,
+        ChangeKind::FlagChange => NotifyEvent::FlagChange,
+        ChangeKind::MailboxCreated | ChangeKind::MailboxDeleted | ChangeKind::MailboxRenamed(_) => {
+            NotifyEvent::MailboxName
+        }
+        ChangeKind::SubscriptionChange => NotifyEvent::SubscriptionChange,
+    }
+}
+
+/// Waits for the next change. Never resolves if the client has no notifications enabled.
+pub async fn next_change(
+    changes: &mut Option<broadcast::Receiver<MailboxChange>>,
+) -> Result<MailboxChange, RecvError> {
+    match changes {
+        Some(receiver) => receiver.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Starts or stops listening for changes depending on whether the client enabled NOTIFY
+pub async fn update_change_subscription(
+    data: &Data,
+    storage: &Storage,
+    changes: &mut Option<broadcast::Receiver<MailboxChange>>,
+) {
+    let con_state = data.con_state.read().await;
+    match &con_state.username {
+        Some(username) if !con_state.notify.is_empty() => {
+            if changes.is_none() {
+                *changes = Some(storage.subscribe_changes(username));
+            }
+        }
+        _ => *changes = None,
+    }
+}
+
+/// Tells the client about a change if it asked for it using NOTIFY
+#[instrument(skip(data, lines, storage, change))]
+pub async fn send_notification<S, E>(
+    data: &Data,
+    lines: &mut S,
+    storage: &Storage,
+    change: Result<MailboxChange, RecvError>,
+) -> color_eyre::eyre::Result<()>
+where
+    E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+    S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+{
+    let change = match change {
+        Ok(change) => change,
+        Err(RecvError::Lagged(missed)) => {
+            // We can't tell the client what it missed so notifications get disabled as required by RFC 5465
+            debug!("[IMAP] Missed {} changes", missed);
+            data.con_state.write().await.notify = vec![];
+            lines
+                .send(String::from(
+                    "* OK [NOTIFICATIONOVERFLOW] Too many changes. Notifications are disabled",
+                ))
+                .await?;
+            return Ok(());
+        }
+        Err(RecvError::Closed) => return Ok(()),
+    };
+
+    let (username, selected, groups) = {
+        let con_state = data.con_state.read().await;
+        let Some(username) = con_state.username.clone() else {
+            return Ok(());
+        };
+        (
+            username,
+            selected_mailbox(&con_state.state),
+            con_state.notify.clone(),
+        )
+    };
+    let is_selected = selected.as_ref() == Some(&change.mailbox);
+    let event = event_for_change(&change.kind);
+    let Some(group) = find_group(&groups, &change.mailbox, is_selected) else {
+        return Ok(());
+    };
+    if !group.events.contains(&event) {
+        return Ok(());
+    }
+
+    let mailbox_path = storage.to_ondisk_path(change.mailbox.clone(), username)?;
+    match change.kind {
+        ChangeKind::MessageNew | ChangeKind::MessageExpunge | ChangeKind::FlagChange => {
+            if is_selected {
+                let allow_expunge = group.filter != NotifyFilter::SelectedDelayed;
+                send_updates(data, lines, storage, allow_expunge).await?;
+            } else {
+                let values = status_values(storage, &mailbox_path, &STATUS_ITEMS).await?;
+                lines
+                    .feed(format!("* STATUS \"{}\" ({values})", change.mailbox))
+                    .await?;
+            }
+        }
+        ChangeKind::MailboxCreated => {
+            lines
+                .feed(format!("* LIST () \".\" \"{}\"", change.mailbox))
+                .await?;
+        }
+        ChangeKind::MailboxDeleted => {
+            lines
+                .feed(format!(
+                    "* LIST (\\NonExistent) \".\" \"{}\"",
+                    change.mailbox
+                ))
+                .await?;
+        }
+        ChangeKind::MailboxRenamed(old_name) => {
+            lines
+                .feed(format!(
+                    "* LIST () \".\" \"{}\" (\"OLDNAME\" (\"{old_name}\"))",
+                    change.mailbox
+                ))
+                .await?;
+        }
+        ChangeKind::SubscriptionChange => {
+            let flags = storage.get_flags(&mailbox_path).await.unwrap_or_default();
+            let subscribed = if flags.iter().any(|flag| flag == "\\Subscribed") {
+                "\\Subscribed"
+            } else {
+                ""
+            };
+            lines
+                .feed(format!(
+                    "* LIST ({subscribed}) \".\" \"{}\"",
+                    change.mailbox
+                ))
+                .await?;
+        }
+    }
+    lines.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_group() {
+        let groups = vec![
+            NotifyEventGroup {
+                filter: NotifyFilter::Selected,
+                events: vec![NotifyEvent::MessageNew],
+            },
+            NotifyEventGroup {
+                filter: NotifyFilter::Subtree(vec![String::from("Lists")]),
+                events: vec![NotifyEvent::MessageNew, NotifyEvent::FlagChange],
+            },
+            NotifyEventGroup {
+                filter: NotifyFilter::Inboxes,
+                events: vec![],
+            },
+        ];
+
+        assert_eq!(find_group(&groups, "INBOX", true), Some(&groups[0]));
+        assert_eq!(find_group(&groups, "INBOX", false), Some(&groups[2]));
+        assert_eq!(find_group(&groups, "Lists.Rust", false), Some(&groups[1]));
+        assert_eq!(find_group(&groups, "Lists", false), Some(&groups[1]));
+        assert_eq!(find_group(&groups, "Listserv", false), None);
+    }
+}
@@ -1,11 +1,11 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag_no_case, take_while1},
-    character::complete::{char, digit1, space1},
-    combinator::{map, opt},
+    bytes::complete::{escaped_transform, tag_no_case, take_while1},
+    character::complete::{char, digit1, none_of, one_of, space1},
+    combinator::{map, map_res, opt, verify},
     error::{context, VerboseError},
-    multi::separated_list0,
-    sequence::{delimited, pair, separated_pair, tuple},
+    multi::{separated_list0, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     IResult,
 };
 use tracing::instrument;
@@ -77,6 +77,10 @@ pub enum FetchAttributes {
     Binary(Option<SectionText>, Option<(u64, u64)>),
     BinaryPeek(Option<SectionText>, Option<(u64, u64)>),
     BinarySize(Option<SectionText>),
+    EmailId,
+    ThreadId,
+    /// Whether the client is fine with NIL if the preview is not cached
+    Preview(bool),
 }
 
 #[allow(clippy::too_many_lines)]
@@ -95,6 +99,15 @@ fn fetch_attributes(input: &str) -> Res<FetchAttributes> {
                 FetchAttributes::RFC822Header
             }),
             map(tag_no_case("UID"), |_| FetchAttributes::Uid),
+            map(tag_no_case("EMAILID"), |_| FetchAttributes::EmailId),
+            map(tag_no_case("THREADID"), |_| FetchAttributes::ThreadId),
+            map(
+                preceded(
+                    tag_no_case("PREVIEW"),
+                    opt(preceded(space1, tag_no_case("(LAZY)"))),
+                ),
+                |lazy| FetchAttributes::Preview(lazy.is_some()),
+            ),
             map(
                 tuple((
                     tag_no_case("BODY.PEEK"),
@@ -291,16 +304,46 @@ pub fn fetch_arguments(input: &str) -> Res<FetchArguments> {
     context("fetch_arguments", inner_fetch_arguments)(input)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RangeEnd {
     End(i64),
     All,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Range {
     Single(i64),
     Range(i64, RangeEnd),
+    /// The `$` marker referring to the last saved search result
+    Saved,
+}
+
+impl Range {
+    /// Whether a message is part of this range.
+    /// `id` is the UID for UID commands and the sequence number otherwise, `max` is the largest
+    /// one in use which `*` stands for. `in_saved` tells whether the message is part of the
+    /// saved search result.
+    #[must_use]
+    pub fn contains(&self, id: i64, max: i64, in_saved: bool) -> bool {
+        match self {
+            Range::Single(single) => id == *single,
+            Range::Range(start, end) => {
+                let end = match end {
+                    RangeEnd::End(end) => *end,
+                    RangeEnd::All => max,
+                };
+                // The order of the two ends does not matter (RFC 9051 section 9)
+                id >= *start.min(&end) && id <= *start.max(&end)
+            }
+            Range::Saved => in_saved,
+        }
+    }
+}
+
+/// Whether a message is part of any of the ranges of a sequence set. See [`Range::contains`].
+#[must_use]
+pub fn in_ranges(ranges: &[Range], id: i64, max: i64, in_saved: bool) -> bool {
+    ranges.iter().any(|range| range.contains(id, max, in_saved))
 }
 
 #[instrument(skip(input))]
@@ -326,6 +369,7 @@ pub fn parse_selected_range(input: &str) -> Res<Vec<Range>> {
                 map(digit1, |x: &str| {
                     Range::Single(x.parse::<i64>().expect("single range is a number"))
                 }),
+                map(char('$'), |_| Range::Saved),
             )),
         ),
     )(input)
@@ -398,6 +442,47 @@ pub enum DateTime {
     DateTime(String),
 }
 
+impl DateTime {
+    /// The date as a unix timestamp
+    #[must_use]
+    pub fn timestamp(&self) -> Option<i64> {
+        let (DateTime::DayName(date) | DateTime::DateTime(date)) = self;
+        mailparse::dateparse(date).ok()
+    }
+}
+
+/// The quoted date-time of RFC 3501, e.g. `"17-Jul-1996 02:44:25 -0700"`
+#[instrument(skip(input))]
+fn quoted_date_time(input: &str) -> Res<DateTime> {
+    context(
+        "quoted_date_time",
+        map(
+            delimited(
+                char('"'),
+                tuple((
+                    opt(char(' ')),
+                    digit1,
+                    char('-'),
+                    month,
+                    char('-'),
+                    digit1,
+                    space1,
+                    time,
+                    space1,
+                    tuple((alt((tag_no_case("+"), tag_no_case("-"))), digit1)),
+                )),
+                char('"'),
+            ),
+            |(_, day, _, month, _, year, _, time, _, (zone_dir, zone_offset))| {
+                DateTime::DateTime(format!(
+                    "{} {} {} {} {}{}",
+                    day, month, year, time.0, zone_dir, zone_offset
+                ))
+            },
+        ),
+    )(input)
+}
+
 #[instrument(skip(input))]
 fn date_time(input: &str) -> Res<DateTime> {
     context(
@@ -432,11 +517,632 @@ fn date_time(input: &str) -> Res<DateTime> {
     )(input)
 }
 
+/// A day as the number of days since the unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchDate(pub i64);
+
+#[instrument(skip(input))]
+fn search_date(input: &str) -> Res<SearchDate> {
+    let date = map_res(
+        tuple((digit1, char('-'), month, char('-'), digit1)),
+        |(day, _, month, _, year)| {
+            mailparse::dateparse(&format!("{day} {month} {year} 00:00:00 +0000"))
+                .map(|timestamp| SearchDate(timestamp.div_euclid(86400)))
+        },
+    );
+    context(
+        "search_date",
+        alt((delimited(char('"'), date, char('"')), date)),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn quoted(input: &str) -> Res<String> {
+    context(
+        "quoted",
+        map(
+            delimited(
+                char('"'),
+                opt(escaped_transform(none_of("\\\""), '\\', one_of("\\\""))),
+                char('"'),
+            ),
+            Option::unwrap_or_default,
+        ),
+    )(input)
+}
+
+/// An atom or a quoted string. Literals are rejected before parsing (see `get_capabilities`).
+#[instrument(skip(input))]
+fn astring(input: &str) -> Res<String> {
+    context(
+        "astring",
+        alt((
+            quoted,
+            map(
+                take_while1(|c: char| {
+                    !c.is_whitespace() && c != '(' && c != ')' && c != '"' && c != '{'
+                }),
+                ToString::to_string,
+            ),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn number(input: &str) -> Res<u64> {
+    context("number", map_res(digit1, str::parse::<u64>))(input)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchKey {
+    All,
+    Answered,
+    Deleted,
+    Draft,
+    Flagged,
+    New,
+    Old,
+    Recent,
+    Seen,
+    Unanswered,
+    Undeleted,
+    Undraft,
+    Unflagged,
+    Unseen,
+    Bcc(String),
+    Cc(String),
+    From(String),
+    Subject(String),
+    To(String),
+    Header(String, String),
+    Body(String),
+    Text(String),
+    EmailId(String),
+    ThreadId(String),
+    Before(SearchDate),
+    On(SearchDate),
+    Since(SearchDate),
+    SentBefore(SearchDate),
+    SentOn(SearchDate),
+    SentSince(SearchDate),
+    Larger(u64),
+    Smaller(u64),
+    Uid(Vec<Range>),
+    SequenceSet(Vec<Range>),
+    Not(Box<SearchKey>),
+    Or(Box<SearchKey>, Box<SearchKey>),
+    And(Vec<SearchKey>),
+}
+
+#[instrument(skip(input))]
+fn search_flag_key(input: &str) -> Res<SearchKey> {
+    context(
+        "search_flag_key",
+        alt((
+            map(tag_no_case("ALL"), |_| SearchKey::All),
+            map(tag_no_case("ANSWERED"), |_| SearchKey::Answered),
+            map(tag_no_case("DELETED"), |_| SearchKey::Deleted),
+            map(tag_no_case("DRAFT"), |_| SearchKey::Draft),
+            map(tag_no_case("FLAGGED"), |_| SearchKey::Flagged),
+            map(tag_no_case("NEW"), |_| SearchKey::New),
+            map(tag_no_case("OLD"), |_| SearchKey::Old),
+            map(tag_no_case("RECENT"), |_| SearchKey::Recent),
+            map(tag_no_case("SEEN"), |_| SearchKey::Seen),
+            map(tag_no_case("UNANSWERED"), |_| SearchKey::Unanswered),
+            map(tag_no_case("UNDELETED"), |_| SearchKey::Undeleted),
+            map(tag_no_case("UNDRAFT"), |_| SearchKey::Undraft),
+            map(tag_no_case("UNFLAGGED"), |_| SearchKey::Unflagged),
+            map(tag_no_case("UNSEEN"), |_| SearchKey::Unseen),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn search_string_key(input: &str) -> Res<SearchKey> {
+    context(
+        "search_string_key",
+        alt((
+            map(
+                preceded(pair(tag_no_case("BCC"), space1), astring),
+                SearchKey::Bcc,
+            ),
+            map(
+                preceded(pair(tag_no_case("CC"), space1), astring),
+                SearchKey::Cc,
+            ),
+            map(
+                preceded(pair(tag_no_case("FROM"), space1), astring),
+                SearchKey::From,
+            ),
+            map(
+                preceded(pair(tag_no_case("SUBJECT"), space1), astring),
+                SearchKey::Subject,
+            ),
+            map(
+                preceded(pair(tag_no_case("TO"), space1), astring),
+                SearchKey::To,
+            ),
+            map(
+                preceded(
+                    pair(tag_no_case("HEADER"), space1),
+                    separated_pair(astring, space1, astring),
+                ),
+                |(name, value)| SearchKey::Header(name, value),
+            ),
+            map(
+                preceded(pair(tag_no_case("BODY"), space1), astring),
+                SearchKey::Body,
+            ),
+            map(
+                preceded(pair(tag_no_case("TEXT"), space1), astring),
+                SearchKey::Text,
+            ),
+            map(
+                preceded(pair(tag_no_case("EMAILID"), space1), astring),
+                SearchKey::EmailId,
+            ),
+            map(
+                preceded(pair(tag_no_case("THREADID"), space1), astring),
+                SearchKey::ThreadId,
+            ),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn search_date_key(input: &str) -> Res<SearchKey> {
+    context(
+        "search_date_key",
+        alt((
+            map(
+                preceded(pair(tag_no_case("BEFORE"), space1), search_date),
+                SearchKey::Before,
+            ),
+            map(
+                preceded(pair(tag_no_case("ON"), space1), search_date),
+                SearchKey::On,
+            ),
+            map(
+                preceded(pair(tag_no_case("SINCE"), space1), search_date),
+                SearchKey::Since,
+            ),
+            map(
+                preceded(pair(tag_no_case("SENTBEFORE"), space1), search_date),
+                SearchKey::SentBefore,
+            ),
+            map(
+                preceded(pair(tag_no_case("SENTON"), space1), search_date),
+                SearchKey::SentOn,
+            ),
+            map(
+                preceded(pair(tag_no_case("SENTSINCE"), space1), search_date),
+                SearchKey::SentSince,
+            ),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn search_key(input: &str) -> Res<SearchKey> {
+    context(
+        "search_key",
+        alt((
+            search_string_key,
+            search_date_key,
+            map(
+                preceded(pair(tag_no_case("LARGER"), space1), number),
+                SearchKey::Larger,
+            ),
+            map(
+                preceded(pair(tag_no_case("SMALLER"), space1), number),
+                SearchKey::Smaller,
+            ),
+            map(
+                preceded(pair(tag_no_case("UID"), space1), parse_selected_range),
+                SearchKey::Uid,
+            ),
+            map(
+                preceded(pair(tag_no_case("NOT"), space1), search_key),
+                |key| SearchKey::Not(Box::new(key)),
+            ),
+            map(
+                preceded(
+                    pair(tag_no_case("OR"), space1),
+                    separated_pair(search_key, space1, search_key),
+                ),
+                |(left, right)| SearchKey::Or(Box::new(left), Box::new(right)),
+            ),
+            map(
+                delimited(char('('), separated_list1(space1, search_key), char(')')),
+                SearchKey::And,
+            ),
+            search_flag_key,
+            map(
+                verify(parse_selected_range, |ranges: &Vec<Range>| {
+                    !ranges.is_empty()
+                }),
+                SearchKey::SequenceSet,
+            ),
+        )),
+    )(input)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchReturnOption {
+    Min,
+    Max,
+    Count,
+    All,
+    Save,
+}
+
+#[instrument(skip(input))]
+fn search_return_options(input: &str) -> Res<Vec<SearchReturnOption>> {
+    context(
+        "search_return_options",
+        preceded(
+            pair(tag_no_case("RETURN"), space1),
+            delimited(
+                char('('),
+                separated_list0(
+                    space1,
+                    alt((
+                        map(tag_no_case("MIN"), |_| SearchReturnOption::Min),
+                        map(tag_no_case("MAX"), |_| SearchReturnOption::Max),
+                        map(tag_no_case("COUNT"), |_| SearchReturnOption::Count),
+                        map(tag_no_case("ALL"), |_| SearchReturnOption::All),
+                        map(tag_no_case("SAVE"), |_| SearchReturnOption::Save),
+                    )),
+                ),
+                char(')'),
+            ),
+        ),
+    )(input)
+}
+
+pub type SearchArguments = (
+    Option<Vec<SearchReturnOption>>,
+    Option<String>,
+    Vec<SearchKey>,
+);
+
+/// Parses the search program.
+/// Returns the optional return options, the optional charset and the list of keys which all have to match.
+#[instrument(skip(input))]
+pub fn search_arguments(input: &str) -> Res<SearchArguments> {
+    context(
+        "search_arguments",
+        tuple((
+            opt(terminated(search_return_options, space1)),
+            opt(terminated(
+                preceded(pair(tag_no_case("CHARSET"), space1), astring),
+                space1,
+            )),
+            separated_list1(space1, search_key),
+        )),
+    )(input)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyFilter {
+    Selected,
+    SelectedDelayed,
+    Inboxes,
+    Personal,
+    Subtree(Vec<String>),
+    Mailboxes(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyEvent {
+    MessageNew,
+    MessageExpunge,
+    FlagChange,
+    AnnotationChange,
+    MailboxName,
+    SubscriptionChange,
+    MailboxMetadataChange,
+    ServerMetadataChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyEventGroup {
+    pub filter: NotifyFilter,
+    /// An empty list means that no events are wanted for the mailboxes
+    pub events: Vec<NotifyEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyArguments {
+    None,
+    Set {
+        status: bool,
+        groups: Vec<NotifyEventGroup>,
+    },
+}
+
+#[instrument(skip(input))]
+fn one_or_more_mailbox(input: &str) -> Res<Vec<String>> {
+    context(
+        "one_or_more_mailbox",
+        alt((
+            delimited(char('('), separated_list1(space1, astring), char(')')),
+            map(astring, |mailbox| vec![mailbox]),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn notify_filter(input: &str) -> Res<NotifyFilter> {
+    context(
+        "notify_filter",
+        alt((
+            map(tag_no_case("SELECTED-DELAYED"), |_| {
+                NotifyFilter::SelectedDelayed
+            }),
+            map(tag_no_case("SELECTED"), |_| NotifyFilter::Selected),
+            map(tag_no_case("INBOXES"), |_| NotifyFilter::Inboxes),
+            map(tag_no_case("PERSONAL"), |_| NotifyFilter::Personal),
+            map(
+                preceded(pair(tag_no_case("SUBTREE"), space1), one_or_more_mailbox),
+                NotifyFilter::Subtree,
+            ),
+            map(
+                preceded(pair(tag_no_case("MAILBOXES"), space1), one_or_more_mailbox),
+                NotifyFilter::Mailboxes,
+            ),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn notify_event(input: &str) -> Res<NotifyEvent> {
+    context(
+        "notify_event",
+        alt((
+            map(tag_no_case("MessageNew"), |_| NotifyEvent::MessageNew),
+            map(tag_no_case("MessageExpunge"), |_| {
+                NotifyEvent::MessageExpunge
+            }),
+            map(tag_no_case("FlagChange"), |_| NotifyEvent::FlagChange),
+            map(tag_no_case("AnnotationChange"), |_| {
+                NotifyEvent::AnnotationChange
+            }),
+            map(tag_no_case("MailboxName"), |_| NotifyEvent::MailboxName),
+            map(tag_no_case("SubscriptionChange"), |_| {
+                NotifyEvent::SubscriptionChange
+            }),
+            map(tag_no_case("MailboxMetadataChange"), |_| {
+                NotifyEvent::MailboxMetadataChange
+            }),
+            map(tag_no_case("ServerMetadataChange"), |_| {
+                NotifyEvent::ServerMetadataChange
+            }),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn notify_event_group(input: &str) -> Res<NotifyEventGroup> {
+    context(
+        "notify_event_group",
+        map(
+            delimited(
+                char('('),
+                separated_pair(
+                    notify_filter,
+                    space1,
+                    alt((
+                        delimited(char('('), separated_list1(space1, notify_event), char(')')),
+                        map(tag_no_case("NONE"), |_| vec![]),
+                    )),
+                ),
+                char(')'),
+            ),
+            |(filter, events)| NotifyEventGroup { filter, events },
+        ),
+    )(input)
+}
+
+/// Parses the arguments of the NOTIFY command (RFC 5465)
+#[instrument(skip(input))]
+pub fn notify_arguments(input: &str) -> Res<NotifyArguments> {
+    context(
+        "notify_arguments",
+        alt((
+            map(tag_no_case("NONE"), |_| NotifyArguments::None),
+            map(
+                preceded(
+                    tag_no_case("SET"),
+                    pair(
+                        map(opt(preceded(space1, tag_no_case("STATUS"))), |status| {
+                            status.is_some()
+                        }),
+                        preceded(space1, separated_list1(space1, notify_event_group)),
+                    ),
+                ),
+                |(status, groups)| NotifyArguments::Set { status, groups },
+            ),
+        )),
+    )(input)
+}
+
+/// The fields a client sent using ID. `None` if it sent `NIL`.
+pub type IdArguments = Option<Vec<(String, Option<String>)>>;
+
+/// `NIL` or a quoted string. Literals are rejected before parsing (see `get_capabilities`).
+#[instrument(skip(input))]
+fn nstring(input: &str) -> Res<Option<String>> {
+    context(
+        "nstring",
+        alt((map(tag_no_case("NIL"), |_| None), map(quoted, Some))),
+    )(input)
+}
+
+#[instrument(skip(input))]
+pub fn id_arguments(input: &str) -> Res<IdArguments> {
+    context(
+        "id_arguments",
+        alt((
+            map(tag_no_case("NIL"), |_| None),
+            map(
+                delimited(
+                    char('('),
+                    separated_list0(space1, separated_pair(quoted, space1, nstring)),
+                    char(')'),
+                ),
+                Some,
+            ),
+        )),
+    )(input)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataDepth {
+    /// Only the requested entry
+    Zero,
+    /// The requested entry and its direct children
+    One,
+    /// The requested entry and everything below it
+    Infinity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GetMetadataOption {
+    MaxSize(usize),
+    Depth(MetadataDepth),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetMetadataArguments {
+    pub max_size: Option<usize>,
+    pub depth: MetadataDepth,
+    /// The mailbox. Empty for server metadata.
+    pub mailbox: String,
+    pub entries: Vec<String>,
+}
+
+#[instrument(skip(input))]
+fn getmetadata_option(input: &str) -> Res<GetMetadataOption> {
+    context(
+        "getmetadata_option",
+        alt((
+            map(
+                preceded(
+                    tag_no_case("MAXSIZE "),
+                    map_res(digit1, |size: &str| size.parse::<usize>()),
+                ),
+                GetMetadataOption::MaxSize,
+            ),
+            map(
+                preceded(
+                    tag_no_case("DEPTH "),
+                    alt((
+                        map(tag_no_case("0"), |_| MetadataDepth::Zero),
+                        map(tag_no_case("1"), |_| MetadataDepth::One),
+                        map(tag_no_case("infinity"), |_| MetadataDepth::Infinity),
+                    )),
+                ),
+                GetMetadataOption::Depth,
+            ),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+fn metadata_entries(input: &str) -> Res<Vec<String>> {
+    context(
+        "metadata_entries",
+        alt((
+            delimited(char('('), separated_list1(space1, astring), char(')')),
+            map(astring, |entry| vec![entry]),
+        )),
+    )(input)
+}
+
+#[instrument(skip(input))]
+pub fn getmetadata_arguments(input: &str) -> Res<GetMetadataArguments> {
+    context(
+        "getmetadata_arguments",
+        map(
+            tuple((
+                opt(terminated(
+                    delimited(
+                        char('('),
+                        separated_list1(space1, getmetadata_option),
+                        char(')'),
+                    ),
+                    space1,
+                )),
+                astring,
+                preceded(space1, metadata_entries),
+            )),
+            |(options, mailbox, entries)| {
+                let mut arguments = GetMetadataArguments {
+                    max_size: None,
+                    depth: MetadataDepth::Zero,
+                    mailbox,
+                    entries,
+                };
+                for option in options.unwrap_or_default() {
+                    match option {
+                        GetMetadataOption::MaxSize(size) => arguments.max_size = Some(size),
+                        GetMetadataOption::Depth(depth) => arguments.depth = depth,
+                    }
+                }
+                arguments
+            },
+        ),
+    )(input)
+}
+
+/// The mailbox and the entries to set. A value of `None` removes the entry.
+pub type SetMetadataArguments = (String, Vec<(String, Option<String>)>);
+
+#[instrument(skip(input))]
+pub fn setmetadata_arguments(input: &str) -> Res<SetMetadataArguments> {
+    context(
+        "setmetadata_arguments",
+        separated_pair(
+            astring,
+            space1,
+            delimited(
+                char('('),
+                separated_list1(space1, separated_pair(astring, space1, nstring)),
+                char(')'),
+            ),
+        ),
+    )(input)
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct LiteralSize {
     pub length: usize,
     pub continuation: bool,
 }
 
+/// The announcement of a literal like `{42}` or the non-synchronizing `{42+}`
+#[instrument(skip(input))]
+pub fn literal_size(input: &str) -> Res<LiteralSize> {
+    context(
+        "literal_size",
+        map(
+            delimited(
+                char('{'),
+                pair(
+                    map_res(digit1, str::parse::<usize>),
+                    map(opt(char('+')), |x| x.is_some()),
+                ),
+                char('}'),
+            ),
+            |(length, continuation)| LiteralSize {
+                length,
+                continuation,
+            },
+        ),
+    )(input)
+}
+
 pub type AppendArgs<'a> = (Option<Vec<&'a str>>, Option<DateTime>, LiteralSize);
 
 #[instrument(skip(input))]
@@ -454,28 +1160,15 @@ pub fn append_arguments(input: &str) -> Res<AppendArgs> {
                     char(')'),
                 )),
                 opt(space1),
-                opt(date_time),
+                opt(alt((quoted_date_time, date_time))),
                 opt(space1),
                 opt(tag_no_case("UTF8")),
                 opt(space1),
                 opt(tag_no_case("(")),
                 opt(tag_no_case("~")),
-                tag_no_case("{"),
-                map(
-                    pair(
-                        map(take_while1(|c: char| c.is_ascii_digit()), |s: &str| {
-                            s.parse::<usize>().expect("literal size is a number")
-                        }),
-                        map(opt(tag_no_case("+")), |x| x.is_some()),
-                    ),
-                    |(length, continuation)| LiteralSize {
-                        length,
-                        continuation,
-                    },
-                ),
-                tag_no_case("}"),
+                literal_size,
             )),
-            |(flags, _, datetime, _, _, _, _, _, _, literal, _)| (flags, datetime, literal),
+            |(flags, _, datetime, _, _, _, _, _, _, literal)| (flags, datetime, literal),
         ),
     )(input)
 }
@@ -515,6 +1208,22 @@ mod tests {
         assert_eq!(unparsed, "");
     }
 
+    #[test]
+    fn test_preview_attribute() {
+        assert!(matches!(
+            fetch_attributes("PREVIEW"),
+            Ok(("", FetchAttributes::Preview(false)))
+        ));
+        assert!(matches!(
+            fetch_attributes("PREVIEW (LAZY)"),
+            Ok(("", FetchAttributes::Preview(true)))
+        ));
+        assert!(matches!(
+            inner_fetch_arguments("(UID PREVIEW FLAGS)"),
+            Ok(("", FetchArguments::List(attributes))) if attributes.len() == 3
+        ));
+    }
+
     #[tokio::test]
     async fn test_inner_fetch_arguments() {
         let input = "(UID RFC822.SIZE FLAGS BODY.PEEK[HEADER.FIELDS (From To Cc Bcc Subject Date Message-ID Priority X-Priority References Newsgroups In-Reply-To Content-Type Reply-To x-spamd-result x-spam-score x-rspamd-score x-spam-status x-mailscanner-spamcheck X-Spam-Flag x-spam-level)])";
@@ -525,6 +1234,132 @@ mod tests {
         assert_eq!(unparsed, "");
     }
 
+    #[test]
+    fn test_search_arguments() {
+        let (unparsed, (return_options, charset, keys)) =
+            search_arguments("CHARSET UTF-8 UNSEEN FROM \"Smith\" BODY hello").unwrap();
+        assert_eq!(unparsed, "");
+        assert_eq!(return_options, None);
+        assert_eq!(charset, Some(String::from("UTF-8")));
+        assert_eq!(
+            keys,
+            vec![
+                SearchKey::Unseen,
+                SearchKey::From(String::from("Smith")),
+                SearchKey::Body(String::from("hello")),
+            ]
+        );
+
+        let (unparsed, (_, _, keys)) = search_arguments("EMAILID E12 THREADID T7").unwrap();
+        assert_eq!(unparsed, "");
+        assert_eq!(
+            keys,
+            vec![
+                SearchKey::EmailId(String::from("E12")),
+                SearchKey::ThreadId(String::from("T7")),
+            ]
+        );
+
+        let (unparsed, (_, charset, keys)) =
+            search_arguments("OR (TEXT \"foo bar\" 1:3) NOT UID 4,6:* SINCE 1-Feb-1994").unwrap();
+        assert_eq!(unparsed, "");
+        assert_eq!(charset, None);
+        assert_eq!(
+            keys,
+            vec![
+                SearchKey::Or(
+                    Box::new(SearchKey::And(vec![
+                        SearchKey::Text(String::from("foo bar")),
+                        SearchKey::SequenceSet(vec![Range::Range(1, RangeEnd::End(3))]),
+                    ])),
+                    Box::new(SearchKey::Not(Box::new(SearchKey::Uid(vec![
+                        Range::Single(4),
+                        Range::Range(6, RangeEnd::All),
+                    ])))),
+                ),
+                SearchKey::Since(SearchDate(8797)),
+            ]
+        );
+
+        let (unparsed, (return_options, charset, keys)) =
+            search_arguments("RETURN (MIN COUNT SAVE) UID $").unwrap();
+        assert_eq!(unparsed, "");
+        assert_eq!(
+            return_options,
+            Some(vec![
+                SearchReturnOption::Min,
+                SearchReturnOption::Count,
+                SearchReturnOption::Save,
+            ])
+        );
+        assert_eq!(charset, None);
+        assert_eq!(keys, vec![SearchKey::Uid(vec![Range::Saved])]);
+
+        let (unparsed, (return_options, _, keys)) = search_arguments("RETURN () ALL").unwrap();
+        assert_eq!(unparsed, "");
+        assert_eq!(return_options, Some(vec![]));
+        assert_eq!(keys, vec![SearchKey::All]);
+    }
+
+    #[test]
+    fn test_notify_arguments() {
+        assert_eq!(notify_arguments("NONE"), Ok(("", NotifyArguments::None)));
+
+        let (unparsed, args) = notify_arguments(
+            "SET STATUS (selected (MessageExpunge MessageNew FlagChange)) (subtree Lists (MessageNew)) (mailboxes (INBOX \"Other Folder\") NONE)",
+        )
+        .unwrap();
+        assert_eq!(unparsed, "");
+        assert_eq!(
+            args,
+            NotifyArguments::Set {
+                status: true,
+                groups: vec![
+                    NotifyEventGroup {
+                        filter: NotifyFilter::Selected,
+                        events: vec![
+                            NotifyEvent::MessageExpunge,
+                            NotifyEvent::MessageNew,
+                            NotifyEvent::FlagChange,
+                        ],
+                    },
+                    NotifyEventGroup {
+                        filter: NotifyFilter::Subtree(vec![String::from("Lists")]),
+                        events: vec![NotifyEvent::MessageNew],
+                    },
+                    NotifyEventGroup {
+                        filter: NotifyFilter::Mailboxes(vec![
+                            String::from("INBOX"),
+                            String::from("Other Folder"),
+                        ]),
+                        events: vec![],
+                    },
+                ],
+            }
+        );
+
+        let (unparsed, args) =
+            notify_arguments("SET (selected-delayed (MessageNew)) (personal (MailboxName))")
+                .unwrap();
+        assert_eq!(unparsed, "");
+        assert_eq!(
+            args,
+            NotifyArguments::Set {
+                status: false,
+                groups: vec![
+                    NotifyEventGroup {
+                        filter: NotifyFilter::SelectedDelayed,
+                        events: vec![NotifyEvent::MessageNew],
+                    },
+                    NotifyEventGroup {
+                        filter: NotifyFilter::Personal,
+                        events: vec![NotifyEvent::MailboxName],
+                    },
+                ],
+            }
+        );
+    }
+
     #[tokio::test]
     async fn test_fetch_arguments() {
         let input = "UID RFC822.SIZE FLAGS BODY.PEEK[HEADER.FIELDS (From To Cc Bcc Subject Date Message-ID Priority X-Priority References Newsgroups In-Reply-To Content-Type Reply-To x-spamd-result x-spam-score x-rspamd-score x-spam-status x-mailscanner-spamcheck X-Spam-Flag x-spam-level)]";
@@ -534,4 +1369,143 @@ mod tests {
         let (unparsed, _) = args.unwrap();
         assert_eq!(unparsed, "");
     }
+
+    #[test]
+    fn test_literal_size() {
+        assert_eq!(
+            literal_size("{42}"),
+            Ok((
+                "",
+                LiteralSize {
+                    length: 42,
+                    continuation: false
+                }
+            ))
+        );
+        assert_eq!(
+            literal_size("{7+}"),
+            Ok((
+                "",
+                LiteralSize {
+                    length: 7,
+                    continuation: true
+                }
+            ))
+        );
+        assert!(literal_size("{x}").is_err());
+        assert!(literal_size("\"{42}\"").is_err());
+    }
+
+    #[test]
+    fn test_append_date_time() {
+        let (_, (_, datetime, _)) =
+            append_arguments("(\\Seen) \"17-Jul-1996 02:44:25 -0700\" {310}").unwrap();
+        let datetime = datetime.unwrap();
+        assert_eq!(
+            datetime,
+            DateTime::DateTime(String::from("17 Jul 1996 02:44:25 -0700"))
+        );
+        assert_eq!(datetime.timestamp(), Some(837_596_665));
+
+        let (_, (_, datetime, _)) =
+            append_arguments("\" 7-Jul-1996 02:44:25 +0000\" {310}").unwrap();
+        assert_eq!(
+            datetime,
+            Some(DateTime::DateTime(String::from(
+                "7 Jul 1996 02:44:25 +0000"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_id_arguments() {
+        assert_eq!(id_arguments("NIL"), Ok(("", None)));
+        assert_eq!(
+            id_arguments("(\"name\" \"iPhone Mail\" \"version\" \"20A362\" \"os\" NIL)"),
+            Ok((
+                "",
+                Some(vec![
+                    (String::from("name"), Some(String::from("iPhone Mail"))),
+                    (String::from("version"), Some(String::from("20A362"))),
+                    (String::from("os"), None),
+                ])
+            ))
+        );
+    }
+
+    #[test]
+    fn test_range_contains() {
+        assert!(Range::Single(3).contains(3, 10, false));
+        assert!(!Range::Single(3).contains(4, 10, false));
+        assert!(Range::Range(2, RangeEnd::End(4)).contains(4, 10, false));
+        assert!(Range::Range(4, RangeEnd::End(2)).contains(3, 10, false));
+        assert!(!Range::Range(2, RangeEnd::End(4)).contains(5, 10, false));
+        assert!(Range::Range(2, RangeEnd::All).contains(10, 10, false));
+        assert!(!Range::Range(2, RangeEnd::All).contains(1, 10, false));
+        // `*` is the largest number in use even if the start is larger
+        assert!(Range::Range(12, RangeEnd::All).contains(10, 10, false));
+        assert!(!Range::Range(12, RangeEnd::All).contains(9, 10, false));
+        assert!(Range::Saved.contains(1, 10, true));
+        assert!(!Range::Saved.contains(10, 10, false));
+        assert!(in_ranges(
+            &[Range::Single(1), Range::Range(5, RangeEnd::End(6))],
+            6,
+            10,
+            false
+        ));
+        assert!(!in_ranges(&[], 1, 10, true));
+    }
+
+    #[test]
+    fn test_getmetadata_arguments() {
+        assert_eq!(
+            getmetadata_arguments("\"\" /shared/comment"),
+            Ok((
+                "",
+                GetMetadataArguments {
+                    max_size: None,
+                    depth: MetadataDepth::Zero,
+                    mailbox: String::new(),
+                    entries: vec![String::from("/shared/comment")],
+                }
+            ))
+        );
+        assert_eq!(
+            getmetadata_arguments(
+                "(MAXSIZE 1024 DEPTH infinity) INBOX (/private/comment /shared/vendor)"
+            ),
+            Ok((
+                "",
+                GetMetadataArguments {
+                    max_size: Some(1024),
+                    depth: MetadataDepth::Infinity,
+                    mailbox: String::from("INBOX"),
+                    entries: vec![
+                        String::from("/private/comment"),
+                        String::from("/shared/vendor")
+                    ],
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_setmetadata_arguments() {
+        assert_eq!(
+            setmetadata_arguments("INBOX (/private/comment \"My comment\" /shared/comment NIL)"),
+            Ok((
+                "",
+                (
+                    String::from("INBOX"),
+                    vec![
+                        (
+                            String::from("/private/comment"),
+                            Some(String::from("My comment"))
+                        ),
+                        (String::from("/shared/comment"), None),
+                    ]
+                )
+            ))
+        );
+    }
 }
@@ -3,7 +3,6 @@ use color_eyre::eyre::ContextCompat;
 use erooster_core::backend::storage::{MailStorage, Storage};
 use futures::{Sink, SinkExt};
 use std::sync::Arc;
-use tokio::fs;
 use tracing::instrument;
 
 pub struct Rename<'a> {
@@ -24,32 +23,210 @@ impl Rename<'_> {
     {
         let args = &command_data.arguments;
         assert!(args.len() == 2);
+        let username = self
+            .data
+            .con_state
+            .read()
+            .await
+            .username
+            .clone()
+            .context("Username missing in internal State")?;
         let old_folder = args[0].replace('/', ".");
-        let old_mailbox_path = storage.to_ondisk_path(
-            old_folder.clone(),
-            self.data
-                .con_state
-                .read()
-                .await
-                .username
-                .clone()
-                .context("Username missing in internal State")?,
-        )?;
+        let old_mailbox_path = storage.to_ondisk_path(old_folder, username.clone())?;
         let new_folder = args[1].replace('/', ".");
-        let new_mailbox_path = storage.to_ondisk_path(
-            new_folder.clone(),
-            self.data
-                .con_state
-                .read()
-                .await
-                .username
-                .clone()
-                .context("Username missing in internal State")?,
-        )?;
-        fs::rename(old_mailbox_path, new_mailbox_path).await?;
+        if new_folder.trim_matches('"').eq_ignore_ascii_case("INBOX") {
+            lines
+                .send(format!(
+                    "{} NO [ALREADYEXISTS] INBOX already exists",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        }
+        let new_mailbox_path = storage.to_ondisk_path(new_folder, username)?;
+        if !old_mailbox_path.exists() {
+            lines
+                .send(format!(
+                    "{} NO [NONEXISTENT] Mailbox does not exist",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        }
+        if new_mailbox_path.exists() {
+            lines
+                .send(format!(
+                    "{} NO [ALREADYEXISTS] Mailbox already exists",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        }
+        let old_name = old_mailbox_path
+            .file_name()
+            .context("Unable to get file name")?
+            .to_string_lossy();
+        let new_name = new_mailbox_path
+            .file_name()
+            .context("Unable to get file name")?
+            .to_string_lossy();
+        // INBOX is the only mailbox which may move its messages into one of its children
+        if old_name != "INBOX" && new_name.starts_with(&format!("{old_name}.")) {
+            lines
+                .send(format!(
+                    "{} NO [CANNOT] Mailbox can not be moved below itself",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        }
+        storage
+            .rename_mailbox(&old_mailbox_path, &new_mailbox_path)
+            .await?;
         lines
             .send(format!("{} OK RENAME completed", command_data.tag))
             .await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::commands::{CommandData, Commands};
+    use crate::servers::state::{Connection, State};
+    use futures::{channel::mpsc, StreamExt};
+
+    async fn setup() -> (Data, Arc<Storage>, String) {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(
+            erooster_core::backend::database::get_database(Arc::clone(&config))
+                .await
+                .unwrap(),
+        );
+        let storage = Arc::new(erooster_core::backend::storage::get_storage(
+            database,
+            Arc::clone(&config),
+        ));
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let username = format!("rename-{nanos}@localhost");
+        let con_state = Connection::new(true);
+        con_state.write().await.state = State::Authenticated;
+        con_state.write().await.username = Some(username.clone());
+        (Data { con_state }, storage, username)
+    }
+
+    async fn rename(data: &Data, storage: &Arc<Storage>, from: &str, to: &str) -> Option<String> {
+        let cmd_data = CommandData {
+            tag: "1",
+            command: Commands::Rename,
+            arguments: &[from, to],
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        let res = Rename { data }
+            .exec(&mut tx, &cmd_data, Arc::clone(storage))
+            .await;
+        assert!(res.is_ok());
+        rx.next().await
+    }
+
+    #[tokio::test]
+    async fn test_rename_inbox() {
+        let (data, storage, username) = setup().await;
+        let inbox = storage
+            .to_ondisk_path(String::from("INBOX"), username.clone())
+            .unwrap();
+        let old_mail = storage
+            .to_ondisk_path(String::from("Old"), username)
+            .unwrap();
+        storage.create_dirs(&inbox).unwrap();
+        storage
+            .store_new(&inbox, b"Subject: Test\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+
+        // INBOX stays and only its messages move
+        assert_eq!(
+            rename(&data, &storage, "INBOX", "Old").await,
+            Some(String::from("1 OK RENAME completed"))
+        );
+        assert!(inbox.exists());
+        assert_eq!(storage.count_new(&inbox) + storage.count_cur(&inbox), 0);
+        assert_eq!(storage.count_new(&old_mail), 1);
+
+        assert_eq!(
+            rename(&data, &storage, "Old", "INBOX").await,
+            Some(String::from("1 NO [ALREADYEXISTS] INBOX already exists"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_with_children() {
+        let (data, storage, username) = setup().await;
+        let parent = storage
+            .to_ondisk_path(String::from("Parent"), username.clone())
+            .unwrap();
+        let child = storage
+            .to_ondisk_path(String::from("Parent/Child"), username.clone())
+            .unwrap();
+        storage.create_dirs(&parent).unwrap();
+        storage.create_dirs(&child).unwrap();
+        storage.add_flag(&child, "\\Subscribed").await.unwrap();
+
+        assert_eq!(
+            rename(&data, &storage, "Parent", "Parent/Below").await,
+            Some(String::from(
+                "1 NO [CANNOT] Mailbox can not be moved below itself"
+            ))
+        );
+
+        assert_eq!(
+            rename(&data, &storage, "Parent", "Other").await,
+            Some(String::from("1 OK RENAME completed"))
+        );
+        let other = storage
+            .to_ondisk_path(String::from("Other"), username.clone())
+            .unwrap();
+        let other_child = storage
+            .to_ondisk_path(String::from("Other/Child"), username)
+            .unwrap();
+        assert!(!parent.exists());
+        assert!(!child.exists());
+        assert!(other.exists());
+        assert!(other_child.exists());
+        // The children keep their subscription
+        assert_eq!(
+            storage.get_flags(&other_child).await.unwrap(),
+            vec![String::from("\\Subscribed")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_errors() {
+        let (data, storage, username) = setup().await;
+        let first = storage
+            .to_ondisk_path(String::from("First"), username.clone())
+            .unwrap();
+        let second = storage
+            .to_ondisk_path(String::from("Second"), username)
+            .unwrap();
+        storage.create_dirs(&first).unwrap();
+        storage.create_dirs(&second).unwrap();
+
+        assert_eq!(
+            rename(&data, &storage, "Missing", "Third").await,
+            Some(String::from("1 NO [NONEXISTENT] Mailbox does not exist"))
+        );
+        assert_eq!(
+            rename(&data, &storage, "First", "Second").await,
+            Some(String::from("1 NO [ALREADYEXISTS] Mailbox already exists"))
+        );
+        assert!(first.exists());
+    }
+}
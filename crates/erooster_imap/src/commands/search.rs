@@ -1,12 +1,24 @@
-use crate::commands::{CommandData, Data};
+use crate::{
+    commands::{
+        parsers::{in_ranges, search_arguments, SearchKey, SearchReturnOption},
+        updates::{ensure_index, mail_entries},
+        CommandData, Data,
+    },
+    servers::state::State,
+};
 use color_eyre::eyre::ContextCompat;
-use erooster_core::backend::storage::{MailEntry, MailStorage, Storage};
+use erooster_core::backend::{
+    search::{text_matches, SearchField},
+    storage::{MailEntry, MailEntryType, MailStorage, Storage},
+};
 use futures::{Sink, SinkExt};
+use nom::{error::convert_error, Finish};
 use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
 };
-use tracing::instrument;
+use tracing::{debug, error, instrument};
 
 pub struct Search<'a> {
     pub data: &'a Data,
@@ -19,12 +31,437 @@ impl Search<'_> {
         lines: &mut S,
         storage: Arc<Storage>,
         command_data: &CommandData<'_>,
+        uid: bool,
     ) -> color_eyre::eyre::Result<()>
     where
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
         S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
     {
+        let offset = usize::from(uid);
+        let State::Selected(folder, _) = self.data.con_state.read().await.state.clone() else {
+            lines
+                .send(format!(
+                    "{} NO [TRYCREATE] No mailbox selected",
+                    command_data.tag
+                ))
+                .await?;
+            return Ok(());
+        };
+        let folder = folder.replace('/', ".");
+        let mailbox_path = storage.to_ondisk_path(
+            folder.clone(),
+            self.data
+                .con_state
+                .read()
+                .await
+                .username
+                .clone()
+                .context("Username missing in internal State")?,
+        )?;
+
+        let search_args = command_data.arguments[offset..].join(" ");
+        let search_args_borrow: &str = &search_args;
+        let (return_options, keys) = match search_arguments(search_args_borrow).finish() {
+            Ok(("", (return_options, _, keys))) => (return_options, keys),
+            Ok((left, _)) => {
+                error!("Failed to parse search arguments. Leftover: {}", left);
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+            Err(e) => {
+                error!(
+                    "Failed to parse search arguments: {}",
+                    convert_error(search_args_borrow, e)
+                );
+                lines
+                    .send(format!("{} BAD Unable to parse", command_data.tag))
+                    .await?;
+                return Ok(());
+            }
+        };
+        debug!("Search keys: {:?}", keys);
+
+        // The order comes from the mailbox index so the messages don't need to be parsed
+        ensure_index(self.data, &storage, &mailbox_path).await?;
+        let (max_sequence, max_uid, order) = {
+            let read_lock = self.data.con_state.read().await;
+            let index = read_lock
+                .mailbox_index
+                .as_ref()
+                .context("Mailbox index missing")?;
+            let order: Vec<String> = index
+                .messages
+                .iter()
+                .map(|mail| mail.maildir_id.clone())
+                .collect();
+            (
+                index.messages.len() as i64,
+                index
+                    .messages
+                    .iter()
+                    .map(|mail| mail.uid)
+                    .max()
+                    .unwrap_or_default(),
+                order,
+            )
+        };
+        let mut entries = mail_entries(&storage, &mailbox_path).await;
+        // Pairs of sequence number and message
+        let mails: Vec<(i64, MailEntryType)> = order
+            .into_iter()
+            .enumerate()
+            .filter_map(|(position, maildir_id)| {
+                let mail = entries.remove(&maildir_id)?;
+                Some((position as i64 + 1, mail))
+            })
+            .collect();
+
+        let mut text_matches = HashMap::new();
+        for (field, query) in text_queries(&keys) {
+            if text_matches.contains_key(&(field, query.clone())) {
+                continue;
+            }
+            // If the index fails we fall back to looking at the messages themselves
+            let matches = match storage.search_text(&mailbox_path, field, &query).await {
+                Ok(matches) => matches,
+                Err(e) => {
+                    error!("[Search] Failed to query search index: {}", e);
+                    None
+                }
+            };
+            text_matches.insert((field, query), matches);
+        }
+        let context = SearchContext {
+            text_matches,
+            max_sequence,
+            max_uid,
+            saved_search: self.data.con_state.read().await.saved_search.clone(),
+        };
+
+        // Pairs of sequence number and uid
+        let found: Vec<(i64, i64)> = mails
+            .into_iter()
+            .filter_map(|(sequence, mut mail)| {
+                keys.iter()
+                    .all(|key| matches(key, &mut mail, sequence, &context))
+                    .then(|| (sequence, mail.uid()))
+            })
+            .collect();
+        let results: Vec<i64> = found
+            .iter()
+            .map(|(sequence, mail_uid)| if uid { *mail_uid } else { *sequence })
+            .collect();
+
+        if let Some(mut return_options) = return_options {
+            // An empty list is the same as asking for ALL
+            if return_options.is_empty() {
+                return_options.push(SearchReturnOption::All);
+            }
+            if return_options.contains(&SearchReturnOption::Save) {
+                let saved = saved_result(&return_options, &found);
+                self.data.con_state.write().await.saved_search = saved;
+            }
+            if return_options != [SearchReturnOption::Save] {
+                lines
+                    .feed(esearch_response(
+                        command_data.tag,
+                        uid,
+                        &return_options,
+                        &results,
+                    ))
+                    .await?;
+            }
+        } else {
+            let results = results
+                .iter()
+                .map(|id| format!(" {id}"))
+                .collect::<String>();
+            lines.feed(format!("* SEARCH{results}")).await?;
+        }
+        if uid {
+            lines
+                .feed(format!("{} OK UID SEARCH completed", command_data.tag))
+                .await?;
+        } else {
+            lines
+                .feed(format!("{} OK SEARCH completed", command_data.tag))
+                .await?;
+        }
         lines.flush().await?;
         Ok(())
     }
 }
+
+struct SearchContext {
+    /// Candidates found by the search index. `None` means every message needs to be checked.
+    text_matches: HashMap<(SearchField, String), Option<HashSet<String>>>,
+    max_sequence: i64,
+    max_uid: i64,
+    /// UIDs referenced by `$`
+    saved_search: HashSet<i64>,
+}
+
+/// The UIDs to remember for `$`.
+///
+/// Combined with only MIN and/or MAX just those messages are saved (RFC 5182 section 2.4).
+fn saved_result(return_options: &[SearchReturnOption], found: &[(i64, i64)]) -> HashSet<i64> {
+    let only_min_max = !return_options.contains(&SearchReturnOption::All)
+        && !return_options.contains(&SearchReturnOption::Count)
+        && (return_options.contains(&SearchReturnOption::Min)
+            || return_options.contains(&SearchReturnOption::Max));
+    if only_min_max {
+        let mut saved = HashSet::new();
+        if return_options.contains(&SearchReturnOption::Min) {
+            saved.extend(
+                found
+                    .iter()
+                    .min_by_key(|(sequence, _)| *sequence)
+                    .map(|(_, uid)| *uid),
+            );
+        }
+        if return_options.contains(&SearchReturnOption::Max) {
+            saved.extend(
+                found
+                    .iter()
+                    .max_by_key(|(sequence, _)| *sequence)
+                    .map(|(_, uid)| *uid),
+            );
+        }
+        saved
+    } else {
+        found.iter().map(|(_, uid)| *uid).collect()
+    }
+}
+
+fn esearch_response(
+    tag: &str,
+    uid: bool,
+    return_options: &[SearchReturnOption],
+    results: &[i64],
+) -> String {
+    let mut response = format!("* ESEARCH (TAG \"{tag}\")");
+    if uid {
+        response.push_str(" UID");
+    }
+    if return_options.contains(&SearchReturnOption::Min) {
+        if let Some(min) = results.iter().min() {
+            response.push_str(&format!(" MIN {min}"));
+        }
+    }
+    if return_options.contains(&SearchReturnOption::Max) {
+        if let Some(max) = results.iter().max() {
+            response.push_str(&format!(" MAX {max}"));
+        }
+    }
+    if return_options.contains(&SearchReturnOption::Count) {
+        response.push_str(&format!(" COUNT {}", results.len()));
+    }
+    if return_options.contains(&SearchReturnOption::All) && !results.is_empty() {
+        response.push_str(&format!(" ALL {}", sequence_set(results)));
+    }
+    response
+}
+
+/// Formats the ids as compact sequence set like `2,4:7,10`
+fn sequence_set(ids: &[i64]) -> String {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut parts = Vec::new();
+    let mut iter = ids.into_iter();
+    if let Some(first) = iter.next() {
+        let (mut start, mut end) = (first, first);
+        for id in iter {
+            if id == end + 1 {
+                end = id;
+            } else {
+                parts.push(format_range(start, end));
+                start = id;
+                end = id;
+            }
+        }
+        parts.push(format_range(start, end));
+    }
+    parts.join(",")
+}
+
+fn format_range(start: i64, end: i64) -> String {
+    if start == end {
+        start.to_string()
+    } else {
+        format!("{start}:{end}")
+    }
+}
+
+/// Collects all text searches which can be answered by the search index
+fn text_queries(keys: &[SearchKey]) -> Vec<(SearchField, String)> {
+    let mut queries = Vec::new();
+    for key in keys {
+        match key {
+            SearchKey::Body(query) => queries.push((SearchField::Body, query.clone())),
+            SearchKey::Text(query) => queries.push((SearchField::Text, query.clone())),
+            SearchKey::Not(key) => queries.extend(text_queries(std::slice::from_ref(key))),
+            SearchKey::Or(left, right) => {
+                queries.extend(text_queries(std::slice::from_ref(left)));
+                queries.extend(text_queries(std::slice::from_ref(right)));
+            }
+            SearchKey::And(keys) => queries.extend(text_queries(keys)),
+            _ => {}
+        }
+    }
+    queries
+}
+
+fn is_recent(path: &Path) -> bool {
+    path.parent()
+        .and_then(Path::file_name)
+        .map_or(false, |folder| folder == "new")
+}
+
+/// The Date header of the message. It is only parsed once it is needed.
+fn sent_date(mail: &mut MailEntryType) -> Option<i64> {
+    if mail.date().is_none() {
+        mail.load();
+    }
+    mail.date()
+}
+
+fn header_contains(mail: &mut MailEntryType, name: &str, needle: &str) -> bool {
+    let needle = needle.to_lowercase();
+    mail.headers().map_or(false, |headers| {
+        headers.iter().any(|header| {
+            header.get_key().eq_ignore_ascii_case(name)
+                && header.get_value().to_lowercase().contains(&needle)
+        })
+    })
+}
+
+/// Looks at the message itself the same way the search index would
+fn text_contains(mail: &mut MailEntryType, field: SearchField, query: &str) -> bool {
+    mail.parsed()
+        .map_or(false, |parsed| text_matches(&parsed, field, query))
+}
+
+fn matches_text(
+    mail: &mut MailEntryType,
+    field: SearchField,
+    query: &str,
+    context: &SearchContext,
+) -> bool {
+    // The index only rules messages out. Its candidates still need a look at the text.
+    if let Some(Some(candidates)) = context.text_matches.get(&(field, query.to_string())) {
+        if !candidates.contains(mail.id()) {
+            return false;
+        }
+    }
+    text_contains(mail, field, query)
+}
+
+#[allow(clippy::too_many_lines)]
+fn matches(
+    key: &SearchKey,
+    mail: &mut MailEntryType,
+    sequence: i64,
+    context: &SearchContext,
+) -> bool {
+    match key {
+        SearchKey::All => true,
+        SearchKey::Answered => mail.is_replied(),
+        SearchKey::Deleted => mail.is_trashed(),
+        SearchKey::Draft => mail.is_draft(),
+        SearchKey::Flagged => mail.is_flagged(),
+        SearchKey::New => is_recent(mail.path()) && !mail.is_seen(),
+        SearchKey::Old => !is_recent(mail.path()),
+        SearchKey::Recent => is_recent(mail.path()),
+        SearchKey::Seen => mail.is_seen(),
+        SearchKey::Unanswered => !mail.is_replied(),
+        SearchKey::Undeleted => !mail.is_trashed(),
+        SearchKey::Undraft => !mail.is_draft(),
+        SearchKey::Unflagged => !mail.is_flagged(),
+        SearchKey::Unseen => !mail.is_seen(),
+        SearchKey::Bcc(value) => header_contains(mail, "Bcc", value),
+        SearchKey::Cc(value) => header_contains(mail, "Cc", value),
+        SearchKey::From(value) => header_contains(mail, "From", value),
+        SearchKey::Subject(value) => header_contains(mail, "Subject", value),
+        SearchKey::To(value) => header_contains(mail, "To", value),
+        SearchKey::Header(name, value) => header_contains(mail, name, value),
+        SearchKey::Body(query) => matches_text(mail, SearchField::Body, query, context),
+        SearchKey::Text(query) => matches_text(mail, SearchField::Text, query, context),
+        SearchKey::EmailId(email_id) => mail.email_id() == Some(email_id.as_str()),
+        SearchKey::ThreadId(thread_id) => mail.thread_id() == Some(thread_id.as_str()),
+        SearchKey::Before(date) => mail
+            .internal_date()
+            .map_or(false, |received| received.div_euclid(86400) < date.0),
+        SearchKey::On(date) => mail
+            .internal_date()
+            .map_or(false, |received| received.div_euclid(86400) == date.0),
+        SearchKey::Since(date) => mail
+            .internal_date()
+            .map_or(false, |received| received.div_euclid(86400) >= date.0),
+        SearchKey::SentBefore(date) => {
+            sent_date(mail).map_or(false, |sent| sent.div_euclid(86400) < date.0)
+        }
+        SearchKey::SentOn(date) => {
+            sent_date(mail).map_or(false, |sent| sent.div_euclid(86400) == date.0)
+        }
+        SearchKey::SentSince(date) => {
+            sent_date(mail).map_or(false, |sent| sent.div_euclid(86400) >= date.0)
+        }
+        SearchKey::Larger(size) => mail.size().map_or(false, |mail_size| mail_size > *size),
+        SearchKey::Smaller(size) => mail.size().map_or(false, |mail_size| mail_size < *size),
+        SearchKey::Uid(ranges) => in_ranges(
+            ranges,
+            mail.uid(),
+            context.max_uid,
+            context.saved_search.contains(&mail.uid()),
+        ),
+        SearchKey::SequenceSet(ranges) => in_ranges(
+            ranges,
+            sequence,
+            context.max_sequence,
+            context.saved_search.contains(&mail.uid()),
+        ),
+        SearchKey::Not(key) => !matches(key, mail, sequence, context),
+        SearchKey::Or(left, right) => {
+            matches(left, mail, sequence, context) || matches(right, mail, sequence, context)
+        }
+        SearchKey::And(keys) => keys.iter().all(|key| matches(key, mail, sequence, context)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_set() {
+        assert_eq!(sequence_set(&[]), "");
+        assert_eq!(sequence_set(&[7]), "7");
+        assert_eq!(sequence_set(&[10, 2, 4, 5, 6, 7]), "2,4:7,10");
+    }
+
+    #[test]
+    fn test_esearch_response() {
+        assert_eq!(
+            esearch_response(
+                "A282",
+                false,
+                &[SearchReturnOption::Min, SearchReturnOption::Count],
+                &[2, 10, 11]
+            ),
+            "* ESEARCH (TAG \"A282\") MIN 2 COUNT 3"
+        );
+        assert_eq!(
+            esearch_response(
+                "A283",
+                true,
+                &[SearchReturnOption::All, SearchReturnOption::Max],
+                &[]
+            ),
+            "* ESEARCH (TAG \"A283\") UID"
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{
-    commands::{CommandData, Data},
+    commands::{updates::build_index, CommandData, Data},
     servers::state::{Access, State},
 };
 use color_eyre::eyre::ContextCompat;
@@ -41,6 +41,7 @@ where
     };
     {
         write_lock.state = State::Selected(folder.clone(), access);
+        write_lock.saved_search.clear();
     };
 
     let folder_on_disk = folder_arg;
@@ -57,16 +58,47 @@ where
         storage.add_flag(&mailbox_path, "\\Subscribed").await?;
         storage.add_flag(&mailbox_path, "\\NoInferiors").await?;
     }
-    send_success(lines, folder, storage, mailbox_path, rw, command_data).await?;
+    let noselect = storage
+        .get_flags(&mailbox_path)
+        .await
+        .unwrap_or_default()
+        .iter()
+        .any(|flag| flag.eq_ignore_ascii_case("\\Noselect"));
+    if noselect {
+        write_lock.state = State::Authenticated;
+        write_lock.mailbox_index = None;
+        lines
+            .send(format!(
+                "{} NO [CANNOT] Mailbox is not selectable",
+                command_data.tag
+            ))
+            .await?;
+        return Ok(());
+    }
+    let index = build_index(&storage, &mailbox_path).await?;
+    // The EXISTS count has to match the sequence numbers of the index
+    let exists = index.messages.len();
+    write_lock.mailbox_index = Some(index);
+    send_success(
+        lines,
+        folder,
+        storage,
+        mailbox_path,
+        exists,
+        rw,
+        command_data,
+    )
+    .await?;
     Ok(())
 }
 
-#[instrument(skip(lines, folder, storage, mailbox_path, rw, command_data))]
+#[instrument(skip(lines, folder, storage, mailbox_path, exists, rw, command_data))]
 async fn send_success<S, E>(
     lines: &mut S,
     folder: String,
     storage: Arc<Storage>,
     mailbox_path: PathBuf,
+    exists: usize,
     rw: bool,
     command_data: &CommandData<'_>,
 ) -> color_eyre::eyre::Result<()>
@@ -74,8 +106,7 @@ where
     E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
     S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
 {
-    let count = storage.count_cur(&mailbox_path) + storage.count_new(&mailbox_path);
-    lines.feed(format!("* {count} EXISTS")).await?;
+    lines.feed(format!("* {exists} EXISTS")).await?;
     let current_time = SystemTime::now();
     let unix_timestamp = current_time.duration_since(UNIX_EPOCH)?;
     #[allow(clippy::cast_possible_truncation)]
@@ -90,6 +121,10 @@ where
             current_uid + 1,
         ))
         .await?;
+    let mailbox_id = storage.mailbox_id(&mailbox_path).await?;
+    lines
+        .feed(format!("* OK [MAILBOXID ({mailbox_id})] Ok"))
+        .await?;
     lines
         .feed(String::from(
             "* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)",
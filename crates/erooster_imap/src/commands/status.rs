@@ -3,6 +3,7 @@ use color_eyre::eyre::ContextCompat;
 use erooster_core::backend::storage::{MailEntry, MailStorage, Storage};
 use futures::{Sink, SinkExt};
 use std::{
+    path::Path,
     sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -39,49 +40,7 @@ impl Status<'_> {
                 .context("Username missing in internal State")?,
         )?;
 
-        let mut values = String::new();
-        if responses.contains(&"MESSAGES") {
-            let count = storage.count_cur(&mailbox_path) + storage.count_new(&mailbox_path);
-            values.push_str(&format!("MESSAGES {count}"));
-        }
-        if responses.contains(&"UIDNEXT") {
-            let current_uid = storage.get_uid_for_folder(&mailbox_path)?;
-            values.push_str(&format!("UIDNEXT {}", current_uid + 1));
-        }
-        if responses.contains(&"UIDVALIDITY") {
-            let current_time = SystemTime::now();
-            let unix_timestamp = current_time.duration_since(UNIX_EPOCH)?;
-            #[allow(clippy::cast_possible_truncation)]
-            let timestamp = unix_timestamp.as_millis() as u32;
-
-            values.push_str(&format!("UIDVALIDITY {timestamp}"));
-        }
-        if responses.contains(&"UNSEEN") {
-            let mails = storage.list_cur(&mailbox_path).await;
-            let count =
-                mails.iter().filter(|m| !m.is_seen()).count() + storage.count_new(&mailbox_path);
-            values.push_str(&format!("UNSEEN {count}"));
-        }
-        if responses.contains(&"DELETED") {
-            let mails = storage.list_cur(&mailbox_path).await;
-            let count = mails.iter().filter(|m| m.is_trashed()).count();
-            values.push_str(&format!("DELETED {count}"));
-        }
-        if responses.contains(&"SIZE") {
-            let size: usize = storage
-                .list_all(&mailbox_path)
-                .await
-                .iter_mut()
-                .map(|mail| {
-                    if let Ok(parsed) = mail.parsed() {
-                        parsed.raw_bytes.len()
-                    } else {
-                        0
-                    }
-                })
-                .sum();
-            values.push_str(&format!("SIZE {size}"));
-        }
+        let values = status_values(&storage, &mailbox_path, &responses).await?;
         lines
             .feed(format!("* STATUS {folder_on_disk} ({values})"))
             .await?;
@@ -92,3 +51,54 @@ impl Status<'_> {
         Ok(())
     }
 }
+
+/// Generates the list of status items requested by the client for the mailbox
+#[instrument(skip(storage, mailbox_path, requested))]
+pub async fn status_values(
+    storage: &Storage,
+    mailbox_path: &Path,
+    requested: &[&str],
+) -> color_eyre::eyre::Result<String> {
+    let mut values = Vec::new();
+    if requested.contains(&"MESSAGES") {
+        let count = storage.count_cur(mailbox_path) + storage.count_new(mailbox_path);
+        values.push(format!("MESSAGES {count}"));
+    }
+    if requested.contains(&"UIDNEXT") {
+        let current_uid = storage.get_uid_for_folder(mailbox_path)?;
+        values.push(format!("UIDNEXT {}", current_uid + 1));
+    }
+    if requested.contains(&"UIDVALIDITY") {
+        let current_time = SystemTime::now();
+        let unix_timestamp = current_time.duration_since(UNIX_EPOCH)?;
+        #[allow(clippy::cast_possible_truncation)]
+        let timestamp = unix_timestamp.as_millis() as u32;
+
+        values.push(format!("UIDVALIDITY {timestamp}"));
+    }
+    if requested.contains(&"UNSEEN") {
+        let mails = storage.list_cur(mailbox_path).await;
+        let count = mails.iter().filter(|m| !m.is_seen()).count() + storage.count_new(mailbox_path);
+        values.push(format!("UNSEEN {count}"));
+    }
+    if requested.contains(&"DELETED") {
+        let mails = storage.list_cur(mailbox_path).await;
+        let count = mails.iter().filter(|m| m.is_trashed()).count();
+        values.push(format!("DELETED {count}"));
+    }
+    if requested.contains(&"SIZE") {
+        // The file sizes are enough. There is no need to parse every message.
+        let size: u64 = storage
+            .list_all(mailbox_path)
+            .await
+            .iter()
+            .filter_map(MailEntry::size)
+            .sum();
+        values.push(format!("SIZE {size}"));
+    }
+    if requested.contains(&"MAILBOXID") {
+        let mailbox_id = storage.mailbox_id(mailbox_path).await?;
+        values.push(format!("MAILBOXID ({mailbox_id})"));
+    }
+    Ok(values.join(" "))
+}
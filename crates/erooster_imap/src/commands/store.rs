@@ -1,11 +1,17 @@
 use crate::{
-    commands::{CommandData, Data},
+    commands::{
+        fetch::flags_response,
+        parsers::parse_selected_range,
+        updates::{ensure_index, mail_entries},
+        CommandData, Data,
+    },
     servers::state::State,
 };
 use color_eyre::eyre::ContextCompat;
 use erooster_core::backend::storage::{MailEntry, MailEntryType, MailStorage, Storage};
 use futures::{Sink, SinkExt};
-use std::sync::Arc;
+use nom::Finish;
+use std::{path::Path, sync::Arc};
 use tracing::{debug, error, instrument};
 
 pub struct Store<'a> {
@@ -28,52 +34,59 @@ impl Store<'_> {
         let arguments = &command_data.arguments;
         assert!(arguments.len() >= 2 + offset);
         if arguments.len() >= 2 + offset {
-            if let State::Selected(folder, _) = &self.data.con_state.read().await.state {
-                let folder = folder.replace('/', ".");
-                let mailbox_path = storage.to_ondisk_path(
-                    folder.clone(),
-                    self.data
-                        .con_state
-                        .read()
-                        .await
-                        .username
-                        .clone()
-                        .context("Username missing in internal State")?,
-                )?;
-                let mails: Vec<MailEntryType> = storage.list_all(&mailbox_path).await;
+            let selected = {
+                let read_lock = self.data.con_state.read().await;
+                if let State::Selected(folder, _) = &read_lock.state {
+                    Some((
+                        folder.replace('/', "."),
+                        read_lock
+                            .username
+                            .clone()
+                            .context("Username missing in internal State")?,
+                    ))
+                } else {
+                    None
+                }
+            };
+            if let Some((folder, username)) = selected {
+                let mailbox_path = storage.to_ondisk_path(folder, username)?;
+                let Ok((_, ranges)) = parse_selected_range(arguments[offset]).finish() else {
+                    lines
+                        .send(format!("{} BAD Unable to parse", command_data.tag))
+                        .await?;
+                    return Ok(());
+                };
 
-                let filtered_mails: Vec<MailEntryType> =
-                    if command_data.arguments[offset].contains(':') {
-                        let range = command_data.arguments[offset]
-                            .split(':')
-                            .collect::<Vec<_>>();
-                        let start = range[0].parse::<i64>().unwrap_or(1);
-                        let end = range[1];
-                        let end_int = end.parse::<i64>().unwrap_or(i64::max_value());
-                        if end == "*" {
-                            mails
-                                .into_iter()
-                                .filter(|mail| mail.uid() >= start)
-                                .collect()
-                        } else {
-                            mails
-                                .into_iter()
-                                .filter(|mail| mail.uid() >= start && mail.uid() <= end_int)
-                                .collect()
-                        }
-                    } else {
-                        let wanted_id = command_data.arguments[offset].parse::<i64>().unwrap_or(1);
-                        mails
-                            .into_iter()
-                            .filter(|mail| mail.uid() == wanted_id)
-                            .collect()
-                    };
+                ensure_index(self.data, &storage, &mailbox_path).await?;
+                // Sequence numbers always refer to what the client was told about the mailbox
+                let selected: Vec<(i64, String)> = {
+                    let read_lock = self.data.con_state.read().await;
+                    let index = read_lock
+                        .mailbox_index
+                        .as_ref()
+                        .context("Mailbox index missing")?;
+                    index
+                        .selection(&ranges, uid, &read_lock.saved_search)
+                        .map(|(sequence, mail)| (sequence, mail.maildir_id.clone()))
+                        .collect()
+                };
+                let mut entries = mail_entries(&storage, &mailbox_path).await;
+                let filtered_mails: Vec<(i64, MailEntryType)> = selected
+                    .into_iter()
+                    .filter_map(|(sequence, maildir_id)| {
+                        entries.remove(&maildir_id).map(|mail| (sequence, mail))
+                    })
+                    .collect();
+                let changed_ids: Vec<String> = filtered_mails
+                    .iter()
+                    .map(|(_, mail)| mail.id().to_string())
+                    .collect();
                 let action = arguments[1 + offset];
 
                 let flags = command_data.arguments[2 + offset..].to_vec();
                 let flags_string = flags.join(" ");
                 if action.to_lowercase() == "flags" {
-                    for mail in filtered_mails {
+                    for (sequence, mail) in filtered_mails {
                         let mut path = mail.path().clone();
                         path.pop();
                         let path = path
@@ -94,19 +107,19 @@ impl Store<'_> {
                             lines
                                 .feed(format!(
                                     "* {} FETCH (UID {} FLAGS {})",
-                                    mail.uid(),
+                                    sequence,
                                     mail.uid(),
                                     flags_string
                                 ))
                                 .await?;
                         } else {
                             lines
-                                .feed(format!("* {} FETCH (FLAGS {flags_string})", mail.uid()))
+                                .feed(format!("* {sequence} FETCH (FLAGS {flags_string})"))
                                 .await?;
                         }
                     }
                 } else if action.to_lowercase() == "flags.silent" {
-                    for mail in filtered_mails {
+                    for (sequence, mail) in filtered_mails {
                         let mut path = mail.path().clone();
                         path.pop();
                         let path = path
@@ -124,7 +137,7 @@ impl Store<'_> {
                         }
                     }
                 } else if action.to_lowercase() == "+flags" {
-                    for mail in filtered_mails {
+                    for (sequence, mail) in filtered_mails {
                         let mut path = mail.path().clone();
                         path.pop();
                         let path = path
@@ -146,19 +159,19 @@ impl Store<'_> {
                             lines
                                 .feed(format!(
                                     "* {} FETCH (UID {} FLAGS {})",
-                                    mail.uid(),
+                                    sequence,
                                     mail.uid(),
                                     flags_string
                                 ))
                                 .await?;
                         } else {
                             lines
-                                .feed(format!("* {} FETCH (FLAGS {flags_string})", mail.uid()))
+                                .feed(format!("* {sequence} FETCH (FLAGS {flags_string})"))
                                 .await?;
                         }
                     }
                 } else if action.to_lowercase() == "+flags.silent" {
-                    for mail in filtered_mails {
+                    for (sequence, mail) in filtered_mails {
                         let mut path = mail.path().clone();
                         path.pop();
                         let path = path
@@ -176,7 +189,7 @@ impl Store<'_> {
                         }
                     }
                 } else if action.to_lowercase() == "-flags" {
-                    for mail in filtered_mails {
+                    for (sequence, mail) in filtered_mails {
                         let mut current_flags = vec![];
                         if mail.is_replied() {
                             current_flags.push("\\Answered");
@@ -207,7 +220,7 @@ impl Store<'_> {
                             lines
                                 .feed(format!(
                                     "* {} FETCH (UID {} FLAGS ({}))",
-                                    mail.uid(),
+                                    sequence,
                                     mail.uid(),
                                     new_flags.join(" ")
                                 ))
@@ -216,14 +229,14 @@ impl Store<'_> {
                             lines
                                 .feed(format!(
                                     "* {} FETCH (FLAGS ({}))",
-                                    mail.uid(),
+                                    sequence,
                                     new_flags.join(" ")
                                 ))
                                 .await?;
                         }
                     }
                 } else if action.to_lowercase() == "-flags.silent" {
-                    for mail in filtered_mails {
+                    for (sequence, mail) in filtered_mails {
                         if let Err(e) = storage.remove_flags(&mailbox_path, mail.id(), &flags) {
                             error!("Failed to store flags or move email {}: {}", mail.id(), e);
                         }
@@ -237,6 +250,7 @@ impl Store<'_> {
                         .await?;
                     return Ok(());
                 }
+                refresh_flags(self.data, &storage, &mailbox_path, &changed_ids).await?;
                 if uid {
                     lines
                         .feed(format!("{} Ok UID STORE completed", command_data.tag))
@@ -267,3 +281,28 @@ impl Store<'_> {
         Ok(())
     }
 }
+
+/// Puts the flags of the changed messages into the mailbox index.
+///
+/// The client already knows about them so they must not be reported again as changes of another session.
+#[instrument(skip(data, storage, mailbox_path, changed_ids))]
+async fn refresh_flags(
+    data: &Data,
+    storage: &Storage,
+    mailbox_path: &Path,
+    changed_ids: &[String],
+) -> color_eyre::eyre::Result<()> {
+    let entries = mail_entries(storage, mailbox_path).await;
+    let mut write_lock = data.con_state.write().await;
+    if let Some(index) = write_lock.mailbox_index.as_mut() {
+        for mail in &mut index.messages {
+            if !changed_ids.contains(&mail.maildir_id) {
+                continue;
+            }
+            if let Some(entry) = entries.get(&mail.maildir_id) {
+                mail.flags = flags_response(entry)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,4 +1,5 @@
-use crate::commands::{fetch::Fetch, store::Store, CommandData, Data};
+use crate::commands::{fetch::Fetch, search::Search, store::Store, CommandData, Data};
+use bytes::Bytes;
 use erooster_core::backend::storage::Storage;
 use futures::{Sink, SinkExt};
 use std::sync::Arc;
@@ -18,7 +19,10 @@ impl Uid<'_> {
     ) -> color_eyre::eyre::Result<()>
     where
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
-        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+        S: Sink<String, Error = E>
+            + Sink<Bytes, Error = E>
+            + std::marker::Unpin
+            + std::marker::Send,
     {
         if command_data.arguments[0].to_lowercase() == "fetch" {
             Fetch { data: self.data }
@@ -38,8 +42,8 @@ impl Uid<'_> {
                 .send(format!("{} BAD Not supported", command_data.tag))
                 .await?;
         } else if command_data.arguments[0].to_lowercase() == "search" {
-            lines
-                .send(format!("{} BAD Not supported", command_data.tag))
+            Search { data: self.data }
+                .exec(lines, storage, command_data, true)
                 .await?;
         } else if command_data.arguments[0].to_lowercase() == "store" {
             Store { data: self.data }
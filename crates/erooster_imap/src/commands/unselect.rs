@@ -0,0 +1,97 @@
+use crate::{
+    commands::{CommandData, Data},
+    servers::state::State,
+};
+use futures::{Sink, SinkExt};
+use tracing::instrument;
+
+pub struct Unselect<'a> {
+    pub data: &'a Data,
+}
+
+impl Unselect<'_> {
+    #[instrument(skip(self, lines, command_data))]
+    pub async fn exec<S, E>(
+        &self,
+        lines: &mut S,
+        command_data: &CommandData<'_>,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let mut write_lock = self.data.con_state.write().await;
+
+        // Unlike CLOSE this never expunges anything
+        if let State::Selected(_, _) = &write_lock.state {
+            write_lock.deselect();
+            lines
+                .send(format!("{} OK UNSELECT completed", command_data.tag))
+                .await?;
+        } else {
+            // RFC 3691 section 2 requires BAD without a selected mailbox
+            lines
+                .send(format!("{} BAD no mailbox selected", command_data.tag))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{CommandData, Commands};
+    use crate::servers::state::{Access, Connection};
+    use futures::{channel::mpsc, StreamExt};
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn test_unselect() {
+        let con_state = Connection::new(true);
+        con_state.write().await.state = State::Selected("INBOX".to_string(), Access::ReadWrite);
+        con_state.write().await.saved_search = HashSet::from([1, 2]);
+        let unselect = Unselect {
+            data: &Data { con_state },
+        };
+        let cmd_data = CommandData {
+            tag: "1",
+            command: Commands::Unselect,
+            arguments: &[],
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        let res = unselect.exec(&mut tx, &cmd_data).await;
+        assert!(res.is_ok());
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("1 OK UNSELECT completed"))
+        );
+        assert_eq!(
+            unselect.data.con_state.read().await.state,
+            State::Authenticated
+        );
+        assert!(unselect.data.con_state.read().await.saved_search.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unselect_not_selected() {
+        let unselect = Unselect {
+            data: &Data {
+                con_state: Connection::new(true),
+            },
+        };
+        let cmd_data = CommandData {
+            tag: "1",
+            command: Commands::Unselect,
+            arguments: &[],
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        let res = unselect.exec(&mut tx, &cmd_data).await;
+        assert!(res.is_ok());
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("1 BAD no mailbox selected"))
+        );
+    }
+}
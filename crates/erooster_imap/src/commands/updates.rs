@@ -0,0 +1,368 @@
+use crate::{
+    commands::{fetch::flags_response, Data},
+    servers::state::{IndexedMail, MailboxIndex, State},
+};
+use color_eyre::eyre::ContextCompat;
+use erooster_core::backend::storage::{MailEntry, MailEntryType, MailStorage, Storage};
+use futures::{Sink, SinkExt};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, instrument};
+
+/// How old a change has to be before no further change can end up with the same modification time
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// The state of the mailbox compared to an index
+struct CurrentMessages {
+    /// FLAGS response of every message which is still in the mailbox keyed by maildir id
+    flags: HashMap<String, String>,
+    /// Messages the index doesn't know about yet ordered by uid
+    new: Vec<IndexedMail>,
+}
+
+/// Loads all messages in the mailbox in the order the client sees them
+#[instrument(skip(storage, mailbox_path))]
+pub async fn build_index(
+    storage: &Storage,
+    mailbox_path: &Path,
+) -> color_eyre::eyre::Result<MailboxIndex> {
+    let changed = last_change(mailbox_path).await;
+    let current = current_messages(storage, mailbox_path, &HashSet::new()).await?;
+    Ok(MailboxIndex {
+        messages: current.new,
+        changed,
+    })
+}
+
+/// Builds the index of the selected mailbox unless the session has one already.
+///
+/// Reading the mailbox may take a while, so the lock is only taken again to install the index.
+#[instrument(skip(data, storage, mailbox_path))]
+pub async fn ensure_index(
+    data: &Data,
+    storage: &Storage,
+    mailbox_path: &Path,
+) -> color_eyre::eyre::Result<()> {
+    let folder = {
+        let read_lock = data.con_state.read().await;
+        if read_lock.mailbox_index.is_some() {
+            return Ok(());
+        }
+        let State::Selected(folder, _) = &read_lock.state else {
+            return Ok(());
+        };
+        folder.clone()
+    };
+    let index = build_index(storage, mailbox_path).await?;
+
+    let mut write_lock = data.con_state.write().await;
+    // The client may have selected another mailbox in the meantime
+    if !matches!(&write_lock.state, State::Selected(selected, _) if *selected == folder) {
+        return Ok(());
+    }
+    // Another command may have been quicker
+    if write_lock.mailbox_index.is_none() {
+        write_lock.mailbox_index = Some(index);
+    }
+    Ok(())
+}
+
+/// When messages were last added to, removed from or renamed in the maildir of the mailbox.
+///
+/// Flags are part of the file names, so changing them counts as well. Returns `None` if the change
+/// is too recent to tell it apart from one happening right after it.
+async fn last_change(mailbox_path: &Path) -> Option<SystemTime> {
+    let mut changed = None;
+    for folder in ["new", "cur"] {
+        let metadata = tokio::fs::metadata(mailbox_path.join(folder)).await.ok()?;
+        changed = changed.max(Some(metadata.modified().ok()?));
+    }
+    let changed = changed?;
+    let age = SystemTime::now().duration_since(changed).ok()?;
+    (age >= SETTLE_TIME).then_some(changed)
+}
+
+/// The entries of the messages in the mailbox keyed by their maildir id.
+///
+/// This only lists the maildir and never parses the messages.
+#[instrument(skip(storage, mailbox_path))]
+pub async fn mail_entries(
+    storage: &Storage,
+    mailbox_path: &Path,
+) -> HashMap<String, MailEntryType> {
+    storage
+        .list_all(mailbox_path)
+        .await
+        .into_iter()
+        .map(|mail| (mail.id().to_string(), mail))
+        .collect()
+}
+
+/// Lists the mailbox and only parses the messages which are not known yet
+#[instrument(skip(storage, mailbox_path, known))]
+async fn current_messages(
+    storage: &Storage,
+    mailbox_path: &Path,
+    known: &HashSet<&str>,
+) -> color_eyre::eyre::Result<CurrentMessages> {
+    let mut flags = HashMap::new();
+    let mut new = Vec::new();
+    for mail in storage.list_all(mailbox_path).await {
+        // Without a uid the client could never refer to the message
+        if mail.uid() == 0 {
+            continue;
+        }
+        let mail_flags = flags_response(&mail)?;
+        if !known.contains(mail.id()) {
+            new.push(IndexedMail {
+                uid: mail.uid(),
+                maildir_id: mail.id().to_string(),
+                flags: mail_flags.clone(),
+                internal_date: mail.internal_date(),
+                headers: None,
+            });
+        }
+        flags.insert(mail.id().to_string(), mail_flags);
+    }
+    // Sequence numbers have to grow with the uids (RFC 9051 section 2.3.1.2)
+    new.sort_by_key(|mail| mail.uid);
+    Ok(CurrentMessages { flags, new })
+}
+
+/// Sends the changes other sessions made to the selected mailbox since the client last heard about it.
+///
+/// `allow_expunge` has to be false while responding to FETCH, STORE and SEARCH as the client
+/// would otherwise lose track of the sequence numbers it used in the command.
+#[instrument(skip(data, lines, storage))]
+pub async fn send_updates<S, E>(
+    data: &Data,
+    lines: &mut S,
+    storage: &Storage,
+    allow_expunge: bool,
+) -> color_eyre::eyre::Result<()>
+where
+    E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+    S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+{
+    let (folder, username, changed) = {
+        let read_lock = data.con_state.read().await;
+        let State::Selected(folder, _) = &read_lock.state else {
+            return Ok(());
+        };
+        let Some(index) = &read_lock.mailbox_index else {
+            return Ok(());
+        };
+        (
+            folder.clone(),
+            read_lock
+                .username
+                .clone()
+                .context("Username missing in internal State")?,
+            index.changed,
+        )
+    };
+    let mailbox_path = storage.to_ondisk_path(folder.replace('/', "."), username)?;
+    // Nothing needs to be read if the mailbox did not change since the last time
+    let last_change = last_change(&mailbox_path).await;
+    if last_change.is_some() && last_change == changed {
+        return Ok(());
+    }
+
+    // Reading the mailbox may take a while. The lock is only taken again to apply the changes.
+    let known: HashSet<String> = {
+        let read_lock = data.con_state.read().await;
+        let Some(index) = &read_lock.mailbox_index else {
+            return Ok(());
+        };
+        index
+            .messages
+            .iter()
+            .map(|mail| mail.maildir_id.clone())
+            .collect()
+    };
+    let known: HashSet<&str> = known.iter().map(String::as_str).collect();
+    let current = current_messages(storage, &mailbox_path, &known).await?;
+
+    let mut write_lock = data.con_state.write().await;
+    // The client may have selected another mailbox in the meantime
+    if !matches!(&write_lock.state, State::Selected(selected, _) if *selected == folder) {
+        return Ok(());
+    }
+    let Some(index) = write_lock.mailbox_index.take() else {
+        return Ok(());
+    };
+    let (mut index, responses) = diff(index, current, allow_expunge);
+    // Expunges held back for later still need another look at the mailbox
+    index.changed = if allow_expunge { last_change } else { None };
+    write_lock.mailbox_index = Some(index);
+    // Commands running alongside this one may need the lock while the responses wait to be sent
+    drop(write_lock);
+    debug!("[IMAP] Sending {} mailbox updates", responses.len());
+    for response in responses {
+        lines.feed(response).await?;
+    }
+    Ok(())
+}
+
+/// Compares what the client knows with the current state.
+///
+/// Returns the new state of the client and the untagged responses needed to get it there.
+fn diff(
+    known: MailboxIndex,
+    current: CurrentMessages,
+    allow_expunge: bool,
+) -> (MailboxIndex, Vec<String>) {
+    let mut messages = known.messages;
+    let mut responses = Vec::new();
+
+    if allow_expunge {
+        // Going from the back keeps the sequence numbers of the following responses valid
+        for index in (0..messages.len()).rev() {
+            if !current.flags.contains_key(&messages[index].maildir_id) {
+                messages.remove(index);
+                responses.push(format!("* {} EXPUNGE", index + 1));
+            }
+        }
+    }
+
+    // The index may have been filled while the mailbox was read
+    let known_ids: HashSet<String> = messages
+        .iter()
+        .map(|mail| mail.maildir_id.clone())
+        .collect();
+    let new: Vec<IndexedMail> = current
+        .new
+        .into_iter()
+        .filter(|mail| !known_ids.contains(&mail.maildir_id))
+        .collect();
+    if !new.is_empty() {
+        messages.extend(new);
+        responses.push(format!("* {} EXISTS", messages.len()));
+    }
+
+    for (index, mail) in messages.iter_mut().enumerate() {
+        if let Some(current_flags) = current.flags.get(&mail.maildir_id) {
+            if *current_flags != mail.flags {
+                mail.flags = current_flags.clone();
+                responses.push(format!("* {} FETCH ({})", index + 1, mail.flags));
+            }
+        }
+    }
+
+    (
+        MailboxIndex {
+            messages,
+            changed: None,
+        },
+        responses,
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn mail(uid: i64, flags: &str) -> IndexedMail {
+        IndexedMail {
+            uid,
+            maildir_id: uid.to_string(),
+            flags: flags.to_string(),
+            internal_date: Some(uid),
+            headers: None,
+        }
+    }
+
+    fn index(messages: &[(i64, &str)]) -> MailboxIndex {
+        MailboxIndex {
+            messages: messages
+                .iter()
+                .map(|(uid, flags)| mail(*uid, flags))
+                .collect(),
+            changed: None,
+        }
+    }
+
+    fn current(messages: &[(i64, &str)], new: &[(i64, &str)]) -> CurrentMessages {
+        CurrentMessages {
+            flags: messages
+                .iter()
+                .chain(new)
+                .map(|(uid, flags)| (uid.to_string(), (*flags).to_string()))
+                .collect(),
+            new: new.iter().map(|(uid, flags)| mail(*uid, flags)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let known = index(&[(1, "FLAGS ()"), (2, "FLAGS ()"), (3, "FLAGS ()")]);
+        let now = [(1, "FLAGS ()"), (3, "FLAGS (\\Seen)")];
+        let new = [(4, "FLAGS ()")];
+
+        let (updated, responses) = diff(known.clone(), current(&now, &new), true);
+        assert_eq!(
+            updated,
+            index(&[(1, "FLAGS ()"), (3, "FLAGS (\\Seen)"), (4, "FLAGS ()")])
+        );
+        assert_eq!(
+            responses,
+            vec![
+                String::from("* 2 EXPUNGE"),
+                String::from("* 3 EXISTS"),
+                String::from("* 2 FETCH (FLAGS (\\Seen))"),
+            ]
+        );
+
+        let (updated, responses) = diff(known, current(&now, &new), false);
+        assert_eq!(
+            updated,
+            index(&[
+                (1, "FLAGS ()"),
+                (2, "FLAGS ()"),
+                (3, "FLAGS (\\Seen)"),
+                (4, "FLAGS ()")
+            ])
+        );
+        assert_eq!(
+            responses,
+            vec![
+                String::from("* 4 EXISTS"),
+                String::from("* 3 FETCH (FLAGS (\\Seen))"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_last_change() {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let mailbox_path = std::env::temp_dir().join(format!("erooster-updates-{nanos}"));
+        assert_eq!(last_change(&mailbox_path).await, None);
+
+        tokio::fs::create_dir_all(mailbox_path.join("new"))
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(mailbox_path.join("cur"))
+            .await
+            .unwrap();
+        // A change that just happened could be followed by another one with the same time
+        assert_eq!(last_change(&mailbox_path).await, None);
+
+        tokio::time::sleep(SETTLE_TIME).await;
+        let changed = last_change(&mailbox_path).await;
+        assert!(changed.is_some());
+        assert_eq!(last_change(&mailbox_path).await, changed);
+
+        tokio::fs::write(mailbox_path.join("new").join("mail"), "Subject: Test\r\n")
+            .await
+            .unwrap();
+        assert_eq!(last_change(&mailbox_path).await, None);
+        tokio::fs::remove_dir_all(mailbox_path).await.unwrap();
+    }
+}
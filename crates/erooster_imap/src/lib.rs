@@ -52,7 +52,7 @@ pub(crate) mod servers;
 /// A const variant of the Capabilities we welcome clients with
 pub const CAPABILITY_HELLO: &str = formatcp!(
     "* OK [{}] IMAP4rev1/IMAP4rev2 Service Ready",
-    get_capabilities()
+    get_capabilities(false)
 );
 
 /// An implementation of a imap server
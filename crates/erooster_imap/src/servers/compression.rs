@@ -0,0 +1,57 @@
+use async_compression::tokio::{bufread::DeflateDecoder, write::DeflateEncoder};
+use erooster_core::{line_codec::LinesCodec, LINE_LIMIT};
+use std::io::Cursor;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// The reading side of a connection. Boxed so that it can be wrapped in a compression layer later on.
+pub type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// The writing side of a connection. Boxed so that it can be wrapped in a compression layer later on.
+pub type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// The lines sent by the client
+pub type LinesReader = FramedRead<BoxedReader, LinesCodec>;
+
+/// Accepts both response lines as `String` and raw literal data as `Bytes`
+pub type LinesWriter = FramedWrite<BoxedWriter, LinesCodec>;
+
+/// Splits a client connection into its framed writing and reading side
+pub fn framed<T>(stream: T) -> (LinesWriter, LinesReader)
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (read_half, write_half) = tokio::io::split(stream);
+    (
+        FramedWrite::new(
+            Box::new(write_half) as BoxedWriter,
+            LinesCodec::new_with_max_length(LINE_LIMIT),
+        ),
+        FramedRead::new(
+            Box::new(read_half) as BoxedReader,
+            LinesCodec::new_with_max_length(LINE_LIMIT),
+        ),
+    )
+}
+
+/// Puts a raw deflate (RFC 1951) layer underneath the line framing of a connection as required by RFC 4978.
+///
+/// Data which was already read but not yet decoded is treated as compressed.
+#[must_use]
+pub fn start_compression(writer: LinesWriter, reader: LinesReader) -> (LinesWriter, LinesReader) {
+    let writer_parts = writer.into_parts();
+    let reader_parts = reader.into_parts();
+    let compressed_reader =
+        BufReader::new(Cursor::new(reader_parts.read_buf.to_vec()).chain(reader_parts.io));
+    (
+        // Flushing does a sync flush so every response reaches the client right away
+        FramedWrite::new(
+            Box::new(DeflateEncoder::new(writer_parts.io)) as BoxedWriter,
+            writer_parts.codec,
+        ),
+        FramedRead::new(
+            Box::new(DeflateDecoder::new(compressed_reader)) as BoxedReader,
+            reader_parts.codec,
+        ),
+    )
+}
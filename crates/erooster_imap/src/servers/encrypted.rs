@@ -1,10 +1,11 @@
-use crate::{commands::Data, servers::state::Connection, Server, CAPABILITY_HELLO};
+use crate::{
+    servers::{compression::framed, session::serve, state::Connection},
+    Server, CAPABILITY_HELLO,
+};
 use async_trait::async_trait;
 use erooster_core::{
     backend::{database::DB, storage::Storage},
     config::Config,
-    line_codec::LinesCodec,
-    LINE_LIMIT,
 };
 use futures::{SinkExt, StreamExt};
 use std::{
@@ -20,8 +21,7 @@ use tokio_rustls::{
     TlsAcceptor,
 };
 use tokio_stream::wrappers::TcpListenerStream;
-use tokio_util::codec::Framed;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, field, info, info_span, instrument, Instrument};
 
 /// An encrypted imap Server
 pub struct Encrypted;
@@ -145,79 +145,52 @@ async fn listen(
 
         // Start talking with new peer on new thread
         let acceptor = acceptor.clone();
-        tokio::spawn(async move {
-            // Accept TCP connection
-            let tls_stream = acceptor.accept(tcp_stream).await;
-
-            // Continue if it worked
-            match tls_stream {
-                Ok(stream) => {
-                    debug!("[IMAP] TLS negotiation done");
-
-                    // Proceed as normal
-                    let lines = Framed::new(stream, LinesCodec::new_with_max_length(LINE_LIMIT));
-                    // We split these as we handle the sink in a broadcast instead to be able to push non linear data over the socket
-                    let (mut lines_sender, mut lines_reader) = lines.split();
-
-                    // Greet the client with the capabilities we provide
-                    if let Err(e) = lines_sender.send(CAPABILITY_HELLO.to_string()).await {
-                        error!(
-                            "Unable to send greeting to client. Closing connection. Error: {}",
-                            e
-                        );
-                        return;
-                    }
-                    // Create our Connection
-                    let connection = Connection::new(true);
-
-                    // Read lines from the stream
-                    while let Some(Ok(line)) = lines_reader.next().await {
-                        let data = Data {
-                            con_state: Arc::clone(&connection),
-                        };
-                        debug!("[IMAP] [{}] Got Command: {}", peer, line);
-                        // TODO make sure to handle IDLE different as it needs us to stream lines
-
-                        {
-                            let close = data
-                                .parse(
-                                    &mut lines_sender,
-                                    Arc::clone(&config),
-                                    Arc::clone(&database),
-                                    Arc::clone(&storage),
-                                    line,
-                                )
-                                .await;
-                            match close {
-                                Ok(close) => {
-                                    // Cleanup timeout managers
-                                    if close {
-                                        // Used for later session timer management
-                                        debug!("[IMAP] Closing TLS connection");
-                                        break;
-                                    }
-                                }
-                                // We try a last time to do a graceful shutdown before closing
-                                Err(e) => {
-                                    if let Err(e) = lines_sender
-                                        .send(format!(
-                                            "* BAD [SERVERBUG] This should not happen: {}",
-                                            e
-                                        ))
-                                        .await
-                                    {
-                                        error!("Unable to send error response: {}", e);
-                                    }
-                                    error!("[IMAP] Failure happened: {}", e);
-                                    debug!("[IMAP] Closing TLS connection");
-                                    break;
-                                }
-                            }
-                        };
+        // Client details sent using ID get recorded in here
+        let session_span = info_span!(
+            "imap_session",
+            %peer,
+            client_name = field::Empty,
+            client_version = field::Empty
+        );
+        tokio::spawn(
+            async move {
+                // Accept TCP connection
+                let tls_stream = acceptor.accept(tcp_stream).await;
+
+                // Continue if it worked
+                match tls_stream {
+                    Ok(stream) => {
+                        debug!("[IMAP] TLS negotiation done");
+
+                        // Proceed as normal
+                        // We split these as we handle the sink in a broadcast instead to be able to push non linear data over the socket
+                        let (mut lines_sender, lines_reader) = framed(stream);
+
+                        // Greet the client with the capabilities we provide
+                        if let Err(e) = lines_sender.send(CAPABILITY_HELLO.to_string()).await {
+                            error!(
+                                "Unable to send greeting to client. Closing connection. Error: {}",
+                                e
+                            );
+                            return;
+                        }
+                        // Create our Connection
+                        let connection = Connection::new(true);
+
+                        serve(
+                            lines_sender,
+                            lines_reader,
+                            connection,
+                            config,
+                            database,
+                            storage,
+                        )
+                        .await;
                     }
+                    Err(e) => error!("[IMAP] Got error while accepting TLS: {}", e),
                 }
-                Err(e) => error!("[IMAP] Got error while accepting TLS: {}", e),
             }
-        });
+            .instrument(session_span),
+        );
     }
 }
@@ -1,3 +1,5 @@
+pub mod compression;
 pub mod encrypted;
+pub mod session;
 pub mod state;
 pub mod unencrypted;
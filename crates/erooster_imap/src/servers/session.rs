@@ -0,0 +1,417 @@
+use crate::{
+    commands::{
+        notify::{next_change, send_notification, update_change_subscription},
+        Data,
+    },
+    servers::{
+        compression::{start_compression, LinesReader, LinesWriter},
+        state::Connection,
+    },
+};
+use bytes::Bytes;
+use erooster_core::{
+    backend::{database::DB, storage::Storage},
+    config::Config,
+};
+use futures::{
+    channel::mpsc::{self, SendError},
+    Sink, SinkExt, StreamExt,
+};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::{debug, error, instrument, Instrument};
+
+/// How many responses a command may queue up before it has to wait for them to be sent
+const RESPONSE_BUFFER: usize = 64;
+
+/// How many commands of a single connection may run at the same time.
+///
+/// No further lines are read while this many are running, so a client pipelining lots of
+/// commands can't make us start an unbounded number of tasks.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// Something a command sends to the client
+#[derive(Debug)]
+pub enum Response {
+    Line(String),
+    Raw(Bytes),
+}
+
+/// Collects the responses of a single command.
+///
+/// They get sent once all commands the client sent before are done.
+#[derive(Debug)]
+pub struct ResponseSender(mpsc::Sender<Response>);
+
+impl Sink<String> for ResponseSender {
+    type Error = SendError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Sink::<Response>::poll_ready(Pin::new(&mut self.0), cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: String) -> Result<(), SendError> {
+        Sink::<Response>::start_send(Pin::new(&mut self.0), Response::Line(item))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Sink::<Response>::poll_flush(Pin::new(&mut self.0), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Sink::<Response>::poll_close(Pin::new(&mut self.0), cx)
+    }
+}
+
+impl Sink<Bytes> for ResponseSender {
+    type Error = SendError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Sink::<Response>::poll_ready(Pin::new(&mut self.0), cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), SendError> {
+        Sink::<Response>::start_send(Pin::new(&mut self.0), Response::Raw(item))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Sink::<Response>::poll_flush(Pin::new(&mut self.0), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Sink::<Response>::poll_close(Pin::new(&mut self.0), cx)
+    }
+}
+
+/// A command which is still running or whose responses were not all sent yet
+struct InFlight {
+    responses: mpsc::Receiver<Response>,
+    /// Resolves to true if the connection has to be closed afterwards
+    task: JoinHandle<color_eyre::eyre::Result<bool>>,
+}
+
+/// Starts running the command in the background
+fn start(
+    connection: &Arc<RwLock<Connection>>,
+    config: &Arc<Config>,
+    database: &DB,
+    storage: &Arc<Storage>,
+    line: String,
+) -> InFlight {
+    let (sender, responses) = mpsc::channel(RESPONSE_BUFFER);
+    let data = Data {
+        con_state: Arc::clone(connection),
+    };
+    let config = Arc::clone(config);
+    let database = Arc::clone(database);
+    let storage = Arc::clone(storage);
+    let task = tokio::spawn(
+        async move {
+            let mut lines = ResponseSender(sender);
+            data.parse(&mut lines, config, database, storage, line)
+                .await
+        }
+        .in_current_span(),
+    );
+    InFlight { responses, task }
+}
+
+/// The next response of the oldest command. `None` once that command is done.
+async fn next_response(in_flight: &mut VecDeque<InFlight>) -> Option<Response> {
+    match in_flight.front_mut() {
+        Some(command) => command.responses.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Writes the response and everything else the command already queued up
+async fn write_responses(
+    lines_sender: &mut LinesWriter,
+    first: Response,
+    responses: &mut mpsc::Receiver<Response>,
+) -> color_eyre::eyre::Result<()> {
+    let mut response = first;
+    loop {
+        match response {
+            Response::Line(line) => lines_sender.feed(line).await?,
+            Response::Raw(raw) => lines_sender.feed(raw).await?,
+        }
+        match responses.try_next() {
+            Ok(Some(next)) => response = next,
+            // Either the command is done or it is still working on the next response
+            Ok(None) | Err(_) => break,
+        }
+    }
+    SinkExt::<String>::flush(lines_sender).await?;
+    Ok(())
+}
+
+/// Reads the commands of a client and sends back the responses until the connection gets closed.
+///
+/// Commands which can't interfere with each other run at the same time. The responses are
+/// still sent in the order the commands arrived in, so the responses of one command never
+/// get mixed with the ones of another.
+#[allow(clippy::too_many_lines)]
+#[instrument(skip(lines_sender, lines_reader, connection, config, database, storage))]
+pub async fn serve(
+    mut lines_sender: LinesWriter,
+    mut lines_reader: LinesReader,
+    connection: Arc<RwLock<Connection>>,
+    config: Arc<Config>,
+    database: DB,
+    storage: Arc<Storage>,
+) {
+    // Changes of other sessions the client asked to be told about using NOTIFY
+    let mut changes = None;
+    // Whether the stream is already wrapped in the deflate layer
+    let mut compressed = false;
+    // Commands in the order the client sent them
+    let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+    // A command which has to wait until the commands before it are done
+    let mut waiting: Option<String> = None;
+    // Whether the running command has to be done before the next one may start
+    let mut exclusive = false;
+
+    loop {
+        if in_flight.is_empty() {
+            exclusive = false;
+            if !compressed && connection.read().await.compressed {
+                (lines_sender, lines_reader) = start_compression(lines_sender, lines_reader);
+                compressed = true;
+            }
+            if let Some(line) = waiting.take() {
+                exclusive = true;
+                in_flight.push_back(start(&connection, &config, &database, &storage, line));
+                continue;
+            }
+        }
+
+        // Past the cap lines stay unread until one of the running commands is done
+        let reads_lines = waiting.is_none() && !exclusive && in_flight.len() < MAX_IN_FLIGHT;
+        tokio::select! {
+            response = next_response(&mut in_flight), if !in_flight.is_empty() => {
+                if let Some(response) = response {
+                    let Some(command) = in_flight.front_mut() else {
+                        continue;
+                    };
+                    if let Err(e) =
+                        write_responses(&mut lines_sender, response, &mut command.responses).await
+                    {
+                        error!("[IMAP] Unable to send response: {}", e);
+                        break;
+                    }
+                    continue;
+                }
+                let Some(command) = in_flight.pop_front() else {
+                    continue;
+                };
+                let result = match command.task.await {
+                    Ok(result) => result,
+                    Err(e) => Err(e.into()),
+                };
+                match result {
+                    Ok(close) => {
+                        // Cleanup timeout managers
+                        if close {
+                            // Used for later session timer management
+                            debug!("[IMAP] Closing connection");
+                            break;
+                        }
+                    }
+                    // We try a last time to do a graceful shutdown before closing
+                    Err(e) => {
+                        if let Err(e) = lines_sender
+                            .send(format!("* BAD [SERVERBUG] This should not happen: {e}"))
+                            .await
+                        {
+                            error!("Unable to send error response: {}", e);
+                        }
+                        error!("[IMAP] Failure happened: {}", e);
+                        debug!("[IMAP] Closing connection");
+                        break;
+                    }
+                }
+                let data = Data {
+                    con_state: Arc::clone(&connection),
+                };
+                update_change_subscription(&data, &storage, &mut changes).await;
+            }
+            line = lines_reader.next(), if reads_lines => {
+                let Some(Ok(line)) = line else {
+                    break;
+                };
+                debug!("[IMAP] Got Command: {}", line);
+                let data = Data {
+                    con_state: Arc::clone(&connection),
+                };
+                if data.runs_concurrently(&line).await {
+                    in_flight.push_back(start(&connection, &config, &database, &storage, line));
+                } else if in_flight.is_empty() {
+                    exclusive = true;
+                    in_flight.push_back(start(&connection, &config, &database, &storage, line));
+                } else {
+                    waiting = Some(line);
+                }
+            }
+            change = next_change(&mut changes), if in_flight.is_empty() => {
+                let data = Data {
+                    con_state: Arc::clone(&connection),
+                };
+                if let Err(e) = send_notification(&data, &mut lines_sender, &storage, change).await {
+                    error!("[IMAP] Unable to send notification: {}", e);
+                }
+                update_change_subscription(&data, &storage, &mut changes).await;
+            }
+        }
+    }
+
+    // Nobody is going to read the responses anymore
+    for command in in_flight {
+        command.task.abort();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::servers::{compression::framed, state::State};
+    use erooster_core::backend::storage::MailStorage;
+
+    /// Reads response lines until the tagged response of the given tag
+    async fn read_until(lines_reader: &mut LinesReader, tag: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(Ok(line)) = lines_reader.next().await {
+            let done = line.starts_with(&format!("{tag} "));
+            lines.push(line);
+            if done {
+                break;
+            }
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_expunge_not_sent_during_fetch() {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(
+            erooster_core::backend::database::get_database(Arc::clone(&config))
+                .await
+                .unwrap(),
+        );
+        let storage = Arc::new(erooster_core::backend::storage::get_storage(
+            Arc::clone(&database),
+            Arc::clone(&config),
+        ));
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let username = format!("session-{nanos}@localhost");
+        let inbox = storage
+            .to_ondisk_path(String::from("INBOX"), username.clone())
+            .unwrap();
+        storage.create_dirs(&inbox).unwrap();
+        let first = storage
+            .store_new(&inbox, b"Subject: First\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+        storage
+            .store_new(&inbox, b"Subject: Second\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+
+        let connection = Connection::new(true);
+        connection.write().await.state = State::Authenticated;
+        connection.write().await.username = Some(username);
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let (lines_sender, lines_reader) = framed(server);
+        tokio::spawn(serve(
+            lines_sender,
+            lines_reader,
+            connection,
+            config,
+            database,
+            Arc::clone(&storage),
+        ));
+        let (mut commands, mut responses) = framed(client);
+
+        commands.send(String::from("s SELECT INBOX")).await.unwrap();
+        read_until(&mut responses, "s").await;
+
+        // Another session removes the first message
+        storage.expunge(&inbox, &first).await.unwrap();
+
+        // STATUS runs alongside the FETCH and must not report the EXPUNGE
+        for command in ["a FETCH 1:* (FLAGS)", "b STATUS INBOX (MESSAGES)", "c NOOP"] {
+            commands.feed(String::from(command)).await.unwrap();
+        }
+        SinkExt::<String>::flush(&mut commands).await.unwrap();
+        let lines = read_until(&mut responses, "c").await;
+        let position = |prefix: &str| {
+            lines
+                .iter()
+                .position(|line| line.starts_with(prefix))
+                .unwrap()
+        };
+        assert!(position("a OK") < position("b OK"));
+        // The sequence numbers the FETCH used stay valid until the NOOP reports the EXPUNGE
+        assert!(lines.iter().any(|line| line.starts_with("* 2 FETCH")));
+        assert!(position("* 1 EXPUNGE") > position("b OK"));
+        assert!(position("* 1 EXPUNGE") < position("c OK"));
+        assert_eq!(
+            lines.iter().filter(|line| line.contains("EXPUNGE")).count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands_beyond_the_cap() {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(
+            erooster_core::backend::database::get_database(Arc::clone(&config))
+                .await
+                .unwrap(),
+        );
+        let storage = Arc::new(erooster_core::backend::storage::get_storage(
+            Arc::clone(&database),
+            Arc::clone(&config),
+        ));
+        let connection = Connection::new(true);
+        connection.write().await.state = State::Authenticated;
+        connection.write().await.username = Some(String::from("session-cap@localhost"));
+        let (server, client) = tokio::io::duplex(64 * 1024);
+        let (lines_sender, lines_reader) = framed(server);
+        tokio::spawn(serve(
+            lines_sender,
+            lines_reader,
+            connection,
+            config,
+            database,
+            storage,
+        ));
+        let (mut commands, mut responses) = framed(client);
+
+        // Commands past the cap wait in the socket until earlier ones are done
+        let count = MAX_IN_FLIGHT * 3;
+        for i in 0..count {
+            commands.feed(format!("c{i} CAPABILITY")).await.unwrap();
+        }
+        SinkExt::<String>::flush(&mut commands).await.unwrap();
+        let lines = read_until(&mut responses, &format!("c{}", count - 1)).await;
+        let tagged: Vec<&String> = lines.iter().filter(|line| !line.starts_with('*')).collect();
+        assert_eq!(tagged.len(), count);
+        for (i, line) in tagged.iter().enumerate() {
+            assert!(line.starts_with(&format!("c{i} OK")));
+        }
+    }
+}
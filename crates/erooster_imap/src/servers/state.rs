@@ -1,6 +1,10 @@
-use crate::commands::{auth::AuthenticationMethod, parsers::DateTime};
-use std::sync::Arc;
+use crate::commands::{
+    auth::AuthenticationMethod,
+    parsers::{in_ranges, DateTime, NotifyEventGroup, Range},
+};
+use std::{collections::HashSet, sync::Arc, time::SystemTime};
 use tokio::sync::RwLock;
+use tracing::Span;
 
 /// State of the connection session between us and the Client
 #[derive(Debug)]
@@ -9,6 +13,16 @@ pub struct Connection {
     pub secure: bool,
     pub username: Option<String>,
     pub active_capabilities: Vec<Capabilities>,
+    /// UIDs of the last search result saved using `RETURN (SAVE)`
+    pub saved_search: HashSet<i64>,
+    /// What the client knows about the selected mailbox
+    pub mailbox_index: Option<MailboxIndex>,
+    /// The events the client asked to be notified about using NOTIFY. Empty if it is disabled.
+    pub notify: Vec<NotifyEventGroup>,
+    /// Whether the client enabled COMPRESS=DEFLATE
+    pub compressed: bool,
+    /// The span of the whole session. Client details sent using ID are recorded in it.
+    pub session_span: Span,
 }
 
 impl Connection {
@@ -18,8 +32,72 @@ impl Connection {
             secure,
             username: None,
             active_capabilities: vec![],
+            saved_search: HashSet::new(),
+            mailbox_index: None,
+            notify: vec![],
+            compressed: false,
+            session_span: Span::current(),
         }))
     }
+
+    /// Leaves the selected mailbox and forgets everything that belonged to it
+    pub fn deselect(&mut self) {
+        self.state = State::Authenticated;
+        self.mailbox_index = None;
+        // The saved search only refers to the mailbox it was made in (RFC 5182 section 2.1)
+        self.saved_search.clear();
+    }
+}
+
+/// The messages of the selected mailbox as last reported to the client.
+///
+/// Used to tell the client about changes made by other sessions. It is kept in sync incrementally
+/// so that commands don't need to list and parse every message of the mailbox again.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxIndex {
+    /// The messages in sequence order
+    pub messages: Vec<IndexedMail>,
+    /// When the mailbox last changed on disk before it was read into the index.
+    /// `None` if it has to be read again on the next check.
+    pub changed: Option<SystemTime>,
+}
+
+impl MailboxIndex {
+    /// The messages matching any of the ranges together with their sequence number
+    pub fn selection<'a>(
+        &'a self,
+        ranges: &'a [Range],
+        is_uid: bool,
+        saved_search: &'a HashSet<i64>,
+    ) -> impl Iterator<Item = (i64, &'a IndexedMail)> + 'a {
+        let max = if is_uid {
+            self.messages.iter().map(|mail| mail.uid).max().unwrap_or(0)
+        } else {
+            self.messages.len() as i64
+        };
+        self.messages
+            .iter()
+            .enumerate()
+            .map(|(position, mail)| (position as i64 + 1, mail))
+            .filter(move |(sequence, mail)| {
+                let id = if is_uid { mail.uid } else { *sequence };
+                in_ranges(ranges, id, max, saved_search.contains(&mail.uid))
+            })
+    }
+}
+
+/// What the session knows about a single message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedMail {
+    pub uid: i64,
+    /// The id of the message inside of the maildir
+    pub maildir_id: String,
+    /// FLAGS response of the message
+    pub flags: String,
+    /// The time the message was delivered
+    pub internal_date: Option<i64>,
+    /// Headers of the message. Loaded the first time a client asks for them.
+    pub headers: Option<Vec<(String, String)>>,
 }
 
 #[derive(Debug, Clone)]
@@ -56,6 +134,8 @@ pub struct AppendingState {
     pub data: Option<Vec<u8>>,
     pub datalen: usize,
     pub tag: String,
+    /// The message is larger than the APPENDLIMIT. Its data gets read but not stored.
+    pub too_big: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -1,17 +1,17 @@
-use crate::{commands::Data, servers::state::Connection, Server, CAPABILITY_HELLO};
+use crate::{
+    servers::{compression::framed, session::serve, state::Connection},
+    Server, CAPABILITY_HELLO,
+};
 use async_trait::async_trait;
 use erooster_core::{
     backend::{database::DB, storage::Storage},
     config::Config,
-    line_codec::LinesCodec,
-    LINE_LIMIT,
 };
 use futures::{SinkExt, StreamExt};
 use std::{net::SocketAddr, sync::Arc};
 use tokio::net::TcpListener;
 use tokio_stream::wrappers::TcpListenerStream;
-use tokio_util::codec::Framed;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, field, info, info_span, instrument, Instrument};
 
 /// An unencrypted imap Server
 pub struct Unencrypted;
@@ -70,60 +70,28 @@ async fn listen(
         let config = Arc::clone(&config);
         let database = Arc::clone(&database);
         let storage = Arc::clone(&storage);
-        tokio::spawn(async move {
-            let lines = Framed::new(tcp_stream, LinesCodec::new_with_max_length(LINE_LIMIT));
-            let (mut lines_sender, mut lines_reader) = lines.split();
-            if let Err(e) = lines_sender.send(CAPABILITY_HELLO.to_string()).await {
-                error!(
-                    "Unable to send greeting to client. Closing connection. Error: {}",
-                    e
-                );
-                return;
-            }
-            let state = Connection::new(false);
-
-            while let Some(Ok(line)) = lines_reader.next().await {
-                let data = Data {
-                    con_state: Arc::clone(&state),
-                };
-
-                debug!("[IMAP] [{}] Got Command: {}", peer, line);
-
-                // TODO make sure to handle IDLE different as it needs us to stream lines
-                // TODO pass lines and make it possible to not need new lines in responds but instead directly use `lines.send`
-                let response = data
-                    .parse(
-                        &mut lines_sender,
-                        Arc::clone(&config),
-                        Arc::clone(&database),
-                        Arc::clone(&storage),
-                        line,
-                    )
-                    .await;
-
-                match response {
-                    Ok(response) => {
-                        // Cleanup timeout managers
-                        if response {
-                            // Used for later session timer management
-                            debug!("[IMAP] Closing connection");
-                            break;
-                        }
-                    }
-                    // We try a last time to do a graceful shutdown before closing
-                    Err(e) => {
-                        if let Err(e) = lines_sender
-                            .send(format!("* BAD [SERVERBUG] This should not happen: {e}"))
-                            .await
-                        {
-                            error!("Unable to send error response: {}", e);
-                        }
-                        error!("[IMAP] Failure happened: {}", e);
-                        debug!("[IMAP] Closing connection");
-                        break;
-                    }
+        // Client details sent using ID get recorded in here
+        let session_span = info_span!(
+            "imap_session",
+            %peer,
+            client_name = field::Empty,
+            client_version = field::Empty
+        );
+        tokio::spawn(
+            async move {
+                let (mut lines_sender, lines_reader) = framed(tcp_stream);
+                if let Err(e) = lines_sender.send(CAPABILITY_HELLO.to_string()).await {
+                    error!(
+                        "Unable to send greeting to client. Closing connection. Error: {}",
+                        e
+                    );
+                    return;
                 }
+                let state = Connection::new(false);
+
+                serve(lines_sender, lines_reader, state, config, database, storage).await;
             }
-        });
+            .instrument(session_span),
+        );
     }
 }
@@ -2,6 +2,7 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use erooster_core::backend::database::{get_database, Database, DB};
 use erooster_core::backend::storage::get_storage;
 use erooster_core::{config::Config, line_codec::LinesCodec};
+use erooster_smtp::servers::limits::ConnectionLimits;
 use futures::{SinkExt, StreamExt};
 use secrecy::SecretString;
 use sqlx::migrate::MigrateDatabase;
@@ -30,6 +31,18 @@ async fn receive_message() {
     let resp = reader.next().await.unwrap().unwrap();
     assert_eq!(resp, String::from("250-STARTTLS"));
     let resp = reader.next().await.unwrap().unwrap();
+    assert_eq!(resp, String::from("250-SIZE 26214400"));
+    let resp = reader.next().await.unwrap().unwrap();
+    assert_eq!(resp, String::from("250-PIPELINING"));
+    let resp = reader.next().await.unwrap().unwrap();
+    assert_eq!(resp, String::from("250-8BITMIME"));
+    let resp = reader.next().await.unwrap().unwrap();
+    assert_eq!(resp, String::from("250-BINARYMIME"));
+    let resp = reader.next().await.unwrap().unwrap();
+    assert_eq!(resp, String::from("250-CHUNKING"));
+    let resp = reader.next().await.unwrap().unwrap();
+    assert_eq!(resp, String::from("250-DSN"));
+    let resp = reader.next().await.unwrap().unwrap();
     assert_eq!(resp, String::from("250 SMTPUTF8"));
 
     // Login to SMTP server (not needed here)
@@ -129,9 +142,13 @@ pub fn criterion_benchmark(c: &mut Criterion) {
                 let storage = Arc::new(get_storage(Arc::clone(&database), Arc::clone(&config)));
 
                 info!("Starting SMTP Server");
-                if let Err(e) =
-                    erooster_smtp::servers::unencrypted::Unencrypted::run(config, database, storage)
-                        .await
+                if let Err(e) = erooster_smtp::servers::unencrypted::Unencrypted::run(
+                    Arc::clone(&config),
+                    database,
+                    storage,
+                    ConnectionLimits::new(&config.smtp),
+                )
+                .await
                 {
                     panic!("Unable to start server: {:?}", e);
                 }
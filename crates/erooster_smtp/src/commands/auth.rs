@@ -4,7 +4,10 @@ use crate::{
     commands::{CommandData, Data},
     servers::state::{AuthState, State},
 };
-use erooster_core::backend::database::{Database, DB};
+use erooster_core::{
+    backend::database::{Database, DB},
+    config::ListenerRole,
+};
 use futures::{Sink, SinkExt};
 use secrecy::{ExposeSecret, SecretString, SecretVec};
 use simdutf8::compat::from_utf8;
@@ -25,8 +28,18 @@ impl Auth<'_> {
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
         S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
     {
-        //let secure = self.data.con_state.read().await.secure;
-        let secure = true;
+        let (role, secure) = {
+            let read_lock = self.data.con_state.read().await;
+            (read_lock.role, read_lock.secure)
+        };
+        if role == ListenerRole::Mx {
+            lines
+                .send(String::from(
+                    "502 5.5.1 Authentication is not available on this port",
+                ))
+                .await?;
+            return Ok(());
+        }
         if secure {
             if command_data.arguments.is_empty() {
                 lines
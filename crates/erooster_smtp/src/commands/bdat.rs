@@ -0,0 +1,403 @@
+use crate::{
+    commands::{data::DataCommand, CommandData, Data},
+    servers::state::{Chunks, Connection, State},
+};
+use bytes::Bytes;
+use erooster_core::{
+    backend::{database::DB, storage::Storage},
+    config::Config,
+};
+use futures::{Sink, SinkExt};
+use std::sync::atomic::Ordering;
+use tracing::{debug, instrument};
+
+pub struct Bdat<'a> {
+    pub data: &'a Data,
+}
+
+impl Bdat<'_> {
+    #[instrument(skip(self, lines, config, database, storage, command_data))]
+    pub async fn exec<S, E>(
+        &self,
+        lines: &mut S,
+        config: &Config,
+        database: &DB,
+        storage: &Storage,
+        command_data: &CommandData<'_>,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let Some((size, last)) = chunk_arguments(command_data.arguments) else {
+            lines
+                .send(String::from("501 5.5.4 Syntax: BDAT <size> [LAST]"))
+                .await?;
+            return Ok(());
+        };
+
+        let mut write_lock = self.data.con_state.write().await;
+        let connection: &mut Connection = &mut write_lock;
+        // The chunk follows the command no matter what we reply to it
+        connection
+            .read_mode
+            .raw_bytes
+            .store(size, Ordering::Release);
+        let too_big = |data: &[u8]| {
+            data.len()
+                .checked_add(size)
+                .map_or(true, |total| total > config.mail.max_message_size)
+        };
+        match &mut connection.state {
+            State::ReceivingChunks(chunks) => {
+                chunks.too_big |= too_big(&chunks.data);
+                chunks.size = size;
+                chunks.remaining = size;
+                chunks.last = last;
+            }
+            State::NotAuthenticated | State::Authenticated(_)
+                if connection.sender.is_some() && connection.receipts.is_some() =>
+            {
+                let username = match &connection.state {
+                    State::Authenticated(username) => Some(username.clone()),
+                    _ => None,
+                };
+                connection.state = State::ReceivingChunks(Chunks {
+                    username,
+                    data: Vec::new(),
+                    size,
+                    remaining: size,
+                    last,
+                    too_big: too_big(&[]),
+                });
+            }
+            _ => {
+                lines
+                    .send(String::from("503 5.5.1 Bad sequence of commands"))
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        if size == 0 {
+            self.finish_chunk(connection, config, lines, database, storage)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Adds the next part of the current chunk to the message
+    #[instrument(skip(self, lines, config, database, storage, chunk))]
+    pub async fn receive<S, E>(
+        &self,
+        lines: &mut S,
+        config: &Config,
+        database: &DB,
+        storage: &Storage,
+        chunk: Bytes,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let mut write_lock = self.data.con_state.write().await;
+        let connection: &mut Connection = &mut write_lock;
+        let State::ReceivingChunks(chunks) = &mut connection.state else {
+            debug!("Dropping chunk of a rejected BDAT command");
+            return Ok(());
+        };
+        chunks.remaining = chunks.remaining.saturating_sub(chunk.len());
+        if !chunks.too_big {
+            chunks.data.extend_from_slice(&chunk);
+        }
+        if chunks.remaining == 0 {
+            self.finish_chunk(connection, config, lines, database, storage)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Replies to the chunk once all of it arrived and delivers the message after the last one
+    async fn finish_chunk<S, E>(
+        &self,
+        connection: &mut Connection,
+        config: &Config,
+        lines: &mut S,
+        database: &DB,
+        storage: &Storage,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        let State::ReceivingChunks(chunks) = &mut connection.state else {
+            return Ok(());
+        };
+        if chunks.too_big {
+            // The transaction is over and the client has to start again
+            connection.state = match chunks.username.take() {
+                Some(username) => State::Authenticated(username),
+                None => State::NotAuthenticated,
+            };
+            connection.reset_transaction();
+            lines
+                .send(String::from(
+                    "552 5.3.4 Message size exceeds fixed maximum message size",
+                ))
+                .await?;
+        } else if chunks.last {
+            let username = chunks.username.take();
+            let data = std::mem::take(&mut chunks.data);
+            connection.state = State::ReceivingData((username, data));
+            DataCommand { data: self.data }
+                .deliver(connection, config, lines, database, storage)
+                .await?;
+        } else {
+            lines
+                .send(format!("250 2.0.0 {} octets received", chunks.size))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// The size of the chunk and whether it is the last one
+fn chunk_arguments(arguments: &[&str]) -> Option<(usize, bool)> {
+    match arguments {
+        [size] => Some((size.parse().ok()?, false)),
+        [size, last] if last.eq_ignore_ascii_case("LAST") => Some((size.parse().ok()?, true)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::commands::{rset::Rset, Commands};
+    use erooster_core::{
+        backend::{database::get_database, storage::get_storage},
+        config::ListenerRole,
+    };
+    use futures::{channel::mpsc, StreamExt};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_chunk_arguments() {
+        assert_eq!(chunk_arguments(&["1000"]), Some((1000, false)));
+        assert_eq!(chunk_arguments(&["0", "LAST"]), Some((0, true)));
+        assert_eq!(chunk_arguments(&["42", "last"]), Some((42, true)));
+        assert_eq!(chunk_arguments(&[]), None);
+        assert_eq!(chunk_arguments(&["-1"]), None);
+        assert_eq!(chunk_arguments(&["12", "FIRST"]), None);
+    }
+
+    async fn setup() -> (Arc<Config>, DB, Arc<Storage>) {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(get_database(Arc::clone(&config)).await.unwrap());
+        let storage = Arc::new(get_storage(Arc::clone(&database), Arc::clone(&config)));
+        (config, database, storage)
+    }
+
+    fn transaction() -> Data {
+        let con_state = Connection::new(true, String::from("127.0.0.1"), ListenerRole::Mx);
+        {
+            let mut write_lock = con_state.try_write().unwrap();
+            write_lock.sender = Some(String::from("sender@example.com"));
+            write_lock.receipts = Some(vec![String::from("rcpt@localhost")]);
+        };
+        Data { con_state }
+    }
+
+    fn bdat<'a>(arguments: &'a [&'a str]) -> CommandData<'a> {
+        CommandData {
+            command: Commands::BDAT,
+            arguments,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bdat_without_transaction() {
+        let (config, database, storage) = setup().await;
+        let data = Data {
+            con_state: Connection::new(true, String::from("127.0.0.1"), ListenerRole::Mx),
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        Bdat { data: &data }
+            .exec(&mut tx, &config, &database, &storage, &bdat(&["5"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("503 5.5.1 Bad sequence of commands"))
+        );
+        // The chunk still has to be skipped
+        let read_lock = data.con_state.read().await;
+        assert_eq!(read_lock.read_mode.raw_bytes.load(Ordering::Acquire), 5);
+        assert!(matches!(read_lock.state, State::NotAuthenticated));
+    }
+
+    #[tokio::test]
+    async fn test_chunks() {
+        let (config, database, storage) = setup().await;
+        let data = transaction();
+        let (mut tx, mut rx) = mpsc::unbounded();
+        Bdat { data: &data }
+            .exec(&mut tx, &config, &database, &storage, &bdat(&["10"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            data.con_state
+                .read()
+                .await
+                .read_mode
+                .raw_bytes
+                .load(Ordering::Acquire),
+            10
+        );
+
+        // The reply only comes once the whole chunk arrived
+        Bdat { data: &data }
+            .receive(&mut tx, &config, &database, &storage, Bytes::from("hello"))
+            .await
+            .unwrap();
+        assert!(rx.try_next().is_err());
+        Bdat { data: &data }
+            .receive(&mut tx, &config, &database, &storage, Bytes::from("world"))
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("250 2.0.0 10 octets received"))
+        );
+
+        Bdat { data: &data }
+            .exec(&mut tx, &config, &database, &storage, &bdat(&["1"]))
+            .await
+            .unwrap();
+        Bdat { data: &data }
+            .receive(&mut tx, &config, &database, &storage, Bytes::from("!"))
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("250 2.0.0 1 octets received"))
+        );
+        let read_lock = data.con_state.read().await;
+        let State::ReceivingChunks(chunks) = &read_lock.state else {
+            panic!("Expected to be receiving chunks");
+        };
+        assert_eq!(chunks.data, b"helloworld!");
+        assert!(!chunks.last);
+        assert!(!chunks.too_big);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_too_big() {
+        let (config, database, storage) = setup().await;
+        let data = transaction();
+        let (mut tx, mut rx) = mpsc::unbounded();
+        let size = (config.mail.max_message_size + 1).to_string();
+        Bdat { data: &data }
+            .exec(
+                &mut tx,
+                &config,
+                &database,
+                &storage,
+                &bdat(&[size.as_str(), "LAST"]),
+            )
+            .await
+            .unwrap();
+        Bdat { data: &data }
+            .receive(
+                &mut tx,
+                &config,
+                &database,
+                &storage,
+                Bytes::from(vec![b'a'; config.mail.max_message_size + 1]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from(
+                "552 5.3.4 Message size exceeds fixed maximum message size"
+            ))
+        );
+        let read_lock = data.con_state.read().await;
+        assert!(matches!(read_lock.state, State::NotAuthenticated));
+        assert!(read_lock.receipts.is_none());
+        assert!(read_lock.sender.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chunk_size_overflow() {
+        let (config, database, storage) = setup().await;
+        let data = transaction();
+        let (mut tx, _rx) = mpsc::unbounded();
+        Bdat { data: &data }
+            .exec(&mut tx, &config, &database, &storage, &bdat(&["1"]))
+            .await
+            .unwrap();
+        Bdat { data: &data }
+            .receive(&mut tx, &config, &database, &storage, Bytes::from("a"))
+            .await
+            .unwrap();
+        let size = usize::MAX.to_string();
+        Bdat { data: &data }
+            .exec(
+                &mut tx,
+                &config,
+                &database,
+                &storage,
+                &bdat(&[size.as_str()]),
+            )
+            .await
+            .unwrap();
+        let read_lock = data.con_state.read().await;
+        let State::ReceivingChunks(chunks) = &read_lock.state else {
+            panic!("Expected to be receiving chunks");
+        };
+        assert!(chunks.too_big);
+    }
+
+    #[tokio::test]
+    async fn test_rset_while_chunking() {
+        let (config, database, storage) = setup().await;
+        let data = transaction();
+        let (mut tx, mut rx) = mpsc::unbounded();
+        Bdat { data: &data }
+            .exec(&mut tx, &config, &database, &storage, &bdat(&["5"]))
+            .await
+            .unwrap();
+        Bdat { data: &data }
+            .receive(&mut tx, &config, &database, &storage, Bytes::from("hello"))
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("250 2.0.0 5 octets received"))
+        );
+
+        Rset { data: &data }.exec(&mut tx).await.unwrap();
+        assert_eq!(rx.next().await, Some(String::from("250 OK")));
+        {
+            let read_lock = data.con_state.read().await;
+            assert!(matches!(read_lock.state, State::NotAuthenticated));
+            assert!(read_lock.sender.is_none());
+            assert!(read_lock.receipts.is_none());
+        };
+
+        // A new transaction is needed before more chunks are accepted
+        Bdat { data: &data }
+            .exec(&mut tx, &config, &database, &storage, &bdat(&["1", "LAST"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("503 5.5.1 Bad sequence of commands"))
+        );
+    }
+}
@@ -1,25 +1,27 @@
 use crate::{
     commands::Data,
     servers::{
-        sending::{send_email_job, EmailPayload},
-        state::State,
+        sending::{dkim_sign, send_email_job, EmailPayload},
+        state::{Connection, State},
+    },
+    utils::{
+        delivery::{delivery_headers, is_local, save_sent_copy, store_in_inbox},
+        rspamd::Response,
+        sender::{owns_header_from, rewrite_header_from},
     },
-    utils::rspamd::Response,
 };
 use color_eyre::eyre::ContextCompat;
 use erooster_core::{
     backend::{
         database::{Database, DB},
-        storage::{MailStorage, Storage},
+        storage::Storage,
     },
-    config::{Config, Rspamd},
+    config::{Config, Rspamd, SenderPolicy},
 };
 use futures::{Sink, SinkExt};
-use simdutf8::compat::from_utf8;
-use std::io::Write;
-use std::{collections::BTreeMap, path::Path, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, sync::atomic::Ordering, time::Duration};
 use time::{macros::format_description, OffsetDateTime};
-use tracing::{debug, instrument};
+use tracing::{debug, error, instrument};
 
 #[allow(clippy::module_name_repetitions)]
 pub struct DataCommand<'a> {
@@ -36,12 +38,21 @@ impl DataCommand<'_> {
         debug!("Waiting for incoming data");
         {
             let mut write_lock = self.data.con_state.write().await;
+            // Binary messages can't be sent line by line (RFC 3030 section 3)
+            if write_lock.binary {
+                lines
+                    .send(String::from("503 5.5.1 BINARYMIME requires BDAT"))
+                    .await?;
+                return Ok(());
+            }
             let username = if let State::Authenticated(username) = &write_lock.state {
                 Some(username.clone())
             } else {
                 None
             };
             write_lock.state = State::ReceivingData((username, Vec::new()));
+            // The message gets read as bytes until the line with the single dot
+            write_lock.read_mode.data.store(true, Ordering::Release);
         };
         lines
             .send(String::from("354 Start mail input; end with <CRLF>.<CRLF>"))
@@ -49,155 +60,275 @@ impl DataCommand<'_> {
         Ok(())
     }
 
-    #[instrument(skip(self, config, lines, line, database, storage))]
-    pub async fn receive<S, E>(
+    /// Adds the next part of the message to the data received so far
+    #[instrument(skip(self, config, data))]
+    pub async fn receive(&self, config: &Config, data: &[u8]) -> color_eyre::eyre::Result<()> {
+        debug!("Reading incoming data");
+        let mut write_lock = self.data.con_state.write().await;
+        if let State::ReceivingData((username, message)) = &mut write_lock.state {
+            // Stop keeping the message once it is too large but read it up to the end
+            if message.len() + data.len() > config.mail.max_message_size {
+                debug!("Message exceeds the maximum size");
+                write_lock.state = State::DiscardingData(username.clone());
+            } else {
+                message.extend_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the end of the message once the client sent the line with the single dot
+    #[instrument(skip(self, config, lines, database, storage))]
+    pub async fn end<S, E>(
         &self,
-        config: Arc<Config>,
+        config: &Config,
         lines: &mut S,
-        line: &str,
-        database: DB,
-        storage: Arc<Storage>,
+        database: &DB,
+        storage: &Storage,
     ) -> color_eyre::eyre::Result<()>
     where
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
         S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
     {
-        debug!("Reading incoming data");
+        debug!("Got end of data");
+        let mut write_lock = self.data.con_state.write().await;
+        if let State::DiscardingData(username) = &write_lock.state {
+            write_lock.state = match username {
+                Some(username) => State::Authenticated(username.clone()),
+                None => State::NotAuthenticated,
+            };
+            lines
+                .send(String::from(
+                    "552 5.3.4 Message size exceeds fixed maximum message size",
+                ))
+                .await?;
+            return Ok(());
+        }
+        self.deliver(&mut write_lock, config, lines, database, storage)
+            .await
+    }
 
+    /// Delivers the message received using DATA or BDAT to local mailboxes or queues it for
+    /// other servers
+    #[allow(clippy::too_many_lines)]
+    #[instrument(skip(self, connection, config, lines, database, storage))]
+    pub async fn deliver<S, E>(
+        &self,
+        connection: &mut Connection,
+        config: &Config,
+        lines: &mut S,
+        database: &DB,
+        storage: &Storage,
+    ) -> color_eyre::eyre::Result<()>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
         let date_format = format_description!(
             "[weekday repr:short], [day] [month] [year] [hour]:[minute]:[second] [offset_hour \
          sign:mandatory]"
         );
-        {
-            let write_lock = &mut self.data.con_state.write().await;
-            if line == "." {
-                debug!("Got end of line");
-                let Some(receipts) = &write_lock.receipts else {
-color_eyre::eyre::bail!("No receipts")
-};
-                write_lock.state = if let State::ReceivingData((Some(username), data)) =
-                    &write_lock.state
-                {
-                    debug!("Authenticated user: {}", username);
-
-                    let mut inner_data = data.clone();
-                    inner_data.truncate(inner_data.len() - 2);
-                    for address in receipts {
-                        let mut to: BTreeMap<String, Vec<String>> = BTreeMap::new();
-                        let domain = address.split('@').collect::<Vec<&str>>()[1];
-                        to.entry(domain.to_string())
-                            .or_default()
-                            .push(address.clone());
-                        let received_header = format!(
-                            "Received: from {} ({} [{}])\r\n	by {} (Erooster) with ESMTPS\r\n	id 00000001\r\n	(envelope-from <{}>)\r\n	for <{}>; {}\r\n",
-                            write_lock.ehlo.as_ref().context("Missing ehlo")?,
-                            write_lock.ehlo.as_ref().context("Missing ehlo")?,
-                            write_lock.peer_addr,
-                            config.mail.hostname,
-                            write_lock.sender.as_ref().context("Missing sender")?,
-                            address,
-                            OffsetDateTime::now_utc().format(&date_format)?
-                        );
-                        let temp_data = [received_header.as_bytes(), &inner_data].concat();
-                        let data = from_utf8(&temp_data)?;
-
-                        let data = if let Some(rspamd_config) = &config.rspamd {
-                            self.call_rspamd(
-                                rspamd_config,
-                                data,
-                                write_lock.ehlo.as_ref().context("Missing ehlo")?,
-                                &write_lock.peer_addr,
-                                write_lock.sender.as_ref().context("Missing sender")?,
-                                address,
-                                Some(username.to_string()),
-                            )
-                            .await?
-                        } else {
-                            data
-                        };
-
-                        let email_payload = EmailPayload {
-                            to,
-                            from: write_lock
-                                .sender
-                                .clone()
-                                .context("Missing sender in internal state")?,
-                            body: data.to_string(),
-                            sender_domain: config.mail.hostname.clone(),
-                            dkim_key_path: config.mail.dkim_key_path.clone(),
-                            dkim_key_selector: config.mail.dkim_key_selector.clone(),
-                        };
-                        let pool = database.get_pool();
-                        send_email_job
-                            .builder()
-                            .set_json(&email_payload)?
-                            .spawn(pool)
+        let Some(receipts) = &connection.receipts else {
+            color_eyre::eyre::bail!("No receipts")
+        };
+        connection.state = if let State::ReceivingData((Some(username), data)) = &connection.state {
+            debug!("Authenticated user: {}", username);
+
+            let mut inner_data = data.clone();
+            if inner_data.ends_with(b"\r\n") {
+                inner_data.truncate(inner_data.len() - 2);
+            }
+            // The From header has to name the user just like the envelope sender
+            let addresses = database.sender_addresses(username).await?;
+            let accepted = owns_header_from(&inner_data, &addresses)
+                || match config.smtp.sender_policy {
+                    SenderPolicy::Reject => false,
+                    SenderPolicy::Rewrite => {
+                        inner_data = rewrite_header_from(&inner_data, username);
+                        true
+                    }
+                };
+            let signed = if accepted {
+                // Signed once so every recipient and the Sent copy get exactly the submitted message
+                match dkim_sign(
+                    &config.mail.hostname,
+                    &inner_data,
+                    &config.mail.dkim_key_path,
+                    &config.mail.dkim_key_selector,
+                ) {
+                    Ok(signed) => Some(signed),
+                    Err(e) => {
+                        error!("Unable to sign the message of {}: {}", username, e);
+                        lines
+                            .send(String::from("451 4.7.0 Unable to sign the message"))
                             .await?;
-                        debug!("Email added to queue");
+                        None
                     }
+                }
+            } else {
+                lines
+                    .send(format!(
+                        "553 5.7.1 From header not owned by user {username}"
+                    ))
+                    .await?;
+                None
+            };
+            if let Some(signed) = signed {
+                let sender = connection
+                    .sender
+                    .clone()
+                    .context("Missing sender in internal state")?;
+                // Local recipients are served first. If storing fails the client sends the
+                // message again, which must not reach the other servers twice.
+                let mut remote = Vec::new();
+                for address in receipts {
+                    let received_header = format!(
+                        "Received: from {} ({} [{}])\r\n	by {} (Erooster) with ESMTPS\r\n	id 00000001\r\n	(envelope-from <{}>)\r\n	for <{}>; {}\r\n",
+                        connection.ehlo.as_ref().context("Missing ehlo")?,
+                        connection.ehlo.as_ref().context("Missing ehlo")?,
+                        connection.peer_addr,
+                        config.mail.hostname,
+                        sender,
+                        address,
+                        OffsetDateTime::now_utc().format(&date_format)?
+                    );
+                    let temp_data = [received_header.as_bytes(), &signed].concat();
 
-                    lines
-                        .send(String::from("250 2.6.0 Message accepted"))
-                        .await?;
+                    let data = if let Some(rspamd_config) = &config.rspamd {
+                        self.call_rspamd(
+                            rspamd_config,
+                            &temp_data,
+                            connection.ehlo.as_ref().context("Missing ehlo")?,
+                            &connection.peer_addr,
+                            &sender,
+                            address,
+                            Some(username.to_string()),
+                        )
+                        .await?
+                    } else {
+                        &temp_data
+                    };
 
-                    State::Authenticated(username.clone())
-                } else if let State::ReceivingData((None, data)) = &write_lock.state {
-                    debug!("No authenticated user");
-                    for receipt in receipts {
-                        let folder = "INBOX".to_string();
-                        let mailbox_path = Path::new(&config.mail.maildir_folders)
-                            .join(receipt.clone())
-                            .join(folder.clone());
-                        if !mailbox_path.exists() {
-                            storage.create_dirs(&mailbox_path)?;
-                            storage.add_flag(&mailbox_path, "\\Subscribed").await?;
-                            storage.add_flag(&mailbox_path, "\\NoInferiors").await?;
-                        }
-                        let received_header = format!(
-                            "Received: from {} ({} [{}])\r\n	by {} (Erooster) with ESMTPS\r\n	id 00000001\r\n	for <{}>; {}\r\n",
-                            write_lock.ehlo.as_ref().context("Missing ehlo")?,
-                            write_lock.ehlo.as_ref().context("Missing ehlo")?,
-                            write_lock.peer_addr,
-                            config.mail.hostname,
-                            receipt,
-                            OffsetDateTime::now_utc().format(&date_format)?,
-                        );
-                        let temp_data = [received_header.as_bytes(), data].concat();
-                        let data = from_utf8(&temp_data)?;
-
-                        let data = if let Some(rspamd_config) = &config.rspamd {
-                            self.call_rspamd(
-                                rspamd_config,
-                                data,
-                                write_lock.ehlo.as_ref().context("Missing ehlo")?,
-                                &write_lock.peer_addr,
-                                write_lock.sender.as_ref().context("Missing sender")?,
-                                receipt,
-                                None,
-                            )
-                            .await?
-                        } else {
-                            data
-                        };
-
-                        let message_id = storage.store_new(&mailbox_path, data.as_bytes()).await?;
-                        debug!("Stored message: {}", message_id);
+                    // Mail for our own users never has to leave the server
+                    if !is_local(address, &config.mail.hostname) {
+                        remote.push((address, data.to_vec()));
+                        continue;
                     }
-                    // TODO cleanup after we are done
-                    lines
-                        .send(String::from("250 2.6.0 Message accepted"))
+                    // The alias or mailbox may be gone since RCPT TO accepted it
+                    let Some(owner) = database.mailbox_owner(address).await? else {
+                        error!("Mailbox of {} is gone, dropping the message for it", address);
+                        continue;
+                    };
+                    let message = [delivery_headers(&sender, address).as_bytes(), data].concat();
+                    let message_id =
+                        store_in_inbox(&config.mail.maildir_folders, storage, &owner, &message)
+                            .await?;
+                    debug!("Stored message: {}", message_id);
+                }
+
+                for (address, data) in remote {
+                    let mut to: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                    let domain = address.split('@').collect::<Vec<&str>>()[1];
+                    to.entry(domain.to_string())
+                        .or_default()
+                        .push(address.clone());
+                    let email_payload = EmailPayload {
+                        to,
+                        from: sender.clone(),
+                        body: data,
+                        legacy_body: None,
+                        sender_domain: config.mail.hostname.clone(),
+                        dkim_key_path: config.mail.dkim_key_path.clone(),
+                        dkim_key_selector: config.mail.dkim_key_selector.clone(),
+                        signed: true,
+                        dsn: connection.dsn.for_recipient(address),
+                        queued_at: OffsetDateTime::now_utc().unix_timestamp(),
+                        delay_warned: false,
+                    };
+                    let pool = database.get_pool();
+                    send_email_job
+                        .builder()
+                        .set_json(&email_payload)?
+                        .spawn(pool)
                         .await?;
-                    State::NotAuthenticated
+                    debug!("Email added to queue");
+                }
+
+                // The mail is on its way already so a missing copy must not make the client
+                // send it again
+                match save_sent_copy(config, database, storage, username, &signed).await {
+                    Ok(Some(message_id)) => debug!("Saved sent message: {}", message_id),
+                    Ok(None) => {}
+                    Err(e) => error!("Unable to save the sent message of {}: {}", username, e),
+                }
+
+                lines
+                    .send(String::from("250 2.6.0 Message accepted"))
+                    .await?;
+            }
+
+            State::Authenticated(username.clone())
+        } else if let State::ReceivingData((None, data)) = &connection.state {
+            debug!("No authenticated user");
+            let sender = connection.sender.as_ref().context("Missing sender")?;
+            let mut delivered = 0;
+            for receipt in receipts {
+                // The alias or mailbox may be gone since RCPT TO accepted it
+                let Some(owner) = database.mailbox_owner(receipt).await? else {
+                    error!("Mailbox of {} is gone, dropping the message for it", receipt);
+                    continue;
+                };
+                let received_header = format!(
+                    "Received: from {} ({} [{}])\r\n	by {} (Erooster) with ESMTPS\r\n	id 00000001\r\n	for <{}>; {}\r\n",
+                    connection.ehlo.as_ref().context("Missing ehlo")?,
+                    connection.ehlo.as_ref().context("Missing ehlo")?,
+                    connection.peer_addr,
+                    config.mail.hostname,
+                    receipt,
+                    OffsetDateTime::now_utc().format(&date_format)?,
+                );
+                let temp_data = [received_header.as_bytes(), data].concat();
+
+                let data = if let Some(rspamd_config) = &config.rspamd {
+                    self.call_rspamd(
+                        rspamd_config,
+                        &temp_data,
+                        connection.ehlo.as_ref().context("Missing ehlo")?,
+                        &connection.peer_addr,
+                        sender,
+                        receipt,
+                        None,
+                    )
+                    .await?
                 } else {
-                    write_lock.state = State::NotAuthenticated;
-                    lines
-                        .send(String::from("250 2.6.0 Message accepted"))
-                        .await?;
-                    color_eyre::eyre::bail!("Invalid state");
+                    &temp_data
                 };
-            } else if let State::ReceivingData((_, data)) = &mut write_lock.state {
-                write!(data, "{line}\r\n")?;
+
+                let message = [delivery_headers(sender, receipt).as_bytes(), data].concat();
+                let message_id =
+                    store_in_inbox(&config.mail.maildir_folders, storage, &owner, &message).await?;
+                debug!("Stored message: {}", message_id);
+                delivered += 1;
+            }
+            // TODO cleanup after we are done
+            if delivered == 0 {
+                lines
+                    .send(String::from(
+                        "550 5.1.1 No recipient mailbox exists anymore",
+                    ))
+                    .await?;
+            } else {
+                lines
+                    .send(String::from("250 2.6.0 Message accepted"))
+                    .await?;
             }
+            State::NotAuthenticated
+        } else {
+            connection.state = State::NotAuthenticated;
+            lines
+                .send(String::from("250 2.6.0 Message accepted"))
+                .await?;
+            color_eyre::eyre::bail!("Invalid state");
         };
         Ok(())
     }
@@ -206,13 +337,13 @@ color_eyre::eyre::bail!("No receipts")
     async fn call_rspamd<'a>(
         &self,
         rspamd_config: &Rspamd,
-        data: &'a str,
+        data: &'a [u8],
         ehlo: &str,
         ip: &str,
         sender: &str,
         rcpt: &str,
         username: Option<String>,
-    ) -> color_eyre::Result<&'a str> {
+    ) -> color_eyre::Result<&'a [u8]> {
         let client = reqwest::Client::builder()
             .trust_dns(true)
             .timeout(Duration::from_secs(30))
@@ -220,7 +351,7 @@ color_eyre::eyre::bail!("No receipts")
             .build()?;
         let base_req = client
             .post(format!("{}/checkv2", rspamd_config.address))
-            .body(data.to_string())
+            .body(data.to_vec())
             .header("From", sender)
             .header("HELO", ehlo)
             .header("RCPT", rcpt);
@@ -236,3 +367,92 @@ color_eyre::eyre::bail!("No receipts")
         Ok(data)
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::commands::{rcpt::Rcpt, CommandData, Commands};
+    use erooster_core::{
+        backend::{
+            database::get_database,
+            storage::{get_storage, MailStorage},
+        },
+        config::ListenerRole,
+    };
+    use futures::{channel::mpsc, StreamExt};
+    use std::{path::Path, sync::Arc};
+
+    #[tokio::test]
+    async fn test_every_recipient_gets_the_message() {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(get_database(Arc::clone(&config)).await.unwrap());
+        let storage = get_storage(Arc::clone(&database), Arc::clone(&config));
+        let nanos = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let recipients = [
+            format!("first-{nanos}@{}", config.mail.hostname),
+            format!("second-{nanos}@{}", config.mail.hostname),
+        ];
+        for recipient in &recipients {
+            database.add_user(recipient).await.unwrap();
+        }
+
+        let data = Data {
+            con_state: Connection::new(false, String::from("127.0.0.1"), ListenerRole::Mx),
+        };
+        {
+            let mut write_lock = data.con_state.write().await;
+            write_lock.ehlo = Some(String::from("client.example.com"));
+            write_lock.sender = Some(String::from("sender@example.com"));
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        for recipient in &recipients {
+            let address = format!("<{recipient}>");
+            let command_data = CommandData {
+                command: Commands::RCPTTO,
+                arguments: &[&address],
+            };
+            Rcpt { data: &data }
+                .exec(
+                    &mut tx,
+                    Arc::clone(&database),
+                    &config.mail.hostname,
+                    &command_data,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                rx.next().await,
+                Some(format!("250 2.1.5 Recipient {address} OK"))
+            );
+        }
+
+        let command = DataCommand { data: &data };
+        command.exec(&mut tx).await.unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("354 Start mail input; end with <CRLF>.<CRLF>"))
+        );
+        command
+            .receive(&config, b"Subject: Test\r\n\r\nHello\r\n")
+            .await
+            .unwrap();
+        command
+            .end(&config, &mut tx, &database, &storage)
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from("250 2.6.0 Message accepted"))
+        );
+
+        for recipient in &recipients {
+            let inbox = Path::new(&config.mail.maildir_folders)
+                .join(recipient)
+                .join("INBOX");
+            assert_eq!(storage.list_new(&inbox).await.len(), 1, "{recipient}");
+        }
+    }
+}
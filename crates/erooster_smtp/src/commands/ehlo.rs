@@ -1,4 +1,5 @@
 use color_eyre::eyre::bail;
+use erooster_core::config::ListenerRole;
 use futures::{Sink, SinkExt};
 use tracing::instrument;
 
@@ -9,10 +10,11 @@ pub struct Ehlo<'a> {
 }
 
 impl Ehlo<'_> {
-    #[instrument(skip(self, hostname, lines, command_data))]
+    #[instrument(skip(self, hostname, max_message_size, lines, command_data))]
     pub async fn exec<S, E>(
         &self,
         hostname: &str,
+        max_message_size: usize,
         lines: &mut S,
         command_data: &CommandData<'_>,
     ) -> color_eyre::eyre::Result<()>
@@ -26,15 +28,99 @@ impl Ehlo<'_> {
         let mut write_lock = self.data.con_state.write().await;
         write_lock.ehlo = Some(command_data.arguments[0].to_string());
         lines.feed(format!("250-{hostname}")).await?;
-        lines.feed(String::from("250-ENHANCEDSTATUSCODES")).await?;
-        if !write_lock.secure {
-            lines.feed(String::from("250-STARTTLS")).await?;
+        let capabilities = capabilities(write_lock.role, write_lock.secure, max_message_size);
+        for (index, capability) in capabilities.iter().enumerate() {
+            if index == capabilities.len() - 1 {
+                lines.feed(format!("250 {capability}")).await?;
+            } else {
+                lines.feed(format!("250-{capability}")).await?;
+            }
         }
-        if write_lock.secure {
-            lines.feed(String::from("250-AUTH LOGIN PLAIN")).await?;
-        }
-        lines.feed(String::from("250 SMTPUTF8")).await?;
         lines.flush().await?;
         Ok(())
     }
 }
+
+/// The extensions offered on a listener with the given role
+fn capabilities(role: ListenerRole, secure: bool, max_message_size: usize) -> Vec<String> {
+    let mut capabilities = vec![String::from("ENHANCEDSTATUSCODES")];
+    if !secure {
+        capabilities.push(String::from("STARTTLS"));
+    }
+    // Only users submitting mail can authenticate and only once the connection is encrypted
+    if role == ListenerRole::Submission && secure {
+        capabilities.push(String::from("AUTH LOGIN PLAIN"));
+    }
+    capabilities.push(format!("SIZE {max_message_size}"));
+    capabilities.push(String::from("PIPELINING"));
+    capabilities.push(String::from("8BITMIME"));
+    capabilities.push(String::from("BINARYMIME"));
+    capabilities.push(String::from("CHUNKING"));
+    capabilities.push(String::from("DSN"));
+    capabilities.push(String::from("SMTPUTF8"));
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        assert_eq!(
+            capabilities(ListenerRole::Mx, false, 1024),
+            vec![
+                "ENHANCEDSTATUSCODES",
+                "STARTTLS",
+                "SIZE 1024",
+                "PIPELINING",
+                "8BITMIME",
+                "BINARYMIME",
+                "CHUNKING",
+                "DSN",
+                "SMTPUTF8"
+            ]
+        );
+        assert_eq!(
+            capabilities(ListenerRole::Mx, true, 1024),
+            vec![
+                "ENHANCEDSTATUSCODES",
+                "SIZE 1024",
+                "PIPELINING",
+                "8BITMIME",
+                "BINARYMIME",
+                "CHUNKING",
+                "DSN",
+                "SMTPUTF8"
+            ]
+        );
+        assert_eq!(
+            capabilities(ListenerRole::Submission, false, 1024),
+            vec![
+                "ENHANCEDSTATUSCODES",
+                "STARTTLS",
+                "SIZE 1024",
+                "PIPELINING",
+                "8BITMIME",
+                "BINARYMIME",
+                "CHUNKING",
+                "DSN",
+                "SMTPUTF8"
+            ]
+        );
+        assert_eq!(
+            capabilities(ListenerRole::Submission, true, 1024),
+            vec![
+                "ENHANCEDSTATUSCODES",
+                "AUTH LOGIN PLAIN",
+                "SIZE 1024",
+                "PIPELINING",
+                "8BITMIME",
+                "BINARYMIME",
+                "CHUNKING",
+                "DSN",
+                "SMTPUTF8"
+            ]
+        );
+    }
+}
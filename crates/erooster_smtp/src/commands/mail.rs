@@ -1,6 +1,18 @@
-use crate::commands::{parsers::localpart_arguments, CommandData, Data};
+use crate::{
+    commands::{parsers::localpart_arguments, CommandData, Data},
+    servers::state::State,
+    utils::{
+        dsn::{decode_xtext, Dsn, Ret},
+        sender::owns,
+    },
+};
 use color_eyre::eyre::bail;
+use erooster_core::{
+    backend::database::{Database, DB},
+    config::{Config, ListenerRole, SenderPolicy},
+};
 use futures::{Sink, SinkExt};
+use std::collections::BTreeMap;
 use tracing::{error, instrument};
 
 pub struct Mail<'a> {
@@ -8,10 +20,12 @@ pub struct Mail<'a> {
 }
 
 impl Mail<'_> {
-    #[instrument(skip(self, lines, command_data))]
+    #[instrument(skip(self, lines, database, config, command_data))]
     pub async fn exec<S, E>(
         &self,
         lines: &mut S,
+        database: DB,
+        config: &Config,
         command_data: &CommandData<'_>,
     ) -> color_eyre::eyre::Result<()>
     where
@@ -22,18 +36,142 @@ impl Mail<'_> {
             bail!("Failed to parse localpart arguments (no arguments)");
         }
 
+        // Submitted mail only gets accepted from users over an encrypted connection (RFC 6409 section 4.3)
+        {
+            let read_lock = self.data.con_state.read().await;
+            if read_lock.role == ListenerRole::Submission {
+                if !read_lock.secure {
+                    lines
+                        .send(String::from(
+                            "530 5.7.0 Must issue a STARTTLS command first",
+                        ))
+                        .await?;
+                    return Ok(());
+                }
+                if !matches!(read_lock.state, State::Authenticated(_)) {
+                    lines
+                        .send(String::from("530 5.7.0 Authentication required"))
+                        .await?;
+                    return Ok(());
+                }
+            }
+        };
+
+        // The client may tell us the size of the message upfront (RFC 1870)
+        let size = command_data.arguments[1..].iter().find_map(|parameter| {
+            parameter
+                .split_once('=')
+                .filter(|(keyword, _)| keyword.eq_ignore_ascii_case("SIZE"))
+                .map(|(_, value)| value.parse::<usize>())
+        });
+        match size {
+            Some(Err(_)) => {
+                lines
+                    .send(String::from("501 5.5.4 Invalid SIZE parameter"))
+                    .await?;
+                return Ok(());
+            }
+            Some(Ok(size)) if size > config.mail.max_message_size => {
+                lines
+                    .send(String::from(
+                        "552 5.3.4 Message size exceeds fixed maximum message size",
+                    ))
+                    .await?;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // How the message is encoded (RFC 6152 and RFC 3030)
+        let body = command_data.arguments[1..].iter().find_map(|parameter| {
+            parameter
+                .split_once('=')
+                .filter(|(keyword, _)| keyword.eq_ignore_ascii_case("BODY"))
+                .map(|(_, value)| value.to_ascii_uppercase())
+        });
+        let binary = match body.as_deref() {
+            None | Some("7BIT" | "8BITMIME") => false,
+            Some("BINARYMIME") => true,
+            Some(_) => {
+                lines
+                    .send(String::from("501 5.5.4 Unrecognized BODY parameter"))
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        // What the client wants to hear about the delivery (RFC 3461 section 4)
+        let ret = command_data.arguments[1..].iter().find_map(|parameter| {
+            parameter
+                .split_once('=')
+                .filter(|(keyword, _)| keyword.eq_ignore_ascii_case("RET"))
+                .map(|(_, value)| value.parse::<Ret>())
+        });
+        let ret = match ret {
+            Some(Err(())) => {
+                lines
+                    .send(String::from("501 5.5.4 Invalid RET parameter"))
+                    .await?;
+                return Ok(());
+            }
+            Some(Ok(ret)) => Some(ret),
+            None => None,
+        };
+        let envid = command_data.arguments[1..].iter().find_map(|parameter| {
+            parameter
+                .split_once('=')
+                .filter(|(keyword, _)| keyword.eq_ignore_ascii_case("ENVID"))
+                .map(|(_, value)| decode_xtext(value).filter(|envid| envid.len() <= 100))
+        });
+        let envid = match envid {
+            Some(None) => {
+                lines
+                    .send(String::from("501 5.5.4 Invalid ENVID parameter"))
+                    .await?;
+                return Ok(());
+            }
+            envid => envid.flatten(),
+        };
+
         match localpart_arguments(command_data.arguments[0]).map(|(_, senders)| senders) {
             Ok(args) => {
-                let senders: Vec<_> = args.iter().map(ToString::to_string).collect();
+                // The null reverse-path `<>` of bounces and delivery reports has no address
+                // (RFC 5321 section 4.5.5)
+                let mut sender = args.first().map(ToString::to_string).unwrap_or_default();
+                let username = match &self.data.con_state.read().await.state {
+                    State::Authenticated(username) => Some(username.clone()),
+                    _ => None,
+                };
+                // Users may only send as one of their own addresses
+                if let Some(username) = username {
+                    let addresses = database.sender_addresses(&username).await?;
+                    if !owns(&addresses, &sender) {
+                        match config.smtp.sender_policy {
+                            SenderPolicy::Reject => {
+                                lines
+                                    .send(format!(
+                                        "553 5.7.1 Sender address {} not owned by user {username}",
+                                        command_data.arguments[0]
+                                    ))
+                                    .await?;
+                                return Ok(());
+                            }
+                            SenderPolicy::Rewrite => sender = username,
+                        }
+                    }
+                }
                 {
                     let mut write_lock = self.data.con_state.write().await;
-                    write_lock.sender = Some(senders[0].clone());
+                    write_lock.sender = Some(sender.clone());
+                    write_lock.binary = binary;
+                    write_lock.dsn = Dsn {
+                        ret,
+                        envid,
+                        recipients: BTreeMap::new(),
+                    };
                 };
                 lines
-                    .send(format!(
-                        "250 2.1.0 Originator {} OK",
-                        command_data.arguments[0]
-                    ))
+                    .send(format!("250 2.1.0 Originator <{sender}> OK"))
                     .await?;
             }
             Err(e) => {
@@ -45,3 +183,84 @@ impl Mail<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{commands::Commands, servers::state::Connection};
+    use erooster_core::backend::database::get_database;
+    use futures::{channel::mpsc, StreamExt};
+    use std::sync::Arc;
+
+    async fn mail_from(arguments: &[&str]) -> (Option<String>, Dsn) {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(get_database(Arc::clone(&config)).await.unwrap());
+        let data = Data {
+            con_state: Connection::new(false, String::from("127.0.0.1"), ListenerRole::Mx),
+        };
+        let command_data = CommandData {
+            command: Commands::MAILFROM,
+            arguments,
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        Mail { data: &data }
+            .exec(&mut tx, database, &config, &command_data)
+            .await
+            .unwrap();
+        let dsn = data.con_state.read().await.dsn.clone();
+        (rx.next().await, dsn)
+    }
+
+    #[tokio::test]
+    async fn test_dsn_parameters() {
+        let (reply, dsn) =
+            mail_from(&["<sender@example.com>", "RET=HDRS", "ENVID=QQ+2B314159"]).await;
+        assert_eq!(
+            reply,
+            Some(String::from("250 2.1.0 Originator <sender@example.com> OK"))
+        );
+        assert_eq!(
+            dsn,
+            Dsn {
+                ret: Some(Ret::Hdrs),
+                envid: Some(String::from("QQ+314159")),
+                recipients: BTreeMap::new(),
+            }
+        );
+
+        let (reply, dsn) = mail_from(&["<sender@example.com>"]).await;
+        assert_eq!(
+            reply,
+            Some(String::from("250 2.1.0 Originator <sender@example.com> OK"))
+        );
+        assert_eq!(dsn, Dsn::default());
+    }
+
+    #[tokio::test]
+    async fn test_null_sender() {
+        let (reply, _) = mail_from(&["<>"]).await;
+        assert_eq!(reply, Some(String::from("250 2.1.0 Originator <> OK")));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_dsn_parameters() {
+        let (reply, _) = mail_from(&["<sender@example.com>", "RET=ALL"]).await;
+        assert_eq!(reply, Some(String::from("501 5.5.4 Invalid RET parameter")));
+
+        let (reply, _) = mail_from(&["<sender@example.com>", "ENVID=a=b"]).await;
+        assert_eq!(
+            reply,
+            Some(String::from("501 5.5.4 Invalid ENVID parameter"))
+        );
+
+        let too_long = format!("ENVID={}", "a".repeat(101));
+        let (reply, _) = mail_from(&["<sender@example.com>", &too_long]).await;
+        assert_eq!(
+            reply,
+            Some(String::from("501 5.5.4 Invalid ENVID parameter"))
+        );
+    }
+}
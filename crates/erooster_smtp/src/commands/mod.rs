@@ -1,9 +1,12 @@
 use crate::{
     commands::{
-        auth::Auth, data::DataCommand, ehlo::Ehlo, mail::Mail, noop::Noop, quit::Quit, rcpt::Rcpt,
-        rset::Rset,
+        auth::Auth, bdat::Bdat, data::DataCommand, ehlo::Ehlo, mail::Mail, noop::Noop, quit::Quit,
+        rcpt::Rcpt, rset::Rset,
+    },
+    servers::{
+        codec::Frame,
+        state::{AuthState, Connection, State},
     },
-    servers::state::{AuthState, Connection, State},
 };
 use erooster_core::{
     backend::{database::DB, storage::Storage},
@@ -27,6 +30,7 @@ use tracing::{debug, error, instrument, warn};
 use std::fmt::Display;
 
 mod auth;
+mod bdat;
 mod data;
 mod ehlo;
 mod mail;
@@ -62,6 +66,7 @@ pub struct CommandData<'a> {
 )]
 pub enum Commands {
     AUTH,
+    BDAT,
     DATA,
     EHLO,
     MAILFROM,
@@ -83,6 +88,7 @@ impl TryFrom<&str> for Commands {
             "mail from" => Ok(Commands::MAILFROM),
             "rcpt to" => Ok(Commands::RCPTTO),
             "data" => Ok(Commands::DATA),
+            "bdat" => Ok(Commands::BDAT),
             "auth" => Ok(Commands::AUTH),
             "noop" => Ok(Commands::NOOP),
             "rset" => Ok(Commands::RSET),
@@ -159,13 +165,7 @@ impl Data {
 
         let con_clone = Arc::clone(&self.con_state);
         let state = { con_clone.read().await.state.clone() };
-        if matches!(state, State::ReceivingData(_)) {
-            DataCommand { data: self }
-                .receive(config, lines, &line, database, storage)
-                .await?;
-            // We are done here
-            return Ok(Response::Continue);
-        } else if let State::Authenticating(auth_state) = state {
+        if let State::Authenticating(auth_state) = state {
             match auth_state {
                 AuthState::Username => {
                     Auth { data: self }.username(lines, &line).await?;
@@ -197,6 +197,19 @@ impl Data {
                     }
                 };
 
+                // Only more chunks may follow until the message is complete or the client gives up
+                if matches!(state, State::ReceivingChunks(_))
+                    && !matches!(
+                        command_data.command,
+                        Commands::BDAT | Commands::RSET | Commands::QUIT | Commands::NOOP
+                    )
+                {
+                    lines
+                        .send(String::from("503 5.5.1 Bad sequence of commands"))
+                        .await?;
+                    return Ok(Response::Continue);
+                }
+
                 match command_data.command {
                     Commands::STARTTLS => {
                         // We need to accept tls from this point on
@@ -204,11 +217,16 @@ impl Data {
                         return Ok(Response::STARTTLS);
                     }
                     Commands::RSET => {
-                        Rset.exec(lines).await?;
+                        Rset { data: self }.exec(lines).await?;
                     }
                     Commands::EHLO => {
                         Ehlo { data: self }
-                            .exec(&config.mail.hostname, lines, &command_data)
+                            .exec(
+                                &config.mail.hostname,
+                                config.mail.max_message_size,
+                                lines,
+                                &command_data,
+                            )
                             .await?;
                     }
                     Commands::QUIT => {
@@ -217,7 +235,9 @@ impl Data {
                         return Ok(Response::Exit);
                     }
                     Commands::MAILFROM => {
-                        Mail { data: self }.exec(lines, &command_data).await?;
+                        Mail { data: self }
+                            .exec(lines, database, &config, &command_data)
+                            .await?;
                     }
                     Commands::RCPTTO => {
                         Rcpt { data: self }
@@ -227,6 +247,11 @@ impl Data {
                     Commands::DATA => {
                         DataCommand { data: self }.exec(lines).await?;
                     }
+                    Commands::BDAT => {
+                        Bdat { data: self }
+                            .exec(lines, &config, &database, &storage, &command_data)
+                            .await?;
+                    }
                     Commands::AUTH => {
                         Auth { data: self }
                             .exec(lines, database, &command_data)
@@ -250,6 +275,40 @@ impl Data {
         }
         Ok(Response::Continue)
     }
+
+    /// Handles whatever the client sent next
+    #[instrument(skip(self, lines, config, database, storage, frame))]
+    pub async fn handle<S, E>(
+        &self,
+        lines: &mut S,
+        config: Arc<Config>,
+        database: DB,
+        storage: Arc<Storage>,
+        frame: Frame,
+    ) -> color_eyre::eyre::Result<Response>
+    where
+        E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
+        S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
+    {
+        match frame {
+            Frame::Line(line) => return self.parse(lines, config, database, storage, line).await,
+            Frame::Chunk(chunk) => {
+                debug!("Got {} bytes of a chunk", chunk.len());
+                Bdat { data: self }
+                    .receive(lines, &config, &database, &storage, chunk)
+                    .await?;
+            }
+            Frame::Data(data) => {
+                DataCommand { data: self }.receive(&config, &data).await?;
+            }
+            Frame::DataEnd => {
+                DataCommand { data: self }
+                    .end(&config, lines, &database, &storage)
+                    .await?;
+            }
+        }
+        Ok(Response::Continue)
+    }
 }
 
 #[cfg(test)]
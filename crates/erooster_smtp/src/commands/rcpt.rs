@@ -1,9 +1,16 @@
 use crate::{
     commands::{parsers::localpart_arguments, CommandData, Data},
     servers::state::State,
+    utils::{
+        delivery::is_local,
+        dsn::{decode_orcpt, Notify, RecipientDsn},
+    },
 };
 use color_eyre::eyre::bail;
-use erooster_core::backend::database::{Database, DB};
+use erooster_core::{
+    backend::database::{Database, DB},
+    config::ListenerRole,
+};
 use futures::{Sink, SinkExt};
 use tracing::{info, instrument};
 
@@ -35,34 +42,97 @@ impl Rcpt<'_> {
             .map(ToString::to_string)
             .collect();
 
+        // When and how the sender wants to hear about the delivery (RFC 3461 section 4)
+        let notify = command_data.arguments[1..].iter().find_map(|parameter| {
+            parameter
+                .split_once('=')
+                .filter(|(keyword, _)| keyword.eq_ignore_ascii_case("NOTIFY"))
+                .map(|(_, value)| value.parse::<Notify>())
+        });
+        let notify = match notify {
+            Some(Err(())) => {
+                lines
+                    .send(String::from("501 5.5.4 Invalid NOTIFY parameter"))
+                    .await?;
+                return Ok(());
+            }
+            Some(Ok(notify)) => Some(notify),
+            None => None,
+        };
+        let orcpt = command_data.arguments[1..].iter().find_map(|parameter| {
+            parameter
+                .split_once('=')
+                .filter(|(keyword, _)| keyword.eq_ignore_ascii_case("ORCPT"))
+                .map(|(_, value)| decode_orcpt(value))
+        });
+        let orcpt = match orcpt {
+            Some(None) => {
+                lines
+                    .send(String::from("501 5.5.4 Invalid ORCPT parameter"))
+                    .await?;
+                return Ok(());
+            }
+            orcpt => orcpt.flatten(),
+        };
+
         {
             let mut write_lock = self.data.con_state.write().await;
-            if matches!(&write_lock.state, State::NotAuthenticated) {
-                for receipt in &receipts {
-                    if !receipt.contains(hostname) {
+            // Only authenticated users of the submission port may send mail to other servers
+            let may_relay = write_lock.role == ListenerRole::Submission
+                && matches!(&write_lock.state, State::Authenticated(_));
+            for receipt in &receipts {
+                if !is_local(receipt, hostname) {
+                    // Mail for other servers gets relayed using DATA which can't carry binary
+                    // messages (RFC 3030 section 3)
+                    if may_relay && write_lock.binary {
                         lines
-                            .feed(String::from(
-                                "551-5.7.1 Forwarding to remote hosts disabled",
+                            .send(String::from(
+                                "554 5.6.1 BINARYMIME messages can't be relayed",
                             ))
                             .await?;
-                        lines
-                            .feed(String::from(
-                                "551 5.7.1 Select another host to act as your forwarder",
-                            ))
-                            .await?;
-                        lines.flush().await?;
                         return Ok(());
                     }
-                    if !database.user_exists(&receipt.to_lowercase()).await {
-                        lines
-                            .send(format!("550 5.1.1 Mailbox \"{receipt}\" does not exist"))
-                            .await?;
-                        return Ok(());
+                    if may_relay {
+                        continue;
                     }
+                    lines
+                        .feed(String::from(
+                            "551-5.7.1 Forwarding to remote hosts disabled",
+                        ))
+                        .await?;
+                    lines
+                        .feed(String::from(
+                            "551 5.7.1 Select another host to act as your forwarder",
+                        ))
+                        .await?;
+                    lines.flush().await?;
+                    return Ok(());
+                }
+                // Local mail gets delivered right away so the mailbox has to exist
+                if database.mailbox_owner(receipt).await?.is_none() {
+                    lines
+                        .send(format!("550 5.1.1 Mailbox \"{receipt}\" does not exist"))
+                        .await?;
+                    return Ok(());
                 }
             }
 
-            write_lock.receipts = Some(receipts);
+            // Every RCPT TO of the transaction adds to the recipients
+            let all_receipts = write_lock.receipts.get_or_insert_with(Vec::new);
+            for receipt in &receipts {
+                if !all_receipts.contains(receipt) {
+                    all_receipts.push(receipt.clone());
+                }
+            }
+            for receipt in receipts {
+                write_lock.dsn.recipients.insert(
+                    receipt,
+                    RecipientDsn {
+                        notify,
+                        orcpt: orcpt.clone(),
+                    },
+                );
+            }
         };
 
         lines
@@ -74,3 +144,121 @@ impl Rcpt<'_> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::{commands::Commands, servers::state::Connection};
+    use erooster_core::backend::database::get_database;
+    use futures::{channel::mpsc, StreamExt};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_binary_not_relayed() {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(get_database(Arc::clone(&config)).await.unwrap());
+        let con_state = Connection::new(true, String::from("127.0.0.1"), ListenerRole::Submission);
+        {
+            let mut write_lock = con_state.write().await;
+            write_lock.state = State::Authenticated(String::from("test@localhost"));
+            write_lock.sender = Some(String::from("test@localhost"));
+            write_lock.binary = true;
+        };
+        let data = Data { con_state };
+        let command_data = CommandData {
+            command: Commands::RCPTTO,
+            arguments: &["<someone@example.com>"],
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        Rcpt { data: &data }
+            .exec(&mut tx, database, &config.mail.hostname, &command_data)
+            .await
+            .unwrap();
+        assert_eq!(
+            rx.next().await,
+            Some(String::from(
+                "554 5.6.1 BINARYMIME messages can't be relayed"
+            ))
+        );
+        assert!(data.con_state.read().await.receipts.is_none());
+    }
+
+    async fn rcpt_to(arguments: &[&str]) -> (Option<String>, Data) {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(get_database(Arc::clone(&config)).await.unwrap());
+        let data = Data {
+            con_state: Connection::new(false, String::from("127.0.0.1"), ListenerRole::Mx),
+        };
+        data.con_state.write().await.sender = Some(String::from("sender@example.com"));
+        let command_data = CommandData {
+            command: Commands::RCPTTO,
+            arguments,
+        };
+        let (mut tx, mut rx) = mpsc::unbounded();
+        Rcpt { data: &data }
+            .exec(&mut tx, database, &config.mail.hostname, &command_data)
+            .await
+            .unwrap();
+        (rx.next().await, data)
+    }
+
+    #[tokio::test]
+    async fn test_dsn_parameters() {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = get_database(Arc::clone(&config)).await.unwrap();
+        let recipient = format!("dsn@{}", config.mail.hostname);
+        if !database.user_exists(&recipient).await {
+            database.add_user(&recipient).await.unwrap();
+        }
+
+        let address = format!("<{recipient}>");
+        let (reply, data) = rcpt_to(&[
+            &address,
+            "NOTIFY=SUCCESS,DELAY",
+            "ORCPT=rfc822;Dsn+40example.com",
+        ])
+        .await;
+        assert_eq!(reply, Some(format!("250 2.1.5 Recipient {address} OK")));
+        assert_eq!(
+            data.con_state.read().await.dsn.recipient(&recipient),
+            RecipientDsn {
+                notify: Some(Notify {
+                    success: true,
+                    failure: false,
+                    delay: true,
+                }),
+                orcpt: Some(String::from("rfc822;Dsn@example.com")),
+            }
+        );
+
+        let (reply, data) = rcpt_to(&[&address, "NOTIFY=NEVER"]).await;
+        assert_eq!(reply, Some(format!("250 2.1.5 Recipient {address} OK")));
+        assert_eq!(
+            data.con_state.read().await.dsn.recipient(&recipient).notify,
+            Some(Notify::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_dsn_parameters() {
+        let (reply, data) = rcpt_to(&["<dsn@localhost>", "NOTIFY=NEVER,SUCCESS"]).await;
+        assert_eq!(
+            reply,
+            Some(String::from("501 5.5.4 Invalid NOTIFY parameter"))
+        );
+        assert!(data.con_state.read().await.receipts.is_none());
+
+        let (reply, _) = rcpt_to(&["<dsn@localhost>", "ORCPT=user@example.com"]).await;
+        assert_eq!(
+            reply,
+            Some(String::from("501 5.5.4 Invalid ORCPT parameter"))
+        );
+    }
+}
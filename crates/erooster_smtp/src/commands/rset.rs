@@ -1,15 +1,29 @@
+use crate::{commands::Data, servers::state::State};
 use futures::{Sink, SinkExt};
 use tracing::instrument;
 
-pub struct Rset;
+pub struct Rset<'a> {
+    pub data: &'a Data,
+}
 
-impl Rset {
+impl Rset<'_> {
     #[instrument(skip(self, lines))]
     pub async fn exec<S, E>(&self, lines: &mut S) -> color_eyre::eyre::Result<()>
     where
         E: std::error::Error + std::marker::Sync + std::marker::Send + 'static,
         S: Sink<String, Error = E> + std::marker::Unpin + std::marker::Send,
     {
+        {
+            // Forget the current transaction (RFC 5321 section 4.1.1.5)
+            let mut write_lock = self.data.con_state.write().await;
+            write_lock.reset_transaction();
+            if let State::ReceivingChunks(chunks) = &mut write_lock.state {
+                write_lock.state = match chunks.username.take() {
+                    Some(username) => State::Authenticated(username),
+                    None => State::NotAuthenticated,
+                };
+            }
+        };
         lines.feed(String::from("250 OK")).await?;
         lines.flush().await?;
         Ok(())
@@ -0,0 +1,198 @@
+use bytes::{Buf, Bytes, BytesMut};
+use erooster_core::line_codec::{LinesCodec, LinesCodecError};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Something the client sent
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// A command
+    Line(String),
+    /// A part of a chunk sent using BDAT (RFC 3030)
+    Chunk(Bytes),
+    /// A part of a message sent using DATA. The dot-stuffing is already removed.
+    Data(Bytes),
+    /// The line with the single dot ending a message sent using DATA
+    DataEnd,
+}
+
+/// Tells the codec how to read what comes next. Shared with the state of the session.
+#[derive(Debug, Default)]
+pub struct ReadMode {
+    /// Bytes of the current BDAT chunk which were not read yet
+    pub raw_bytes: AtomicUsize,
+    /// Whether the client is sending a message using DATA
+    pub data: AtomicBool,
+}
+
+/// Splits what the client sends into lines, except for messages.
+///
+/// Messages are read as bytes so they are stored exactly like the client sent them. The DATA
+/// and BDAT commands switch the codec to reading a message using the [`ReadMode`].
+#[derive(Debug)]
+pub struct SmtpCodec {
+    lines: LinesCodec,
+    mode: Arc<ReadMode>,
+    /// Whether the next byte of a message sent using DATA starts a line
+    line_start: bool,
+}
+
+impl SmtpCodec {
+    pub const fn new(max_length: usize, mode: Arc<ReadMode>) -> Self {
+        Self {
+            lines: LinesCodec::new_with_max_length(max_length),
+            mode,
+            line_start: true,
+        }
+    }
+
+    /// Takes the next part of the chunk from the buffer
+    fn chunk(&self, buf: &mut BytesMut, raw_bytes: usize) -> Option<Frame> {
+        if buf.is_empty() {
+            return None;
+        }
+        let chunk = buf.split_to(raw_bytes.min(buf.len())).freeze();
+        self.mode.raw_bytes.fetch_sub(chunk.len(), Ordering::AcqRel);
+        Some(Frame::Chunk(chunk))
+    }
+
+    /// Takes the next part of the message from the buffer and undoes the dot-stuffing
+    /// (RFC 5321 section 4.5.2)
+    fn data(&mut self, buf: &mut BytesMut) -> Option<Frame> {
+        if self.line_start {
+            for end in [&b".\r\n"[..], &b".\n"[..]] {
+                if buf.starts_with(end) {
+                    buf.advance(end.len());
+                    self.mode.data.store(false, Ordering::Release);
+                    return Some(Frame::DataEnd);
+                }
+            }
+            // Whether the line ends the message is not known yet
+            if b".\r\n".starts_with(buf) {
+                return None;
+            }
+            if buf[0] == b'.' {
+                buf.advance(1);
+            }
+            self.line_start = false;
+        }
+        if buf.is_empty() {
+            return None;
+        }
+        // Long lines are passed on in parts so they don't have to be buffered
+        let end = buf
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(buf.len(), |newline| {
+                self.line_start = true;
+                newline + 1
+            });
+        Some(Frame::Data(buf.split_to(end).freeze()))
+    }
+}
+
+impl Decoder for SmtpCodec {
+    type Item = Frame;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, LinesCodecError> {
+        let raw_bytes = self.mode.raw_bytes.load(Ordering::Acquire);
+        if raw_bytes > 0 {
+            return Ok(self.chunk(buf, raw_bytes));
+        }
+        if self.mode.data.load(Ordering::Acquire) {
+            return Ok(self.data(buf));
+        }
+        Ok(self.lines.decode(buf)?.map(Frame::Line))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, LinesCodecError> {
+        let raw_bytes = self.mode.raw_bytes.load(Ordering::Acquire);
+        if raw_bytes > 0 {
+            return Ok(self.chunk(buf, raw_bytes));
+        }
+        if self.mode.data.load(Ordering::Acquire) {
+            return Ok(self.data(buf));
+        }
+        Ok(self.lines.decode_eof(buf)?.map(Frame::Line))
+    }
+}
+
+impl Encoder<String> for SmtpCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: String, buf: &mut BytesMut) -> Result<(), LinesCodecError> {
+        self.lines.encode(line, buf)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks() {
+        let mode = Arc::new(ReadMode::default());
+        let mut codec = SmtpCodec::new(1024, Arc::clone(&mode));
+        let mut buf = BytesMut::from(&b"BDAT 7 LAST\r\nab\r\n\x00\xffdQUIT\r\n"[..]);
+
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Line(String::from("BDAT 7 LAST")))
+        );
+        // Set by the BDAT command
+        mode.raw_bytes.store(7, Ordering::Release);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Chunk(Bytes::from_static(b"ab\r\n\x00\xffd")))
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Line(String::from("QUIT")))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn test_data() {
+        let mode = Arc::new(ReadMode::default());
+        let mut codec = SmtpCodec::new(1024, Arc::clone(&mode));
+        let mut buf = BytesMut::from(&b"DATA\r\n"[..]);
+
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Line(String::from("DATA")))
+        );
+        // Set by the DATA command
+        mode.data.store(true, Ordering::Release);
+
+        let mut message = Vec::new();
+        for part in [
+            &b"Subject: caf\xe9\r\n\r\n..leading dot\r\nsplit"[..],
+            b" line\r\n.",
+            b"\r\nNOOP\r\n",
+        ] {
+            buf.extend_from_slice(part);
+            while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                match frame {
+                    Frame::Data(data) => message.extend_from_slice(&data),
+                    Frame::DataEnd => break,
+                    frame => panic!("Unexpected frame {frame:?}"),
+                }
+            }
+        }
+        assert_eq!(
+            message,
+            b"Subject: caf\xe9\r\n\r\n.leading dot\r\nsplit line\r\n".to_vec()
+        );
+        assert!(!mode.data.load(Ordering::Acquire));
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Line(String::from("NOOP")))
+        );
+    }
+}
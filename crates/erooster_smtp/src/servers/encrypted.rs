@@ -1,19 +1,26 @@
 use crate::{
     commands::{Data, Response},
-    servers::{send_capabilities, state::Connection},
+    servers::{
+        codec::SmtpCodec,
+        limits::{reject, too_many_connections, ConnectionLimits, SessionPermit},
+        listener_addrs,
+        pipelining::Pipelined,
+        send_capabilities,
+        state::Connection,
+    },
+    utils::dsn::Dsn,
 };
 use color_eyre::eyre::Context;
 use erooster_core::{
     backend::{database::DB, storage::Storage},
-    config::Config,
+    config::{Config, ListenerRole},
     line_codec::LinesCodec,
     LINE_LIMIT,
 };
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
 use std::{
     fs,
     io::{self, BufReader},
-    net::SocketAddr,
     path::Path,
     sync::Arc,
 };
@@ -82,24 +89,16 @@ impl Encrypted {
     /// # Errors
     ///
     /// Returns an error if the cert setup fails
-    #[instrument(skip(config, database, storage))]
+    #[instrument(skip(config, database, storage, limits))]
     pub(crate) async fn run(
         config: Arc<Config>,
         database: DB,
         storage: Arc<Storage>,
+        limits: Arc<ConnectionLimits>,
     ) -> color_eyre::eyre::Result<()> {
         let acceptor = get_tls_acceptor(&config)?;
-        // Opens the listener
-        let addrs: Vec<SocketAddr> = if let Some(listen_ips) = &config.listen_ips {
-            listen_ips
-                .iter()
-                .map(|ip| format!("{ip}:465").parse())
-                .filter_map(Result::ok)
-                .collect()
-        } else {
-            vec!["0.0.0.0:465".parse()?]
-        };
-        for addr in addrs {
+        // Opens the listeners
+        for (addr, role) in listener_addrs(&config, true) {
             info!("[SMTP] Trying to listen on {:?}", addr);
             let listener = TcpListener::bind(addr).await?;
             info!("[SMTP] Listening on ecrypted Port");
@@ -108,6 +107,7 @@ impl Encrypted {
             let database = Arc::clone(&database);
             let storage = Arc::clone(&storage);
             let acceptor = acceptor.clone();
+            let limits = Arc::clone(&limits);
             tokio::spawn(async move {
                 listen(
                     stream,
@@ -115,6 +115,8 @@ impl Encrypted {
                     Arc::clone(&database),
                     Arc::clone(&storage),
                     acceptor.clone(),
+                    limits,
+                    role,
                 )
                 .await;
             });
@@ -124,24 +126,52 @@ impl Encrypted {
     }
 }
 
-#[instrument(skip(stream, config, database, storage, acceptor))]
+#[instrument(skip(stream, config, database, storage, acceptor, limits))]
 async fn listen(
     mut stream: TcpListenerStream,
     config: Arc<Config>,
     database: DB,
     storage: Arc<Storage>,
     acceptor: TlsAcceptor,
+    limits: Arc<ConnectionLimits>,
+    role: ListenerRole,
 ) {
-    // Looks for new peers
-    while let Some(Ok(tcp_stream)) = stream.next().await {
+    // Looks for new peers while there is room for them
+    while let Some(slot) = limits.reserve().await {
+        let Some(Ok(tcp_stream)) = stream.next().await else {
+            break;
+        };
+        let Ok(peer) = tcp_stream.peer_addr() else {
+            continue;
+        };
+        let permit = match limits.admit(slot, peer.ip()) {
+            Ok(permit) => permit,
+            Err(slot) => {
+                info!("[SMTP][TLS] Rejecting {}: Too many connections", peer);
+                let acceptor = acceptor.clone();
+                let config = Arc::clone(&config);
+                tokio::spawn(reject(slot, peer, async move {
+                    let tls_stream = acceptor.accept(tcp_stream).await?;
+                    let mut lines =
+                        Framed::new(tls_stream, LinesCodec::new_with_max_length(LINE_LIMIT));
+                    lines
+                        .send(too_many_connections(&config.mail.hostname))
+                        .await
+                }));
+                continue;
+            }
+        };
+        let data = Data {
+            con_state: Connection::new(true, peer.ip().to_string(), role),
+        };
         if let Err(e) = listen_tls(
             tcp_stream,
             Arc::clone(&config),
             Arc::clone(&database),
             Arc::clone(&storage),
             acceptor.clone(),
-            None,
-            false,
+            data,
+            permit,
         )
         .await
         {
@@ -150,19 +180,23 @@ async fn listen(
     }
 }
 
+/// Runs a TLS session on its own task.
+///
+/// `data` is either a new session or the one of a plaintext connection which used STARTTLS.
 pub async fn listen_tls(
     tcp_stream: TcpStream,
     config: Arc<Config>,
     database: DB,
     storage: Arc<Storage>,
     acceptor: TlsAcceptor,
-    upper_data: Option<Data>,
-    starttls: bool,
+    data: Data,
+    permit: SessionPermit,
 ) -> color_eyre::eyre::Result<()> {
     let peer = tcp_stream
         .peer_addr()
         .context("[SMTP] peer addr to exist")?;
     debug!("[SMTP] Got new TLS peer: {:?}", peer);
+    let starttls = !data.con_state.read().await.secure;
 
     // We need to clone these as we move into a new thread
     let config = Arc::clone(&config);
@@ -171,8 +205,11 @@ pub async fn listen_tls(
 
     // Start talking with new peer on new thread
     tokio::spawn(async move {
-        // Accept TCP connection
-        let tls_stream = acceptor.accept(tcp_stream).await;
+        // Accept TCP connection. A client stalling the handshake only gets as long as it would for a command.
+        let Ok(tls_stream) = permit.limit(acceptor.accept(tcp_stream)).await else {
+            debug!("[SMTP][TLS] [{}] TLS negotiation timed out", peer);
+            return;
+        };
 
         // Continue if it worked
         match tls_stream {
@@ -180,13 +217,21 @@ pub async fn listen_tls(
                 debug!("[SMTP] TLS negotiation done");
 
                 // Proceed as normal
-                let lines = Framed::new(stream, LinesCodec::new_with_max_length(LINE_LIMIT));
+                let read_mode = Arc::clone(&data.con_state.read().await.read_mode);
+                let lines = Framed::new(stream, SmtpCodec::new(LINE_LIMIT, read_mode));
                 // We split these as we handle the sink in a broadcast instead to be able to push non linear data over the socket
-                let (mut lines_sender, mut lines_reader) = lines.split();
+                let (lines_sender, mut lines_reader) = lines.split();
+                let mut lines_sender = Pipelined::new(lines_sender);
 
-                // Create our Connection
-                let connection = Connection::new(true, peer.ip().to_string());
-                if !starttls {
+                if starttls {
+                    // Everything the client told us before has to be forgotten (RFC 3207 section 4.2)
+                    let mut write_lock = data.con_state.write().await;
+                    write_lock.secure = true;
+                    write_lock.ehlo = None;
+                    write_lock.sender = None;
+                    write_lock.receipts = None;
+                    write_lock.dsn = Dsn::default();
+                } else {
                     // Greet the client with the capabilities we provide
                     if let Err(e) = send_capabilities(Arc::clone(&config), &mut lines_sender).await
                     {
@@ -199,27 +244,44 @@ pub async fn listen_tls(
                 }
 
                 // Read lines from the stream
-                while let Some(Ok(line)) = lines_reader.next().await {
-                    let data = if let Some(data) = upper_data.clone() {
-                        {
-                            data.con_state.write().await.secure = true;
-                        };
-                        data
-                    } else {
-                        Data {
-                            con_state: Arc::clone(&connection),
+                loop {
+                    let frame = match lines_reader.next().now_or_never() {
+                        // The client pipelined more commands so the replies can wait
+                        Some(frame) => Ok(frame),
+                        None => {
+                            if let Err(e) = lines_sender.flush_replies().await {
+                                debug!("[SMTP][TLS] [{}] Unable to send replies: {}", peer, e);
+                                break;
+                            }
+                            permit.limit(lines_reader.next()).await
+                        }
+                    };
+                    let frame = match frame {
+                        Ok(Some(Ok(frame))) => frame,
+                        Ok(_) => break,
+                        Err(expired) => {
+                            debug!("[SMTP][TLS] [{}] Session timed out: {:?}", peer, expired);
+                            if let Err(e) = lines_sender
+                                .send(expired.reply(&config.mail.hostname))
+                                .await
+                            {
+                                debug!(
+                                    "[SMTP][TLS] [{}] Unable to send timeout reply: {}",
+                                    peer, e
+                                );
+                            }
+                            break;
                         }
                     };
-                    debug!("[SMTP][TLS] [{}] Got Command: {}", peer, line);
 
                     {
                         let response = data
-                            .parse(
+                            .handle(
                                 &mut lines_sender,
                                 Arc::clone(&config),
                                 Arc::clone(&database),
                                 Arc::clone(&storage),
-                                line,
+                                frame,
                             )
                             .await;
                         match response {
@@ -247,6 +309,12 @@ pub async fn listen_tls(
                         }
                     };
                 }
+                if let Err(e) = lines_sender.flush_replies().await {
+                    debug!(
+                        "[SMTP][TLS] [{}] Unable to send the last replies: {}",
+                        peer, e
+                    );
+                }
             }
             Err(e) => error!("[SMTP][TLS] Got error while accepting TLS: {}", e),
         }
@@ -0,0 +1,212 @@
+//! Limits on how many sessions may be open and for how long they may last
+
+use erooster_core::config::Smtp;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{timeout, timeout_at, Instant},
+};
+use tracing::debug;
+
+/// How long telling a peer about too many sessions may take, including the TLS handshake
+const REJECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps track of the open sessions. Shared by all ports.
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    slots: Arc<Semaphore>,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    max_per_ip: usize,
+    idle_timeout: Duration,
+    session_timeout: Duration,
+}
+
+impl ConnectionLimits {
+    /// Creates the limits as configured
+    #[must_use]
+    pub fn new(config: &Smtp) -> Arc<Self> {
+        Arc::new(Self {
+            slots: Arc::new(Semaphore::new(config.max_connections)),
+            per_ip: Mutex::new(HashMap::new()),
+            max_per_ip: config.max_connections_per_ip,
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            session_timeout: Duration::from_secs(config.session_timeout),
+        })
+    }
+
+    /// Waits until there is room for another session.
+    ///
+    /// Listeners call this before accepting so clients queue up in the backlog of the socket
+    /// while the server is full.
+    pub async fn reserve(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.slots).acquire_owned().await.ok()
+    }
+
+    /// Takes the reserved slot for a session of the peer.
+    ///
+    /// # Errors
+    ///
+    /// Hands the slot back if the peer already has too many sessions open. It stays taken
+    /// until the peer got told so.
+    pub fn admit(
+        self: &Arc<Self>,
+        slot: OwnedSemaphorePermit,
+        ip: IpAddr,
+    ) -> Result<SessionPermit, OwnedSemaphorePermit> {
+        let mut per_ip = self.per_ip.lock().unwrap_or_else(PoisonError::into_inner);
+        let open = per_ip.entry(ip).or_default();
+        if *open >= self.max_per_ip {
+            return Err(slot);
+        }
+        *open += 1;
+        Ok(SessionPermit {
+            _slot: slot,
+            limits: Arc::clone(self),
+            ip,
+            deadline: Instant::now() + self.session_timeout,
+        })
+    }
+}
+
+/// Allows a single session to run. The slot is freed again once it gets dropped.
+///
+/// It stays with the session when it switches to TLS using STARTTLS.
+#[derive(Debug)]
+pub struct SessionPermit {
+    _slot: OwnedSemaphorePermit,
+    limits: Arc<ConnectionLimits>,
+    ip: IpAddr,
+    /// When the session gets closed no matter what
+    deadline: Instant,
+}
+
+impl SessionPermit {
+    /// Waits for the client within the timeouts of RFC 5321 section 4.5.3.2.
+    ///
+    /// Each wait is limited by the idle timeout and by the time left for the session.
+    ///
+    /// # Errors
+    ///
+    /// Returns which timeout ran out if the client took too long
+    pub async fn limit<F: Future>(&self, future: F) -> Result<F::Output, Expired> {
+        let idle_deadline = Instant::now() + self.limits.idle_timeout;
+        match timeout_at(idle_deadline.min(self.deadline), future).await {
+            Ok(output) => Ok(output),
+            Err(_) if idle_deadline < self.deadline => Err(Expired::Idle),
+            Err(_) => Err(Expired::Session),
+        }
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut per_ip = self
+            .limits
+            .per_ip
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(open) = per_ip.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The timeout which ended a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expired {
+    /// The client didn't send a command for too long
+    Idle,
+    /// The session lasted too long overall
+    Session,
+}
+
+impl Expired {
+    /// The reply telling the client that the session is over
+    #[must_use]
+    pub fn reply(self, hostname: &str) -> String {
+        match self {
+            Expired::Idle => format!("421 4.4.2 {hostname} Timeout waiting for command"),
+            Expired::Session => format!("421 4.4.2 {hostname} Session took too long"),
+        }
+    }
+}
+
+/// The reply sent to peers which have too many sessions open already
+#[must_use]
+pub fn too_many_connections(hostname: &str) -> String {
+    format!("421 4.7.0 {hostname} Too many connections from your address, try again later")
+}
+
+/// Tells a peer with too many sessions that it has to come back later.
+///
+/// The slot is held until then so rejected peers can't open more connections than there are
+/// slots. Peers which don't take the reply in time only get their connection closed.
+pub async fn reject<F, E>(slot: OwnedSemaphorePermit, peer: SocketAddr, reply: F)
+where
+    F: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    match timeout(REJECT_TIMEOUT, reply).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("[SMTP] Unable to reject {}: {}", peer, e),
+        Err(_) => debug!("[SMTP] Rejecting {} timed out", peer),
+    }
+    drop(slot);
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_per_ip_limit() {
+        let limits = ConnectionLimits::new(&Smtp {
+            max_connections: 3,
+            max_connections_per_ip: 1,
+            ..Smtp::default()
+        });
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = limits.admit(limits.reserve().await.unwrap(), peer);
+        assert!(first.is_ok());
+        assert!(limits.admit(limits.reserve().await.unwrap(), peer).is_err());
+        assert!(limits.admit(limits.reserve().await.unwrap(), other).is_ok());
+
+        drop(first);
+        assert!(limits.admit(limits.reserve().await.unwrap(), peer).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_peer_keeps_slot() {
+        let limits = ConnectionLimits::new(&Smtp {
+            max_connections: 2,
+            max_connections_per_ip: 1,
+            ..Smtp::default()
+        });
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let _first = limits.admit(limits.reserve().await.unwrap(), peer).unwrap();
+        let rejected = limits
+            .admit(limits.reserve().await.unwrap(), peer)
+            .unwrap_err();
+        assert_eq!(limits.slots.available_permits(), 0);
+
+        reject(rejected, SocketAddr::new(peer, 25), async {
+            Ok::<(), std::io::Error>(())
+        })
+        .await;
+        assert_eq!(limits.slots.available_permits(), 1);
+    }
+}
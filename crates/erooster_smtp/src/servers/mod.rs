@@ -1,18 +1,21 @@
-use crate::servers::sending::send_email_job;
+use crate::servers::{limits::ConnectionLimits, sending::send_email_job};
 use erooster_core::{
     backend::{
         database::{Database, DB},
         storage::Storage,
     },
-    config::Config,
+    config::{Config, ListenerRole},
 };
 use futures::{Sink, SinkExt};
 use sqlxmq::{JobRegistry, JobRunnerHandle};
 use std::error::Error;
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 use tracing::instrument;
 
+pub(crate) mod codec;
 pub(crate) mod encrypted;
+pub mod limits;
+pub(crate) mod pipelining;
 pub(crate) mod sending;
 pub(crate) mod state;
 
@@ -34,6 +37,30 @@ where
     Ok(())
 }
 
+/// The addresses of the configured listeners together with their role.
+///
+/// `tls` selects the listeners using implicit TLS instead of the ones using STARTTLS.
+pub(crate) fn listener_addrs(config: &Config, tls: bool) -> Vec<(SocketAddr, ListenerRole)> {
+    let ips: Vec<&str> = config.listen_ips.as_ref().map_or_else(
+        || vec!["0.0.0.0"],
+        |listen_ips| listen_ips.iter().map(String::as_str).collect(),
+    );
+    config
+        .smtp
+        .listeners
+        .iter()
+        .filter(|listener| listener.tls == tls)
+        .flat_map(|listener| {
+            ips.iter().filter_map(move |ip| {
+                format!("{ip}:{}", listener.port)
+                    .parse()
+                    .ok()
+                    .map(|addr| (addr, listener.role))
+            })
+        })
+        .collect()
+}
+
 /// Starts the smtp server
 ///
 /// # Errors
@@ -45,26 +72,33 @@ pub async fn start(
     database: DB,
     storage: Arc<Storage>,
 ) -> color_eyre::eyre::Result<JobRunnerHandle> {
+    // Shared by all ports so the limits apply to the server as a whole
+    let limits = ConnectionLimits::new(&config.smtp);
     let config_clone = Arc::clone(&config);
     let db_clone = Arc::clone(&database);
     let storage_clone = Arc::clone(&storage);
+    let limits_clone = Arc::clone(&limits);
     tokio::spawn(async move {
         if let Err(e) = unencrypted::Unencrypted::run(
             Arc::clone(&config_clone),
             Arc::clone(&db_clone),
             Arc::clone(&storage_clone),
+            limits_clone,
         )
         .await
         {
             panic!("Unable to start server: {e:?}");
         }
     });
+    let config_clone = Arc::clone(&config);
     let db_clone = Arc::clone(&database);
+    let storage_clone = Arc::clone(&storage);
     tokio::spawn(async move {
         if let Err(e) = encrypted::Encrypted::run(
-            Arc::clone(&config),
+            Arc::clone(&config_clone),
             Arc::clone(&db_clone),
-            Arc::clone(&storage),
+            Arc::clone(&storage_clone),
+            limits,
         )
         .await
         {
@@ -81,8 +115,10 @@ pub async fn start(
         tracing::error!("Job `{}` failed: {}", name, error);
     });
 
-    // And add context
-    registry.set_context("");
+    // And add context. Reports about the delivery get stored in the mailbox of the sender.
+    registry.set_context(config);
+    registry.set_context(Arc::clone(&database));
+    registry.set_context(storage);
 
     let runner = registry
         // Create a job runner using the connection pool.
@@ -0,0 +1,84 @@
+//! Replies to commands the client pipelined (RFC 2920)
+
+use futures::{Sink, SinkExt};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Holds back the replies until the client waits for them.
+///
+/// Commands send their replies like they would without pipelining. Flushing is up to the
+/// session which does so once it has read every command the client sent so far.
+#[derive(Debug)]
+pub(crate) struct Pipelined<S> {
+    inner: S,
+}
+
+impl<S> Pipelined<S> {
+    pub(crate) const fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub(crate) fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> Pipelined<S>
+where
+    S: Sink<String> + Unpin,
+{
+    /// Sends the replies held back so far
+    pub(crate) async fn flush_replies(&mut self) -> Result<(), S::Error> {
+        self.inner.flush().await
+    }
+}
+
+impl<S> Sink<String> for Pipelined<S>
+where
+    S: Sink<String> + Unpin,
+{
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: String) -> Result<(), S::Error> {
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        // Done by the session at the next synchronization point
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use erooster_core::line_codec::LinesCodec;
+    use futures::{FutureExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn test_replies_wait_for_flush() {
+        let (server, client) = tokio::io::duplex(1024);
+        let mut replies = Pipelined::new(Framed::new(server, LinesCodec::new()));
+        let mut client = Framed::new(client, LinesCodec::new());
+
+        replies.send(String::from("250 2.1.0 Ok")).await.unwrap();
+        replies.send(String::from("250 2.1.5 Ok")).await.unwrap();
+        assert!(client.next().now_or_never().is_none());
+
+        replies.flush_replies().await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), "250 2.1.0 Ok");
+        assert_eq!(client.next().await.unwrap().unwrap(), "250 2.1.5 Ok");
+    }
+}
@@ -1,61 +1,239 @@
-use color_eyre::{eyre::Context, Result};
-use erooster_core::line_codec::{LinesCodec, LinesCodecError};
+use crate::utils::{
+    delivery::{delivery_headers, store_in_inbox},
+    dsn::{delivery_report, Action, Dsn, RecipientReport, Report},
+};
+use bytes::Bytes;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use erooster_core::{
+    backend::{
+        database::{Database, DB},
+        storage::Storage,
+    },
+    config::{Config, Smtp},
+    line_codec::{LinesCodec, LinesCodecError},
+};
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use mail_auth::{common::headers::HeaderWriter, dkim::Signature, PrivateKey};
 use rustls::OwnedTrustAnchor;
 use serde::{Deserialize, Serialize};
 use sqlxmq::{job, CurrentJob};
 use std::{
-    collections::BTreeMap, error::Error, io, net::IpAddr, path::Path, sync::Arc, time::Duration,
+    borrow::Cow, collections::BTreeMap, error::Error, fmt, io, net::IpAddr, path::Path, sync::Arc,
+    time::Duration,
 };
+use time::OffsetDateTime;
 use tokio::{net::TcpStream, time::timeout};
 use tokio_rustls::TlsConnector;
 use tokio_util::codec::Framed;
 use tracing::{debug, error, instrument, warn};
-use trust_dns_resolver::TokioAsyncResolver;
+use trust_dns_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EmailPayload {
     // Map to addresses by domain
     pub to: BTreeMap<String, Vec<String>>,
     pub from: String,
-    pub body: String,
+    /// The message exactly as it was received. Stored as base64 as it doesn't need to be UTF-8.
+    #[serde(rename = "body_base64", with = "base64_body", default)]
+    pub body: Vec<u8>,
+    /// The message of jobs queued before it was stored as bytes
+    #[serde(rename = "body", default, skip_serializing)]
+    pub legacy_body: Option<String>,
     pub sender_domain: String,
     pub dkim_key_path: String,
     pub dkim_key_selector: String,
+    /// The body carries our DKIM signature already
+    #[serde(default)]
+    pub signed: bool,
+    /// What the sender wants to hear about the delivery
+    #[serde(default)]
+    pub dsn: Dsn,
+    /// Unix timestamp of when the mail was accepted
+    #[serde(default = "unix_now")]
+    pub queued_at: i64,
+    /// Whether the sender was already told that the delivery takes longer
+    #[serde(default)]
+    pub delay_warned: bool,
+}
+
+fn unix_now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// Stores the message of the payload as a base64 string in the JSON of the job
+mod base64_body {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(encoded).map_err(serde::de::Error::custom)
+    }
 }
 
-fn dkim_sign(
+/// Prepends the DKIM signature of our domain to the email
+pub fn dkim_sign(
     domain: &str,
-    raw_email: &str,
+    raw_email: &[u8],
     dkim_key_path: &str,
     dkim_key_selector: &str,
-) -> Result<String> {
-    let private_key = std::fs::read_to_string(Path::new(&dkim_key_path))?;
-    let pk_rsa = PrivateKey::from_rsa_pkcs1_pem(&private_key).expect("Failed to load private key");
+) -> Result<Vec<u8>> {
+    let private_key = std::fs::read_to_string(Path::new(&dkim_key_path))
+        .wrap_err_with(|| format!("Failed to read DKIM key {dkim_key_path}"))?;
+    let pk_rsa = PrivateKey::from_rsa_pkcs1_pem(&private_key)
+        .map_err(|e| eyre!("Failed to load private key: {}", e))?;
     let signature_rsa = Signature::new()
         .headers(["From", "To", "Subject"])
         .domain(domain)
         .selector(dkim_key_selector)
-        .sign(raw_email.as_bytes(), &pk_rsa)
-        .expect("Failed to sign email");
+        .sign(raw_email, &pk_rsa)
+        .map_err(|e| eyre!("Failed to sign email: {}", e))?;
+
+    let mut signed = signature_rsa.to_header().into_bytes();
+    signed.extend_from_slice(raw_email);
+    Ok(signed)
+}
+
+/// Doubles the dot at the start of lines so the receiver doesn't take them for the end of the
+/// message (RFC 5321 section 4.5.2)
+fn dot_stuff(body: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(body.len());
+    for line in body.split_inclusive(|byte| *byte == b'\n') {
+        if line.starts_with(b".") {
+            stuffed.push(b'.');
+        }
+        stuffed.extend_from_slice(line);
+    }
+    stuffed
+}
+
+/// The server of the recipients did not reply as expected
+#[derive(Debug)]
+struct UnexpectedReply {
+    /// What we were trying to do
+    context: &'static str,
+    reply: String,
+}
+
+impl UnexpectedReply {
+    /// Replies starting with 5 won't change when trying again (RFC 5321 section 4.2.1)
+    fn is_permanent(&self) -> bool {
+        self.reply.starts_with('5')
+    }
+}
 
-    Ok(format!("{}{raw_email}", signature_rsa.to_header()))
+impl fmt::Display for UnexpectedReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.reply)
+    }
+}
+
+impl Error for UnexpectedReply {}
+
+/// The domain of the recipients doesn't exist or has no address to deliver mail to
+/// (RFC 5321 section 5.1)
+#[derive(Debug)]
+struct NoMailServer {
+    domain: String,
+}
+
+impl fmt::Display for NoMailServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No mail server found for {}", self.domain)
+    }
+}
+
+impl Error for NoMailServer {}
+
+/// Why the mail could not be handed to the server of the recipients
+#[derive(Debug, PartialEq, Eq)]
+struct Failure {
+    reason: String,
+    /// The reply of the server if it sent one
+    reply: Option<String>,
+    /// The status code if we found out about the problem ourselves
+    status: Option<&'static str>,
+    /// Trying again won't help
+    permanent: bool,
+}
+
+impl Failure {
+    fn new(error: &(dyn Error + Send + Sync + 'static)) -> Self {
+        if let Some(unexpected) = error.downcast_ref::<UnexpectedReply>() {
+            Self {
+                reason: unexpected.to_string(),
+                reply: Some(unexpected.reply.clone()),
+                status: None,
+                permanent: unexpected.is_permanent(),
+            }
+        } else if error.is::<NoMailServer>() {
+            Self {
+                reason: error.to_string(),
+                reply: None,
+                // Bad destination system address (RFC 3463 section 3.2)
+                status: Some("5.1.2"),
+                permanent: true,
+            }
+        } else {
+            Self {
+                reason: error.to_string(),
+                reply: None,
+                status: None,
+                permanent: false,
+            }
+        }
+    }
+}
+
+/// When a queued mail arrived and what happens to the recipients which were not reached yet
+#[derive(Debug, PartialEq, Eq)]
+struct Deadlines {
+    arrival_date: OffsetDateTime,
+    retry_until: OffsetDateTime,
+    /// The mail was retried for long enough
+    give_up: bool,
+    /// The sender has to be told that the delivery takes longer
+    warn_delay: bool,
+}
+
+impl Deadlines {
+    fn new(email: &EmailPayload, config: &Smtp, now: OffsetDateTime) -> Result<Self> {
+        let arrival_date = OffsetDateTime::from_unix_timestamp(email.queued_at)?;
+        let retry_until = arrival_date + Duration::from_secs(config.queue_lifetime);
+        Ok(Self {
+            arrival_date,
+            retry_until,
+            give_up: now >= retry_until,
+            warn_delay: !email.delay_warned
+                && now >= arrival_date + Duration::from_secs(config.delay_warning),
+        })
+    }
 }
 
 #[allow(clippy::too_many_lines)]
 #[instrument(skip(con, email, current_job, to))]
 async fn send_email<T>(
-    con: T,
+    mut con: T,
     email: &EmailPayload,
     current_job: &CurrentJob,
     to: &Vec<String>,
     tls: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync + 'static>>
 where
-    T: Stream<Item = Result<String, LinesCodecError>> + Sink<String, Error = LinesCodecError>,
+    T: Stream<Item = Result<String, LinesCodecError>>
+        + Sink<String, Error = LinesCodecError>
+        + Sink<Bytes, Error = LinesCodecError>
+        + Unpin,
 {
-    let (mut lines_sender, mut lines_reader) = con.split();
     debug!(
         "[{}] [{}] Fully Connected. Waiting for response",
         current_job.id(),
@@ -63,7 +241,7 @@ where
     );
     // TODO this is totally dumb code currently.
     // We check if we get a ready status
-    let first = lines_reader
+    let first = con
         .next()
         .await
         .ok_or("Server did not send ready status")??;
@@ -75,23 +253,24 @@ where
         first
     );
     if !first.starts_with("220") {
-        lines_sender.send(String::from("RSET")).await?;
-        lines_sender.send(String::from("QUIT")).await?;
+        con.send(String::from("RSET")).await?;
+        con.send(String::from("QUIT")).await?;
         debug!(
             "[{}] [{}] Got full {:?}",
             current_job.id(),
             if tls { "TLS" } else { "Plain" },
-            lines_reader
-                .filter_map(|x| async move { x.ok() })
+            con.filter_map(|x| async move { x.ok() })
                 .collect::<Vec<String>>()
                 .await
         );
-        return Err("Server did not send ready status".into());
+        return Err(UnexpectedReply {
+            context: "Server did not send ready status",
+            reply: first,
+        }
+        .into());
     }
     // We send EHLO
-    lines_sender
-        .send(format!("EHLO {}", email.sender_domain))
-        .await?;
+    con.send(format!("EHLO {}", email.sender_domain)).await?;
 
     debug!(
         "[{}] [{}] Sent EHLO",
@@ -101,10 +280,7 @@ where
     // Check if we get greeted and finished all caps
     let mut capabilities_happening = true;
     while capabilities_happening {
-        let line = lines_reader
-            .next()
-            .await
-            .ok_or("Server did not respond")??;
+        let line = con.next().await.ok_or("Server did not respond")??;
         debug!(
             "[{}] [{}] Got: {}",
             current_job.id(),
@@ -121,18 +297,13 @@ where
     }
 
     // We send MAIL FROM
-    lines_sender
-        .send(format!("MAIL FROM:<{}>", email.from))
-        .await?;
+    con.send(format!("MAIL FROM:<{}>", email.from)).await?;
     debug!(
         "[{}] [{}] Sent MAIL FROM",
         current_job.id(),
         if tls { "TLS" } else { "Plain" },
     );
-    let line = lines_reader
-        .next()
-        .await
-        .ok_or("Server did not respond")??;
+    let line = con.next().await.ok_or("Server did not respond")??;
     debug!(
         "[{}] [{}] got {}",
         current_job.id(),
@@ -140,33 +311,33 @@ where
         line
     );
     if !line.starts_with("250") {
-        lines_sender.send(String::from("RSET")).await?;
-        lines_sender.send(String::from("QUIT")).await?;
+        con.send(String::from("RSET")).await?;
+        con.send(String::from("QUIT")).await?;
         debug!(
             "[{}] [{}] Got full {:?}",
             current_job.id(),
             if tls { "TLS" } else { "Plain" },
-            lines_reader
-                .filter_map(|x| async move { x.ok() })
+            con.filter_map(|x| async move { x.ok() })
                 .collect::<Vec<String>>()
                 .await
         );
-        return Err("Server did not accept MAIL FROM command".into());
+        return Err(UnexpectedReply {
+            context: "Server did not accept MAIL FROM command",
+            reply: line,
+        }
+        .into());
     }
 
     // We send RCPT TO
     // TODO actually follow spec here. This may be garbage :P
     for to in to {
-        lines_sender.send(format!("RCPT TO:<{to}>")).await?;
+        con.send(format!("RCPT TO:<{to}>")).await?;
         debug!(
             "[{}] [{}] Sent RCPT TO",
             current_job.id(),
             if tls { "TLS" } else { "Plain" },
         );
-        let line = lines_reader
-            .next()
-            .await
-            .ok_or("Server did not respond")??;
+        let line = con.next().await.ok_or("Server did not respond")??;
         debug!(
             "[{}] [{}] Got {}",
             current_job.id(),
@@ -174,33 +345,33 @@ where
             line
         );
         if !line.starts_with("250") && !line.starts_with("550 No such user here") {
-            lines_sender.send(String::from("RSET")).await?;
-            lines_sender.send(String::from("QUIT")).await?;
+            con.send(String::from("RSET")).await?;
+            con.send(String::from("QUIT")).await?;
             debug!(
                 "[{}] [{}] Got full {:?}",
                 current_job.id(),
                 if tls { "TLS" } else { "Plain" },
-                lines_reader
-                    .filter_map(|x| async move { x.ok() })
+                con.filter_map(|x| async move { x.ok() })
                     .collect::<Vec<String>>()
                     .await
             );
-            return Err("Server did not accept RCPT TO command".into());
+            return Err(UnexpectedReply {
+                context: "Server did not accept RCPT TO command",
+                reply: line,
+            }
+            .into());
         }
     }
 
     // Send the body
-    lines_sender.send(String::from("DATA")).await?;
+    con.send(String::from("DATA")).await?;
     debug!(
         "[{}] [{}] Sent DATA",
         current_job.id(),
         if tls { "TLS" } else { "Plain" },
     );
 
-    let line = lines_reader
-        .next()
-        .await
-        .ok_or("Server did not respond")??;
+    let line = con.next().await.ok_or("Server did not respond")??;
     debug!(
         "[{}] [{}] Got {}",
         current_job.id(),
@@ -208,70 +379,89 @@ where
         line
     );
     if !line.starts_with("354") {
-        lines_sender.send(String::from("RSET")).await?;
-        lines_sender.send(String::from("QUIT")).await?;
+        con.send(String::from("RSET")).await?;
+        con.send(String::from("QUIT")).await?;
 
         debug!(
             "[{}] [{}] Got full {:?}",
             current_job.id(),
             if tls { "TLS" } else { "Plain" },
-            lines_reader
-                .filter_map(|x| async move { x.ok() })
+            con.filter_map(|x| async move { x.ok() })
                 .collect::<Vec<String>>()
                 .await
         );
-        return Err("Server did not accept data start command".into());
+        return Err(UnexpectedReply {
+            context: "Server did not accept data start command",
+            reply: line,
+        }
+        .into());
     }
 
-    let signed_body = dkim_sign(
-        &email.sender_domain,
-        &email.body,
-        &email.dkim_key_path,
-        &email.dkim_key_selector,
-    )?;
-    lines_sender.send(signed_body).await?;
-    lines_sender.send(String::from(".")).await?;
+    // Jobs queued by older versions still have to be signed
+    let signed_body = if email.signed {
+        Cow::Borrowed(&email.body)
+    } else {
+        Cow::Owned(dkim_sign(
+            &email.sender_domain,
+            &email.body,
+            &email.dkim_key_path,
+            &email.dkim_key_selector,
+        )?)
+    };
+    // The message is sent as is so it doesn't need to be valid UTF-8
+    let mut stuffed_body = dot_stuff(&signed_body);
+    if !stuffed_body.ends_with(b"\r\n") {
+        stuffed_body.extend_from_slice(b"\r\n");
+    }
+    con.send(Bytes::from(stuffed_body)).await?;
+    con.send(String::from(".")).await?;
     debug!(
         "[{}] [{}] Sent body and ending",
         current_job.id(),
         if tls { "TLS" } else { "Plain" },
     );
 
-    let line = lines_reader
-        .next()
-        .await
-        .ok_or("Server did not respond")??;
+    let line = con.next().await.ok_or("Server did not respond")??;
     debug!("[{}] Got {}", current_job.id(), line);
     if !line.starts_with("250") {
-        lines_sender.send(String::from("RSET")).await?;
-        lines_sender.send(String::from("QUIT")).await?;
+        con.send(String::from("RSET")).await?;
+        con.send(String::from("QUIT")).await?;
         debug!(
             "[{}] [{}] Got full {:?}",
             current_job.id(),
             if tls { "TLS" } else { "Plain" },
-            lines_reader
-                .filter_map(|x| async move { x.ok() })
+            con.filter_map(|x| async move { x.ok() })
                 .collect::<Vec<String>>()
                 .await
         );
-        return Err("Server did not accept data command".into());
+        return Err(UnexpectedReply {
+            context: "Server did not accept data command",
+            reply: line,
+        }
+        .into());
     }
 
     // QUIT after sending
-    lines_sender.send(String::from("QUIT")).await?;
+    con.send(String::from("QUIT")).await?;
     Ok(())
 }
 
-// Note this is a hack to get max retries. Please fix this
-#[job(retries = 4294967295, backoff_secs = 1200)]
+/// How long to wait before trying to send mail again
+const RETRY_DELAY: Duration = Duration::from_secs(1200);
+
+// Failed deliveries are retried by queueing a new job for the recipients which were not reached.
+// Retrying the job itself would send the mail to everyone again.
+#[job(retries = 0)]
 #[allow(clippy::too_many_lines)]
-#[instrument(skip(current_job, _message))]
+#[instrument(skip(current_job, config, database, storage))]
 pub async fn send_email_job(
     // The first argument should always be the current job.
     mut current_job: CurrentJob,
     // Additional arguments are optional, but can be used to access context
     // provided via [`JobRegistry::set_context`].
-    _message: &'static str,
+    config: Arc<Config>,
+    database: DB,
+    storage: Arc<Storage>,
 ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     debug!(
         "[{}] Starting to send email job: {}",
@@ -280,163 +470,328 @@ pub async fn send_email_job(
     );
     // Decode a JSON payload
     let email: Option<EmailPayload> = current_job.json()?;
-    if let Some(email) = email {
+    if let Some(mut email) = email {
+        if let Some(legacy_body) = email.legacy_body.take() {
+            email.body = legacy_body.into_bytes();
+        }
         debug!("[{}] Found payload", current_job.id());
         let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
 
         debug!("[{}] Setup for tls connection done", current_job.id());
+        let mut relayed = Vec::new();
+        let mut failed = Vec::new();
+        let mut deferred: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut delayed = Vec::new();
         for (target, to) in &email.to {
+            let failure = match send_to_domain(&resolver, &email, &current_job, target, to).await {
+                Ok(()) => {
+                    relayed.extend(to.iter().map(|address| RecipientReport {
+                        address: address.clone(),
+                        orcpt: None,
+                        reason: String::from("Handed to the server of the recipient"),
+                        reply: None,
+                        status: None,
+                    }));
+                    continue;
+                }
+                Err(e) => Failure::new(e.as_ref()),
+            };
+            warn!(
+                "[{}] Unable to send email to {}: {}",
+                current_job.id(),
+                target,
+                failure.reason
+            );
+            let reports = to.iter().map(|address| RecipientReport {
+                address: address.clone(),
+                orcpt: None,
+                reason: failure.reason.clone(),
+                reply: failure.reply.clone(),
+                status: failure.status,
+            });
+            if failure.permanent {
+                failed.extend(reports);
+            } else {
+                delayed.extend(reports);
+                deferred.insert(target.clone(), to.clone());
+            }
+        }
+
+        let Deadlines {
+            arrival_date,
+            retry_until,
+            give_up,
+            warn_delay,
+        } = Deadlines::new(&email, &config.smtp, OffsetDateTime::now_utc())?;
+        if give_up {
+            debug!("[{}] Giving up on {:?}", current_job.id(), deferred);
+            failed.append(&mut delayed);
+            deferred.clear();
+        }
+
+        let mut reports = vec![(Action::Relayed, &relayed), (Action::Failed, &failed)];
+        if warn_delay {
+            reports.push((Action::Delayed, &delayed));
+        }
+        for (action, recipients) in reports {
+            let report = Report {
+                action,
+                hostname: &config.mail.hostname,
+                sender: &email.from,
+                envid: email.dsn.envid.as_deref(),
+                ret: email.dsn.ret,
+                arrival_date,
+                retry_until,
+                recipients,
+                message: &email.body,
+            };
+            // The mail itself was dealt with so the job must not be repeated because of this
+            if let Err(e) =
+                notify_sender(&config, &database, &storage, &email, &current_job, report).await
+            {
+                error!(
+                    "[{}] Unable to store delivery report: {}",
+                    current_job.id(),
+                    e
+                );
+            }
+        }
+
+        // The retry is queued together with finishing this job so the mail is neither lost nor
+        // sent twice
+        let mut transaction = database.get_pool().begin().await?;
+        if !deferred.is_empty() {
             debug!(
-                "[{}] Looking up mx records for {}",
+                "[{}] Retrying {:?} in {:?}",
                 current_job.id(),
-                target
+                deferred,
+                RETRY_DELAY
             );
-            let mx_record_resp = resolver.mx_lookup(target.clone()).await;
+            let retry = EmailPayload {
+                to: deferred,
+                delay_warned: email.delay_warned || warn_delay,
+                ..email
+            };
+            send_email_job
+                .builder()
+                .set_json(&retry)?
+                .set_delay(RETRY_DELAY)
+                .spawn(&mut transaction)
+                .await?;
+        }
+        // Mark the job as complete
+        current_job
+            .complete_with_transaction(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        debug!(
+            "[{}] Finished sending email job: {}",
+            current_job.id(),
+            current_job.id()
+        );
+    } else {
+        debug!("[{}] Something broken", current_job.id());
+        return Err("No email payload found".into());
+    }
+
+    Ok(())
+}
+
+/// Whether the lookup failed for another reason than the name having no such records.
+///
+/// Names which don't exist at all (NXDOMAIN) have no records either.
+fn lookup_failed<T>(response: &Result<T, ResolveError>) -> bool {
+    response.as_ref().err().map_or(false, |error| {
+        !matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. })
+    })
+}
+
+/// Sends the mail to the server responsible for the domain of the recipients
+#[allow(clippy::too_many_lines)]
+#[instrument(skip(resolver, email, current_job, to))]
+async fn send_to_domain(
+    resolver: &TokioAsyncResolver,
+    email: &EmailPayload,
+    current_job: &CurrentJob,
+    target: &str,
+    to: &Vec<String>,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    debug!(
+        "[{}] Looking up mx records for {}",
+        current_job.id(),
+        target
+    );
+    let mx_record_resp = resolver.mx_lookup(target).await;
+    // Without a working resolver we can't tell if the domain has a mail server
+    let mut resolver_failed = lookup_failed(&mx_record_resp);
 
+    debug!(
+        "[{}] Looking up IP records for {}",
+        current_job.id(),
+        target
+    );
+    let mut address: Option<IpAddr> = None;
+    let mut tls_domain: String = target.to_string();
+    let response = resolver.ipv6_lookup(target).await;
+    resolver_failed |= lookup_failed(&response);
+    if let Ok(response) = response {
+        address = Some(IpAddr::V6(
+            *response.iter().next().ok_or("No address found")?,
+        ));
+        debug!("[{}] Got {:?} for {}", current_job.id(), address, target);
+    } else {
+        debug!("[{}] Looking up A records for {}", current_job.id(), target);
+        let response = resolver.ipv4_lookup(target).await;
+        resolver_failed |= lookup_failed(&response);
+        if let Ok(response) = response {
+            address = Some(IpAddr::V4(
+                *response.iter().next().ok_or("No address found")?,
+            ));
+            debug!("[{}] Got {:?} for {}", current_job.id(), address, target);
+        }
+    }
+
+    debug!(
+        "[{}] Checking mx record results for {}",
+        current_job.id(),
+        target
+    );
+    if let Ok(mx_record_resp) = mx_record_resp {
+        for record in mx_record_resp {
             debug!(
-                "[{}] Looking up IP records for {}",
+                "[{}] Found MX: {} {}",
                 current_job.id(),
-                target
+                record.preference(),
+                record.exchange()
             );
-            let mut address: Option<IpAddr> = None;
-            let mut tls_domain: String = target.to_string();
-            let response = resolver.ipv6_lookup(target.clone()).await;
+            let response = resolver.ipv6_lookup(record.exchange().clone()).await;
+            resolver_failed |= lookup_failed(&response);
             if let Ok(response) = response {
                 address = Some(IpAddr::V6(
                     *response.iter().next().ok_or("No address found")?,
                 ));
+                let exchange_record = record.exchange().to_utf8();
+                tls_domain = if let Some(record) = exchange_record.strip_suffix('.') {
+                    record.to_string()
+                } else {
+                    exchange_record
+                };
                 debug!("[{}] Got {:?} for {}", current_job.id(), address, target);
-            } else {
-                debug!("[{}] Looking up A records for {}", current_job.id(), target);
-                let response = resolver.ipv4_lookup(target.clone()).await;
-                if let Ok(response) = response {
-                    address = Some(IpAddr::V4(
-                        *response.iter().next().ok_or("No address found")?,
-                    ));
-                    debug!("[{}] Got {:?} for {}", current_job.id(), address, target);
-                }
+                break;
             }
 
-            debug!(
-                "[{}] Checking mx record results for {}",
-                current_job.id(),
-                target
-            );
-            if let Ok(mx_record_resp) = mx_record_resp {
-                for record in mx_record_resp {
-                    debug!(
-                        "[{}] Found MX: {} {}",
-                        current_job.id(),
-                        record.preference(),
-                        record.exchange()
-                    );
-                    let response = resolver.ipv6_lookup(record.exchange().clone()).await;
-                    if let Ok(response) = response {
-                        address = Some(IpAddr::V6(
-                            *response.iter().next().ok_or("No address found")?,
-                        ));
-                        let exchange_record = record.exchange().to_utf8();
-                        tls_domain = if let Some(record) = exchange_record.strip_suffix('.') {
-                            record.to_string()
-                        } else {
-                            exchange_record
-                        };
-                        debug!("[{}] Got {:?} for {}", current_job.id(), address, target);
-                        break;
-                    }
-
-                    debug!("[{}] Looking up A records for {}", current_job.id(), target);
-                    let response = resolver.ipv4_lookup(record.exchange().clone()).await;
-                    if let Ok(response) = response {
-                        address = Some(IpAddr::V4(
-                            *response.iter().next().ok_or("No address found")?,
-                        ));
-                        let exchange_record = record.exchange().to_utf8();
-                        tls_domain = if let Some(record) = exchange_record.strip_suffix('.') {
-                            record.to_string()
-                        } else {
-                            exchange_record
-                        };
-                        debug!("[{}] Got {:?} for {}", current_job.id(), address, target);
-                        break;
-                    }
-                }
+            debug!("[{}] Looking up A records for {}", current_job.id(), target);
+            let response = resolver.ipv4_lookup(record.exchange().clone()).await;
+            resolver_failed |= lookup_failed(&response);
+            if let Ok(response) = response {
+                address = Some(IpAddr::V4(
+                    *response.iter().next().ok_or("No address found")?,
+                ));
+                let exchange_record = record.exchange().to_utf8();
+                tls_domain = if let Some(record) = exchange_record.strip_suffix('.') {
+                    record.to_string()
+                } else {
+                    exchange_record
+                };
+                debug!("[{}] Got {:?} for {}", current_job.id(), address, target);
+                break;
             }
+        }
+    }
 
-            if matches!(address, None) {
-                debug!("[{}] No address found for {}", current_job.id(), target);
-                continue;
-            }
-            let Some(address) = address else { continue };
-
-            match get_secure_connection(address, &current_job, target, &tls_domain).await {
-                Ok(secure_con) => {
-                    if let Err(e) = send_email(secure_con, &email, &current_job, to, true).await {
-                        warn!(
-                            "[{}] Error sending email via tls on port 465 to {}: {}",
-                            current_job.id(),
-                            target,
-                            e
-                        );
-                        // TODO try starttls first
-                        match get_unsecure_connection(address, &current_job, target).await {
-                            Ok(unsecure_con) => {
-                                if let Err(e) =
-                                    send_email(unsecure_con, &email, &current_job, to, false).await
-                                {
-                                    return Err(From::from(format!(
-                                        "[{}] Error sending email via tcp on port 25 to {}: {}",
-                                        current_job.id(),
-                                        target,
-                                        e
-                                    )));
-                                }
-                            }
-                            Err(e) => {
-                                return Err(From::from(format!(
-                                    "[{}] Error sending email via tcp on port 25 to {}: {}",
-                                    current_job.id(),
-                                    target,
-                                    e
-                                )));
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!(
-                        "[{}] Error sending email via tls on port 465 to {}: {}",
-                        current_job.id(),
-                        target,
-                        e
-                    );
-                    // TODO try starttls first
-                    let unsecure_con =
-                        get_unsecure_connection(address, &current_job, target).await?;
-                    if let Err(e) = send_email(unsecure_con, &email, &current_job, to, false).await
-                    {
-                        return Err(From::from(format!(
-                            "[{}] Error sending email via tcp on port 25 to {}: {}",
-                            current_job.id(),
-                            target,
-                            e
-                        )));
-                    }
+    let Some(address) = address else {
+        debug!("[{}] No address found for {}", current_job.id(), target);
+        if resolver_failed {
+            return Err("No address found".into());
+        }
+        return Err(NoMailServer {
+            domain: target.to_string(),
+        }
+        .into());
+    };
+
+    let target = target.to_string();
+    match get_secure_connection(address, current_job, &target, &tls_domain).await {
+        Ok(secure_con) => {
+            if let Err(e) = send_email(secure_con, email, current_job, to, true).await {
+                // The server made up its mind so trying again without TLS won't help
+                if e.is::<UnexpectedReply>() {
+                    return Err(e);
                 }
+                warn!(
+                    "[{}] Error sending email via tls on port 465 to {}: {}",
+                    current_job.id(),
+                    target,
+                    e
+                );
+                // TODO try starttls first
+                let unsecure_con = get_unsecure_connection(address, current_job, &target).await?;
+                send_email(unsecure_con, email, current_job, to, false).await?;
             }
         }
-        debug!(
-            "[{}] Finished sending email job: {}",
-            current_job.id(),
-            current_job.id()
-        );
-        // Mark the job as complete
-        current_job.complete().await?;
-    } else {
-        debug!("[{}] Something broken", current_job.id());
-        return Err("No email payload found".into());
+        Err(e) => {
+            error!(
+                "[{}] Error sending email via tls on port 465 to {}: {}",
+                current_job.id(),
+                target,
+                e
+            );
+            // TODO try starttls first
+            let unsecure_con = get_unsecure_connection(address, current_job, &target).await?;
+            send_email(unsecure_con, email, current_job, to, false).await?;
+        }
     }
+    Ok(())
+}
 
+/// Stores a report in the INBOX of the sender if they asked for it
+#[instrument(skip(config, database, storage, email, current_job, report))]
+async fn notify_sender(
+    config: &Config,
+    database: &DB,
+    storage: &Storage,
+    email: &EmailPayload,
+    current_job: &CurrentJob,
+    report: Report<'_>,
+) -> Result<()> {
+    let recipients: Vec<RecipientReport> = report
+        .recipients
+        .iter()
+        .filter_map(|recipient| {
+            let dsn = email.dsn.recipient(&recipient.address);
+            dsn.wants(report.action).then(|| RecipientReport {
+                orcpt: dsn.orcpt,
+                ..recipient.clone()
+            })
+        })
+        .collect();
+    // Reports are never sent for mail without a sender (RFC 5321 section 4.5.5)
+    if recipients.is_empty() || email.from.is_empty() {
+        return Ok(());
+    }
+    let Some(owner) = database.mailbox_owner(&email.from).await? else {
+        warn!(
+            "[{}] Sender {} has no mailbox to report to",
+            current_job.id(),
+            email.from
+        );
+        return Ok(());
+    };
+    let message = delivery_report(
+        &Report {
+            recipients: &recipients,
+            ..report
+        },
+        &format!("{}/{}", current_job.id(), config.mail.hostname),
+    )?;
+    let message = [delivery_headers("", &email.from).as_bytes(), &message].concat();
+    let message_id =
+        store_in_inbox(&config.mail.maildir_folders, storage, &owner, &message).await?;
+    debug!(
+        "[{}] Stored delivery report: {}",
+        current_job.id(),
+        message_id
+    );
     Ok(())
 }
 
@@ -446,7 +801,10 @@ async fn get_unsecure_connection(
     current_job: &CurrentJob,
     target: &String,
 ) -> Result<
-    impl Stream<Item = Result<String, LinesCodecError>> + Sink<String, Error = LinesCodecError>,
+    impl Stream<Item = Result<String, LinesCodecError>>
+        + Sink<String, Error = LinesCodecError>
+        + Sink<Bytes, Error = LinesCodecError>
+        + Unpin,
     Box<dyn Error + Send + Sync + 'static>,
 > {
     match timeout(Duration::from_secs(5), TcpStream::connect(&(addr, 25))).await {
@@ -484,7 +842,10 @@ async fn get_secure_connection(
     target: &str,
     tls_domain: &str,
 ) -> Result<
-    impl Stream<Item = Result<String, LinesCodecError>> + Sink<String, Error = LinesCodecError>,
+    impl Stream<Item = Result<String, LinesCodecError>>
+        + Sink<String, Error = LinesCodecError>
+        + Sink<Bytes, Error = LinesCodecError>
+        + Unpin,
     Box<dyn Error + Send + Sync + 'static>,
 > {
     let mut roots = rustls::RootCertStore::empty();
@@ -534,3 +895,142 @@ async fn get_secure_connection(
         .into()),
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dot_stuff() {
+        assert_eq!(dot_stuff(b"Hello\r\nWorld"), b"Hello\r\nWorld");
+        assert_eq!(dot_stuff(b".\r\n.. two\r\n.end"), b"..\r\n... two\r\n..end");
+        assert_eq!(dot_stuff(b"not.at.start\r\n"), b"not.at.start\r\n");
+        // Binary content is left alone
+        assert_eq!(dot_stuff(b"\xff\xfe\r\n.\x00"), b"\xff\xfe\r\n..\x00");
+    }
+
+    #[test]
+    fn test_payload_body() {
+        let payload = EmailPayload {
+            to: BTreeMap::new(),
+            from: String::from("sender@localhost"),
+            body: b"Subject: Test\r\n\r\n\xe4\x00\xff\r\n".to_vec(),
+            legacy_body: None,
+            sender_domain: String::from("localhost"),
+            dkim_key_path: String::new(),
+            dkim_key_selector: String::new(),
+            signed: false,
+            dsn: Dsn::default(),
+            queued_at: 0,
+            delay_warned: false,
+        };
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["body_base64"], "U3ViamVjdDogVGVzdA0KDQrkAP8NCg==");
+        let decoded: EmailPayload = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.body, payload.body);
+
+        // Jobs queued by older versions still carry the message as text
+        let legacy: EmailPayload = serde_json::from_str(
+            r#"{"to":{},"from":"sender@localhost","body":"Hello","sender_domain":"localhost","dkim_key_path":"","dkim_key_selector":""}"#,
+        )
+        .unwrap();
+        assert!(legacy.body.is_empty());
+        assert_eq!(legacy.legacy_body.as_deref(), Some("Hello"));
+        assert!(!legacy.signed);
+    }
+
+    fn payload(queued_at: i64, delay_warned: bool) -> EmailPayload {
+        EmailPayload {
+            to: BTreeMap::new(),
+            from: String::from("sender@localhost"),
+            body: Vec::new(),
+            legacy_body: None,
+            sender_domain: String::from("localhost"),
+            dkim_key_path: String::new(),
+            dkim_key_selector: String::new(),
+            signed: false,
+            dsn: Dsn::default(),
+            queued_at,
+            delay_warned,
+        }
+    }
+
+    #[test]
+    fn test_failure() {
+        let permanent: Box<dyn Error + Send + Sync> = Box::new(UnexpectedReply {
+            context: "Server did not accept RCPT TO command",
+            reply: String::from("550 5.1.1 No such user"),
+        });
+        assert_eq!(
+            Failure::new(permanent.as_ref()),
+            Failure {
+                reason: String::from(
+                    "Server did not accept RCPT TO command: 550 5.1.1 No such user"
+                ),
+                reply: Some(String::from("550 5.1.1 No such user")),
+                status: None,
+                permanent: true,
+            }
+        );
+
+        let temporary: Box<dyn Error + Send + Sync> = Box::new(UnexpectedReply {
+            context: "Server did not accept MAIL FROM command",
+            reply: String::from("451 4.3.0 Try again later"),
+        });
+        assert!(!Failure::new(temporary.as_ref()).permanent);
+
+        let no_mail_server: Box<dyn Error + Send + Sync> = Box::new(NoMailServer {
+            domain: String::from("unknown.example"),
+        });
+        assert_eq!(
+            Failure::new(no_mail_server.as_ref()),
+            Failure {
+                reason: String::from("No mail server found for unknown.example"),
+                reply: None,
+                status: Some("5.1.2"),
+                permanent: true,
+            }
+        );
+
+        // Anything else might work the next time
+        let other: Box<dyn Error + Send + Sync> = "No address found".into();
+        assert!(!Failure::new(other.as_ref()).permanent);
+    }
+
+    #[test]
+    fn test_deadlines() {
+        let config = Smtp {
+            queue_lifetime: 5 * 24 * 60 * 60,
+            delay_warning: 4 * 60 * 60,
+            ..Smtp::default()
+        };
+        let now = OffsetDateTime::from_unix_timestamp(1_000_000_000).unwrap();
+
+        let fresh = Deadlines::new(&payload(1_000_000_000 - 60, false), &config, now).unwrap();
+        assert_eq!(
+            fresh.retry_until,
+            OffsetDateTime::from_unix_timestamp(1_000_000_000 - 60 + 5 * 24 * 60 * 60).unwrap()
+        );
+        assert!(!fresh.give_up);
+        assert!(!fresh.warn_delay);
+
+        let delayed =
+            Deadlines::new(&payload(1_000_000_000 - 5 * 60 * 60, false), &config, now).unwrap();
+        assert!(!delayed.give_up);
+        assert!(delayed.warn_delay);
+
+        // The sender only hears about the delay once
+        let warned =
+            Deadlines::new(&payload(1_000_000_000 - 5 * 60 * 60, true), &config, now).unwrap();
+        assert!(!warned.warn_delay);
+
+        let expired = Deadlines::new(
+            &payload(1_000_000_000 - 6 * 24 * 60 * 60, true),
+            &config,
+            now,
+        )
+        .unwrap();
+        assert!(expired.give_up);
+    }
+}
@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use crate::{servers::codec::ReadMode, utils::dsn::Dsn};
+use erooster_core::config::ListenerRole;
 use tokio::sync::RwLock;
 
 /// State of the connection session between us and the Client
@@ -11,10 +13,18 @@ pub struct Connection {
     pub sender: Option<String>,
     pub ehlo: Option<String>,
     pub peer_addr: String,
+    /// What the port the client connected to is used for
+    pub role: ListenerRole,
+    /// The client announced `BODY=BINARYMIME` so the message has to be sent using BDAT
+    pub binary: bool,
+    /// How the codec reads what the client sends next. Changed by DATA and BDAT.
+    pub read_mode: Arc<ReadMode>,
+    /// What the client wants to hear about the delivery of the current transaction
+    pub dsn: Dsn,
 }
 
 impl Connection {
-    pub fn new(secure: bool, peer_addr: String) -> Arc<RwLock<Self>> {
+    pub fn new(secure: bool, peer_addr: String, role: ListenerRole) -> Arc<RwLock<Self>> {
         Arc::new(RwLock::new(Connection {
             secure,
             state: State::NotAuthenticated,
@@ -22,8 +32,20 @@ impl Connection {
             sender: None,
             ehlo: None,
             peer_addr,
+            role,
+            binary: false,
+            read_mode: Arc::new(ReadMode::default()),
+            dsn: Dsn::default(),
         }))
     }
+
+    /// Forgets the sender and recipients of the current mail transaction
+    pub fn reset_transaction(&mut self) {
+        self.sender = None;
+        self.receipts = None;
+        self.binary = false;
+        self.dsn = Dsn::default();
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -32,12 +54,32 @@ pub enum State {
     NotAuthenticated,
     /// DATA command issued, if not None this means we were authenticated
     ReceivingData((Option<String>, Vec<u8>)),
+    /// The message got too large during DATA. The rest of it is read but not kept.
+    DiscardingData(Option<String>),
+    /// BDAT command issued, the message is sent in chunks
+    ReceivingChunks(Chunks),
     /// Authentication in progress
     Authenticating(AuthState),
     /// Authentication done
     Authenticated(String),
 }
 
+/// A message sent using BDAT (RFC 3030)
+#[derive(Debug, Clone)]
+pub struct Chunks {
+    /// If not None this means we were authenticated
+    pub username: Option<String>,
+    pub data: Vec<u8>,
+    /// Size of the current chunk
+    pub size: usize,
+    /// Bytes of the current chunk which did not arrive yet
+    pub remaining: usize,
+    /// Whether the current chunk is the last one
+    pub last: bool,
+    /// The message got too large. The rest of it is read but not kept.
+    pub too_big: bool,
+}
+
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug, Clone)]
 pub enum AuthState {
@@ -1,7 +1,11 @@
 use crate::{
     commands::{Data, Response},
     servers::{
+        codec::SmtpCodec,
         encrypted::{get_tls_acceptor, listen_tls},
+        limits::{reject, too_many_connections, ConnectionLimits, SessionPermit},
+        listener_addrs,
+        pipelining::Pipelined,
         send_capabilities,
         state::Connection,
     },
@@ -9,13 +13,13 @@ use crate::{
 use color_eyre::{eyre::Context, Result};
 use erooster_core::{
     backend::{database::DB, storage::Storage},
-    config::Config,
+    config::{Config, ListenerRole},
     line_codec::LinesCodec,
     LINE_LIMIT,
 };
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
 use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::TcpListener, task::JoinHandle};
+use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::codec::Framed;
 use tracing::{debug, error, info, instrument};
@@ -27,23 +31,14 @@ impl Unencrypted {
     // TODO make this only pub for benches and tests
     #[allow(missing_docs)]
     #[allow(clippy::missing_errors_doc)]
-    #[instrument(skip(config, database, storage))]
+    #[instrument(skip(config, database, storage, limits))]
     pub async fn run(
         config: Arc<Config>,
         database: DB,
         storage: Arc<Storage>,
+        limits: Arc<ConnectionLimits>,
     ) -> color_eyre::eyre::Result<()> {
-        let addrs: Vec<SocketAddr> = if let Some(listen_ips) = &config.listen_ips {
-            listen_ips
-                .iter()
-                .map(|ip| format!("{ip}:587").parse())
-                .chain(listen_ips.iter().map(|ip| format!("{ip}:25").parse()))
-                .filter_map(Result::ok)
-                .collect()
-        } else {
-            vec!["0.0.0.0:587".parse()?, "0.0.0.0:25".parse()?]
-        };
-        for addr in addrs {
+        for (addr, role) in listener_addrs(&config, false) {
             info!("[SMTP] Trying to listen on {:?}", addr);
             let listener = TcpListener::bind(addr).await?;
             info!("[SMTP] Listening on unecrypted Port");
@@ -52,12 +47,15 @@ impl Unencrypted {
             let config = Arc::clone(&config);
             let database = Arc::clone(&database);
             let storage = Arc::clone(&storage);
+            let limits = Arc::clone(&limits);
             tokio::spawn(async move {
                 listen(
                     stream,
                     Arc::clone(&config),
                     Arc::clone(&database),
                     Arc::clone(&storage),
+                    limits,
+                    role,
                 )
                 .await;
             });
@@ -67,109 +65,281 @@ impl Unencrypted {
     }
 }
 
-#[allow(clippy::too_many_lines)]
 async fn listen(
     mut stream: TcpListenerStream,
     config: Arc<Config>,
     database: DB,
     storage: Arc<Storage>,
+    limits: Arc<ConnectionLimits>,
+    role: ListenerRole,
 ) {
-    while let Some(Ok(tcp_stream)) = stream.next().await {
-        let peer = tcp_stream.peer_addr().expect("[SMTP] peer addr to exist");
+    // We only accept new clients while there is room for them
+    while let Some(slot) = limits.reserve().await {
+        let Some(Ok(tcp_stream)) = stream.next().await else {
+            break;
+        };
+        let Ok(peer) = tcp_stream.peer_addr() else {
+            continue;
+        };
         debug!("[SMTP] Got new peer: {}", peer);
 
         let config = Arc::clone(&config);
+        let permit = match limits.admit(slot, peer.ip()) {
+            Ok(permit) => permit,
+            Err(slot) => {
+                info!("[SMTP] Rejecting {}: Too many connections", peer);
+                tokio::spawn(reject(slot, peer, async move {
+                    let mut lines =
+                        Framed::new(tcp_stream, LinesCodec::new_with_max_length(LINE_LIMIT));
+                    lines
+                        .send(too_many_connections(&config.mail.hostname))
+                        .await
+                }));
+                continue;
+            }
+        };
         let database = Arc::clone(&database);
         let storage = Arc::clone(&storage);
-        let connection: JoinHandle<Result<()>> = tokio::spawn(async move {
-            let lines = Framed::new(tcp_stream, LinesCodec::new_with_max_length(LINE_LIMIT));
-            let (mut lines_sender, mut lines_reader) = lines.split();
-
-            let state = Connection::new(false, peer.ip().to_string());
-
-            // Greet the client with the capabilities we provide
-            send_capabilities(Arc::clone(&config), &mut lines_sender)
-                .await
-                .context("Unable to send greeting to client. Closing connection.")?;
-
-            let data = Data {
-                con_state: Arc::clone(&state),
-            };
-
-            let mut do_starttls = false;
-            while let Some(Ok(line)) = lines_reader.next().await {
-                debug!("[SMTP] [{}] Got Command: {}", peer, line);
-
-                // TODO make sure to handle IDLE different as it needs us to stream lines
-                // TODO pass lines and make it possible to not need new lines in responds but instead directly use `lines.send`
-                let response = data
-                    .parse(
-                        &mut lines_sender,
-                        Arc::clone(&config),
-                        Arc::clone(&database),
-                        Arc::clone(&storage),
-                        line,
-                    )
-                    .await;
+        // Every session runs on its own so a slow client doesn't hold up anyone else
+        tokio::spawn(async move {
+            if let Err(e) = session(tcp_stream, peer, config, database, storage, permit, role).await
+            {
+                error!("[SMTP] Error: {:?}", e);
+            }
+        });
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+async fn session(
+    tcp_stream: TcpStream,
+    peer: SocketAddr,
+    config: Arc<Config>,
+    database: DB,
+    storage: Arc<Storage>,
+    permit: SessionPermit,
+    role: ListenerRole,
+) -> Result<()> {
+    let state = Connection::new(false, peer.ip().to_string(), role);
+    let read_mode = Arc::clone(&state.read().await.read_mode);
+
+    let lines = Framed::new(tcp_stream, SmtpCodec::new(LINE_LIMIT, read_mode));
+    let (lines_sender, mut lines_reader) = lines.split();
+    let mut lines_sender = Pipelined::new(lines_sender);
+
+    // Greet the client with the capabilities we provide
+    send_capabilities(Arc::clone(&config), &mut lines_sender)
+        .await
+        .context("Unable to send greeting to client. Closing connection.")?;
+
+    let data = Data {
+        con_state: Arc::clone(&state),
+    };
+
+    let mut do_starttls = false;
+    loop {
+        let frame = match lines_reader.next().now_or_never() {
+            // The client pipelined more commands so the replies can wait
+            Some(frame) => Ok(frame),
+            None => {
+                if let Err(e) = lines_sender.flush_replies().await {
+                    debug!("[SMTP] [{}] Unable to send replies: {}", peer, e);
+                    break;
+                }
+                permit.limit(lines_reader.next()).await
+            }
+        };
+        let frame = match frame {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(_) => break,
+            Err(expired) => {
+                debug!("[SMTP] [{}] Session timed out: {:?}", peer, expired);
+                if let Err(e) = lines_sender
+                    .send(expired.reply(&config.mail.hostname))
+                    .await
+                {
+                    debug!("[SMTP] [{}] Unable to send timeout reply: {}", peer, e);
+                }
+                break;
+            }
+        };
 
+        // TODO make sure to handle IDLE different as it needs us to stream lines
+        // TODO pass lines and make it possible to not need new lines in responds but instead directly use `lines.send`
+        let response = data
+            .handle(
+                &mut lines_sender,
+                Arc::clone(&config),
+                Arc::clone(&database),
+                Arc::clone(&storage),
+                frame,
+            )
+            .await;
+
+        match response {
+            Ok(response) => {
+                // Cleanup timeout managers
                 match response {
-                    Ok(response) => {
-                        // Cleanup timeout managers
-                        match response {
-                            Response::Exit => {
-                                // Used for later session timer management
-                                debug!("[SMTP] Closing connection");
-                                break;
-                            }
-                            Response::STARTTLS => {
-                                debug!("[SMTP] Switching context");
-                                do_starttls = true;
-                                lines_sender.send(String::from("220 TLS go ahead")).await?;
-                                break;
-                            }
-                            Response::Continue => {}
-                        }
-                    }
-                    // We try a last time to do a graceful shutdown before closing
-                    Err(e) => {
-                        if let Err(e) = lines_sender
-                            .send(format!("500 This should not happen: {e}"))
-                            .await
-                        {
-                            error!("[SMTP] Error sending response: {:?}", e);
-                        }
-                        error!("[SMTP] Failure happened: {}", e);
+                    Response::Exit => {
+                        // Used for later session timer management
                         debug!("[SMTP] Closing connection");
                         break;
                     }
+                    Response::STARTTLS => {
+                        debug!("[SMTP] Switching context");
+                        do_starttls = true;
+                        lines_sender.send(String::from("220 TLS go ahead")).await?;
+                        break;
+                    }
+                    Response::Continue => {}
                 }
             }
-            if do_starttls {
-                debug!("[SMTP] Starting to reunite");
-                let framed_stream = lines_sender.reunite(lines_reader)?;
-                let stream = framed_stream.into_inner();
-                debug!("[SMTP] Finished to reunite");
-                let acceptor = get_tls_acceptor(&config)?;
-                debug!("[SMTP] Starting to listen using tls");
-                if let Err(e) = listen_tls(
-                    stream,
-                    config,
-                    database,
-                    storage,
-                    acceptor,
-                    Some(data),
-                    true,
-                )
-                .await
+            // We try a last time to do a graceful shutdown before closing
+            Err(e) => {
+                if let Err(e) = lines_sender
+                    .send(format!("500 This should not happen: {e}"))
+                    .await
                 {
-                    error!("[SMTP] Error while upgrading to tls: {}", e);
+                    error!("[SMTP] Error sending response: {:?}", e);
                 }
+                error!("[SMTP] Failure happened: {}", e);
+                debug!("[SMTP] Closing connection");
+                break;
             }
-            Ok(())
-        });
-        let resp = connection.await;
-        if let Ok(Err(e)) = resp {
-            error!("[SMTP] Error: {:?}", e);
+        }
+    }
+    if let Err(e) = lines_sender.flush_replies().await {
+        debug!("[SMTP] [{}] Unable to send the last replies: {}", peer, e);
+    }
+    if do_starttls {
+        debug!("[SMTP] Starting to reunite");
+        let framed_stream = lines_sender.into_inner().reunite(lines_reader)?;
+        let stream = framed_stream.into_inner();
+        debug!("[SMTP] Finished to reunite");
+        let acceptor = get_tls_acceptor(&config)?;
+        debug!("[SMTP] Starting to listen using tls");
+        if let Err(e) = listen_tls(stream, config, database, storage, acceptor, data, permit).await
+        {
+            error!("[SMTP] Error while upgrading to tls: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use erooster_core::backend::{
+        database::{get_database, Database},
+        storage::{get_storage, MailStorage},
+    };
+    use std::path::Path;
+    use time::OffsetDateTime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Reads from the server until what it sent so far ends with `end`
+    async fn read_until(client: &mut TcpStream, end: &str) -> String {
+        let mut received = Vec::new();
+        let mut buffer = [0; 4096];
+        while !received.ends_with(end.as_bytes()) {
+            let read = client.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "Connection closed after {received:?}");
+            received.extend_from_slice(&buffer[..read]);
+        }
+        String::from_utf8(received).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_replies() {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(get_database(Arc::clone(&config)).await.unwrap());
+        let storage = Arc::new(get_storage(Arc::clone(&database), Arc::clone(&config)));
+        // Fresh users so their INBOX only holds the message of this test
+        let nanos = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let recipients = [
+            format!("pipelining-a-{nanos}@{}", config.mail.hostname),
+            format!("pipelining-b-{nanos}@{}", config.mail.hostname),
+        ];
+        for recipient in &recipients {
+            database.add_user(recipient).await.unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, peer) = listener.accept().await.unwrap();
+        let limits = ConnectionLimits::new(&config.smtp);
+        let permit = limits
+            .admit(limits.reserve().await.unwrap(), peer.ip())
+            .unwrap();
+        tokio::spawn(session(
+            server,
+            peer,
+            Arc::clone(&config),
+            database,
+            Arc::clone(&storage),
+            permit,
+            ListenerRole::Mx,
+        ));
+
+        assert_eq!(
+            read_until(&mut client, "\r\n").await,
+            format!("220 {} ESMTP Erooster\r\n", config.mail.hostname)
+        );
+        client
+            .write_all(b"EHLO client.example.com\r\n")
+            .await
+            .unwrap();
+        // The last line of the EHLO reply has a space after the code
+        let mut ehlo = String::new();
+        while !ehlo
+            .lines()
+            .last()
+            .map_or(false, |line| line.starts_with("250 "))
+        {
+            ehlo.push_str(&read_until(&mut client, "\r\n").await);
+        }
+
+        client
+            .write_all(
+                format!(
+                    "MAIL FROM:<sender@example.com>\r\nRCPT TO:<{}>\r\n\
+                     RCPT TO:<{}>\r\nDATA\r\n",
+                    recipients[0], recipients[1]
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        // All replies arrive in the order of the commands
+        assert_eq!(
+            read_until(&mut client, "<CRLF>.<CRLF>\r\n").await,
+            format!(
+                "250 2.1.0 Originator <sender@example.com> OK\r\n\
+                 250 2.1.5 Recipient <{}> OK\r\n\
+                 250 2.1.5 Recipient <{}> OK\r\n\
+                 354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+                recipients[0], recipients[1]
+            )
+        );
+
+        client
+            .write_all(b"Subject: Pipelining\r\n\r\nHello\r\n.\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_until(&mut client, "\r\n").await,
+            "250 2.6.0 Message accepted\r\n"
+        );
+        for recipient in &recipients {
+            let inbox = Path::new(&config.mail.maildir_folders)
+                .join(recipient)
+                .join("INBOX");
+            assert_eq!(storage.list_new(&inbox).await.len(), 1, "{recipient}");
         }
     }
 }
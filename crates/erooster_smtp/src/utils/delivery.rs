@@ -0,0 +1,224 @@
+use erooster_core::{
+    backend::{
+        database::{Database, DB},
+        storage::{MailStorage, Storage},
+    },
+    config::Config,
+};
+use std::path::Path;
+
+/// Whether the address belongs to a domain this server receives mail for
+pub fn is_local(address: &str, hostname: &str) -> bool {
+    address
+        .rsplit_once('@')
+        .map_or(false, |(_, domain)| domain.eq_ignore_ascii_case(hostname))
+}
+
+/// The trace headers added when the message reaches the mailbox of the recipient.
+///
+/// See RFC 5321 section 4.4 for Return-Path. Delivered-To names the address the message was sent
+/// to, which may be an alias of the user.
+pub fn delivery_headers(sender: &str, recipient: &str) -> String {
+    format!("Return-Path: <{sender}>\r\nDelivered-To: {recipient}\r\n")
+}
+
+/// Stores the message as new mail in the INBOX of the user and creates the INBOX if needed
+///
+/// # Errors
+///
+/// Returns an error if the INBOX can't be created or the message can't be written
+pub async fn store_in_inbox(
+    maildir_folders: &str,
+    storage: &Storage,
+    username: &str,
+    message: &[u8],
+) -> color_eyre::eyre::Result<String> {
+    let mailbox_path = Path::new(maildir_folders).join(username).join("INBOX");
+    if !mailbox_path.exists() {
+        storage.create_dirs(&mailbox_path)?;
+        storage.add_flag(&mailbox_path, "\\Subscribed").await?;
+        storage.add_flag(&mailbox_path, "\\NoInferiors").await?;
+    }
+    storage.store_new(&mailbox_path, message).await
+}
+
+/// Stores the message as seen mail in the `\Sent` mailbox of the user.
+///
+/// Creates a Sent mailbox if the user has none yet.
+///
+/// # Errors
+///
+/// Returns an error if the mailbox can't be created or the message can't be written
+pub async fn store_in_sent(
+    maildir_folders: &str,
+    storage: &Storage,
+    username: &str,
+    message: &[u8],
+) -> color_eyre::eyre::Result<String> {
+    let user_path = Path::new(maildir_folders).join(username);
+    let mut sent_path = None;
+    for path in storage.list_subdirs(&user_path)? {
+        if storage
+            .get_flags(&path)
+            .await?
+            .iter()
+            .any(|flag| flag == "\\Sent")
+        {
+            sent_path = Some(path);
+            break;
+        }
+    }
+    let sent_path = match sent_path {
+        Some(path) => path,
+        None => {
+            let path = user_path.join(".Sent");
+            storage.create_dirs(&path)?;
+            storage.add_flag(&path, "\\Sent").await?;
+            storage.add_flag(&path, "\\Subscribed").await?;
+            path
+        }
+    };
+    storage
+        .store_cur_with_flags(&sent_path, message, vec![String::from("\\Seen")], None)
+        .await
+}
+
+/// Keeps a copy of the signed message the user sent if they turned that on
+///
+/// Returns the id of the copy or `None` if the user doesn't want one.
+///
+/// # Errors
+///
+/// Returns an error if the setting can't be read or the message can't be stored
+pub async fn save_sent_copy(
+    config: &Config,
+    database: &DB,
+    storage: &Storage,
+    username: &str,
+    message: &[u8],
+) -> color_eyre::eyre::Result<Option<String>> {
+    if !database.saves_sent_mail(username).await? {
+        return Ok(None);
+    }
+    store_in_sent(&config.mail.maildir_folders, storage, username, message)
+        .await
+        .map(Some)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use erooster_core::backend::{
+        database::get_database,
+        storage::{get_storage, MailEntry},
+    };
+    use std::sync::Arc;
+    use time::OffsetDateTime;
+
+    const MESSAGE: &[u8] =
+        b"From: sender@localhost\r\nTo: rcpt@example.com\r\nSubject: Test\r\n\r\nHello\r\n";
+
+    async fn setup() -> (Arc<Config>, DB, Arc<Storage>, String) {
+        let config = erooster_core::get_config(String::from("./config.yml"))
+            .await
+            .unwrap();
+        let database = Arc::new(get_database(Arc::clone(&config)).await.unwrap());
+        let storage = Arc::new(get_storage(Arc::clone(&database), Arc::clone(&config)));
+        // Every test gets a user of its own so nothing is left over from earlier runs
+        let username = format!(
+            "sent-{}@{}",
+            OffsetDateTime::now_utc().unix_timestamp_nanos(),
+            config.mail.hostname
+        );
+        database.add_user(&username).await.unwrap();
+        (config, database, storage, username)
+    }
+
+    #[tokio::test]
+    async fn test_store_in_sent() {
+        let (config, _database, storage, username) = setup().await;
+        let user_path = Path::new(&config.mail.maildir_folders).join(&username);
+
+        // A Sent mailbox gets created if there is none
+        let id = store_in_sent(&config.mail.maildir_folders, &storage, &username, MESSAGE)
+            .await
+            .unwrap();
+        let sent_path = user_path.join(".Sent");
+        let flags = storage.get_flags(&sent_path).await.unwrap();
+        assert!(flags.iter().any(|flag| flag == "\\Sent"));
+        assert!(flags.iter().any(|flag| flag == "\\Subscribed"));
+        let mails = storage.list_cur(&sent_path).await;
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].id(), id);
+        assert!(mails[0].is_seen());
+
+        // It is used from then on
+        store_in_sent(&config.mail.maildir_folders, &storage, &username, MESSAGE)
+            .await
+            .unwrap();
+        assert_eq!(storage.list_cur(&sent_path).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_store_in_existing_sent() {
+        let (config, _database, storage, username) = setup().await;
+        let user_path = Path::new(&config.mail.maildir_folders).join(&username);
+        let outbox_path = user_path.join(".Outbox");
+        storage.create_dirs(&outbox_path).unwrap();
+        storage.add_flag(&outbox_path, "\\Sent").await.unwrap();
+
+        store_in_sent(&config.mail.maildir_folders, &storage, &username, MESSAGE)
+            .await
+            .unwrap();
+        assert_eq!(storage.list_cur(&outbox_path).await.len(), 1);
+        assert!(!user_path.join(".Sent").exists());
+    }
+
+    #[tokio::test]
+    async fn test_save_sent_copy() {
+        let (config, database, storage, username) = setup().await;
+        let sent_path = Path::new(&config.mail.maildir_folders)
+            .join(&username)
+            .join(".Sent");
+
+        // Nothing is kept unless the user asks for it
+        let copy = save_sent_copy(&config, &database, &storage, &username, MESSAGE)
+            .await
+            .unwrap();
+        assert!(copy.is_none());
+        assert!(!sent_path.exists());
+
+        database.set_save_sent_mail(&username, true).await.unwrap();
+        let copy = save_sent_copy(&config, &database, &storage, &username, MESSAGE)
+            .await
+            .unwrap();
+        let mails = storage.list_cur(&sent_path).await;
+        assert_eq!(mails.len(), 1);
+        assert_eq!(Some(mails[0].id()), copy.as_deref());
+
+        database.set_save_sent_mail(&username, false).await.unwrap();
+        let copy = save_sent_copy(&config, &database, &storage, &username, MESSAGE)
+            .await
+            .unwrap();
+        assert!(copy.is_none());
+        assert_eq!(storage.list_cur(&sent_path).await.len(), 1);
+    }
+
+    #[test]
+    fn test_is_local() {
+        assert!(is_local("user@localhost", "localhost"));
+        assert!(is_local("User@LocalHost", "localhost"));
+        assert!(!is_local("user@localhost.example", "localhost"));
+        assert!(!is_local("user@example.com", "localhost"));
+        assert!(!is_local("localhost", "localhost"));
+    }
+
+    #[test]
+    fn test_delivery_headers() {
+        assert_eq!(
+            delivery_headers("sender@example.com", "alias@localhost"),
+            "Return-Path: <sender@example.com>\r\nDelivered-To: alias@localhost\r\n"
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+/// The DSN parameters the client gave for a transaction (RFC 3461)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dsn {
+    /// How much of the message a report should return
+    pub ret: Option<Ret>,
+    /// The identifier the client gave to the transaction
+    pub envid: Option<String>,
+    /// The parameters given with RCPT TO by recipient address
+    pub recipients: BTreeMap<String, RecipientDsn>,
+}
+
+impl Dsn {
+    /// The parameters of the transaction limited to a single recipient
+    #[must_use]
+    pub fn for_recipient(&self, address: &str) -> Self {
+        Self {
+            ret: self.ret,
+            envid: self.envid.clone(),
+            recipients: self
+                .recipients
+                .get(address)
+                .map(|recipient| (address.to_string(), recipient.clone()))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// The parameters given for the recipient
+    #[must_use]
+    pub fn recipient(&self, address: &str) -> RecipientDsn {
+        self.recipients.get(address).cloned().unwrap_or_default()
+    }
+}
+
+/// The DSN parameters of RCPT TO
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecipientDsn {
+    /// None if the client did not say, which means failures and delays get reported
+    pub notify: Option<Notify>,
+    /// The address the client was originally given as `addr-type;address`
+    pub orcpt: Option<String>,
+}
+
+impl RecipientDsn {
+    /// Whether the sender wants a report about this action
+    #[must_use]
+    pub fn wants(&self, action: Action) -> bool {
+        match (action, self.notify) {
+            (Action::Failed | Action::Delayed, None) => true,
+            (Action::Relayed, None) => false,
+            (Action::Failed, Some(notify)) => notify.failure,
+            (Action::Delayed, Some(notify)) => notify.delay,
+            (Action::Relayed, Some(notify)) => notify.success,
+        }
+    }
+}
+
+/// What the sender wants to hear about. `NOTIFY=NEVER` leaves everything unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notify {
+    /// Report when the mail got delivered or relayed
+    pub success: bool,
+    /// Report when the mail can't be delivered
+    pub failure: bool,
+    /// Report when the delivery takes long
+    pub delay: bool,
+}
+
+impl FromStr for Notify {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Ok(Self::default());
+        }
+        let mut notify = Self::default();
+        for keyword in value.split(',') {
+            match keyword.to_ascii_uppercase().as_str() {
+                "SUCCESS" => notify.success = true,
+                "FAILURE" => notify.failure = true,
+                "DELAY" => notify.delay = true,
+                _ => return Err(()),
+            }
+        }
+        Ok(notify)
+    }
+}
+
+/// How much of the message gets returned in a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Ret {
+    /// The whole message
+    Full,
+    /// Only the headers of the message
+    Hdrs,
+}
+
+impl FromStr for Ret {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        if value.eq_ignore_ascii_case("FULL") {
+            Ok(Self::Full)
+        } else if value.eq_ignore_ascii_case("HDRS") {
+            Ok(Self::Hdrs)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// Decodes the xtext the values of ENVID and ORCPT are sent as (RFC 3461 section 4)
+#[must_use]
+pub fn decode_xtext(value: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '+' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return None;
+                }
+                decoded.push(char::from(u8::from_str_radix(&hex, 16).ok()?));
+            }
+            '=' | ' ' => return None,
+            c if c.is_control() => return None,
+            c => decoded.push(c),
+        }
+    }
+    Some(decoded)
+}
+
+/// Decodes the value of ORCPT into `addr-type;address`
+#[must_use]
+pub fn decode_orcpt(value: &str) -> Option<String> {
+    let (addr_type, address) = value.split_once(';')?;
+    if addr_type.is_empty() {
+        return None;
+    }
+    Some(format!("{addr_type};{}", decode_xtext(address)?))
+}
+
+/// What happened to the mail for a recipient (RFC 3464 section 2.3.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// The mail can't be delivered and won't be retried
+    Failed,
+    /// The mail could not be delivered yet but is still being retried
+    Delayed,
+    /// The mail was handed to a server which doesn't report on the delivery
+    Relayed,
+}
+
+impl Action {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Failed => "failed",
+            Self::Delayed => "delayed",
+            Self::Relayed => "relayed",
+        }
+    }
+}
+
+/// The outcome for a single recipient
+#[derive(Debug, Clone)]
+pub struct RecipientReport {
+    pub address: String,
+    pub orcpt: Option<String>,
+    /// Why the mail is not delivered yet
+    pub reason: String,
+    /// The reply of the server of the recipient if it sent one
+    pub reply: Option<String>,
+    /// The status code of a problem we found ourselves, like a domain without a mail server
+    pub status: Option<&'static str>,
+}
+
+/// A report about the delivery of a message to some of its recipients
+#[derive(Debug)]
+pub struct Report<'a> {
+    pub action: Action,
+    /// The name of this server
+    pub hostname: &'a str,
+    /// The address the report is sent to
+    pub sender: &'a str,
+    pub envid: Option<&'a str>,
+    pub ret: Option<Ret>,
+    /// When the message was accepted by us
+    pub arrival_date: OffsetDateTime,
+    /// Until when the message is retried if the action is [`Action::Delayed`]
+    pub retry_until: OffsetDateTime,
+    pub recipients: &'a [RecipientReport],
+    /// The message as it was sent
+    pub message: &'a [u8],
+}
+
+/// Builds the `multipart/report` message telling the sender what happened (RFC 3464)
+///
+/// # Errors
+///
+/// Returns an error if the dates can't be formatted
+pub fn delivery_report(report: &Report<'_>, boundary: &str) -> color_eyre::eyre::Result<Vec<u8>> {
+    let subject = match report.action {
+        Action::Failed => "Undelivered Mail Returned to Sender",
+        Action::Delayed => "Delayed Mail (still being retried)",
+        Action::Relayed => "Successful Mail Delivery Report",
+    };
+    let retry_until = report.retry_until.format(&Rfc2822)?;
+    let mut lines = vec![
+        format!(
+            "From: Mail Delivery System <MAILER-DAEMON@{}>",
+            report.hostname
+        ),
+        format!("To: <{}>", report.sender),
+        format!("Subject: {subject}"),
+        format!("Date: {}", OffsetDateTime::now_utc().format(&Rfc2822)?),
+        String::from("Auto-Submitted: auto-replied"),
+        String::from("MIME-Version: 1.0"),
+        String::from("Content-Type: multipart/report; report-type=delivery-status;"),
+        format!("\tboundary=\"{boundary}\""),
+        String::new(),
+        String::from("This is a MIME-encapsulated message."),
+        String::new(),
+        format!("--{boundary}"),
+        String::from("Content-Type: text/plain; charset=utf-8"),
+        String::new(),
+    ];
+    match report.action {
+        Action::Failed => lines.push(String::from(
+            "Your message could not be delivered to the following recipients.",
+        )),
+        Action::Delayed => {
+            lines.push(String::from(
+                "Your message could not be delivered to the following recipients yet.",
+            ));
+            lines.push(format!("Delivery will be retried until {retry_until}."));
+        }
+        Action::Relayed => {
+            lines.push(String::from(
+                "Your message was handed to the mail server of the following recipients.",
+            ));
+            lines.push(String::from("That server may not report on the delivery."));
+        }
+    }
+    for recipient in report.recipients {
+        lines.push(String::new());
+        lines.push(format!("<{}>: {}", recipient.address, recipient.reason));
+    }
+    lines.push(String::new());
+
+    lines.push(format!("--{boundary}"));
+    lines.push(String::from("Content-Type: message/delivery-status"));
+    lines.push(String::new());
+    lines.push(format!("Reporting-MTA: dns; {}", report.hostname));
+    if let Some(envid) = report.envid {
+        lines.push(format!("Original-Envelope-Id: {envid}"));
+    }
+    lines.push(format!(
+        "Arrival-Date: {}",
+        report.arrival_date.format(&Rfc2822)?
+    ));
+    for recipient in report.recipients {
+        lines.push(String::new());
+        lines.push(format!("Final-Recipient: rfc822; {}", recipient.address));
+        if let Some(orcpt) = &recipient.orcpt {
+            lines.push(format!("Original-Recipient: {orcpt}"));
+        }
+        lines.push(format!("Action: {}", report.action.name()));
+        lines.push(format!(
+            "Status: {}",
+            recipient.status.map_or_else(
+                || status(report.action, recipient.reply.as_deref()),
+                String::from
+            )
+        ));
+        if let Some(reply) = &recipient.reply {
+            lines.push(format!("Diagnostic-Code: smtp; {reply}"));
+        }
+        if report.action == Action::Delayed {
+            lines.push(format!("Will-Retry-Until: {retry_until}"));
+        }
+    }
+    lines.push(String::new());
+
+    // Only failures return the whole message unless the sender asked otherwise
+    let ret = report.ret.unwrap_or(match report.action {
+        Action::Failed => Ret::Full,
+        Action::Delayed | Action::Relayed => Ret::Hdrs,
+    });
+    lines.push(format!("--{boundary}"));
+    let returned = match ret {
+        Ret::Full => {
+            lines.push(String::from("Content-Type: message/rfc822"));
+            report.message
+        }
+        Ret::Hdrs => {
+            lines.push(String::from("Content-Type: text/rfc822-headers"));
+            headers(report.message)
+        }
+    };
+    lines.push(String::new());
+    lines.push(String::new());
+
+    let mut message = lines.join("\r\n").into_bytes();
+    message.extend_from_slice(returned);
+    if !returned.ends_with(b"\r\n") {
+        message.extend_from_slice(b"\r\n");
+    }
+    message.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    Ok(message)
+}
+
+/// The header section of the message including the line ending of the last header
+fn headers(message: &[u8]) -> &[u8] {
+    message
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(message, |end| &message[..end + 2])
+}
+
+/// The enhanced status code of the reply (RFC 3463) or a generic one matching the action
+fn status(action: Action, reply: Option<&str>) -> String {
+    let class = match action {
+        Action::Failed => "5.",
+        Action::Delayed => "4.",
+        Action::Relayed => "2.",
+    };
+    let code = reply
+        .and_then(|reply| reply.get(4..)?.split_whitespace().next())
+        .filter(|code| {
+            code.starts_with(class)
+                && code.split('.').count() == 3
+                && code
+                    .split('.')
+                    .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        });
+    match (action, code) {
+        (_, Some(code)) => code,
+        (Action::Failed, None) if reply.map_or(false, |reply| reply.starts_with('5')) => "5.0.0",
+        // We gave up as the server kept failing temporarily
+        (Action::Failed, None) => "5.4.7",
+        (Action::Delayed, None) => "4.0.0",
+        (Action::Relayed, None) => "2.0.0",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify() {
+        assert_eq!("NEVER".parse(), Ok(Notify::default()));
+        assert_eq!(
+            "success,Delay".parse(),
+            Ok(Notify {
+                success: true,
+                failure: false,
+                delay: true
+            })
+        );
+        assert_eq!("NEVER,FAILURE".parse::<Notify>(), Err(()));
+        assert_eq!("".parse::<Notify>(), Err(()));
+    }
+
+    #[test]
+    fn test_wants() {
+        let default = RecipientDsn::default();
+        assert!(default.wants(Action::Failed));
+        assert!(default.wants(Action::Delayed));
+        assert!(!default.wants(Action::Relayed));
+        let never = RecipientDsn {
+            notify: Some(Notify::default()),
+            orcpt: None,
+        };
+        assert!(!never.wants(Action::Failed));
+    }
+
+    #[test]
+    fn test_decode_xtext() {
+        assert_eq!(decode_xtext("QQ+2Bx+3D1"), Some(String::from("QQ+x=1")));
+        assert_eq!(decode_xtext("a+2"), None);
+        assert_eq!(decode_xtext("a=b"), None);
+        assert_eq!(
+            decode_orcpt("rfc822;user+2Bdsn@example.com"),
+            Some(String::from("rfc822;user+dsn@example.com"))
+        );
+        assert_eq!(decode_orcpt("user@example.com"), None);
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(
+            status(Action::Failed, Some("550 5.1.1 No such user")),
+            "5.1.1"
+        );
+        assert_eq!(status(Action::Failed, Some("550 No such user")), "5.0.0");
+        assert_eq!(status(Action::Failed, Some("451 4.3.0 Try again")), "5.4.7");
+        assert_eq!(
+            status(Action::Delayed, Some("451 4.3.0 Try again")),
+            "4.3.0"
+        );
+        assert_eq!(status(Action::Delayed, None), "4.0.0");
+        assert_eq!(status(Action::Relayed, None), "2.0.0");
+    }
+
+    #[test]
+    fn test_delivery_report() {
+        let recipients = [RecipientReport {
+            address: String::from("user@example.com"),
+            orcpt: Some(String::from("rfc822;user@example.com")),
+            reason: String::from("Server did not accept RCPT TO command"),
+            reply: Some(String::from("550 5.1.1 No such user")),
+            status: None,
+        }];
+        let report = Report {
+            action: Action::Failed,
+            hostname: "localhost",
+            sender: "sender@localhost",
+            envid: Some("QQ314159"),
+            ret: Some(Ret::Hdrs),
+            arrival_date: OffsetDateTime::UNIX_EPOCH,
+            retry_until: OffsetDateTime::UNIX_EPOCH,
+            recipients: &recipients,
+            message: b"Subject: Hello\r\n\r\nSecret body",
+        };
+        let message = String::from_utf8(delivery_report(&report, "boundary").unwrap()).unwrap();
+
+        assert!(message.contains("To: <sender@localhost>\r\n"));
+        assert!(message.contains("report-type=delivery-status"));
+        assert!(message.contains("Original-Envelope-Id: QQ314159\r\n"));
+        assert!(message.contains("Final-Recipient: rfc822; user@example.com\r\n"));
+        assert!(message.contains("Original-Recipient: rfc822;user@example.com\r\n"));
+        assert!(message.contains("Action: failed\r\nStatus: 5.1.1\r\n"));
+        assert!(message.contains("Diagnostic-Code: smtp; 550 5.1.1 No such user\r\n"));
+        assert!(message.contains("text/rfc822-headers\r\n\r\nSubject: Hello\r\n--boundary--\r\n"));
+        assert!(!message.contains("Secret body"));
+    }
+
+    #[test]
+    fn test_delivery_report_own_status() {
+        let recipients = [RecipientReport {
+            address: String::from("user@unknown.example"),
+            orcpt: None,
+            reason: String::from("No mail server found for unknown.example"),
+            reply: None,
+            status: Some("5.1.2"),
+        }];
+        let report = Report {
+            action: Action::Failed,
+            hostname: "localhost",
+            sender: "sender@localhost",
+            envid: None,
+            ret: None,
+            arrival_date: OffsetDateTime::UNIX_EPOCH,
+            retry_until: OffsetDateTime::UNIX_EPOCH,
+            recipients: &recipients,
+            message: b"Subject: Hello\r\n\r\nBody\r\n",
+        };
+        let message = String::from_utf8(delivery_report(&report, "boundary").unwrap()).unwrap();
+
+        assert!(message.contains("Action: failed\r\nStatus: 5.1.2\r\n"));
+        assert!(!message.contains("Diagnostic-Code"));
+        // Failures return the whole message by default
+        assert!(message.contains("message/rfc822\r\n\r\nSubject: Hello\r\n\r\nBody\r\n"));
+    }
+}
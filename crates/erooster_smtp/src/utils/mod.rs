@@ -1 +1,4 @@
+pub mod delivery;
+pub mod dsn;
 pub mod rspamd;
+pub mod sender;
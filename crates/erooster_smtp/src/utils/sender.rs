@@ -0,0 +1,104 @@
+use mailparse::{addrparse_header, parse_headers, MailAddr};
+
+/// Whether the address is one of the addresses of the user
+pub fn owns(addresses: &[String], address: &str) -> bool {
+    addresses
+        .iter()
+        .any(|owned| owned.eq_ignore_ascii_case(address))
+}
+
+/// The addresses in the From header fields of the message.
+///
+/// Returns `None` if the header can't be parsed.
+fn header_from(message: &[u8]) -> Option<Vec<String>> {
+    let (headers, _) = parse_headers(message).ok()?;
+    let mut addresses = Vec::new();
+    for header in headers
+        .iter()
+        .filter(|header| header.get_key_ref().eq_ignore_ascii_case("from"))
+    {
+        for address in addrparse_header(header).ok()?.iter() {
+            match address {
+                MailAddr::Single(single) => addresses.push(single.addr.clone()),
+                MailAddr::Group(group) => {
+                    addresses.extend(group.addrs.iter().map(|single| single.addr.clone()));
+                }
+            }
+        }
+    }
+    Some(addresses)
+}
+
+/// Whether every address in the From header belongs to the user
+pub fn owns_header_from(message: &[u8], addresses: &[String]) -> bool {
+    header_from(message).map_or(false, |from| {
+        !from.is_empty() && from.iter().all(|address| owns(addresses, address))
+    })
+}
+
+/// Replaces the From header fields of the message with one naming the address
+pub fn rewrite_header_from(message: &[u8], address: &str) -> Vec<u8> {
+    let header_end = parse_headers(message).map_or(0, |(_, body_offset)| body_offset);
+    let (header, body) = message.split_at(header_end);
+    let mut rewritten = format!("From: <{address}>\r\n").into_bytes();
+    let mut skipping = false;
+    for line in header.split_inclusive(|byte| *byte == b'\n') {
+        // Folded lines continue the field before them
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            skipping = line
+                .split(|byte| *byte == b':')
+                .next()
+                .map_or(false, |name| {
+                    String::from_utf8_lossy(name)
+                        .trim()
+                        .eq_ignore_ascii_case("from")
+                });
+        }
+        if !skipping {
+            rewritten.extend_from_slice(line);
+        }
+    }
+    rewritten.extend_from_slice(body);
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owns_header_from() {
+        let addresses = vec![
+            String::from("user@localhost"),
+            String::from("alias@localhost"),
+        ];
+        assert!(owns_header_from(
+            b"From: User <User@localhost>\r\nSubject: Hi\r\n\r\nBody",
+            &addresses
+        ));
+        assert!(owns_header_from(
+            b"From: alias@localhost\r\n\r\nBody",
+            &addresses
+        ));
+        assert!(!owns_header_from(
+            b"From: CEO <ceo@localhost>\r\n\r\nBody",
+            &addresses
+        ));
+        assert!(!owns_header_from(
+            b"From: user@localhost, ceo@localhost\r\n\r\nBody",
+            &addresses
+        ));
+        assert!(!owns_header_from(b"Subject: Hi\r\n\r\nBody", &addresses));
+    }
+
+    #[test]
+    fn test_rewrite_header_from() {
+        assert_eq!(
+            rewrite_header_from(
+                b"Subject: Hi\r\nFrom: CEO\r\n <ceo@localhost>\r\nTo: a@b\r\n\r\nBody\r\nFrom: body",
+                "user@localhost"
+            ),
+            b"From: <user@localhost>\r\nSubject: Hi\r\nTo: a@b\r\n\r\nBody\r\nFrom: body".to_vec()
+        );
+    }
+}
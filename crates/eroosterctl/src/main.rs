@@ -33,7 +33,10 @@
 use clap::{Parser, Subcommand};
 use color_eyre::eyre::Result;
 use erooster_core::{
-    backend::database::{get_database, Database},
+    backend::{
+        database::{get_database, Database},
+        storage::{get_storage, MailStorage},
+    },
     config::Config,
     panic_handler::EroosterPanicMessage,
 };
@@ -84,6 +87,42 @@ enum Commands {
         #[clap(short, long)]
         new_password: Option<SecretString>,
     },
+    /// Rebuild the full-text search index. This works while the server is running.
+    RebuildSearchIndex {
+        /// The email of the user whose index should be rebuilt (optional, defaults to all users)
+        #[clap(short, long)]
+        email: Option<String>,
+    },
+    /// Move the folder flags and subscriptions of older versions from the maildir into the database
+    ImportFolderFlags {
+        /// The email of the user whose folders should be imported (optional, defaults to all users)
+        #[clap(short, long)]
+        email: Option<String>,
+    },
+    /// Record the Message-ID, owner and preview of messages stored by older versions so replies join their threads
+    BackfillMessageIds {
+        /// The email of the user whose messages should be updated (optional, defaults to all users)
+        #[clap(short, long)]
+        email: Option<String>,
+    },
+    /// Allow a user to send mail using another address
+    AddAlias {
+        /// The email of the user
+        #[clap(short, long)]
+        email: String,
+        /// The address the user may send as
+        #[clap(short, long)]
+        alias: String,
+    },
+    /// Save a copy of the mail a user submits in their Sent folder
+    SaveSent {
+        /// The email of the user
+        #[clap(short, long)]
+        email: String,
+        /// Stop saving copies instead
+        #[clap(short, long)]
+        disable: bool,
+    },
 }
 
 #[tokio::main]
@@ -145,6 +184,21 @@ async fn main() -> Result<()> {
         } => {
             change_password(email, current_password, new_password, config).await;
         }
+        Commands::RebuildSearchIndex { email } => {
+            rebuild_search_index(email, config).await;
+        }
+        Commands::ImportFolderFlags { email } => {
+            import_folder_flags(email, config).await;
+        }
+        Commands::BackfillMessageIds { email } => {
+            backfill_message_ids(email, config).await;
+        }
+        Commands::AddAlias { email, alias } => {
+            add_alias(email, alias, config).await;
+        }
+        Commands::SaveSent { email, disable } => {
+            save_sent(email, !disable, config).await;
+        }
     }
     Ok(())
 }
@@ -482,3 +536,199 @@ async fn actual_change_password(
         .await?;
     Ok(())
 }
+
+async fn rebuild_search_index(username: Option<String>, config: Arc<Config>) {
+    let spinner_style = ProgressStyle::default_spinner()
+        .template("{spinner} {wide_msg}")
+        .expect("template working")
+        .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ");
+    clearscreen::clear().expect("failed to clear screen");
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(spinner_style);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb.set_message(
+        "Rebuilding the search index..."
+            .fg::<BrightGreen>()
+            .to_string(),
+    );
+
+    let result = actual_rebuild_search_index(username, config).await;
+
+    clearscreen::clear().expect("failed to clear screen");
+    match result {
+        Ok(count) => pb.finish_with_message(
+            format!("Search index was successfully rebuilt with {count} messages")
+                .fg::<BrightGreen>()
+                .to_string(),
+        ),
+        Err(error) => pb.finish_with_message(format!(
+            "{}\n{}",
+            "There has been an error while rebuilding the search index:".fg::<BrightRed>(),
+            error.fg::<BrightRed>()
+        )),
+    }
+}
+
+async fn actual_rebuild_search_index(
+    username: Option<String>,
+    config: Arc<Config>,
+) -> Result<usize> {
+    let database = get_database(Arc::clone(&config)).await?;
+    let storage = get_storage(Arc::new(database), Arc::clone(&config));
+
+    let mut count = 0;
+    for username in usernames(username, &config)? {
+        count += storage.rebuild_search_index(&username).await?;
+    }
+    Ok(count)
+}
+
+async fn import_folder_flags(username: Option<String>, config: Arc<Config>) {
+    let spinner_style = ProgressStyle::default_spinner()
+        .template("{spinner} {wide_msg}")
+        .expect("template working")
+        .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ");
+    clearscreen::clear().expect("failed to clear screen");
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(spinner_style);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb.set_message("Importing folder flags...".fg::<BrightGreen>().to_string());
+
+    let result = actual_import_folder_flags(username, config).await;
+
+    clearscreen::clear().expect("failed to clear screen");
+    match result {
+        Ok(count) => pb.finish_with_message(
+            format!("Successfully imported the flags of {count} folders")
+                .fg::<BrightGreen>()
+                .to_string(),
+        ),
+        Err(error) => pb.finish_with_message(format!(
+            "{}\n{}",
+            "There has been an error while importing the folder flags:".fg::<BrightRed>(),
+            error.fg::<BrightRed>()
+        )),
+    }
+}
+
+async fn actual_import_folder_flags(
+    username: Option<String>,
+    config: Arc<Config>,
+) -> Result<usize> {
+    let database = get_database(Arc::clone(&config)).await?;
+    let storage = get_storage(Arc::new(database), Arc::clone(&config));
+
+    let mut count = 0;
+    for username in usernames(username, &config)? {
+        count += storage.import_folder_flags(&username).await?;
+    }
+    Ok(count)
+}
+
+async fn backfill_message_ids(username: Option<String>, config: Arc<Config>) {
+    let spinner_style = ProgressStyle::default_spinner()
+        .template("{spinner} {wide_msg}")
+        .expect("template working")
+        .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ");
+    clearscreen::clear().expect("failed to clear screen");
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(spinner_style);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb.set_message("Backfilling message ids...".fg::<BrightGreen>().to_string());
+
+    let result = actual_backfill_message_ids(username, config).await;
+
+    clearscreen::clear().expect("failed to clear screen");
+    match result {
+        Ok(count) => pb.finish_with_message(
+            format!("Successfully updated {count} messages")
+                .fg::<BrightGreen>()
+                .to_string(),
+        ),
+        Err(error) => pb.finish_with_message(format!(
+            "{}\n{}",
+            "There has been an error while backfilling the message ids:".fg::<BrightRed>(),
+            error.fg::<BrightRed>()
+        )),
+    }
+}
+
+async fn actual_backfill_message_ids(
+    username: Option<String>,
+    config: Arc<Config>,
+) -> Result<usize> {
+    let database = get_database(Arc::clone(&config)).await?;
+    let storage = get_storage(Arc::new(database), Arc::clone(&config));
+
+    let mut count = 0;
+    for username in usernames(username, &config)? {
+        count += storage.backfill_message_ids(&username).await?;
+    }
+    Ok(count)
+}
+
+async fn add_alias(username: String, alias: String, config: Arc<Config>) {
+    let result = actual_add_alias(&username, &alias, config).await;
+    match result {
+        Ok(()) => println!(
+            "{}",
+            format!("{username} may now send as {alias}").fg::<BrightGreen>()
+        ),
+        Err(error) => println!(
+            "{}\n{}",
+            "There has been an error while adding the alias:".fg::<BrightRed>(),
+            error.fg::<BrightRed>()
+        ),
+    }
+}
+
+async fn actual_add_alias(username: &str, alias: &str, config: Arc<Config>) -> Result<()> {
+    let database = get_database(config).await?;
+    let username = username.to_lowercase();
+    if !database.user_exists(&username).await {
+        color_eyre::eyre::bail!("The user {username} does not exist");
+    }
+    database.add_alias(&username, alias).await
+}
+
+async fn save_sent(username: String, enabled: bool, config: Arc<Config>) {
+    let result = actual_save_sent(&username, enabled, config).await;
+    match result {
+        Ok(()) if enabled => println!(
+            "{}",
+            format!("Mail submitted by {username} now gets saved in the Sent folder")
+                .fg::<BrightGreen>()
+        ),
+        Ok(()) => println!(
+            "{}",
+            format!("Mail submitted by {username} no longer gets saved in the Sent folder")
+                .fg::<BrightGreen>()
+        ),
+        Err(error) => println!(
+            "{}\n{}",
+            "There has been an error while changing the setting:".fg::<BrightRed>(),
+            error.fg::<BrightRed>()
+        ),
+    }
+}
+
+async fn actual_save_sent(username: &str, enabled: bool, config: Arc<Config>) -> Result<()> {
+    let database = get_database(config).await?;
+    let username = username.to_lowercase();
+    if !database.user_exists(&username).await {
+        color_eyre::eyre::bail!("The user {username} does not exist");
+    }
+    database.set_save_sent_mail(&username, enabled).await
+}
+
+/// The given user or all users which have mail folders
+fn usernames(username: Option<String>, config: &Config) -> Result<Vec<String>> {
+    if let Some(username) = username {
+        return Ok(vec![username.to_lowercase()]);
+    }
+    Ok(std::fs::read_dir(&config.mail.maildir_folders)?
+        .filter_map(std::result::Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str().map(ToString::to_string))
+        .collect())
+}